@@ -0,0 +1,70 @@
+// Compares GC pauses of both collector modes on a program that keeps a big
+// tree alive while making lots of garbage. Run with `cargo bench`. Pauses
+// only mean something next to the collections they finished and how big the
+// heap got meanwhile, a collector that never finishes has tiny pauses too.
+use std::process;
+use std::time::Instant;
+
+use rust_lox::compiler;
+use rust_lox::parser;
+use rust_lox::resolver;
+use rust_lox::vm::{GcMode, Vm};
+
+const SOURCE: &str = "
+class Tree {
+  init(depth) {
+    if (depth > 0) {
+      this.left = Tree(depth - 1);
+      this.right = Tree(depth - 1);
+    }
+  }
+}
+
+var kept = Tree(14);
+var checksum = 0;
+
+for (var i = 0; i < 200000; i = i + 1) {
+  var garbage = Tree(2);
+  var name = \"tree\" + \"!\";
+  checksum = checksum + 1;
+}
+
+print checksum;
+";
+
+fn main() {
+  let statements = parser::parse(SOURCE).unwrap();
+  let resolution = resolver::resolve(&statements).unwrap();
+  let script = compiler::compile(&statements, &resolution).unwrap();
+
+  println!(
+    "{:<14} {:>12} {:>8} {:>12} {:>12} {:>12} {:>12}",
+    "mode", "collections", "pauses", "max pause", "total pause", "peak heap", "run time"
+  );
+
+  for mode in &[GcMode::StopTheWorld, GcMode::Incremental] {
+    let mut vm = Vm::new(Vec::new());
+    vm.heap_mut().set_mode(*mode);
+
+    let start = Instant::now();
+    vm.interpret(script.clone()).unwrap();
+    let run_time = start.elapsed();
+    let stats = vm.heap().stats();
+
+    println!(
+      "{:<14} {:>12} {:>8} {:>12?} {:>12?} {:>12} {:>12?}",
+      format!("{:?}", mode),
+      stats.collections,
+      stats.pauses,
+      stats.max_pause,
+      stats.total_pause,
+      format!("{}KB", stats.peak_bytes / 1024),
+      run_time
+    );
+
+    if stats.collections == 0 {
+      eprintln!("{:?} didn't finish a single collection", mode);
+      process::exit(1);
+    }
+  }
+}
@@ -0,0 +1,266 @@
+// The executable format of the Ruby implementation in s1-web/a_lox, as
+// s1-web/test_suite/precompile.rb writes it. Lets the shared test suite run
+// without going through our own front end.
+mod vm;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Deserialize;
+
+pub use self::vm::Vm;
+
+// a_lox/lib/a_lox/opcodes.rb
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "SCREAMING-KEBAB-CASE")]
+pub enum Opcode {
+  LoadConstant,
+  LoadClosure,
+  Return,
+  Add,
+  Subtract,
+  Multiply,
+  Divide,
+  Equal,
+  Greater,
+  Lesser,
+  Not,
+  Negate,
+  Pop,
+  True,
+  False,
+  Nil,
+  Print,
+  DefineGlobal,
+  GetGlobal,
+  SetGlobal,
+  GetLocal,
+  SetLocal,
+  SetHeap,
+  InitHeap,
+  GetHeap,
+  JumpOnFalse,
+  Jump,
+  Call,
+}
+
+impl Opcode {
+  // As it's spelled in the JSON
+  pub fn name(&self) -> &'static str {
+    match self {
+      Opcode::LoadConstant => "LOAD-CONSTANT",
+      Opcode::LoadClosure => "LOAD-CLOSURE",
+      Opcode::Return => "RETURN",
+      Opcode::Add => "ADD",
+      Opcode::Subtract => "SUBTRACT",
+      Opcode::Multiply => "MULTIPLY",
+      Opcode::Divide => "DIVIDE",
+      Opcode::Equal => "EQUAL",
+      Opcode::Greater => "GREATER",
+      Opcode::Lesser => "LESSER",
+      Opcode::Not => "NOT",
+      Opcode::Negate => "NEGATE",
+      Opcode::Pop => "POP",
+      Opcode::True => "TRUE",
+      Opcode::False => "FALSE",
+      Opcode::Nil => "NIL",
+      Opcode::Print => "PRINT",
+      Opcode::DefineGlobal => "DEFINE-GLOBAL",
+      Opcode::GetGlobal => "GET-GLOBAL",
+      Opcode::SetGlobal => "SET-GLOBAL",
+      Opcode::GetLocal => "GET-LOCAL",
+      Opcode::SetLocal => "SET-LOCAL",
+      Opcode::SetHeap => "SET-HEAP",
+      Opcode::InitHeap => "INIT-HEAP",
+      Opcode::GetHeap => "GET-HEAP",
+      Opcode::JumpOnFalse => "JUMP-ON-FALSE",
+      Opcode::Jump => "JUMP",
+      Opcode::Call => "CALL",
+    }
+  }
+}
+
+// Opcodes are strings and operands are numbers in the same array
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum Code {
+  Op(Opcode),
+  Byte(u8),
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct FunctionDescriptor {
+  pub name: String,
+  pub arity: usize,
+  // Ruby object ids of the variables the function allocates on the heap and
+  // of the ones from enclosing functions it uses
+  pub heap_slots: Vec<i64>,
+  pub heap_usages: Vec<i64>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Constant {
+  Function(FunctionDescriptor),
+  Number(f64),
+  String(String),
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Executable {
+  pub functions: HashMap<String, Vec<Code>>,
+  pub constants: Vec<Constant>,
+}
+
+// One file of s1-web/test_suite/compiled
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct TestCase {
+  pub executable: Executable,
+  // Without the final newline
+  pub expected_output: String,
+}
+
+impl Executable {
+  pub fn from_json(json: &str) -> serde_json::Result<Executable> {
+    serde_json::from_str(json)
+  }
+}
+
+impl TestCase {
+  pub fn from_json(json: &str) -> serde_json::Result<TestCase> {
+    serde_json::from_str(json)
+  }
+}
+
+// The format has no line information, errors point at the instruction instead
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExecutionError {
+  pub function: String,
+  pub offset: usize,
+  pub message: String,
+}
+
+impl fmt::Display for ExecutionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "runtime error. function: {} offset: {} - error: {}",
+      self.function, self.offset, self.message
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run(executable: &Executable) -> Result<String, ExecutionError> {
+    let mut vm = Vm::new(executable, Vec::new());
+    vm.run()?;
+
+    Ok(String::from_utf8(vm.into_output()).unwrap())
+  }
+
+  #[test]
+  fn test_compiled_test_suite() {
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../s1-web/test_suite/compiled");
+    let mut count = 0;
+
+    for entry in std::fs::read_dir(directory).unwrap() {
+      let path = entry.unwrap().path();
+      let test_case = TestCase::from_json(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+      assert_eq!(
+        run(&test_case.executable).unwrap(),
+        format!("{}\n", test_case.expected_output),
+        "{:?}",
+        path
+      );
+      count += 1;
+    }
+
+    assert!(count > 0);
+  }
+
+  #[test]
+  fn test_parse() {
+    let executable = Executable::from_json(
+      r#"{
+        "functions": { "__toplevel__": ["LOAD-CONSTANT", 0, "JUMP-ON-FALSE", 255, 233] },
+        "constants": [
+          { "type": "function", "arity": 1, "name": "f", "heap_slots": [880], "heap_usages": [] },
+          1.0,
+          "a"
+        ]
+      }"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+      executable.functions["__toplevel__"],
+      vec![
+        Code::Op(Opcode::LoadConstant),
+        Code::Byte(0),
+        Code::Op(Opcode::JumpOnFalse),
+        Code::Byte(255),
+        Code::Byte(233)
+      ]
+    );
+    assert!(matches!(&executable.constants[0], Constant::Function(f) if f.heap_slots == [880]));
+    assert_eq!(executable.constants[1], Constant::Number(1.0));
+    assert_eq!(executable.constants[2], Constant::String(String::from("a")));
+  }
+
+  #[test]
+  fn test_unknown_opcode() {
+    assert!(Executable::from_json(
+      r#"{ "functions": { "__toplevel__": ["FROBNICATE"] }, "constants": [] }"#
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn test_malformed_code() {
+    let cases = [
+      (r#"["LOAD-CONSTANT"]"#, "Unexpected end of code"),
+      (r#"["LOAD-CONSTANT", 3, "RETURN"]"#, "No constant 3"),
+      (r#"[0, "RETURN"]"#, "Expected an opcode but got 0"),
+      (
+        r#"["LOAD-CONSTANT", "NIL"]"#,
+        "Expected an operand but got NIL",
+      ),
+      (r#"["ADD"]"#, "Stack underflow"),
+      (r#"["GET-LOCAL", 7]"#, "No local in slot 7"),
+      (r#"["GET-HEAP", 0, 1]"#, "No heap slot 1"),
+      (r#"["JUMP", 0, 9]"#, "Jump past the end of the code"),
+      (r#"["LOAD-CLOSURE", 0]"#, "No function named g"),
+      (r#"["NIL", "CALL", 0]"#, "nil is not callable"),
+    ];
+
+    for (code, message) in &cases {
+      let json = format!(
+        r#"{{ "functions": {{ "__toplevel__": {} }}, "constants": [{{ "type": "function", "arity": 0, "name": "g", "heap_slots": [], "heap_usages": [] }}] }}"#,
+        code
+      );
+      let executable = Executable::from_json(&json).unwrap();
+
+      assert_eq!(run(&executable).unwrap_err().message, *message, "{}", code);
+    }
+  }
+
+  #[test]
+  fn test_runtime_errors() {
+    let executable = Executable::from_json(
+      r#"{
+        "functions": { "__toplevel__": ["GET-GLOBAL", 0, "PRINT", "NIL", "RETURN"] },
+        "constants": ["missing"]
+      }"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+      run(&executable).unwrap_err().to_string(),
+      "runtime error. function: __toplevel__ offset: 0 - error: Undefined global missing"
+    );
+  }
+}
@@ -0,0 +1,478 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+use super::{Code, Constant, Executable, ExecutionError, FunctionDescriptor, Opcode};
+use crate::interpreter::format_number;
+
+const TOP_LEVEL: &str = "__toplevel__";
+
+// Closed over variables always live on the heap in this format, in cells
+// shared by the frame declaring them and every closure using them
+type HeapRef<'e> = Rc<RefCell<Value<'e>>>;
+
+#[derive(Clone)]
+enum Value<'e> {
+  Nil,
+  Bool(bool),
+  Number(f64),
+  String(Rc<str>),
+  Callable(Rc<Callable<'e>>),
+}
+
+impl<'e> Value<'e> {
+  fn is_truthy(&self) -> bool {
+    !matches!(self, Value::Nil | Value::Bool(false))
+  }
+}
+
+impl<'e> PartialEq for Value<'e> {
+  fn eq(&self, other: &Value<'e>) -> bool {
+    match (self, other) {
+      (Value::Nil, Value::Nil) => true,
+      (Value::Bool(a), Value::Bool(b)) => a == b,
+      (Value::Number(a), Value::Number(b)) => a == b,
+      (Value::String(a), Value::String(b)) => a == b,
+      (Value::Callable(a), Value::Callable(b)) => Rc::ptr_eq(a, b),
+      _ => false,
+    }
+  }
+}
+
+// Same as lox_object_to_string in a_lox/lib/a_lox/vm.rb
+impl<'e> fmt::Display for Value<'e> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Value::Nil => write!(f, "nil"),
+      Value::Bool(boolean) => write!(f, "{}", boolean),
+      Value::Number(number) => write!(f, "{}", format_number(*number)),
+      Value::String(string) => write!(f, "{}", string),
+      Value::Callable(callable) => write!(
+        f,
+        "fun {}/{}",
+        callable.descriptor.name, callable.descriptor.arity
+      ),
+    }
+  }
+}
+
+struct Callable<'e> {
+  descriptor: &'e FunctionDescriptor,
+  // The heap cells of enclosing functions this one uses
+  heap_view: HashMap<i16, HeapRef<'e>>,
+}
+
+struct CallFrame<'e> {
+  function: &'e str,
+  code: &'e [Code],
+  ip: usize,
+  // Offset of the instruction being executed
+  start: usize,
+  // Stack index of the first argument, what GET-LOCAL 0 reads
+  stack_top: usize,
+  // None for the top level
+  callable: Option<Rc<Callable<'e>>>,
+  heap_slots: HashMap<i16, HeapRef<'e>>,
+}
+
+type ExecutionResult<T> = Result<T, ExecutionError>;
+
+pub struct Vm<'e, W: Write> {
+  executable: &'e Executable,
+  stack: Vec<Value<'e>>,
+  globals: HashMap<Rc<str>, Value<'e>>,
+  frames: Vec<CallFrame<'e>>,
+  output: W,
+}
+
+impl<'e, W: Write> Vm<'e, W> {
+  pub fn new(executable: &'e Executable, output: W) -> Vm<'e, W> {
+    Vm {
+      executable,
+      stack: Vec::new(),
+      globals: HashMap::new(),
+      frames: Vec::new(),
+      output,
+    }
+  }
+
+  pub fn output(&self) -> &W {
+    &self.output
+  }
+
+  pub fn into_output(self) -> W {
+    self.output
+  }
+
+  // Nothing is trusted, malformed code ends in an error rather than a panic
+  pub fn run(&mut self) -> ExecutionResult<()> {
+    let code = match self.executable.functions.get(TOP_LEVEL) {
+      Some(code) => code,
+      None => {
+        return Err(ExecutionError {
+          function: String::from(TOP_LEVEL),
+          offset: 0,
+          message: format!("No function named {}", TOP_LEVEL),
+        })
+      }
+    };
+
+    self.stack.clear();
+    self.frames = vec![CallFrame {
+      function: TOP_LEVEL,
+      code,
+      ip: 0,
+      start: 0,
+      stack_top: 0,
+      callable: None,
+      heap_slots: HashMap::new(),
+    }];
+
+    while !self.frames.is_empty() {
+      self.execute_instruction()?;
+    }
+
+    Ok(())
+  }
+
+  fn execute_instruction(&mut self) -> ExecutionResult<()> {
+    let frame = self.frame_mut();
+    frame.start = frame.ip;
+
+    match self.read_op()? {
+      Opcode::LoadConstant => {
+        let value = match self.read_constant()? {
+          Constant::Number(number) => Value::Number(*number),
+          Constant::String(string) => Value::String(Rc::from(string.as_str())),
+          Constant::Function(_) => return Err(self.error("Function constant loaded as a value")),
+        };
+
+        self.stack.push(value);
+      }
+      Opcode::LoadClosure => {
+        let descriptor = match self.read_constant()? {
+          Constant::Function(descriptor) => descriptor,
+          _ => return Err(self.error("Closure of a constant that isn't a function")),
+        };
+
+        if !self.executable.functions.contains_key(&descriptor.name) {
+          return Err(self.error(format!("No function named {}", descriptor.name)));
+        }
+
+        let mut heap_view = HashMap::new();
+
+        for usage in &descriptor.heap_usages {
+          heap_view.insert(heap_id(*usage), self.heap(heap_id(*usage))?);
+        }
+
+        self.stack.push(Value::Callable(Rc::new(Callable {
+          descriptor,
+          heap_view,
+        })));
+      }
+      Opcode::Return => {
+        let result = self.pop()?;
+        let frame = self.frames.pop().unwrap();
+
+        if !self.frames.is_empty() {
+          self.stack.truncate(frame.stack_top - 1);
+          self.stack.push(result);
+        }
+      }
+      Opcode::Add => {
+        let right = self.pop()?;
+        let left = self.pop()?;
+
+        let sum = match (left, right) {
+          (Value::Number(left), Value::Number(right)) => Value::Number(left + right),
+          (Value::String(left), Value::String(right)) => {
+            Value::String(Rc::from(format!("{}{}", left, right)))
+          }
+          _ => return Err(self.error("Operands must be two numbers or two strings")),
+        };
+
+        self.stack.push(sum);
+      }
+      Opcode::Subtract => self.binary(|left, right| Value::Number(left - right))?,
+      Opcode::Multiply => self.binary(|left, right| Value::Number(left * right))?,
+      Opcode::Divide => self.binary(|left, right| Value::Number(left / right))?,
+      Opcode::Greater => self.binary(|left, right| Value::Bool(left > right))?,
+      Opcode::Lesser => self.binary(|left, right| Value::Bool(left < right))?,
+      Opcode::Equal => {
+        let right = self.pop()?;
+        let left = self.pop()?;
+
+        self.stack.push(Value::Bool(left == right));
+      }
+      Opcode::Not => {
+        let value = self.pop()?;
+        self.stack.push(Value::Bool(!value.is_truthy()));
+      }
+      Opcode::Negate => match self.pop()? {
+        Value::Number(number) => self.stack.push(Value::Number(-number)),
+        _ => return Err(self.error("Operand must be a number")),
+      },
+      Opcode::Pop => {
+        self.pop()?;
+      }
+      Opcode::True => self.stack.push(Value::Bool(true)),
+      Opcode::False => self.stack.push(Value::Bool(false)),
+      Opcode::Nil => self.stack.push(Value::Nil),
+      Opcode::Print => {
+        let value = self.pop()?;
+
+        if let Err(error) = writeln!(self.output, "{}", value) {
+          return Err(self.error(format!("Couldn't write output: {}", error)));
+        }
+      }
+      Opcode::DefineGlobal => {
+        let name = self.read_name()?;
+        let value = self.pop()?;
+
+        self.globals.insert(name, value);
+      }
+      Opcode::GetGlobal => {
+        let name = self.read_name()?;
+
+        match self.globals.get(&name) {
+          Some(value) => self.stack.push(value.clone()),
+          None => return Err(self.error(format!("Undefined global {}", name))),
+        }
+      }
+      // Defines the global if it doesn't exist, like the Ruby VM
+      Opcode::SetGlobal => {
+        let name = self.read_name()?;
+        let value = self.peek()?;
+
+        self.globals.insert(name, value);
+      }
+      Opcode::GetLocal => {
+        let slot = self.local_slot()?;
+        self.stack.push(self.stack[slot].clone());
+      }
+      Opcode::SetLocal => {
+        let slot = self.local_slot()?;
+        self.stack[slot] = self.peek()?;
+      }
+      Opcode::InitHeap => {
+        let id = self.read_short()?;
+        let value = self.pop()?;
+
+        match self.frame().heap_slots.get(&id) {
+          Some(cell) => *cell.borrow_mut() = value,
+          None => return Err(self.error(format!("No heap slot {}", id))),
+        }
+      }
+      Opcode::SetHeap => {
+        let id = self.read_short()?;
+        let value = self.peek()?;
+
+        *self.heap(id)?.borrow_mut() = value;
+      }
+      Opcode::GetHeap => {
+        let id = self.read_short()?;
+        let value = self.heap(id)?.borrow().clone();
+
+        self.stack.push(value);
+      }
+      Opcode::JumpOnFalse => {
+        let offset = self.read_short()?;
+
+        if !self.peek()?.is_truthy() {
+          self.jump(offset)?;
+        }
+      }
+      Opcode::Jump => {
+        let offset = self.read_short()?;
+        self.jump(offset)?;
+      }
+      Opcode::Call => {
+        let argument_count = self.read_byte()? as usize;
+        self.call(argument_count)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn call(&mut self, argument_count: usize) -> ExecutionResult<()> {
+    if argument_count >= self.stack.len() {
+      return Err(self.error("Stack underflow"));
+    }
+
+    let stack_top = self.stack.len() - argument_count;
+    let callable = match &self.stack[stack_top - 1] {
+      Value::Callable(callable) => Rc::clone(callable),
+      other => return Err(self.error(format!("{} is not callable", other))),
+    };
+
+    if callable.descriptor.arity != argument_count {
+      return Err(self.error(format!(
+        "Expected {} arguments but got {}",
+        callable.descriptor.arity, argument_count
+      )));
+    }
+
+    let executable = self.executable;
+    let (function, code) = executable
+      .functions
+      .get_key_value(&callable.descriptor.name)
+      .expect("[bug] closure created for a missing function");
+
+    let heap_slots = callable
+      .descriptor
+      .heap_slots
+      .iter()
+      .map(|slot| (heap_id(*slot), Rc::new(RefCell::new(Value::Nil))))
+      .collect();
+
+    self.frames.push(CallFrame {
+      function,
+      code,
+      ip: 0,
+      start: 0,
+      stack_top,
+      callable: Some(callable),
+      heap_slots,
+    });
+
+    Ok(())
+  }
+
+  fn binary(&mut self, operation: fn(f64, f64) -> Value<'e>) -> ExecutionResult<()> {
+    let right = self.pop()?;
+    let left = self.pop()?;
+
+    match (left, right) {
+      (Value::Number(left), Value::Number(right)) => {
+        self.stack.push(operation(left, right));
+        Ok(())
+      }
+      _ => Err(self.error("Operands must be numbers")),
+    }
+  }
+
+  fn jump(&mut self, offset: i16) -> ExecutionResult<()> {
+    let frame = self.frame();
+    let target = frame.ip as isize + offset as isize;
+
+    if target < 0 || target as usize > frame.code.len() {
+      return Err(self.error("Jump past the end of the code"));
+    }
+
+    self.frame_mut().ip = target as usize;
+    Ok(())
+  }
+
+  // The enclosing function's cell if this is a closure over it, otherwise one
+  // of the current frame's own
+  fn heap(&self, id: i16) -> ExecutionResult<HeapRef<'e>> {
+    let frame = self.frame();
+    let from_view = frame
+      .callable
+      .as_ref()
+      .and_then(|callable| callable.heap_view.get(&id));
+
+    match from_view.or_else(|| frame.heap_slots.get(&id)) {
+      Some(cell) => Ok(Rc::clone(cell)),
+      None => Err(self.error(format!("No heap slot {}", id))),
+    }
+  }
+
+  fn local_slot(&mut self) -> ExecutionResult<usize> {
+    let slot = self.read_byte()? as usize;
+    let index = self.frame().stack_top + slot;
+
+    if index >= self.stack.len() {
+      return Err(self.error(format!("No local in slot {}", slot)));
+    }
+
+    Ok(index)
+  }
+
+  fn read(&mut self) -> ExecutionResult<Code> {
+    let frame = self.frame_mut();
+
+    match frame.code.get(frame.ip) {
+      Some(code) => {
+        frame.ip += 1;
+        Ok(*code)
+      }
+      None => Err(self.error("Unexpected end of code")),
+    }
+  }
+
+  fn read_op(&mut self) -> ExecutionResult<Opcode> {
+    match self.read()? {
+      Code::Op(op) => Ok(op),
+      Code::Byte(byte) => Err(self.error(format!("Expected an opcode but got {}", byte))),
+    }
+  }
+
+  fn read_byte(&mut self) -> ExecutionResult<u8> {
+    match self.read()? {
+      Code::Byte(byte) => Ok(byte),
+      Code::Op(op) => Err(self.error(format!("Expected an operand but got {}", op.name()))),
+    }
+  }
+
+  // Signed and big endian
+  fn read_short(&mut self) -> ExecutionResult<i16> {
+    Ok(i16::from_be_bytes([self.read_byte()?, self.read_byte()?]))
+  }
+
+  fn read_constant(&mut self) -> ExecutionResult<&'e Constant> {
+    let index = self.read_byte()? as usize;
+
+    match self.executable.constants.get(index) {
+      Some(constant) => Ok(constant),
+      None => Err(self.error(format!("No constant {}", index))),
+    }
+  }
+
+  fn read_name(&mut self) -> ExecutionResult<Rc<str>> {
+    match self.read_constant()? {
+      Constant::String(name) => Ok(Rc::from(name.as_str())),
+      _ => Err(self.error("Global name that isn't a string")),
+    }
+  }
+
+  fn pop(&mut self) -> ExecutionResult<Value<'e>> {
+    match self.stack.pop() {
+      Some(value) => Ok(value),
+      None => Err(self.error("Stack underflow")),
+    }
+  }
+
+  fn peek(&self) -> ExecutionResult<Value<'e>> {
+    match self.stack.last() {
+      Some(value) => Ok(value.clone()),
+      None => Err(self.error("Stack underflow")),
+    }
+  }
+
+  fn frame(&self) -> &CallFrame<'e> {
+    self.frames.last().unwrap()
+  }
+
+  fn frame_mut(&mut self) -> &mut CallFrame<'e> {
+    self.frames.last_mut().unwrap()
+  }
+
+  fn error(&self, message: impl Into<String>) -> ExecutionError {
+    let frame = self.frame();
+
+    ExecutionError {
+      function: frame.function.to_string(),
+      offset: frame.start,
+      message: message.into(),
+    }
+  }
+}
+
+// The code only has room for the low 16 bits of the Ruby object id
+fn heap_id(id: i64) -> i16 {
+  id as i16
+}
@@ -2,7 +2,35 @@
 extern crate rocket;
 
 use rocket::form::Form;
+use rocket::http::ContentType;
+use rust_lox::chunk::CompiledFunction;
+use rust_lox::compiler;
+use rust_lox::parser;
+use rust_lox::resolver;
 use rust_lox::scanner;
+use rust_lox::tree_printer::TreePrinter;
+use rust_lox::vm::{Limits, Vm, VmError};
+use serde_json::json;
+use std::rc::Rc;
+use std::time::Duration;
+
+// Steps /step answers with at most, so a program that never ends still gets
+// a response
+const MAX_STEPS: usize = 10_000;
+// Bytes of serialized steps it answers with at most, since a step holds the
+// whole stack
+const MAX_STEP_BYTES: usize = 8 * 1024 * 1024;
+
+// What a submitted script gets to use, so it can't tie up the server
+fn limits() -> Limits {
+  Limits {
+    instructions: Some(50_000_000),
+    time: Some(Duration::from_secs(2)),
+    call_depth: Some(64),
+    heap_bytes: Some(64 * 1024 * 1024),
+    output_bytes: Some(1024 * 1024),
+  }
+}
 
 #[derive(FromForm)]
 struct Source<'a> {
@@ -10,14 +38,130 @@ struct Source<'a> {
 }
 
 #[post("/", data = "<form>")]
-fn index<'a>(form: Form<Source>) -> String {
+fn index(form: Form<Source>) -> String {
   let source = form.into_inner().source;
   let tokens: Vec<scanner::Token> = scanner::Scan::new(source).collect();
 
   serde_json::to_string(&tokens).unwrap_or(String::from(r#"{error: "can't lex this"}"#))
 }
 
+#[post("/analyze", data = "<form>")]
+fn analyze(form: Form<Source>) -> (ContentType, String) {
+  let source = form.into_inner().source;
+  let tokens: Vec<scanner::Token> = scanner::Scan::new(source).collect();
+
+  let analysis = parser::parse(source)
+    .map_err(|errors| errors.iter().map(|error| error.to_string()).collect())
+    .and_then(|statements| {
+      resolver::resolve(&statements)
+        .map(|resolution| TreePrinter::new(&resolution).print(&statements))
+        .map_err(|errors| errors.iter().map(|error| error.to_string()).collect())
+    });
+
+  let response = match analysis {
+    Ok(tree) => json!({
+      "tokens": tokens,
+      "tree": tree,
+    }),
+    Err::<_, Vec<String>>(errors) => json!({
+      "errors": errors,
+    }),
+  };
+
+  (ContentType::JSON, response.to_string())
+}
+
+#[post("/step", data = "<form>")]
+fn step(form: Form<Source>) -> (ContentType, String) {
+  let response = match compile(form.into_inner().source) {
+    Ok(script) => steps(script),
+    Err(errors) => json!({
+      "errors": errors,
+    }),
+  };
+
+  (ContentType::JSON, response.to_string())
+}
+
+#[post("/run", data = "<form>")]
+fn run(form: Form<Source>) -> (ContentType, String) {
+  let response = match compile(form.into_inner().source) {
+    Ok(script) => {
+      let mut vm = Vm::new(Vec::new());
+      vm.set_limits(limits());
+      let result = vm.interpret(script);
+
+      json!({
+        "output": String::from_utf8_lossy(vm.output()),
+        "error": result.as_ref().err().map(|error| error.to_string()),
+        "limit_exceeded": matches!(result, Err(VmError::LimitExceeded(_))),
+      })
+    }
+    Err(errors) => json!({
+      "errors": errors,
+    }),
+  };
+
+  (ContentType::JSON, response.to_string())
+}
+
+fn compile(source: &str) -> Result<Rc<CompiledFunction>, Vec<String>> {
+  let statements = parser::parse(source).map_err(|errors| messages(&errors))?;
+  let resolution = resolver::resolve(&statements).map_err(|errors| messages(&errors))?;
+
+  compiler::compile(&statements, &resolution).map_err(|errors| messages(&errors))
+}
+
+fn messages(errors: &[impl ToString]) -> Vec<String> {
+  errors.iter().map(|error| error.to_string()).collect()
+}
+
+// Every state the VM goes through with what each instruction printed
+fn steps(script: Rc<CompiledFunction>) -> serde_json::Value {
+  let mut vm = Vm::new(Vec::new());
+  vm.set_limits(limits());
+  let mut steps = Vec::new();
+  let mut bytes = 0;
+  let mut truncated = false;
+  let mut error = vm.start(script).err();
+
+  while error.is_none() {
+    if steps.len() == MAX_STEPS {
+      truncated = true;
+      break;
+    }
+
+    let printed = vm.output().len();
+
+    match vm.step() {
+      Ok(Some(state)) => {
+        let step = json!({
+          "state": state,
+          "output": String::from_utf8_lossy(&vm.output()[printed..]),
+        });
+
+        bytes += step.to_string().len();
+        if bytes > MAX_STEP_BYTES {
+          truncated = true;
+          break;
+        }
+
+        steps.push(step);
+      }
+      Ok(None) => break,
+      Err(vm_error) => error = Some(vm_error),
+    }
+  }
+
+  json!({
+    "steps": steps,
+    "error": error.as_ref().map(|error| error.to_string()),
+    "limit_exceeded": matches!(error, Some(VmError::LimitExceeded(_))),
+    "truncated": truncated,
+  })
+}
+
 #[launch]
 fn rocket() -> _ {
-  rocket::build().mount("/", routes![index])
+  rocket::build().mount("/", routes![index, analyze, step, run])
 }
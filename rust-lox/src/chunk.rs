@@ -0,0 +1,379 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+use super::interpreter::format_number;
+
+// OP_CONSTANT_LONG takes a 24 bit operand
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+// Same order as 26-gc/chunk.h so the byte values match clox
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[repr(u8)]
+pub enum OpCode {
+  Constant,
+  Closure,
+  DefineGlobal,
+  SetGlobal,
+  GetGlobal,
+  SetUpvalue,
+  GetUpvalue,
+  SetLocal,
+  GetLocal,
+  Pop,
+  Nil,
+  True,
+  False,
+  Negate,
+  Add,
+  Subtract,
+  Multiply,
+  Divide,
+  Not,
+  Equal,
+  Greater,
+  Less,
+  Print,
+  JumpIfFalse,
+  Jump,
+  Loop,
+  Return,
+  Call,
+  CloseUpvalue,
+  // Not in clox, for chunks with more than 256 constants
+  ConstantLong,
+  // Classes, as the book adds them after garbage collection
+  Class,
+  GetProperty,
+  SetProperty,
+  Method,
+  Inherit,
+  GetSuper,
+  // Superinstructions only the optimizer emits. OP_ADD_LOCALS is two
+  // OP_GET_LOCALs and an OP_ADD, OP_ADD_CONSTANT an OP_CONSTANT and an OP_ADD.
+  AddLocals,
+  AddConstant,
+}
+
+impl OpCode {
+  // As clox's disassembler calls them
+  pub fn name(&self) -> &'static str {
+    match self {
+      OpCode::Constant => "OP_CONSTANT",
+      OpCode::Closure => "OP_CLOSURE",
+      OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+      OpCode::SetGlobal => "OP_SET_GLOBAL",
+      OpCode::GetGlobal => "OP_GET_GLOBAL",
+      OpCode::SetUpvalue => "OP_SET_UPVALUE",
+      OpCode::GetUpvalue => "OP_GET_UPVALUE",
+      OpCode::SetLocal => "OP_SET_LOCAL",
+      OpCode::GetLocal => "OP_GET_LOCAL",
+      OpCode::Pop => "OP_POP",
+      OpCode::Nil => "OP_NIL",
+      OpCode::True => "OP_TRUE",
+      OpCode::False => "OP_FALSE",
+      OpCode::Negate => "OP_NEGATE",
+      OpCode::Add => "OP_ADD",
+      OpCode::Subtract => "OP_SUBTRACT",
+      OpCode::Multiply => "OP_MULTIPLY",
+      OpCode::Divide => "OP_DIVIDE",
+      OpCode::Not => "OP_NOT",
+      OpCode::Equal => "OP_EQUAL",
+      OpCode::Greater => "OP_GREATER",
+      OpCode::Less => "OP_LESS",
+      OpCode::Print => "OP_PRINT",
+      OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+      OpCode::Jump => "OP_JUMP",
+      OpCode::Loop => "OP_LOOP",
+      OpCode::Return => "OP_RETURN",
+      OpCode::Call => "OP_CALL",
+      OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+      OpCode::ConstantLong => "OP_CONSTANT_LONG",
+      OpCode::Class => "OP_CLASS",
+      OpCode::GetProperty => "OP_GET_PROPERTY",
+      OpCode::SetProperty => "OP_SET_PROPERTY",
+      OpCode::Method => "OP_METHOD",
+      OpCode::Inherit => "OP_INHERIT",
+      OpCode::GetSuper => "OP_GET_SUPER",
+      OpCode::AddLocals => "OP_ADD_LOCALS",
+      OpCode::AddConstant => "OP_ADD_CONSTANT",
+    }
+  }
+}
+
+impl TryFrom<u8> for OpCode {
+  type Error = u8;
+
+  fn try_from(byte: u8) -> Result<OpCode, u8> {
+    const OPCODES: [OpCode; 38] = [
+      OpCode::Constant,
+      OpCode::Closure,
+      OpCode::DefineGlobal,
+      OpCode::SetGlobal,
+      OpCode::GetGlobal,
+      OpCode::SetUpvalue,
+      OpCode::GetUpvalue,
+      OpCode::SetLocal,
+      OpCode::GetLocal,
+      OpCode::Pop,
+      OpCode::Nil,
+      OpCode::True,
+      OpCode::False,
+      OpCode::Negate,
+      OpCode::Add,
+      OpCode::Subtract,
+      OpCode::Multiply,
+      OpCode::Divide,
+      OpCode::Not,
+      OpCode::Equal,
+      OpCode::Greater,
+      OpCode::Less,
+      OpCode::Print,
+      OpCode::JumpIfFalse,
+      OpCode::Jump,
+      OpCode::Loop,
+      OpCode::Return,
+      OpCode::Call,
+      OpCode::CloseUpvalue,
+      OpCode::ConstantLong,
+      OpCode::Class,
+      OpCode::GetProperty,
+      OpCode::SetProperty,
+      OpCode::Method,
+      OpCode::Inherit,
+      OpCode::GetSuper,
+      OpCode::AddLocals,
+      OpCode::AddConstant,
+    ];
+
+    OPCODES.get(byte as usize).copied().ok_or(byte)
+  }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Constant {
+  Number(f64),
+  String(Rc<str>),
+  Function(Rc<CompiledFunction>),
+}
+
+impl fmt::Display for Constant {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Constant::Number(number) => write!(f, "{}", format_number(*number)),
+      Constant::String(string) => write!(f, "{}", string),
+      Constant::Function(function) => write!(f, "{}", function),
+    }
+  }
+}
+
+// clox's ObjFunction, what the compiler produces for every function and for the
+// top level script
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CompiledFunction {
+  // None for the script
+  pub name: Option<Rc<str>>,
+  pub arity: usize,
+  pub upvalue_count: usize,
+  pub chunk: Chunk,
+  // Debug info, only the debugger looks at it
+  pub locals: Vec<LocalName>,
+  pub upvalue_names: Vec<Rc<str>>,
+}
+
+// A local variable with the stack slot it has in its function's frame while
+// the code in start..end runs
+#[derive(Debug, PartialEq, Clone)]
+pub struct LocalName {
+  pub name: Rc<str>,
+  pub slot: usize,
+  pub start: usize,
+  pub end: usize,
+}
+
+impl fmt::Display for CompiledFunction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.name {
+      Some(name) => write!(f, "<fn {}>", name),
+      None => write!(f, "<script>"),
+    }
+  }
+}
+
+// Start of a run of bytes coming from the same line
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct LineStart {
+  offset: usize,
+  line: u32,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Chunk {
+  pub code: Vec<u8>,
+  pub constants: Vec<Constant>,
+  lines: Vec<LineStart>,
+}
+
+impl Chunk {
+  pub fn new() -> Chunk {
+    Chunk::default()
+  }
+
+  pub fn len(&self) -> usize {
+    self.code.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.code.is_empty()
+  }
+
+  pub fn write(&mut self, byte: u8, line: u32) {
+    if self.lines.last().is_none_or(|last| last.line != line) {
+      self.lines.push(LineStart {
+        offset: self.code.len(),
+        line,
+      });
+    }
+
+    self.code.push(byte);
+  }
+
+  pub fn write_op(&mut self, op: OpCode, line: u32) {
+    self.write(op as u8, line);
+  }
+
+  pub fn add_constant(&mut self, constant: Constant) -> usize {
+    self.constants.push(constant);
+    self.constants.len() - 1
+  }
+
+  // Picks the short or long form depending on the index. None when the pool is
+  // full.
+  pub fn write_constant(&mut self, constant: Constant, line: u32) -> Option<usize> {
+    if self.constants.len() == MAX_CONSTANTS {
+      return None;
+    }
+
+    let index = self.add_constant(constant);
+
+    if index <= u8::MAX as usize {
+      self.write_op(OpCode::Constant, line);
+      self.write(index as u8, line);
+    } else {
+      self.write_op(OpCode::ConstantLong, line);
+
+      for byte in &(index as u32).to_be_bytes()[1..] {
+        self.write(*byte, line);
+      }
+    }
+
+    Some(index)
+  }
+
+  pub fn read_u16(&self, offset: usize) -> u16 {
+    u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+  }
+
+  pub fn read_u24(&self, offset: usize) -> usize {
+    u32::from_be_bytes([
+      0,
+      self.code[offset],
+      self.code[offset + 1],
+      self.code[offset + 2],
+    ]) as usize
+  }
+
+  pub fn line_at(&self, offset: usize) -> u32 {
+    assert!(
+      offset < self.code.len(),
+      "[bug] line of offset {} past the end of the chunk",
+      offset
+    );
+
+    let run = self.lines.partition_point(|start| start.offset <= offset);
+
+    self.lines[run - 1].line
+  }
+
+  // How many runs the line info is stored as
+  pub fn line_runs(&self) -> usize {
+    self.lines.len()
+  }
+
+  // Offset and line each run starts at
+  pub fn line_starts(&self) -> impl Iterator<Item = (usize, u32)> + '_ {
+    self.lines.iter().map(|start| (start.offset, start.line))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_opcode_round_trip() {
+    for byte in 0..=u8::MAX {
+      if let Ok(op) = OpCode::try_from(byte) {
+        assert_eq!(op as u8, byte);
+      }
+    }
+
+    assert_eq!(OpCode::try_from(0), Ok(OpCode::Constant));
+    assert_eq!(OpCode::try_from(28), Ok(OpCode::CloseUpvalue));
+    assert_eq!(OpCode::try_from(35), Ok(OpCode::GetSuper));
+    assert_eq!(OpCode::try_from(37), Ok(OpCode::AddConstant));
+    assert_eq!(OpCode::try_from(38), Err(38));
+  }
+
+  #[test]
+  fn test_line_runs() {
+    let mut chunk = Chunk::new();
+
+    chunk.write_op(OpCode::Nil, 1);
+    chunk.write_op(OpCode::Nil, 1);
+    chunk.write_op(OpCode::Add, 1);
+    chunk.write_op(OpCode::Print, 3);
+    chunk.write_op(OpCode::Nil, 4);
+    chunk.write_op(OpCode::Return, 4);
+
+    assert_eq!(chunk.line_runs(), 3);
+    assert_eq!(
+      (0..chunk.len())
+        .map(|offset| chunk.line_at(offset))
+        .collect::<Vec<_>>(),
+      vec![1, 1, 1, 3, 4, 4]
+    );
+  }
+
+  #[test]
+  #[should_panic]
+  fn test_line_past_the_end() {
+    let mut chunk = Chunk::new();
+    chunk.write_op(OpCode::Return, 1);
+
+    chunk.line_at(1);
+  }
+
+  #[test]
+  fn test_long_constants() {
+    let mut chunk = Chunk::new();
+
+    for number in 0..300 {
+      chunk.write_constant(Constant::Number(number as f64), 1);
+    }
+
+    assert_eq!(
+      &chunk.code[..4],
+      &[OpCode::Constant as u8, 0, OpCode::Constant as u8, 1]
+    );
+
+    let long = 256 * 2;
+    assert_eq!(chunk.code[long], OpCode::ConstantLong as u8);
+    assert_eq!(chunk.read_u24(long + 1), 256);
+    assert_eq!(
+      chunk.constants[chunk.read_u24(long + 1)],
+      Constant::Number(256.0)
+    );
+    assert_eq!(chunk.len(), 256 * 2 + 44 * 4);
+  }
+}
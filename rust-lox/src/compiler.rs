@@ -0,0 +1,973 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use super::chunk::{Chunk, CompiledFunction, Constant, LocalName, OpCode};
+use super::expression::{Expression, Literal, NodeId};
+use super::interner::Interner;
+use super::resolver::{Binding, Resolution};
+use super::scanner::{Token, TokenType};
+use super::statement::{Function, Statement};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompileError {
+  pub line: u32,
+  pub message: String,
+}
+
+impl fmt::Display for CompileError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "compile error. line: {} - error: {}",
+      self.line, self.message
+    )
+  }
+}
+
+// Compiles a resolved program to the function the VM runs as the top level
+// script. Scoping was already worked out by the resolver so this is a single
+// walk over the AST emitting what 26-gc/compiler.c would.
+pub fn compile(
+  statements: &[Statement],
+  resolution: &Resolution,
+) -> Result<Rc<CompiledFunction>, Vec<CompileError>> {
+  let mut compiler = Compiler {
+    resolution,
+    functions: vec![FunctionCompiler::new(None, 0, FunctionKind::Script)],
+    strings: Interner::new(),
+    line: 1,
+    errors: Vec::new(),
+  };
+
+  compiler.compile_statements(statements);
+  let script = compiler.end_function();
+
+  if compiler.errors.is_empty() {
+    Ok(Rc::new(script))
+  } else {
+    Err(compiler.errors)
+  }
+}
+
+// End of a local that's still in scope
+const OPEN: usize = usize::MAX;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FunctionKind {
+  Script,
+  Function,
+  Method,
+  Initializer,
+}
+
+struct FunctionCompiler {
+  function: CompiledFunction,
+  kind: FunctionKind,
+  // Whether each local of each open block is captured, to know if leaving the
+  // block pops it or closes it. The outermost scope of a function is never
+  // popped since returning discards the whole frame.
+  scopes: Vec<Vec<bool>>,
+  // Constant indices of the names used so far, each name is added once
+  names: HashMap<Rc<str>, u8>,
+  // Whether running out of constants was already reported for this chunk
+  full: bool,
+}
+
+impl FunctionCompiler {
+  fn new(name: Option<Rc<str>>, arity: usize, kind: FunctionKind) -> FunctionCompiler {
+    FunctionCompiler {
+      function: CompiledFunction {
+        name,
+        arity,
+        ..CompiledFunction::default()
+      },
+      kind,
+      scopes: Vec::new(),
+      names: HashMap::new(),
+      full: false,
+    }
+  }
+}
+
+struct Compiler<'r> {
+  resolution: &'r Resolution,
+  functions: Vec<FunctionCompiler>,
+  // Names and string literals, shared by every chunk of the program
+  strings: Interner,
+  // Line of the last token seen, every emitted byte is attributed to it
+  line: u32,
+  errors: Vec<CompileError>,
+}
+
+impl<'r> Compiler<'r> {
+  fn compile_statements(&mut self, statements: &[Statement]) {
+    for statement in statements {
+      self.compile_statement(statement);
+    }
+  }
+
+  fn compile_statement(&mut self, statement: &Statement) {
+    match statement {
+      Statement::Block { statements } => {
+        self.begin_scope();
+        self.compile_statements(statements);
+        self.end_scope();
+      }
+      Statement::Class {
+        id,
+        name,
+        superclass,
+        methods,
+      } => self.compile_class(*id, name, superclass.as_ref(), methods),
+      Statement::Expression { expression } => {
+        self.compile_expression(expression);
+        self.emit_op(OpCode::Pop);
+      }
+      Statement::Function { function } => {
+        let binding = self.resolution.binding(function.id);
+
+        self.line = function.name.line;
+        let global = self.global_constant(binding, &function.name);
+        self.compile_function(function, FunctionKind::Function);
+        self.define_variable(binding, global);
+      }
+      Statement::If {
+        condition,
+        then_branch,
+        else_branch,
+      } => {
+        self.compile_expression(condition);
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.compile_statement(then_branch);
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump);
+        self.emit_op(OpCode::Pop);
+
+        if let Some(else_branch) = else_branch {
+          self.compile_statement(else_branch);
+        }
+
+        self.patch_jump(else_jump);
+      }
+      Statement::Print { expression } => {
+        self.compile_expression(expression);
+        self.emit_op(OpCode::Print);
+      }
+      Statement::Return { keyword, value } => {
+        self.line = keyword.line;
+
+        match value {
+          Some(value) => {
+            self.compile_expression(value);
+            self.emit_op(OpCode::Return);
+          }
+          None => self.emit_return(),
+        }
+      }
+      Statement::Var {
+        id,
+        name,
+        initializer,
+      } => {
+        let binding = self.resolution.binding(*id);
+
+        self.line = name.line;
+        let global = self.global_constant(binding, name);
+
+        match initializer {
+          Some(initializer) => self.compile_expression(initializer),
+          None => self.emit_op(OpCode::Nil),
+        }
+
+        self.define_variable(binding, global);
+      }
+      Statement::While { condition, body } => {
+        let loop_start = self.chunk().len();
+        self.compile_expression(condition);
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.compile_statement(body);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(OpCode::Pop);
+      }
+    }
+  }
+
+  fn compile_class(
+    &mut self,
+    id: NodeId,
+    name: &Token,
+    superclass: Option<&Expression>,
+    methods: &[Rc<Function>],
+  ) {
+    let binding = self.resolution.binding(id);
+
+    self.line = name.line;
+    let name_constant = self.identifier_constant(name);
+    self.emit_op(OpCode::Class);
+    self.emit_byte(name_constant);
+    self.define_variable(
+      binding,
+      (binding == Binding::Global).then_some(name_constant),
+    );
+
+    if let Some(superclass) = superclass {
+      self.compile_expression(superclass);
+
+      // The superclass stays on the stack as the `super` local the methods
+      // capture
+      self.begin_scope();
+      let super_binding = self
+        .resolution
+        .super_binding(id)
+        .expect("[bug] subclass without a super binding");
+      self.declare_local(super_binding);
+
+      self.load_variable(binding, name);
+      self.emit_op(OpCode::Inherit);
+    }
+
+    self.load_variable(binding, name);
+
+    for method in methods {
+      let kind = if method.name.text == "init" {
+        FunctionKind::Initializer
+      } else {
+        FunctionKind::Method
+      };
+
+      self.line = method.name.line;
+      self.compile_function(method, kind);
+
+      let method_constant = self.identifier_constant(&method.name);
+      self.emit_op(OpCode::Method);
+      self.emit_byte(method_constant);
+    }
+
+    self.emit_op(OpCode::Pop);
+
+    if superclass.is_some() {
+      self.end_scope();
+    }
+  }
+
+  // Leaves the closure on the stack
+  fn compile_function(&mut self, function: &Function, kind: FunctionKind) {
+    let name = self.strings.intern(function.name.text);
+
+    self.functions.push(FunctionCompiler::new(
+      Some(name),
+      function.parameters.len(),
+      kind,
+    ));
+
+    if kind == FunctionKind::Method || kind == FunctionKind::Initializer {
+      self.name_local("this", 0);
+    }
+
+    for (index, parameter) in function.parameters.iter().enumerate() {
+      self.name_local(parameter.text, index + 1);
+    }
+
+    self.compile_statements(&function.body);
+
+    let upvalues = self.resolution.upvalues(function.id);
+    let mut compiled = self.end_function();
+    compiled.upvalue_count = upvalues.len();
+    compiled.upvalue_names = upvalues
+      .iter()
+      .map(|upvalue| self.upvalue_name(upvalue.is_local, upvalue.index))
+      .collect();
+    self.line = function.name.line;
+
+    let index = self.make_constant(Constant::Function(Rc::new(compiled)));
+    self.emit_op(OpCode::Closure);
+    self.emit_byte(index);
+
+    for upvalue in upvalues {
+      self.emit_byte(upvalue.is_local as u8);
+      self.emit_byte(upvalue.index as u8);
+    }
+  }
+
+  fn end_function(&mut self) -> CompiledFunction {
+    self.emit_return();
+
+    let mut function = self.functions.pop().unwrap().function;
+    let end = function.chunk.len();
+
+    for local in &mut function.locals {
+      if local.end == OPEN {
+        local.end = end;
+      }
+    }
+
+    function
+  }
+
+  fn compile_expression(&mut self, expression: &Expression) {
+    match expression {
+      Expression::Assign { id, name, value } => {
+        self.compile_expression(value);
+        self.line = name.line;
+
+        match self.resolution.binding(*id) {
+          Binding::Global => {
+            let index = self.identifier_constant(name);
+            self.emit_op(OpCode::SetGlobal);
+            self.emit_byte(index);
+          }
+          Binding::Local { slot, .. } => {
+            self.emit_op(OpCode::SetLocal);
+            self.emit_byte(slot as u8);
+          }
+          Binding::Upvalue { index, .. } => {
+            self.emit_op(OpCode::SetUpvalue);
+            self.emit_byte(index as u8);
+          }
+        }
+      }
+      Expression::Binary {
+        left,
+        operator,
+        right,
+      } => {
+        self.compile_expression(left);
+        self.compile_expression(right);
+        self.line = operator.line;
+
+        match operator.token_type {
+          TokenType::Plus => self.emit_op(OpCode::Add),
+          TokenType::Minus => self.emit_op(OpCode::Subtract),
+          TokenType::Star => self.emit_op(OpCode::Multiply),
+          TokenType::Slash => self.emit_op(OpCode::Divide),
+          TokenType::EqualEqual => self.emit_op(OpCode::Equal),
+          TokenType::Greater => self.emit_op(OpCode::Greater),
+          TokenType::Less => self.emit_op(OpCode::Less),
+          // The negated comparisons have no instruction of their own
+          TokenType::BangEqual => self.emit_ops(OpCode::Equal, OpCode::Not),
+          TokenType::GreaterEqual => self.emit_ops(OpCode::Less, OpCode::Not),
+          TokenType::LessEqual => self.emit_ops(OpCode::Greater, OpCode::Not),
+          _ => unreachable!("[bug] unknown binary operator {}", operator.text),
+        }
+      }
+      Expression::Call {
+        callee,
+        paren,
+        arguments,
+      } => {
+        self.compile_expression(callee);
+
+        for argument in arguments {
+          self.compile_expression(argument);
+        }
+
+        self.line = paren.line;
+        self.emit_op(OpCode::Call);
+        self.emit_byte(arguments.len() as u8);
+      }
+      Expression::Get { object, name } => {
+        self.compile_expression(object);
+        self.line = name.line;
+
+        let index = self.identifier_constant(name);
+        self.emit_op(OpCode::GetProperty);
+        self.emit_byte(index);
+      }
+      Expression::Grouping { expression } => self.compile_expression(expression),
+      Expression::Literal { value, line } => {
+        self.line = *line;
+
+        match value {
+          Literal::Number(number) => self.emit_constant(Constant::Number(*number)),
+          Literal::String(string) => {
+            let string = self.strings.intern(string);
+            self.emit_constant(Constant::String(string))
+          }
+          Literal::Bool(true) => self.emit_op(OpCode::True),
+          Literal::Bool(false) => self.emit_op(OpCode::False),
+          Literal::Nil => self.emit_op(OpCode::Nil),
+        }
+      }
+      Expression::Logical {
+        left,
+        operator,
+        right,
+      } => {
+        self.compile_expression(left);
+        self.line = operator.line;
+
+        if operator.token_type == TokenType::And {
+          let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+          self.emit_op(OpCode::Pop);
+          self.compile_expression(right);
+          self.patch_jump(end_jump);
+        } else {
+          let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+          let end_jump = self.emit_jump(OpCode::Jump);
+          self.patch_jump(else_jump);
+          self.emit_op(OpCode::Pop);
+          self.compile_expression(right);
+          self.patch_jump(end_jump);
+        }
+      }
+      Expression::Set {
+        object,
+        name,
+        value,
+      } => {
+        self.compile_expression(object);
+        self.line = name.line;
+        let index = self.identifier_constant(name);
+
+        self.compile_expression(value);
+        self.line = name.line;
+        self.emit_op(OpCode::SetProperty);
+        self.emit_byte(index);
+      }
+      Expression::Super {
+        id,
+        keyword,
+        method,
+      } => {
+        let this_token = Token {
+          text: "this",
+          ..*keyword
+        };
+
+        self.load_variable(self.resolution.receiver(*id), &this_token);
+        self.load_variable(self.resolution.binding(*id), keyword);
+
+        let index = self.identifier_constant(method);
+        self.emit_op(OpCode::GetSuper);
+        self.emit_byte(index);
+      }
+      Expression::This { id, keyword } => self.load_variable(self.resolution.binding(*id), keyword),
+      Expression::Unary { operator, right } => {
+        self.compile_expression(right);
+        self.line = operator.line;
+
+        match operator.token_type {
+          TokenType::Minus => self.emit_op(OpCode::Negate),
+          TokenType::Bang => self.emit_op(OpCode::Not),
+          _ => unreachable!("[bug] unknown unary operator {}", operator.text),
+        }
+      }
+      Expression::Variable { id, name } => self.load_variable(self.resolution.binding(*id), name),
+    }
+  }
+
+  fn load_variable(&mut self, binding: Binding, name: &Token) {
+    self.line = name.line;
+
+    match binding {
+      Binding::Global => {
+        let index = self.identifier_constant(name);
+        self.emit_op(OpCode::GetGlobal);
+        self.emit_byte(index);
+      }
+      Binding::Local { slot, .. } => {
+        self.emit_op(OpCode::GetLocal);
+        self.emit_byte(slot as u8);
+      }
+      Binding::Upvalue { index, .. } => {
+        self.emit_op(OpCode::GetUpvalue);
+        self.emit_byte(index as u8);
+      }
+    }
+  }
+
+  // Like clox the name goes in the constant table before the initializer
+  fn global_constant(&mut self, binding: Binding, name: &Token) -> Option<u8> {
+    match binding {
+      Binding::Global => Some(self.identifier_constant(name)),
+      _ => None,
+    }
+  }
+
+  // The value is on top of the stack. Globals move it to the globals table,
+  // locals simply stay where they are.
+  fn define_variable(&mut self, binding: Binding, global: Option<u8>) {
+    match global {
+      Some(index) => {
+        self.emit_op(OpCode::DefineGlobal);
+        self.emit_byte(index);
+      }
+      None => self.declare_local(binding),
+    }
+  }
+
+  fn declare_local(&mut self, binding: Binding) {
+    let captured = self.resolution.is_captured(binding);
+
+    if let Binding::Local { slot, variable, .. } = binding {
+      self.name_local(&self.resolution.variable(variable).name, slot);
+    }
+
+    if let Some(scope) = self.current().scopes.last_mut() {
+      scope.push(captured);
+    }
+  }
+
+  fn begin_scope(&mut self) {
+    self.current().scopes.push(Vec::new());
+  }
+
+  // Its value is on the stack from here on
+  fn name_local(&mut self, name: &str, slot: usize) {
+    let name = self.strings.intern(name);
+    let start = self.chunk().len();
+
+    self.current().function.locals.push(LocalName {
+      name,
+      slot,
+      start,
+      end: OPEN,
+    });
+  }
+
+  // Names the upvalue of a closure being created in the current function
+  fn upvalue_name(&mut self, is_local: bool, index: usize) -> Rc<str> {
+    let function = &self.current().function;
+
+    let name = if is_local {
+      function
+        .locals
+        .iter()
+        .rev()
+        .find(|local| local.slot == index && local.end == OPEN)
+        .map(|local| &local.name)
+    } else {
+      function.upvalue_names.get(index)
+    };
+
+    // Slot zero of a function has no name
+    name.cloned().unwrap_or_else(|| Rc::from(""))
+  }
+
+  fn end_scope(&mut self) {
+    let scope = self.current().scopes.pop().unwrap();
+    let end = self.chunk().len();
+    let mut closing = scope.len();
+
+    for local in self.current().function.locals.iter_mut().rev() {
+      if closing == 0 {
+        break;
+      }
+
+      if local.end == OPEN {
+        local.end = end;
+        closing -= 1;
+      }
+    }
+
+    for captured in scope.into_iter().rev() {
+      self.emit_op(if captured {
+        OpCode::CloseUpvalue
+      } else {
+        OpCode::Pop
+      });
+    }
+  }
+
+  fn identifier_constant(&mut self, name: &Token) -> u8 {
+    let name = self.strings.intern(name.text);
+
+    if let Some(index) = self.current().names.get(&name) {
+      return *index;
+    }
+
+    let index = self.make_constant(Constant::String(Rc::clone(&name)));
+    self.current().names.insert(name, index);
+    index
+  }
+
+  fn make_constant(&mut self, constant: Constant) -> u8 {
+    let index = self.chunk().add_constant(constant);
+
+    if index > u8::MAX as usize {
+      self.too_many_constants();
+      return 0;
+    }
+
+    index as u8
+  }
+
+  fn emit_constant(&mut self, constant: Constant) {
+    let line = self.line;
+
+    if self.chunk().write_constant(constant, line).is_none() {
+      self.too_many_constants();
+    }
+  }
+
+  // Once per chunk, every constant after the first one that didn't fit fails
+  // the same way
+  fn too_many_constants(&mut self) {
+    if !self.current().full {
+      self.current().full = true;
+      self.error("Too many constants in one chunk");
+    }
+  }
+
+  fn emit_return(&mut self) {
+    if self.current().kind == FunctionKind::Initializer {
+      self.emit_op(OpCode::GetLocal);
+      self.emit_byte(0);
+    } else {
+      self.emit_op(OpCode::Nil);
+    }
+
+    self.emit_op(OpCode::Return);
+  }
+
+  fn emit_jump(&mut self, op: OpCode) -> usize {
+    self.emit_op(op);
+    self.emit_byte(0xff);
+    self.emit_byte(0xff);
+
+    self.chunk().len() - 2
+  }
+
+  fn patch_jump(&mut self, offset: usize) {
+    let jump = self.chunk().len() - offset - 2;
+
+    if jump > u16::MAX as usize {
+      self.error("Too much code to jump over.");
+    }
+
+    let bytes = (jump as u16).to_be_bytes();
+    self.chunk().code[offset..offset + 2].copy_from_slice(&bytes);
+  }
+
+  fn emit_loop(&mut self, loop_start: usize) {
+    self.emit_op(OpCode::Loop);
+
+    let offset = self.chunk().len() - loop_start + 2;
+
+    if offset > u16::MAX as usize {
+      self.error("Loop body too large.");
+    }
+
+    for byte in (offset as u16).to_be_bytes() {
+      self.emit_byte(byte);
+    }
+  }
+
+  fn emit_ops(&mut self, first: OpCode, second: OpCode) {
+    self.emit_op(first);
+    self.emit_op(second);
+  }
+
+  fn emit_op(&mut self, op: OpCode) {
+    self.emit_byte(op as u8);
+  }
+
+  fn emit_byte(&mut self, byte: u8) {
+    let line = self.line;
+    self.chunk().write(byte, line);
+  }
+
+  fn current(&mut self) -> &mut FunctionCompiler {
+    self.functions.last_mut().unwrap()
+  }
+
+  fn chunk(&mut self) -> &mut Chunk {
+    &mut self.current().function.chunk
+  }
+
+  fn error(&mut self, message: &str) {
+    self.errors.push(CompileError {
+      line: self.line,
+      message: message.to_string(),
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::debug::disassemble_function;
+  use crate::parser;
+  use crate::resolver;
+
+  fn disassemble(source: &str) -> String {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    disassemble_function(&compile(&statements, &resolution).unwrap())
+  }
+
+  #[test]
+  fn test_expressions() {
+    assert_eq!(
+      disassemble("print -(1 + 2) * 3 >= 4;\nprint !nil != \"a\";"),
+      "== <script> ==\n\
+       0000    1 OP_CONSTANT         0 '1.0'\n\
+       0002    | OP_CONSTANT         1 '2.0'\n\
+       0004    | OP_ADD\n\
+       0005    | OP_NEGATE\n\
+       0006    | OP_CONSTANT         2 '3.0'\n\
+       0008    | OP_MULTIPLY\n\
+       0009    | OP_CONSTANT         3 '4.0'\n\
+       0011    | OP_LESS\n\
+       0012    | OP_NOT\n\
+       0013    | OP_PRINT\n\
+       0014    2 OP_NIL\n\
+       0015    | OP_NOT\n\
+       0016    | OP_CONSTANT         4 'a'\n\
+       0018    | OP_EQUAL\n\
+       0019    | OP_NOT\n\
+       0020    | OP_PRINT\n\
+       0021    | OP_NIL\n\
+       0022    | OP_RETURN\n"
+    );
+  }
+
+  #[test]
+  fn test_globals_and_locals() {
+    assert_eq!(
+      disassemble("var a = 1;\n{\n  var b = a;\n  b = 2;\n}\na = 3;"),
+      "== <script> ==\n\
+       0000    1 OP_CONSTANT         1 '1.0'\n\
+       0002    | OP_DEFINE_GLOBAL    0 'a'\n\
+       0004    3 OP_GET_GLOBAL       0 'a'\n\
+       0006    4 OP_CONSTANT         2 '2.0'\n\
+       0008    | OP_SET_LOCAL        1\n\
+       0010    | OP_POP\n\
+       0011    | OP_POP\n\
+       0012    6 OP_CONSTANT         3 '3.0'\n\
+       0014    | OP_SET_GLOBAL       0 'a'\n\
+       0016    | OP_POP\n\
+       0017    | OP_NIL\n\
+       0018    | OP_RETURN\n"
+    );
+  }
+
+  #[test]
+  fn test_control_flow() {
+    assert_eq!(
+      disassemble("while (true and false) if (nil) print 1; else print 2;"),
+      "== <script> ==\n\
+       0000    1 OP_TRUE\n\
+       0001    | OP_JUMP_IF_FALSE    1 -> 6\n\
+       0004    | OP_POP\n\
+       0005    | OP_FALSE\n\
+       0006    | OP_JUMP_IF_FALSE    6 -> 28\n\
+       0009    | OP_POP\n\
+       0010    | OP_NIL\n\
+       0011    | OP_JUMP_IF_FALSE   11 -> 21\n\
+       0014    | OP_POP\n\
+       0015    | OP_CONSTANT         0 '1.0'\n\
+       0017    | OP_PRINT\n\
+       0018    | OP_JUMP            18 -> 25\n\
+       0021    | OP_POP\n\
+       0022    | OP_CONSTANT         1 '2.0'\n\
+       0024    | OP_PRINT\n\
+       0025    | OP_LOOP            25 -> 0\n\
+       0028    | OP_POP\n\
+       0029    | OP_NIL\n\
+       0030    | OP_RETURN\n"
+    );
+  }
+
+  #[test]
+  fn test_closures() {
+    assert_eq!(
+      disassemble(
+        "fun outer(x) {\n  fun inner() {\n    return x;\n  }\n  return inner;\n}\n{\n  var y;\n  fun f() { y = 1; }\n}"
+      ),
+      "== <script> ==\n\
+       0000    1 OP_CLOSURE          1 '<fn outer>'\n\
+       0002    | OP_DEFINE_GLOBAL    0 'outer'\n\
+       0004    8 OP_NIL\n\
+       0005    9 OP_CLOSURE          2 '<fn f>'\n\
+       0007    |                     local 1\n\
+       0009    | OP_POP\n\
+       0010    | OP_CLOSE_UPVALUE\n\
+       0011    | OP_NIL\n\
+       0012    | OP_RETURN\n\
+       == <fn outer> ==\n\
+       0000    2 OP_CLOSURE          0 '<fn inner>'\n\
+       0002    |                     local 1\n\
+       0004    5 OP_GET_LOCAL        2\n\
+       0006    | OP_RETURN\n\
+       0007    | OP_NIL\n\
+       0008    | OP_RETURN\n\
+       == <fn inner> ==\n\
+       0000    3 OP_GET_UPVALUE      0\n\
+       0002    | OP_RETURN\n\
+       0003    | OP_NIL\n\
+       0004    | OP_RETURN\n\
+       == <fn f> ==\n\
+       0000    9 OP_CONSTANT         0 '1.0'\n\
+       0002    | OP_SET_UPVALUE      0\n\
+       0004    | OP_POP\n\
+       0005    | OP_NIL\n\
+       0006    | OP_RETURN\n"
+    );
+  }
+
+  #[test]
+  fn test_classes() {
+    assert_eq!(
+      disassemble(
+        "class A {\n  init() { this.x = 1; }\n}\nclass B < A {\n  m() { return super.m; }\n}"
+      ),
+      "== <script> ==\n\
+       0000    1 OP_CLASS            0 'A'\n\
+       0002    | OP_DEFINE_GLOBAL    0 'A'\n\
+       0004    | OP_GET_GLOBAL       0 'A'\n\
+       0006    2 OP_CLOSURE          1 '<fn init>'\n\
+       0008    | OP_METHOD           2 'init'\n\
+       0010    | OP_POP\n\
+       0011    4 OP_CLASS            3 'B'\n\
+       0013    | OP_DEFINE_GLOBAL    3 'B'\n\
+       0015    | OP_GET_GLOBAL       0 'A'\n\
+       0017    | OP_GET_GLOBAL       3 'B'\n\
+       0019    | OP_INHERIT\n\
+       0020    | OP_GET_GLOBAL       3 'B'\n\
+       0022    5 OP_CLOSURE          4 '<fn m>'\n\
+       0024    |                     local 1\n\
+       0026    | OP_METHOD           5 'm'\n\
+       0028    | OP_POP\n\
+       0029    | OP_CLOSE_UPVALUE\n\
+       0030    | OP_NIL\n\
+       0031    | OP_RETURN\n\
+       == <fn init> ==\n\
+       0000    2 OP_GET_LOCAL        0\n\
+       0002    | OP_CONSTANT         1 '1.0'\n\
+       0004    | OP_SET_PROPERTY     0 'x'\n\
+       0006    | OP_POP\n\
+       0007    | OP_GET_LOCAL        0\n\
+       0009    | OP_RETURN\n\
+       == <fn m> ==\n\
+       0000    5 OP_GET_LOCAL        0\n\
+       0002    | OP_GET_UPVALUE      0\n\
+       0004    | OP_GET_SUPER        0 'm'\n\
+       0006    | OP_RETURN\n\
+       0007    | OP_NIL\n\
+       0008    | OP_RETURN\n"
+    );
+  }
+
+  #[test]
+  fn test_strings_are_interned() {
+    let source = "var a = \"a\";\nfun a() { print a + \"a\"; }";
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+    let script = compile(&statements, &resolution).unwrap();
+
+    let strings = |chunk: &Chunk| -> Vec<Rc<str>> {
+      chunk
+        .constants
+        .iter()
+        .filter_map(|constant| match constant {
+          Constant::String(string) => Some(Rc::clone(string)),
+          _ => None,
+        })
+        .collect()
+    };
+    let function = match &script.chunk.constants[2] {
+      Constant::Function(function) => Rc::clone(function),
+      constant => panic!("{:?}", constant),
+    };
+    let mut all = strings(&script.chunk);
+    all.extend(strings(&function.chunk));
+    all.push(Rc::clone(function.name.as_ref().unwrap()));
+
+    assert_eq!(all.len(), 5);
+    assert!(all.iter().all(|string| Rc::ptr_eq(string, &all[0])));
+  }
+
+  #[test]
+  fn test_local_names() {
+    let source = "fun f(a) {\n  var b = a;\n  {\n    var c = b;\n    fun g() { return c; }\n  }\n  print b;\n}";
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+    let script = compile(&statements, &resolution).unwrap();
+
+    let function = |chunk: &Chunk| -> Rc<CompiledFunction> {
+      chunk
+        .constants
+        .iter()
+        .find_map(|constant| match constant {
+          Constant::Function(function) => Some(Rc::clone(function)),
+          _ => None,
+        })
+        .unwrap()
+    };
+    let f = function(&script.chunk);
+    let g = function(&f.chunk);
+
+    let names: Vec<_> = f
+      .locals
+      .iter()
+      .map(|local| (local.name.to_string(), local.slot))
+      .collect();
+    assert_eq!(
+      names,
+      vec![
+        (String::from("a"), 1),
+        (String::from("b"), 2),
+        (String::from("c"), 3),
+        (String::from("g"), 4),
+      ]
+    );
+
+    // Block locals are gone before print b, the others last to the end. g is
+    // declared as its block ends, so it's never in scope.
+    let print = (0..f.chunk.code.len())
+      .find(|offset| f.chunk.line_at(*offset) == 7)
+      .unwrap();
+    assert!(f.locals[2..]
+      .iter()
+      .all(|local| local.start <= local.end && local.end < print));
+    assert!(f.locals[..2]
+      .iter()
+      .all(|local| local.end == f.chunk.code.len()));
+    assert_eq!(g.upvalue_names, vec![Rc::from("c")]);
+  }
+
+  #[test]
+  fn test_too_many_constants() {
+    let source: String = (0..300).map(|n| format!("var v{} = nil;\n", n)).collect();
+    let statements = parser::parse(&source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    let errors = compile(&statements, &resolution).unwrap_err();
+
+    assert_eq!(
+      errors,
+      vec![CompileError {
+        line: 257,
+        message: String::from("Too many constants in one chunk"),
+      }]
+    );
+  }
+
+  #[test]
+  fn test_names_are_added_once() {
+    let source: String = std::iter::once("var s = 0;\n")
+      .chain((0..300).map(|_| "s = s + 1.5;\n"))
+      .collect();
+    let statements = parser::parse(&source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+    let script = compile(&statements, &resolution).unwrap();
+
+    let names = script
+      .chunk
+      .constants
+      .iter()
+      .filter(|constant| matches!(constant, Constant::String(_)))
+      .count();
+    assert_eq!(names, 1);
+  }
+}
@@ -0,0 +1,355 @@
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::chunk::{Chunk, CompiledFunction, Constant, OpCode};
+
+// One captured variable of OP_CLOSURE
+#[derive(Debug, PartialEq, Serialize, Clone, Copy)]
+pub struct Capture {
+  pub local: bool,
+  pub index: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub struct Instruction {
+  pub offset: usize,
+  pub line: u32,
+  pub name: &'static str,
+  // Constant index, slot, argument count or the raw byte of an unknown opcode
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub operand: Option<usize>,
+  // The other slot of OP_ADD_LOCALS
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub second_operand: Option<usize>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub constant: Option<String>,
+  // Absolute offset a jump lands on
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub target: Option<usize>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub upvalues: Vec<Capture>,
+  // Offset of the instruction after this one
+  #[serde(skip)]
+  pub next: usize,
+}
+
+pub fn disassemble(chunk: &Chunk) -> Vec<Instruction> {
+  let mut instructions = Vec::new();
+  let mut offset = 0;
+
+  while offset < chunk.len() {
+    let instruction = disassemble_instruction(chunk, offset);
+    offset = instruction.next;
+    instructions.push(instruction);
+  }
+
+  instructions
+}
+
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> Instruction {
+  let mut instruction = Instruction {
+    offset,
+    line: chunk.line_at(offset),
+    name: "UNKNOWN",
+    operand: None,
+    second_operand: None,
+    constant: None,
+    target: None,
+    upvalues: Vec::new(),
+    next: offset + 1,
+  };
+
+  let op = match OpCode::try_from(chunk.code[offset]) {
+    Ok(op) => op,
+    Err(byte) => {
+      instruction.operand = Some(byte as usize);
+      return instruction;
+    }
+  };
+
+  instruction.name = op.name();
+
+  match op {
+    OpCode::Constant
+    | OpCode::DefineGlobal
+    | OpCode::SetGlobal
+    | OpCode::GetGlobal
+    | OpCode::Class
+    | OpCode::GetProperty
+    | OpCode::SetProperty
+    | OpCode::Method
+    | OpCode::GetSuper
+    | OpCode::AddConstant => {
+      let index = chunk.code[offset + 1] as usize;
+
+      instruction.operand = Some(index);
+      instruction.constant = Some(chunk.constants[index].to_string());
+      instruction.next = offset + 2;
+    }
+    OpCode::Closure => {
+      let index = chunk.code[offset + 1] as usize;
+      let upvalue_count = match &chunk.constants[index] {
+        Constant::Function(function) => function.upvalue_count,
+        _ => 0,
+      };
+
+      instruction.operand = Some(index);
+      instruction.constant = Some(chunk.constants[index].to_string());
+      instruction.upvalues = (0..upvalue_count)
+        .map(|upvalue| Capture {
+          local: chunk.code[offset + 2 + upvalue * 2] == 1,
+          index: chunk.code[offset + 3 + upvalue * 2] as usize,
+        })
+        .collect();
+      instruction.next = offset + 2 + upvalue_count * 2;
+    }
+    OpCode::ConstantLong => {
+      let index = chunk.read_u24(offset + 1);
+
+      instruction.operand = Some(index);
+      instruction.constant = Some(chunk.constants[index].to_string());
+      instruction.next = offset + 4;
+    }
+    OpCode::SetLocal
+    | OpCode::GetLocal
+    | OpCode::SetUpvalue
+    | OpCode::GetUpvalue
+    | OpCode::Call => {
+      instruction.operand = Some(chunk.code[offset + 1] as usize);
+      instruction.next = offset + 2;
+    }
+    OpCode::AddLocals => {
+      instruction.operand = Some(chunk.code[offset + 1] as usize);
+      instruction.second_operand = Some(chunk.code[offset + 2] as usize);
+      instruction.next = offset + 3;
+    }
+    OpCode::JumpIfFalse | OpCode::Jump | OpCode::Loop => {
+      let jump = chunk.read_u16(offset + 1) as usize;
+
+      instruction.target = Some(if op == OpCode::Loop {
+        (offset + 3).saturating_sub(jump)
+      } else {
+        offset + 3 + jump
+      });
+      instruction.next = offset + 3;
+    }
+    OpCode::Pop
+    | OpCode::Nil
+    | OpCode::True
+    | OpCode::False
+    | OpCode::Negate
+    | OpCode::Add
+    | OpCode::Subtract
+    | OpCode::Multiply
+    | OpCode::Divide
+    | OpCode::Not
+    | OpCode::Equal
+    | OpCode::Greater
+    | OpCode::Less
+    | OpCode::Print
+    | OpCode::Return
+    | OpCode::CloseUpvalue
+    | OpCode::Inherit => (),
+  }
+
+  instruction
+}
+
+// Same layout as clox's disassembleChunk
+pub fn disassemble_chunk(chunk: &Chunk, name: &str) -> String {
+  let mut text = format!("== {} ==\n", name);
+  let mut previous_line = None;
+
+  for instruction in disassemble(chunk) {
+    write_instruction(&mut text, &instruction, previous_line);
+    previous_line = Some(instruction.line);
+  }
+
+  text
+}
+
+// One instruction as clox's disassembleInstruction prints it, for tracing
+pub fn instruction_text(chunk: &Chunk, offset: usize) -> String {
+  let mut text = String::new();
+  let previous_line = offset
+    .checked_sub(1)
+    .map(|previous| chunk.line_at(previous));
+
+  write_instruction(
+    &mut text,
+    &disassemble_instruction(chunk, offset),
+    previous_line,
+  );
+
+  text
+}
+
+fn write_instruction(text: &mut String, instruction: &Instruction, previous_line: Option<u32>) {
+  write!(text, "{:04} ", instruction.offset).unwrap();
+
+  if previous_line == Some(instruction.line) {
+    text.push_str("   | ");
+  } else {
+    write!(text, "{:4} ", instruction.line).unwrap();
+  }
+
+  match instruction {
+    Instruction {
+      name: "UNKNOWN",
+      operand: Some(byte),
+      ..
+    } => write!(text, "Unknown opcode {}", byte).unwrap(),
+    Instruction {
+      constant: Some(constant),
+      operand: Some(index),
+      ..
+    } => write!(text, "{:<16} {:4} '{}'", instruction.name, index, constant).unwrap(),
+    Instruction {
+      target: Some(target),
+      ..
+    } => write!(
+      text,
+      "{:<16} {:4} -> {}",
+      instruction.name, instruction.offset, target
+    )
+    .unwrap(),
+    Instruction {
+      operand: Some(operand),
+      second_operand: Some(second),
+      ..
+    } => write!(text, "{:<16} {:4} {:4}", instruction.name, operand, second).unwrap(),
+    Instruction {
+      operand: Some(operand),
+      ..
+    } => write!(text, "{:<16} {:4}", instruction.name, operand).unwrap(),
+    _ => text.push_str(instruction.name),
+  }
+
+  text.push('\n');
+
+  for (position, capture) in instruction.upvalues.iter().enumerate() {
+    writeln!(
+      text,
+      "{:04}    |                     {} {}",
+      instruction.offset + 2 + position * 2,
+      if capture.local { "local" } else { "upvalue" },
+      capture.index
+    )
+    .unwrap();
+  }
+}
+
+// The function's chunk followed by the chunks of the functions it declares
+pub fn disassemble_function(function: &CompiledFunction) -> String {
+  let mut text = disassemble_chunk(&function.chunk, &function.to_string());
+
+  for constant in &function.chunk.constants {
+    if let Constant::Function(inner) = constant {
+      text.push_str(&disassemble_function(inner));
+    }
+  }
+
+  text
+}
+
+// For the web client to render next to the source
+pub fn disassemble_json(chunk: &Chunk, name: &str) -> Value {
+  json!({
+    "name": name,
+    "instructions": disassemble(chunk),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::Constant;
+
+  fn sample() -> Chunk {
+    let mut chunk = Chunk::new();
+
+    chunk.write_constant(Constant::Number(1.2), 1);
+    chunk.write_op(OpCode::JumpIfFalse, 1);
+    chunk.write(0, 1);
+    chunk.write(2, 1);
+    chunk.write_op(OpCode::GetLocal, 2);
+    chunk.write(1, 2);
+    chunk.write_op(OpCode::Loop, 2);
+    chunk.write(0, 2);
+    chunk.write(10, 2);
+    chunk.write_op(OpCode::Return, 3);
+    chunk.write(200, 3);
+
+    chunk
+  }
+
+  #[test]
+  fn test_text() {
+    assert_eq!(
+      disassemble_chunk(&sample(), "test"),
+      "== test ==\n\
+       0000    1 OP_CONSTANT         0 '1.2'\n\
+       0002    | OP_JUMP_IF_FALSE    2 -> 7\n\
+       0005    2 OP_GET_LOCAL        1\n\
+       0007    | OP_LOOP             7 -> 0\n\
+       0010    3 OP_RETURN\n\
+       0011    | Unknown opcode 200\n"
+    );
+  }
+
+  #[test]
+  fn test_instruction_text() {
+    let chunk = sample();
+
+    assert_eq!(
+      instruction_text(&chunk, 0),
+      "0000    1 OP_CONSTANT         0 '1.2'\n"
+    );
+    assert_eq!(
+      instruction_text(&chunk, 2),
+      "0002    | OP_JUMP_IF_FALSE    2 -> 7\n"
+    );
+    assert_eq!(
+      instruction_text(&chunk, 5),
+      "0005    2 OP_GET_LOCAL        1\n"
+    );
+  }
+
+  #[test]
+  fn test_json() {
+    let json = disassemble_json(&sample(), "test");
+
+    assert_eq!(json["name"], "test");
+    assert_eq!(
+      json["instructions"][0],
+      json!({ "offset": 0, "line": 1, "name": "OP_CONSTANT", "operand": 0, "constant": "1.2" })
+    );
+    assert_eq!(
+      json["instructions"][1],
+      json!({ "offset": 2, "line": 1, "name": "OP_JUMP_IF_FALSE", "target": 7 })
+    );
+    assert_eq!(
+      json["instructions"][4],
+      json!({ "offset": 10, "line": 3, "name": "OP_RETURN" })
+    );
+  }
+
+  #[test]
+  fn test_long_constant() {
+    let mut chunk = Chunk::new();
+
+    for number in 0..257 {
+      chunk.write_constant(Constant::String(number.to_string().into()), 1);
+    }
+
+    let last = disassemble(&chunk).pop().unwrap();
+
+    assert_eq!(last.name, "OP_CONSTANT_LONG");
+    assert_eq!(last.operand, Some(256));
+    assert_eq!(last.constant.as_deref(), Some("256"));
+    assert_eq!(last.next, chunk.len());
+  }
+}
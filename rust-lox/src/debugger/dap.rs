@@ -0,0 +1,795 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use serde_json::{json, Value};
+
+use super::{evaluate, Breakpoint, Debugger, Frontend, Inspected, Paused, Resume, ScopeKind, Stop};
+use crate::compiler;
+use crate::interpreter::Interpreter;
+use crate::parser;
+use crate::resolver;
+use crate::vm::Vm;
+
+// The Debug Adapter Protocol, spoken over a pair of streams. VS Code runs
+// `rust-lox --dap` and talks to it over stdin and stdout. There's one program
+// per session and it runs on the thread reading requests, so requests are only
+// answered while it's stopped and a running program can't be paused.
+
+// The only thread there is, DAP still wants an id for it
+const THREAD: u64 = 1;
+
+// Largest message body read, so a bad Content-Length can't make us allocate
+// whatever it claims
+const MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Backend {
+  Interpreter,
+  Vm,
+}
+
+// The arguments of the launch request
+#[derive(Debug, PartialEq, Clone)]
+struct Launch {
+  program: String,
+  stop_on_entry: bool,
+  backend: Backend,
+}
+
+impl Launch {
+  fn parse(arguments: &Value) -> Result<Launch, String> {
+    let program = arguments["program"]
+      .as_str()
+      .ok_or("Missing the program to debug")?;
+
+    let backend = match arguments["backend"].as_str() {
+      None | Some("interpreter") => Backend::Interpreter,
+      Some("vm") => Backend::Vm,
+      Some(other) => return Err(format!("Unknown backend {}", other)),
+    };
+
+    Ok(Launch {
+      program: String::from(program),
+      stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+      backend,
+    })
+  }
+}
+
+struct Connection {
+  input: Box<dyn BufRead>,
+  output: Box<dyn Write>,
+  seq: u64,
+  // The client went away while the program was stopped
+  disconnected: bool,
+}
+
+impl Connection {
+  // None once the input has ended
+  fn receive(&mut self) -> io::Result<Option<Value>> {
+    let mut length = None;
+
+    loop {
+      let mut header = String::new();
+
+      if self.input.read_line(&mut header)? == 0 {
+        return Ok(None);
+      }
+
+      let header = header.trim_end();
+
+      if header.is_empty() {
+        break;
+      }
+
+      if let Some(value) = header.strip_prefix("Content-Length:") {
+        length = value.trim().parse::<usize>().ok();
+      }
+    }
+
+    let length = length.ok_or_else(|| invalid_data("missing Content-Length"))?;
+
+    if length > MAX_MESSAGE_BYTES {
+      return Err(invalid_data(format!(
+        "Content-Length {} is over the limit of {}",
+        length, MAX_MESSAGE_BYTES
+      )));
+    }
+
+    let mut body = vec![0; length];
+    self.input.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body)
+      .map(Some)
+      .map_err(invalid_data)
+  }
+
+  fn send(&mut self, mut message: Value) -> io::Result<()> {
+    self.seq += 1;
+    message["seq"] = json!(self.seq);
+
+    let body = message.to_string();
+    write!(
+      self.output,
+      "Content-Length: {}\r\n\r\n{}",
+      body.len(),
+      body
+    )?;
+    self.output.flush()
+  }
+
+  fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+    self.send(json!({
+      "type": "response",
+      "request_seq": request["seq"],
+      "command": request["command"],
+      "success": true,
+      "body": body,
+    }))
+  }
+
+  fn fail(&mut self, request: &Value, message: &str) -> io::Result<()> {
+    self.send(json!({
+      "type": "response",
+      "request_seq": request["seq"],
+      "command": request["command"],
+      "success": false,
+      "message": message,
+    }))
+  }
+
+  fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+    self.send(json!({
+      "type": "event",
+      "event": event,
+      "body": body,
+    }))
+  }
+
+  fn print(&mut self, category: &str, output: &str) -> io::Result<()> {
+    self.event(
+      "output",
+      json!({
+        "category": category,
+        "output": output,
+      }),
+    )
+  }
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn command(request: &Value) -> &str {
+  request["command"].as_str().unwrap_or("")
+}
+
+// Runs a debugging session until the client disconnects or the input ends
+pub fn serve(input: impl BufRead + 'static, output: impl Write + 'static) -> io::Result<()> {
+  let connection = Rc::new(RefCell::new(Connection {
+    input: Box::new(input),
+    output: Box::new(output),
+    seq: 0,
+    disconnected: false,
+  }));
+
+  let mut launch = None;
+  let mut configured = false;
+  let mut breakpoints = Vec::new();
+
+  // The client sets things up before the program starts
+  while launch.is_none() || !configured {
+    let mut connection = connection.borrow_mut();
+
+    let request = match connection.receive()? {
+      Some(request) => request,
+      None => return Ok(()),
+    };
+
+    match command(&request) {
+      "initialize" => {
+        connection.respond(
+          &request,
+          json!({
+            "supportsConfigurationDoneRequest": true,
+            "supportsConditionalBreakpoints": true,
+            "supportsEvaluateForHovers": true,
+            "supportsTerminateRequest": true,
+          }),
+        )?;
+        connection.event("initialized", json!({}))?;
+      }
+      "launch" => match Launch::parse(&request["arguments"]) {
+        Ok(arguments) => {
+          launch = Some(arguments);
+          connection.respond(&request, json!({}))?;
+        }
+        Err(message) => connection.fail(&request, &message)?,
+      },
+      "setBreakpoints" => {
+        breakpoints = parse_breakpoints(&request["arguments"]);
+        connection.respond(&request, verified(&breakpoints))?;
+      }
+      "configurationDone" => {
+        configured = true;
+        connection.respond(&request, json!({}))?;
+      }
+      "threads" => connection.respond(&request, threads())?,
+      "disconnect" => return connection.respond(&request, json!({})),
+      _ => connection.fail(&request, "Not supported before launching")?,
+    }
+  }
+
+  let exit_code = run(&connection, &launch.unwrap(), breakpoints)?;
+  let mut connection = connection.borrow_mut();
+
+  if connection.disconnected {
+    return Ok(());
+  }
+
+  connection.event("exited", json!({ "exitCode": exit_code }))?;
+  connection.event("terminated", json!({}))?;
+
+  while let Some(request) = connection.receive()? {
+    match command(&request) {
+      "disconnect" => return connection.respond(&request, json!({})),
+      "threads" => connection.respond(&request, json!({ "threads": [] }))?,
+      _ => connection.fail(&request, "The program has ended")?,
+    }
+  }
+
+  Ok(())
+}
+
+// Runs the program to the end, with the exit code rust-lox would have
+fn run(
+  connection: &Rc<RefCell<Connection>>,
+  launch: &Launch,
+  breakpoints: Vec<Breakpoint>,
+) -> io::Result<i32> {
+  let report = |errors: Vec<String>, code: i32| -> io::Result<i32> {
+    let mut connection = connection.borrow_mut();
+
+    for error in errors {
+      connection.print("stderr", &format!("{}\n", error))?;
+    }
+
+    Ok(code)
+  };
+
+  let source = match fs::read_to_string(&launch.program) {
+    Ok(source) => source,
+    Err(error) => {
+      return report(
+        vec![format!("Couldn't read {}: {}", launch.program, error)],
+        74,
+      )
+    }
+  };
+
+  let statements = match parser::parse(&source) {
+    Ok(statements) => statements,
+    Err(errors) => return report(messages(&errors), 65),
+  };
+
+  let resolution = match resolver::resolve(&statements) {
+    Ok(resolution) => resolution,
+    Err(errors) => return report(messages(&errors), 65),
+  };
+
+  let session = Session {
+    connection: Rc::clone(connection),
+    program: launch.program.clone(),
+    references: Vec::new(),
+  };
+
+  let mut debugger = Debugger::new(session);
+  debugger.set_breakpoints(breakpoints);
+
+  if launch.stop_on_entry {
+    debugger = debugger.stop_on_entry();
+  }
+
+  let output = Output(Rc::clone(connection));
+
+  let error = match launch.backend {
+    Backend::Interpreter => {
+      let mut interpreter = Interpreter::new(&resolution, output);
+      interpreter.set_debugger(Some(debugger));
+      interpreter
+        .interpret(&statements)
+        .err()
+        .map(|error| error.to_string())
+    }
+    Backend::Vm => {
+      let script = match compiler::compile(&statements, &resolution) {
+        Ok(script) => script,
+        Err(errors) => return report(messages(&errors), 65),
+      };
+
+      let mut vm = Vm::new(output);
+      vm.set_debugger(Some(debugger));
+      vm.interpret(script).err().map(|error| error.to_string())
+    }
+  };
+
+  match error {
+    Some(error) if !connection.borrow().disconnected => report(vec![error], 70),
+    Some(_) => Ok(70),
+    None => Ok(0),
+  }
+}
+
+fn messages(errors: &[impl ToString]) -> Vec<String> {
+  errors.iter().map(|error| error.to_string()).collect()
+}
+
+fn parse_breakpoints(arguments: &Value) -> Vec<Breakpoint> {
+  let breakpoints = match arguments["breakpoints"].as_array() {
+    Some(breakpoints) => breakpoints,
+    None => return Vec::new(),
+  };
+
+  breakpoints
+    .iter()
+    .filter_map(|breakpoint| {
+      let line = breakpoint["line"].as_u64()? as u32;
+
+      Some(match breakpoint["condition"].as_str() {
+        Some(condition) if !condition.trim().is_empty() => Breakpoint::conditional(line, condition),
+        _ => Breakpoint::new(line),
+      })
+    })
+    .collect()
+}
+
+// Any line can have a breakpoint, lines without code just never hit
+fn verified(breakpoints: &[Breakpoint]) -> Value {
+  let breakpoints: Vec<_> = breakpoints
+    .iter()
+    .map(|breakpoint| json!({ "verified": true, "line": breakpoint.line }))
+    .collect();
+
+  json!({ "breakpoints": breakpoints })
+}
+
+fn threads() -> Value {
+  json!({ "threads": [{ "id": THREAD, "name": "main" }] })
+}
+
+// What the program prints, shown in the client's debug console
+struct Output(Rc<RefCell<Connection>>);
+
+impl Write for Output {
+  fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+    let output = String::from_utf8_lossy(bytes);
+    self.0.borrow_mut().print("stdout", &output)?;
+
+    Ok(bytes.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+// What a variablesReference stands for, only while the program is stopped
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Reference {
+  Scope(usize, ScopeKind),
+  Object(usize),
+}
+
+struct Session {
+  connection: Rc<RefCell<Connection>>,
+  program: String,
+  // Handed out as their index plus one, zero means nothing to expand
+  references: Vec<Reference>,
+}
+
+impl Frontend for Session {
+  // A connection that fails ends the program, nobody is left to drive it
+  fn stopped(
+    &mut self,
+    stop: Stop,
+    paused: &mut dyn Paused,
+    breakpoints: &mut Vec<Breakpoint>,
+  ) -> Resume {
+    self
+      .answer(stop, paused, breakpoints)
+      .unwrap_or(Resume::Terminate)
+  }
+}
+
+impl Session {
+  fn answer(
+    &mut self,
+    stop: Stop,
+    paused: &mut dyn Paused,
+    breakpoints: &mut Vec<Breakpoint>,
+  ) -> io::Result<Resume> {
+    let connection = Rc::clone(&self.connection);
+    let mut connection = connection.borrow_mut();
+    self.references.clear();
+
+    let reason = match stop {
+      Stop::Entry => "entry",
+      Stop::Breakpoint => "breakpoint",
+      Stop::Step => "step",
+    };
+
+    connection.event(
+      "stopped",
+      json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true }),
+    )?;
+
+    loop {
+      let request = match connection.receive()? {
+        Some(request) => request,
+        None => return Ok(Resume::Terminate),
+      };
+
+      let resume = match command(&request) {
+        "continue" => Some(Resume::Continue),
+        "next" => Some(Resume::StepOver),
+        "stepIn" => Some(Resume::StepIn),
+        "stepOut" => Some(Resume::StepOut),
+        "terminate" => Some(Resume::Terminate),
+        "disconnect" => {
+          connection.disconnected = true;
+          Some(Resume::Terminate)
+        }
+        _ => None,
+      };
+
+      if let Some(resume) = resume {
+        connection.respond(&request, json!({ "allThreadsContinued": true }))?;
+        return Ok(resume);
+      }
+
+      let arguments = &request["arguments"];
+
+      let answer = match command(&request) {
+        "threads" => Ok(threads()),
+        "stackTrace" => Ok(self.stack_trace(paused)),
+        "scopes" => self.scopes(paused, arguments),
+        "variables" => self.variables(paused, arguments),
+        "evaluate" => self.evaluate(paused, arguments),
+        "setBreakpoints" => {
+          *breakpoints = parse_breakpoints(arguments);
+          Ok(verified(breakpoints))
+        }
+        _ => Err(String::from("Not supported")),
+      };
+
+      match answer {
+        Ok(body) => connection.respond(&request, body)?,
+        Err(message) => connection.fail(&request, &message)?,
+      }
+    }
+  }
+
+  fn reference(&mut self, reference: Reference) -> usize {
+    match self.references.iter().position(|other| *other == reference) {
+      Some(index) => index + 1,
+      None => {
+        self.references.push(reference);
+        self.references.len()
+      }
+    }
+  }
+
+  fn frame(&self, paused: &dyn Paused, arguments: &Value) -> Result<usize, String> {
+    let frame = arguments["frameId"].as_u64().unwrap_or(0) as usize;
+
+    if frame < paused.frames().len() {
+      Ok(frame)
+    } else {
+      Err(format!("Unknown frame {}", frame))
+    }
+  }
+
+  fn stack_trace(&self, paused: &dyn Paused) -> Value {
+    let name = Path::new(&self.program)
+      .file_name()
+      .map_or(String::new(), |name| name.to_string_lossy().to_string());
+
+    let frames: Vec<_> = paused
+      .frames()
+      .iter()
+      .enumerate()
+      .map(|(id, frame)| {
+        json!({
+          "id": id,
+          "name": frame.function.as_deref().unwrap_or("script"),
+          "line": frame.line,
+          "column": 1,
+          "source": { "name": name, "path": self.program },
+        })
+      })
+      .collect();
+
+    json!({ "stackFrames": frames, "totalFrames": frames.len() })
+  }
+
+  fn scopes(&mut self, paused: &dyn Paused, arguments: &Value) -> Result<Value, String> {
+    let frame = self.frame(paused, arguments)?;
+
+    let scopes: Vec<_> = [ScopeKind::Locals, ScopeKind::Upvalues, ScopeKind::Globals]
+      .iter()
+      .map(|kind| {
+        json!({
+          "name": kind.name(),
+          "variablesReference": self.reference(Reference::Scope(frame, *kind)),
+          "expensive": *kind == ScopeKind::Globals,
+        })
+      })
+      .collect();
+
+    Ok(json!({ "scopes": scopes }))
+  }
+
+  fn variables(&mut self, paused: &mut dyn Paused, arguments: &Value) -> Result<Value, String> {
+    let reference = arguments["variablesReference"].as_u64().unwrap_or(0) as usize;
+
+    let variables = match reference
+      .checked_sub(1)
+      .and_then(|index| self.references.get(index))
+    {
+      Some(Reference::Scope(frame, kind)) => paused
+        .scopes(*frame)
+        .into_iter()
+        .find(|scope| scope.kind == *kind)
+        .map_or(Vec::new(), |scope| scope.variables),
+      Some(Reference::Object(handle)) => paused.fields(*handle),
+      None => return Err(format!("Unknown variables reference {}", reference)),
+    };
+
+    let variables: Vec<_> = variables
+      .iter()
+      .map(|variable| {
+        json!({
+          "name": variable.name,
+          "value": describe(&variable.value),
+          "variablesReference": self.expand(&variable.value),
+        })
+      })
+      .collect();
+
+    Ok(json!({ "variables": variables }))
+  }
+
+  fn evaluate(&mut self, paused: &mut dyn Paused, arguments: &Value) -> Result<Value, String> {
+    let frame = self.frame(paused, arguments)?;
+    let expression = arguments["expression"].as_str().unwrap_or("");
+    let value = evaluate(paused, frame, expression)?;
+
+    Ok(json!({
+      "result": describe(&value),
+      "variablesReference": self.expand(&value),
+    }))
+  }
+
+  // Instances can be opened up to show their fields
+  fn expand(&mut self, value: &Inspected) -> usize {
+    match value {
+      Inspected::Object {
+        handle,
+        has_fields: true,
+        ..
+      } => self.reference(Reference::Object(*handle)),
+      _ => 0,
+    }
+  }
+}
+
+// Strings are quoted so they can't be mistaken for other values
+fn describe(value: &Inspected) -> String {
+  match value {
+    Inspected::String(string) => format!("{:?}", string),
+    value => value.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::io::Cursor;
+
+  use super::*;
+
+  // Output the test can still read once serve has it
+  #[derive(Clone, Default)]
+  struct Shared(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for Shared {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn framed(requests: &[(&str, Value)]) -> Vec<u8> {
+    let mut input = Vec::new();
+
+    for (seq, (command, arguments)) in requests.iter().enumerate() {
+      let body = json!({
+        "seq": seq + 1,
+        "type": "request",
+        "command": command,
+        "arguments": arguments,
+      })
+      .to_string();
+
+      write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+
+    input
+  }
+
+  // Runs a whole session with the source as the program
+  fn session(name: &str, source: &str, requests: &[(&str, Value)]) -> Vec<Value> {
+    let path = env::temp_dir().join(format!("rust-lox-dap-{}-{}.lox", std::process::id(), name));
+    fs::write(&path, source).unwrap();
+
+    let mut requests = requests.to_vec();
+    requests.insert(0, ("initialize", json!({})));
+    requests.insert(1, ("launch", json!({ "program": path, "backend": name })));
+
+    let output = Shared::default();
+    serve(Cursor::new(framed(&requests)), output.clone()).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let mut connection = Connection {
+      input: Box::new(Cursor::new(output.0.take())),
+      output: Box::new(io::sink()),
+      seq: 0,
+      disconnected: false,
+    };
+
+    let mut messages = Vec::new();
+
+    while let Some(message) = connection.receive().unwrap() {
+      messages.push(message);
+    }
+
+    messages
+  }
+
+  fn bodies<'m>(messages: &'m [Value], command: &str) -> Vec<&'m Value> {
+    messages
+      .iter()
+      .filter(|message| message["command"] == command || message["event"] == command)
+      .map(|message| &message["body"])
+      .collect()
+  }
+
+  const SOURCE: &str = "var a = 1;
+fun f(x) {
+  var y = x * 2;
+  return y;
+}
+print f(a);
+print \"done\";";
+
+  #[test]
+  fn test_session() {
+    for backend in ["interpreter", "vm"] {
+      let messages = session(
+        backend,
+        SOURCE,
+        &[
+          ("setBreakpoints", json!({ "breakpoints": [{ "line": 3 }] })),
+          ("configurationDone", json!({})),
+          ("stackTrace", json!({ "threadId": 1 })),
+          ("scopes", json!({ "frameId": 0 })),
+          ("variables", json!({ "variablesReference": 1 })),
+          ("evaluate", json!({ "expression": "x + a", "frameId": 0 })),
+          ("evaluate", json!({ "expression": "f(1)", "frameId": 0 })),
+          ("next", json!({})),
+          ("continue", json!({})),
+          ("disconnect", json!({})),
+        ],
+      );
+
+      let failed: Vec<_> = messages
+        .iter()
+        .filter(|message| message["success"] == false)
+        .map(|message| &message["command"])
+        .collect();
+      assert_eq!(failed, vec!["evaluate"]);
+
+      let stopped = bodies(&messages, "stopped");
+      assert_eq!(stopped[0]["reason"], "breakpoint");
+      assert_eq!(stopped[1]["reason"], "step");
+
+      let frames = &bodies(&messages, "stackTrace")[0]["stackFrames"];
+      assert_eq!(frames[0]["name"], "f");
+      assert_eq!(frames[0]["line"], 3);
+      assert_eq!(frames[1]["name"], "script");
+      assert_eq!(frames[1]["line"], 6);
+
+      let variables = &bodies(&messages, "variables")[0]["variables"];
+      assert_eq!(
+        variables,
+        &json!([{ "name": "x", "value": "1.0", "variablesReference": 0 }])
+      );
+      assert_eq!(bodies(&messages, "evaluate")[0]["result"], "2.0");
+
+      let output: String = bodies(&messages, "output")
+        .iter()
+        .map(|body| body["output"].as_str().unwrap())
+        .collect();
+      assert_eq!(output, "2.0\ndone\n");
+
+      assert_eq!(bodies(&messages, "exited")[0]["exitCode"], 0);
+      assert_eq!(messages.last().unwrap()["command"], "disconnect");
+    }
+  }
+
+  #[test]
+  fn test_runtime_error() {
+    let messages = session(
+      "vm",
+      "print -nil;",
+      &[("configurationDone", json!({})), ("disconnect", json!({}))],
+    );
+
+    let output = bodies(&messages, "output");
+    assert_eq!(output[0]["category"], "stderr");
+    assert_eq!(bodies(&messages, "exited")[0]["exitCode"], 70);
+  }
+
+  #[test]
+  fn test_disconnect_while_stopped() {
+    let messages = session(
+      "interpreter",
+      SOURCE,
+      &[
+        ("setBreakpoints", json!({ "breakpoints": [{ "line": 3 }] })),
+        ("configurationDone", json!({})),
+        ("disconnect", json!({})),
+      ],
+    );
+
+    // The program ends without printing and without more events
+    assert!(bodies(&messages, "output").is_empty());
+    assert_eq!(messages.last().unwrap()["command"], "disconnect");
+  }
+
+  #[test]
+  fn test_launch_arguments() {
+    assert_eq!(
+      Launch::parse(&json!({ "program": "a.lox" })),
+      Ok(Launch {
+        program: String::from("a.lox"),
+        stop_on_entry: false,
+        backend: Backend::Interpreter,
+      })
+    );
+    assert!(Launch::parse(&json!({})).is_err());
+    assert!(Launch::parse(&json!({ "program": "a.lox", "backend": "jit" })).is_err());
+  }
+
+  #[test]
+  fn test_message_too_long() {
+    let mut connection = Connection {
+      input: Box::new(Cursor::new(
+        b"Content-Length: 99999999999\r\n\r\n{}".to_vec(),
+      )),
+      output: Box::new(io::sink()),
+      seq: 0,
+      disconnected: false,
+    };
+
+    let error = connection.receive().unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+}
@@ -0,0 +1,211 @@
+use std::rc::Rc;
+
+use super::{Inspected, Paused};
+use crate::expression::{Expression, Literal};
+use crate::interpreter::{self, Operand};
+use crate::parser;
+use crate::scanner::{Token, TokenType};
+use crate::statement::Statement;
+
+// Evaluates a Lox expression in a frame of a stopped program. Looking at the
+// program mustn't change it, so calls and assignments aren't allowed.
+pub fn evaluate(paused: &mut dyn Paused, frame: usize, source: &str) -> Result<Inspected, String> {
+  let source = format!("{};", source);
+  let statements = parser::parse(&source).map_err(|errors| errors[0].to_string())?;
+
+  match statements.as_slice() {
+    [Statement::Expression { expression }] => Evaluator { paused, frame }.evaluate(expression),
+    _ => Err(String::from("Expected an expression")),
+  }
+}
+
+struct Evaluator<'p> {
+  paused: &'p mut dyn Paused,
+  frame: usize,
+}
+
+impl<'p> Evaluator<'p> {
+  fn evaluate(&mut self, expression: &Expression) -> Result<Inspected, String> {
+    match expression {
+      Expression::Binary {
+        left,
+        operator,
+        right,
+      } => {
+        let left = self.evaluate(left)?;
+        let right = self.evaluate(right)?;
+
+        interpreter::binary(operator, left, right).map_err(|error| error.message)
+      }
+      Expression::Get { object, name } => match self.evaluate(object)? {
+        Inspected::Object { handle, .. } => self
+          .paused
+          .fields(handle)
+          .into_iter()
+          .find(|field| field.name == name.text)
+          .map(|field| field.value)
+          .ok_or_else(|| format!("Undefined property {}", name.text)),
+        _ => Err(String::from("Can't access property of non-object value")),
+      },
+      Expression::Grouping { expression } => self.evaluate(expression),
+      Expression::Literal { value, .. } => Ok(match value {
+        Literal::Number(number) => Inspected::Number(*number),
+        Literal::String(string) => Inspected::String(Rc::from(*string)),
+        Literal::Bool(boolean) => Inspected::Bool(*boolean),
+        Literal::Nil => Inspected::Nil,
+      }),
+      Expression::Logical {
+        left,
+        operator,
+        right,
+      } => {
+        let left = self.evaluate(left)?;
+
+        let short_circuits = match operator.token_type {
+          TokenType::Or => left.is_truthy(),
+          _ => !left.is_truthy(),
+        };
+
+        if short_circuits {
+          Ok(left)
+        } else {
+          self.evaluate(right)
+        }
+      }
+      Expression::This { keyword, .. } => self.look_up(keyword),
+      Expression::Unary { operator, right } => {
+        let right = self.evaluate(right)?;
+
+        match operator.token_type {
+          TokenType::Bang => Ok(Inspected::Bool(!right.is_truthy())),
+          _ => match right {
+            Inspected::Number(number) => Ok(Inspected::Number(-number)),
+            _ => Err(String::from("Must be a number")),
+          },
+        }
+      }
+      Expression::Variable { name, .. } => self.look_up(name),
+      Expression::Assign { .. } | Expression::Set { .. } | Expression::Call { .. } => Err(
+        String::from("Can't call functions or assign while the program is stopped"),
+      ),
+      Expression::Super { .. } => Err(String::from("Can't evaluate super")),
+    }
+  }
+
+  fn look_up(&mut self, name: &Token) -> Result<Inspected, String> {
+    self
+      .paused
+      .look_up(self.frame, name.text)
+      .ok_or_else(|| format!("Undefined name {}.", name.text))
+  }
+}
+
+impl Operand for Inspected {
+  fn number(number: f64) -> Inspected {
+    Inspected::Number(number)
+  }
+
+  fn bool(boolean: bool) -> Inspected {
+    Inspected::Bool(boolean)
+  }
+
+  fn string(string: Rc<str>) -> Inspected {
+    Inspected::String(string)
+  }
+
+  fn as_number(&self) -> Option<f64> {
+    match self {
+      Inspected::Number(number) => Some(*number),
+      _ => None,
+    }
+  }
+
+  fn as_string(&self) -> Option<&str> {
+    match self {
+      Inspected::String(string) => Some(string),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::debugger::{Scope, Variable};
+  use crate::error::StackFrame;
+
+  // A stopped program with a few names and one instance
+  struct Fake;
+
+  impl Paused for Fake {
+    fn frames(&self) -> Vec<StackFrame> {
+      vec![StackFrame {
+        function: None,
+        line: 1,
+      }]
+    }
+
+    fn scopes(&mut self, _frame: usize) -> Vec<Scope> {
+      Vec::new()
+    }
+
+    fn look_up(&mut self, _frame: usize, name: &str) -> Option<Inspected> {
+      match name {
+        "a" => Some(Inspected::Number(2.0)),
+        "s" => Some(Inspected::String(Rc::from("lox"))),
+        "this" | "point" => Some(Inspected::Object {
+          text: String::from("Point instance"),
+          handle: 0,
+          has_fields: true,
+        }),
+        _ => None,
+      }
+    }
+
+    fn fields(&mut self, handle: usize) -> Vec<Variable> {
+      match handle {
+        0 => vec![Variable {
+          name: String::from("x"),
+          value: Inspected::Number(3.0),
+        }],
+        _ => Vec::new(),
+      }
+    }
+  }
+
+  fn evaluated(source: &str) -> Result<String, String> {
+    evaluate(&mut Fake, 0, source).map(|value| value.to_string())
+  }
+
+  #[test]
+  fn test_expressions() {
+    assert_eq!(evaluated("a * (a + 1)"), Ok(String::from("6.0")));
+    assert_eq!(evaluated("s + \"!\""), Ok(String::from("lox!")));
+    assert_eq!(evaluated("-a < 0 and !nil"), Ok(String::from("true")));
+    assert_eq!(evaluated("nil or a == 2"), Ok(String::from("true")));
+    assert_eq!(evaluated("this.x + point.x"), Ok(String::from("6.0")));
+    assert_eq!(evaluated("point"), Ok(String::from("Point instance")));
+  }
+
+  #[test]
+  fn test_errors() {
+    assert_eq!(evaluated("b"), Err(String::from("Undefined name b.")));
+    assert_eq!(
+      evaluated("point.y"),
+      Err(String::from("Undefined property y"))
+    );
+    assert_eq!(
+      evaluated("a + s"),
+      Err(String::from("Operands must be two numbers or two strings"))
+    );
+    assert_eq!(evaluated("-s"), Err(String::from("Must be a number")));
+    assert_eq!(
+      evaluated("a.x"),
+      Err(String::from("Can't access property of non-object value"))
+    );
+    assert!(evaluated("a = 1").is_err());
+    assert!(evaluated("clock()").is_err());
+    assert!(evaluated("var x = 1").is_err());
+    assert!(evaluated("1 +").is_err());
+  }
+}
@@ -0,0 +1,572 @@
+mod dap;
+mod evaluate;
+
+use std::fmt;
+use std::rc::Rc;
+
+pub use self::dap::serve;
+pub use self::evaluate::evaluate;
+use super::error::StackFrame;
+use super::interpreter::format_number;
+
+// Debugging shared by the tree-walker and the VM. A backend tells the
+// debugger whenever a new line starts, along with a view of its state. The
+// debugger works out whether to stop there and if so lets a front end, like
+// the DAP adapter, look around until it says how to go on.
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Breakpoint {
+  pub line: u32,
+  // A Lox expression, evaluated in the frame that reached the line. Stops
+  // when it's truthy, or when it can't be evaluated.
+  pub condition: Option<String>,
+}
+
+impl Breakpoint {
+  pub fn new(line: u32) -> Breakpoint {
+    Breakpoint {
+      line,
+      condition: None,
+    }
+  }
+
+  pub fn conditional(line: u32, condition: &str) -> Breakpoint {
+    Breakpoint {
+      line,
+      condition: Some(String::from(condition)),
+    }
+  }
+}
+
+// How a stopped program goes on
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Resume {
+  Continue,
+  // Stops at the next line, in whichever function it is
+  StepIn,
+  // Stops at the next line of this function or of the one it returns to
+  StepOver,
+  // Stops once this function has returned
+  StepOut,
+  // Ends the program
+  Terminate,
+}
+
+// Why the program stopped
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Stop {
+  Entry,
+  Breakpoint,
+  Step,
+}
+
+// A value of the stopped program
+#[derive(Debug, PartialEq, Clone)]
+pub enum Inspected {
+  Nil,
+  Bool(bool),
+  Number(f64),
+  String(Rc<str>),
+  // Everything else, printed the way print would. The handle is the same for
+  // the same object and stays valid until the program goes on.
+  Object {
+    text: String,
+    handle: usize,
+    has_fields: bool,
+  },
+}
+
+impl Inspected {
+  pub fn is_truthy(&self) -> bool {
+    !matches!(self, Inspected::Nil | Inspected::Bool(false))
+  }
+}
+
+impl fmt::Display for Inspected {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Inspected::Nil => write!(f, "nil"),
+      Inspected::Bool(boolean) => write!(f, "{}", boolean),
+      Inspected::Number(number) => write!(f, "{}", format_number(*number)),
+      Inspected::String(string) => write!(f, "{}", string),
+      Inspected::Object { text, .. } => write!(f, "{}", text),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Variable {
+  pub name: String,
+  pub value: Inspected,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ScopeKind {
+  Locals,
+  // Variables of enclosing functions the frame's closure can see
+  Upvalues,
+  Globals,
+}
+
+impl ScopeKind {
+  pub fn name(self) -> &'static str {
+    match self {
+      ScopeKind::Locals => "Locals",
+      ScopeKind::Upvalues => "Upvalues",
+      ScopeKind::Globals => "Globals",
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Scope {
+  pub kind: ScopeKind,
+  pub variables: Vec<Variable>,
+}
+
+// A backend stopped at the start of a line. Frames are counted from the
+// innermost one, which is frame zero.
+pub trait Paused {
+  fn frames(&self) -> Vec<StackFrame>;
+  // Locals, upvalues and globals, in that order
+  fn scopes(&mut self, frame: usize) -> Vec<Scope>;
+  // What the name means to the code of the frame, None when it's undefined
+  fn look_up(&mut self, frame: usize, name: &str) -> Option<Inspected>;
+  // Empty for objects other than instances
+  fn fields(&mut self, handle: usize) -> Vec<Variable>;
+}
+
+// What a debugger is driven by, the breakpoints can be changed while stopped
+pub trait Frontend {
+  fn stopped(
+    &mut self,
+    stop: Stop,
+    paused: &mut dyn Paused,
+    breakpoints: &mut Vec<Breakpoint>,
+  ) -> Resume;
+}
+
+impl<F> Frontend for F
+where
+  F: FnMut(Stop, &mut dyn Paused, &mut Vec<Breakpoint>) -> Resume,
+{
+  fn stopped(
+    &mut self,
+    stop: Stop,
+    paused: &mut dyn Paused,
+    breakpoints: &mut Vec<Breakpoint>,
+  ) -> Resume {
+    self(stop, paused, breakpoints)
+  }
+}
+
+// The front end asked for the program to end
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Terminated;
+
+pub struct Debugger {
+  frontend: Box<dyn Frontend>,
+  breakpoints: Vec<Breakpoint>,
+  entry: bool,
+  // How the program was last resumed and how deep its stack was then
+  resume: Resume,
+  depth: usize,
+  // Where the last line was reached. A line with several statements, or a
+  // loop that doesn't leave it, is only reported once.
+  last: Option<(u32, usize)>,
+}
+
+impl Debugger {
+  pub fn new(frontend: impl Frontend + 'static) -> Debugger {
+    Debugger {
+      frontend: Box::new(frontend),
+      breakpoints: Vec::new(),
+      entry: false,
+      resume: Resume::Continue,
+      depth: 0,
+      last: None,
+    }
+  }
+
+  // Stops at the first line with Stop::Entry
+  pub fn stop_on_entry(mut self) -> Debugger {
+    self.entry = true;
+    self
+  }
+
+  pub fn breakpoints(&self) -> &[Breakpoint] {
+    &self.breakpoints
+  }
+
+  pub fn set_breakpoints(&mut self, breakpoints: Vec<Breakpoint>) {
+    self.breakpoints = breakpoints;
+  }
+
+  // For backends, when a frame is pushed. A call on the same line as the
+  // last one is still a new line.
+  pub fn called(&mut self) {
+    self.last = None;
+  }
+
+  // For backends, when a line starts in the innermost frame. The depth counts
+  // the script's frame.
+  pub fn reached(
+    &mut self,
+    line: u32,
+    depth: usize,
+    paused: &mut dyn Paused,
+  ) -> Result<(), Terminated> {
+    if self.last == Some((line, depth)) {
+      return Ok(());
+    }
+
+    self.last = Some((line, depth));
+
+    let stop = if self.entry {
+      self.entry = false;
+      Some(Stop::Entry)
+    } else if self.hits_breakpoint(line, paused) {
+      Some(Stop::Breakpoint)
+    } else {
+      match self.resume {
+        Resume::StepIn => Some(Stop::Step),
+        Resume::StepOver if depth <= self.depth => Some(Stop::Step),
+        Resume::StepOut if depth < self.depth => Some(Stop::Step),
+        _ => None,
+      }
+    };
+
+    if let Some(stop) = stop {
+      self.resume = self.frontend.stopped(stop, paused, &mut self.breakpoints);
+      self.depth = depth;
+    }
+
+    match self.resume {
+      Resume::Terminate => Err(Terminated),
+      _ => Ok(()),
+    }
+  }
+
+  fn hits_breakpoint(&self, line: u32, paused: &mut dyn Paused) -> bool {
+    self
+      .breakpoints
+      .iter()
+      .filter(|breakpoint| breakpoint.line == line)
+      .any(|breakpoint| match &breakpoint.condition {
+        Some(condition) => evaluate(paused, 0, condition).map_or(true, |value| value.is_truthy()),
+        None => true,
+      })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+
+  use super::*;
+  use crate::compiler;
+  use crate::interpreter::Interpreter;
+  use crate::parser;
+  use crate::resolver;
+  use crate::vm::Vm;
+
+  // What a front end saw, once for each stop
+  type Seen<T> = Rc<RefCell<Vec<T>>>;
+
+  // Runs the source on the interpreter and then on the VM, giving back what
+  // each printed
+  fn on_both(source: &str, debugger: impl Fn() -> Debugger) -> (String, String) {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    let mut interpreter = Interpreter::new(&resolution, Vec::new());
+    interpreter.set_debugger(Some(debugger()));
+    let _ = interpreter.interpret(&statements);
+
+    let mut vm = Vm::new(Vec::new());
+    vm.set_debugger(Some(debugger()));
+    let _ = vm.interpret(compiler::compile(&statements, &resolution).unwrap());
+
+    (
+      String::from_utf8(interpreter.into_output()).unwrap(),
+      String::from_utf8(vm.into_output()).unwrap(),
+    )
+  }
+
+  // Looks at every stop with see and goes on as told, then continues
+  fn watching<T: 'static>(
+    breakpoints: Vec<Breakpoint>,
+    resumes: Vec<Resume>,
+    seen: &Seen<T>,
+    see: fn(Stop, &mut dyn Paused) -> T,
+  ) -> Debugger {
+    let seen = Rc::clone(seen);
+    let mut resumes = resumes.into_iter();
+
+    let mut debugger = Debugger::new(
+      move |stop: Stop, paused: &mut dyn Paused, _: &mut Vec<Breakpoint>| {
+        seen.borrow_mut().push(see(stop, paused));
+        resumes.next().unwrap_or(Resume::Continue)
+      },
+    );
+    debugger.set_breakpoints(breakpoints);
+    debugger
+  }
+
+  fn stop_at(stop: Stop, paused: &mut dyn Paused) -> (Stop, Option<String>, u32) {
+    let frame = paused.frames().remove(0);
+    (stop, frame.function, frame.line)
+  }
+
+  // Where the program stops, which has to be the same on both backends
+  fn stops(
+    source: &str,
+    breakpoints: &[Breakpoint],
+    resumes: &[Resume],
+  ) -> Vec<(Stop, Option<String>, u32)> {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    on_both(source, || {
+      watching(breakpoints.to_vec(), resumes.to_vec(), &seen, stop_at)
+    });
+
+    let seen = seen.take();
+    let (interpreter, vm) = seen.split_at(seen.len() / 2);
+    assert_eq!(interpreter, vm);
+
+    interpreter.to_vec()
+  }
+
+  fn function(name: &str) -> Option<String> {
+    Some(String::from(name))
+  }
+
+  const CALLS: &str = "fun f(x) {
+    var y = x + 1;
+    return y;
+  }
+  var a = f(1);
+  print a;
+  print f(a);";
+
+  #[test]
+  fn test_breakpoints() {
+    assert_eq!(
+      stops(CALLS, &[Breakpoint::new(3), Breakpoint::new(6)], &[]),
+      vec![
+        (Stop::Breakpoint, function("f"), 3),
+        (Stop::Breakpoint, None, 6),
+        (Stop::Breakpoint, function("f"), 3),
+      ]
+    );
+
+    // Lines without code never stop
+    assert_eq!(stops(CALLS, &[Breakpoint::new(4)], &[]), vec![]);
+  }
+
+  #[test]
+  fn test_stop_on_entry() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    on_both(CALLS, || {
+      watching(Vec::new(), Vec::new(), &seen, stop_at).stop_on_entry()
+    });
+
+    assert_eq!(
+      seen.take(),
+      vec![(Stop::Entry, None, 1), (Stop::Entry, None, 1)]
+    );
+  }
+
+  #[test]
+  fn test_conditional_breakpoints() {
+    let source = "for (var i = 0; i < 5; i = i + 1) {
+      print i;
+    }";
+
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    on_both(source, || {
+      watching(
+        vec![Breakpoint::conditional(2, "i == 3 or i == 4")],
+        Vec::new(),
+        &seen,
+        |_, paused| evaluate(paused, 0, "i").unwrap(),
+      )
+    });
+
+    let numbers = vec![Inspected::Number(3.0), Inspected::Number(4.0)];
+    assert_eq!(seen.take(), [numbers.clone(), numbers].concat());
+
+    // Conditions that don't evaluate are better seen than hidden
+    assert_eq!(
+      stops(source, &[Breakpoint::conditional(2, "nope")], &[]).len(),
+      5
+    );
+  }
+
+  #[test]
+  fn test_step_in_and_out() {
+    assert_eq!(
+      stops(
+        CALLS,
+        &[Breakpoint::new(5)],
+        &[
+          Resume::StepIn,
+          Resume::StepIn,
+          Resume::StepOut,
+          Resume::StepIn
+        ]
+      ),
+      vec![
+        (Stop::Breakpoint, None, 5),
+        (Stop::Step, function("f"), 2),
+        (Stop::Step, function("f"), 3),
+        (Stop::Step, None, 6),
+        (Stop::Step, None, 7),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_step_over() {
+    assert_eq!(
+      stops(
+        CALLS,
+        &[Breakpoint::new(3)],
+        &[Resume::StepOver, Resume::StepOver, Resume::StepOver]
+      ),
+      vec![
+        (Stop::Breakpoint, function("f"), 3),
+        (Stop::Step, None, 6),
+        (Stop::Step, None, 7),
+        // Stepping over a call still stops at breakpoints in it
+        (Stop::Breakpoint, function("f"), 3),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_terminate() {
+    let (interpreter, vm) = on_both(CALLS, || {
+      let mut debugger =
+        Debugger::new(|_: Stop, _: &mut dyn Paused, _: &mut Vec<Breakpoint>| Resume::Terminate);
+      debugger.set_breakpoints(vec![Breakpoint::new(7)]);
+      debugger
+    });
+
+    assert_eq!(interpreter, "2.0\n");
+    assert_eq!(vm, "2.0\n");
+  }
+
+  #[test]
+  fn test_breakpoints_changed_while_stopped() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    on_both(CALLS, || {
+      let seen = Rc::clone(&seen);
+      let mut debugger = Debugger::new(
+        move |stop: Stop, paused: &mut dyn Paused, breakpoints: &mut Vec<Breakpoint>| {
+          seen.borrow_mut().push(stop_at(stop, paused));
+          *breakpoints = vec![Breakpoint::new(7)];
+          Resume::Continue
+        },
+      );
+      debugger.set_breakpoints(vec![Breakpoint::new(3)]);
+      debugger
+    });
+
+    let stops = vec![
+      (Stop::Breakpoint, function("f"), 3),
+      (Stop::Breakpoint, None, 7),
+    ];
+    assert_eq!(seen.take(), [stops.clone(), stops].concat());
+  }
+
+  fn sorted(scope: &Scope) -> Vec<(String, String)> {
+    let mut variables: Vec<_> = scope
+      .variables
+      .iter()
+      .map(|variable| (variable.name.clone(), variable.value.to_string()))
+      .collect();
+    variables.sort();
+    variables
+  }
+
+  #[test]
+  fn test_scopes() {
+    let source = "var g = \"global\";
+    fun outer() {
+      var captured = 1;
+      fun inner(p) {
+        var local = p + 1;
+        print captured + local;
+      }
+      inner(2);
+    }
+    outer();";
+
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    on_both(source, || {
+      watching(vec![Breakpoint::new(6)], Vec::new(), &seen, |_, paused| {
+        (paused.scopes(0), paused.scopes(1), paused.frames())
+      })
+    });
+
+    for (inner, outer, frames) in seen.take() {
+      let names: Vec<_> = inner.iter().map(|scope| scope.kind).collect();
+      assert_eq!(
+        names,
+        vec![ScopeKind::Locals, ScopeKind::Upvalues, ScopeKind::Globals]
+      );
+
+      assert_eq!(
+        sorted(&inner[0]),
+        vec![
+          (String::from("local"), String::from("3.0")),
+          (String::from("p"), String::from("2.0")),
+        ]
+      );
+      assert!(sorted(&inner[1]).contains(&(String::from("captured"), String::from("1.0"))));
+      assert!(sorted(&inner[2]).contains(&(String::from("g"), String::from("global"))));
+
+      // The caller is stopped where it made the call
+      assert!(sorted(&outer[0]).contains(&(String::from("captured"), String::from("1.0"))));
+      assert_eq!(frames[1].line, 8);
+    }
+  }
+
+  #[test]
+  fn test_fields_and_this() {
+    let source = "class Point {
+      init(x) {
+        this.x = x;
+      }
+      show() {
+        print this.x;
+      }
+    }
+    Point(1).show();";
+
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    on_both(source, || {
+      watching(vec![Breakpoint::new(6)], Vec::new(), &seen, |_, paused| {
+        let this = paused.look_up(0, "this").unwrap();
+
+        let fields = match &this {
+          Inspected::Object {
+            handle,
+            has_fields: true,
+            ..
+          } => paused.fields(*handle),
+          _ => Vec::new(),
+        };
+
+        (this.to_string(), fields)
+      })
+    });
+
+    let fields = vec![Variable {
+      name: String::from("x"),
+      value: Inspected::Number(1.0),
+    }];
+    let expected = (String::from("Point instance"), fields);
+    assert_eq!(seen.take(), vec![expected.clone(), expected]);
+  }
+}
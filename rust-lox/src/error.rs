@@ -0,0 +1,81 @@
+use std::fmt;
+
+// One entry of a Lox stack trace, `function` is None for top level code
+#[derive(Debug, PartialEq, Clone)]
+pub struct StackFrame {
+  pub function: Option<String>,
+  pub line: u32,
+}
+
+impl fmt::Display for StackFrame {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.function {
+      Some(function) => write!(f, "[line {}] in {}()", self.line, function),
+      None => write!(f, "[line {}] in script", self.line),
+    }
+  }
+}
+
+// Shared by every backend so their failures can be compared as they are
+#[derive(Debug, PartialEq, Clone)]
+pub struct RuntimeError {
+  pub line: u32,
+  pub message: String,
+  // Innermost frame first, empty until the error has left the frame it was
+  // raised in
+  pub trace: Vec<StackFrame>,
+}
+
+impl RuntimeError {
+  pub fn new(line: u32, message: impl Into<String>) -> RuntimeError {
+    RuntimeError {
+      line,
+      message: message.into(),
+      trace: Vec::new(),
+    }
+  }
+}
+
+impl fmt::Display for RuntimeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "runtime error. line: {} - error: {}",
+      self.line, self.message
+    )?;
+
+    for frame in &self.trace {
+      write!(f, "\n{}", frame)?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_display() {
+    let error = RuntimeError {
+      line: 3,
+      message: String::from("Must be a number"),
+      trace: vec![
+        StackFrame {
+          function: Some(String::from("inner")),
+          line: 3,
+        },
+        StackFrame {
+          function: None,
+          line: 7,
+        },
+      ],
+    };
+
+    assert_eq!(
+      error.to_string(),
+      "runtime error. line: 3 - error: Must be a number\n[line 3] in inner()\n[line 7] in script"
+    );
+  }
+}
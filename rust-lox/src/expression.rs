@@ -1,22 +1,88 @@
 use super::scanner;
 
+// Identifies the nodes that later passes attach information to (variable
+// references, declarations) since Rust has no equivalent of Ruby's object_id
+pub type NodeId = usize;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Literal<'a> {
+  Number(f64),
+  String(&'a str),
+  Bool(bool),
+  Nil,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Expression<'a> {
+  Assign {
+    id: NodeId,
+    name: scanner::Token<'a>,
+    value: Box<Expression<'a>>,
+  },
   Binary {
     left: Box<Expression<'a>>,
     operator: scanner::Token<'a>,
     right: Box<Expression<'a>>,
   },
+  Call {
+    callee: Box<Expression<'a>>,
+    paren: scanner::Token<'a>,
+    arguments: Vec<Expression<'a>>,
+  },
+  Get {
+    object: Box<Expression<'a>>,
+    name: scanner::Token<'a>,
+  },
   Grouping {
     expression: Box<Expression<'a>>,
   },
   Literal {
-    value: f32,
+    value: Literal<'a>,
+    line: u32,
+  },
+  Logical {
+    left: Box<Expression<'a>>,
+    operator: scanner::Token<'a>,
+    right: Box<Expression<'a>>,
+  },
+  Set {
+    object: Box<Expression<'a>>,
+    name: scanner::Token<'a>,
+    value: Box<Expression<'a>>,
+  },
+  Super {
+    id: NodeId,
+    keyword: scanner::Token<'a>,
+    method: scanner::Token<'a>,
+  },
+  This {
+    id: NodeId,
+    keyword: scanner::Token<'a>,
   },
   Unary {
     operator: scanner::Token<'a>,
     right: Box<Expression<'a>>,
   },
+  Variable {
+    id: NodeId,
+    name: scanner::Token<'a>,
+  },
+}
+
+impl<'a> Expression<'a> {
+  // The line where the expression starts
+  pub fn line(&self) -> u32 {
+    match self {
+      Expression::Assign { name, .. } | Expression::Variable { name, .. } => name.line,
+      Expression::Binary { left, .. } | Expression::Logical { left, .. } => left.line(),
+      Expression::Call { callee, .. } => callee.line(),
+      Expression::Get { object, .. } | Expression::Set { object, .. } => object.line(),
+      Expression::Grouping { expression } => expression.line(),
+      Expression::Literal { line, .. } => *line,
+      Expression::Super { keyword, .. } | Expression::This { keyword, .. } => keyword.line,
+      Expression::Unary { operator, .. } => operator.line,
+    }
+  }
 }
 
 #[cfg(test)]
@@ -25,16 +91,22 @@ mod tests {
 
   #[test]
   fn test_something() {
-    let left = Box::new(Expression::Literal { value: 666.0 });
-    let right = Box::new(Expression::Literal { value: 42.0 });
-    Expression::Binary {
-      left: left,
+    let left = Box::new(Expression::Literal {
+      value: Literal::Number(666.0),
+      line: 1,
+    });
+    let right = Box::new(Expression::Literal {
+      value: Literal::Number(42.0),
+      line: 1,
+    });
+    let _ = Expression::Binary {
+      left,
       operator: scanner::Token {
         token_type: scanner::TokenType::Plus,
         text: "+",
         line: 1,
       },
-      right: right,
+      right,
     };
   }
 }
@@ -0,0 +1,50 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+// Hands out one shared copy of each distinct string, so names and literals
+// used all over a program only get allocated once
+#[derive(Debug, Default)]
+pub struct Interner {
+  strings: HashSet<Rc<str>>,
+}
+
+impl Interner {
+  pub fn new() -> Interner {
+    Interner::default()
+  }
+
+  pub fn intern(&mut self, string: &str) -> Rc<str> {
+    if let Some(interned) = self.strings.get(string) {
+      return Rc::clone(interned);
+    }
+
+    let interned: Rc<str> = Rc::from(string);
+    self.strings.insert(Rc::clone(&interned));
+
+    interned
+  }
+
+  pub fn len(&self) -> usize {
+    self.strings.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.strings.is_empty()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_intern() {
+    let mut interner = Interner::new();
+    let a = interner.intern("a");
+    let other = interner.intern(&String::from("a"));
+
+    assert!(Rc::ptr_eq(&a, &other));
+    assert!(!Rc::ptr_eq(&a, &interner.intern("b")));
+    assert_eq!(interner.len(), 2);
+  }
+}
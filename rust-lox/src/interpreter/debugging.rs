@@ -0,0 +1,209 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::rc::Rc;
+
+use super::environment::EnvironmentRef;
+use super::{Interpreter, Value};
+use crate::debugger::{Debugger, Inspected, Paused, Scope, ScopeKind, Variable};
+use crate::error::{RuntimeError, StackFrame};
+
+impl<'r, 'a, W: Write> Interpreter<'r, 'a, W> {
+  // Starts debugging, or stops it with None. Gives back the debugger there was.
+  pub fn set_debugger(&mut self, debugger: Option<Debugger>) -> Option<Debugger> {
+    std::mem::replace(&mut self.debugger, debugger)
+  }
+
+  // Before a statement starting on the line runs
+  pub(super) fn debug(&mut self, line: u32) -> Result<(), RuntimeError> {
+    let mut debugger = match self.debugger.take() {
+      Some(debugger) => debugger,
+      None => return Ok(()),
+    };
+
+    let depth = self.frames.len() + 1;
+    let result = debugger.reached(
+      line,
+      depth,
+      &mut Inspector {
+        interpreter: self,
+        line,
+        handles: Vec::new(),
+      },
+    );
+    self.debugger = Some(debugger);
+
+    result.map_err(|_| RuntimeError::new(line, "Terminated by the debugger"))
+  }
+}
+
+// The interpreter as the debugger sees it while it's stopped
+struct Inspector<'i, 'r, 'a, W: Write> {
+  interpreter: &'i Interpreter<'r, 'a, W>,
+  line: u32,
+  // Objects handed out so far, by handle
+  handles: Vec<Value<'a>>,
+}
+
+impl<'i, 'r, 'a, W: Write> Inspector<'i, 'r, 'a, W> {
+  // The frame's innermost environment and the one holding its parameters,
+  // None for top level code
+  fn environments(&self, frame: usize) -> (Option<EnvironmentRef<'a>>, Option<EnvironmentRef<'a>>) {
+    let frames = &self.interpreter.frames;
+
+    let current = match frame {
+      0 => self.interpreter.environment.clone(),
+      _ => frames[frames.len() - frame].caller.clone(),
+    };
+
+    let parameters = frames
+      .len()
+      .checked_sub(frame + 1)
+      .map(|index| Rc::clone(&frames[index].environment));
+
+    (current, parameters)
+  }
+
+  fn inspect(&mut self, value: Value<'a>) -> Inspected {
+    match value {
+      Value::Nil => Inspected::Nil,
+      Value::Bool(boolean) => Inspected::Bool(boolean),
+      Value::Number(number) => Inspected::Number(number),
+      Value::String(string) => Inspected::String(string),
+      object => {
+        let has_fields =
+          matches!(&object, Value::Instance(instance) if !instance.fields.borrow().is_empty());
+        let text = object.to_string();
+
+        // Objects are only equal to themselves
+        let handle = match self.handles.iter().position(|handle| *handle == object) {
+          Some(handle) => handle,
+          None => {
+            self.handles.push(object);
+            self.handles.len() - 1
+          }
+        };
+
+        Inspected::Object {
+          text,
+          handle,
+          has_fields,
+        }
+      }
+    }
+  }
+
+  fn variables(&mut self, variables: Vec<(&str, Value<'a>)>) -> Vec<Variable> {
+    variables
+      .into_iter()
+      .map(|(name, value)| Variable {
+        name: String::from(name),
+        value: self.inspect(value),
+      })
+      .collect()
+  }
+}
+
+impl<'i, 'r, 'a, W: Write> Paused for Inspector<'i, 'r, 'a, W> {
+  fn frames(&self) -> Vec<StackFrame> {
+    let mut frames = Vec::new();
+    let mut line = self.line;
+
+    for frame in self.interpreter.frames.iter().rev() {
+      frames.push(StackFrame {
+        function: Some(frame.function.to_string()),
+        line,
+      });
+      line = frame.line;
+    }
+
+    frames.push(StackFrame {
+      function: None,
+      line,
+    });
+
+    frames
+  }
+
+  fn scopes(&mut self, frame: usize) -> Vec<Scope> {
+    let (mut environment, parameters) = self.environments(frame);
+    let mut locals = Vec::new();
+    let mut upvalues = Vec::new();
+    let mut in_function = true;
+    // Inner variables hide outer ones with the same name
+    let mut seen = HashSet::new();
+
+    while let Some(current) = environment {
+      for (name, value) in current.borrow().variables() {
+        if seen.insert(name) {
+          if in_function {
+            locals.push((name, value));
+          } else {
+            upvalues.push((name, value));
+          }
+        }
+      }
+
+      if parameters
+        .as_ref()
+        .is_some_and(|parameters| Rc::ptr_eq(parameters, &current))
+      {
+        in_function = false;
+      }
+
+      environment = current.borrow().enclosing();
+    }
+
+    let mut globals: Vec<_> = self
+      .interpreter
+      .globals
+      .iter()
+      .map(|(name, value)| (*name, value.clone()))
+      .collect();
+    globals.sort_by(|left, right| left.0.cmp(right.0));
+
+    vec![
+      Scope {
+        kind: ScopeKind::Locals,
+        variables: self.variables(locals),
+      },
+      Scope {
+        kind: ScopeKind::Upvalues,
+        variables: self.variables(upvalues),
+      },
+      Scope {
+        kind: ScopeKind::Globals,
+        variables: self.variables(globals),
+      },
+    ]
+  }
+
+  fn look_up(&mut self, frame: usize, name: &str) -> Option<Inspected> {
+    let (mut environment, _) = self.environments(frame);
+
+    while let Some(current) = environment {
+      if let Some(value) = current.borrow().get(name) {
+        return Some(self.inspect(value));
+      }
+
+      environment = current.borrow().enclosing();
+    }
+
+    let value = self.interpreter.globals.get(name)?.clone();
+    Some(self.inspect(value))
+  }
+
+  fn fields(&mut self, handle: usize) -> Vec<Variable> {
+    let mut fields: Vec<_> = match self.handles.get(handle) {
+      Some(Value::Instance(instance)) => instance
+        .fields
+        .borrow()
+        .iter()
+        .map(|(name, value)| (*name, value.clone()))
+        .collect(),
+      _ => Vec::new(),
+    };
+
+    fields.sort_by(|left, right| left.0.cmp(right.0));
+    self.variables(fields)
+  }
+}
@@ -0,0 +1,72 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::value::Value;
+
+pub type EnvironmentRef<'a> = Rc<RefCell<Environment<'a>>>;
+
+// One lexical scope. Globals live in the interpreter itself so the outermost
+// environment has no enclosing one.
+pub struct Environment<'a> {
+  values: HashMap<&'a str, Value<'a>>,
+  enclosing: Option<EnvironmentRef<'a>>,
+}
+
+impl<'a> Environment<'a> {
+  pub fn new(enclosing: Option<EnvironmentRef<'a>>) -> EnvironmentRef<'a> {
+    Rc::new(RefCell::new(Environment {
+      values: HashMap::new(),
+      enclosing,
+    }))
+  }
+
+  pub fn define(&mut self, name: &'a str, value: Value<'a>) {
+    self.values.insert(name, value);
+  }
+
+  pub fn get(&self, name: &str) -> Option<Value<'a>> {
+    self.values.get(name).cloned()
+  }
+
+  // Sorted by name, for the debugger
+  pub fn variables(&self) -> Vec<(&'a str, Value<'a>)> {
+    let mut variables: Vec<_> = self
+      .values
+      .iter()
+      .map(|(name, value)| (*name, value.clone()))
+      .collect();
+
+    variables.sort_by(|left, right| left.0.cmp(right.0));
+    variables
+  }
+
+  pub fn enclosing(&self) -> Option<EnvironmentRef<'a>> {
+    self.enclosing.clone()
+  }
+
+  pub fn assign(&mut self, name: &str, value: Value<'a>) -> bool {
+    match self.values.get_mut(name) {
+      Some(existing) => {
+        *existing = value;
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+pub fn ancestor<'a>(environment: &EnvironmentRef<'a>, depth: usize) -> EnvironmentRef<'a> {
+  let mut environment = Rc::clone(environment);
+
+  for _ in 0..depth {
+    let enclosing = environment
+      .borrow()
+      .enclosing
+      .clone()
+      .expect("[bug] resolved depth is deeper than the environment chain");
+    environment = enclosing;
+  }
+
+  environment
+}
@@ -0,0 +1,1005 @@
+mod debugging;
+mod environment;
+mod value;
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+use self::environment::{ancestor, Environment, EnvironmentRef};
+pub use self::value::{format_number, Class, Instance, LoxFunction, Operand, Value};
+use super::debugger::Debugger;
+use super::error::{RuntimeError, StackFrame};
+use super::expression::{Expression, Literal, NodeId};
+use super::native::{Native, NativeRegistry};
+use super::resolver::{Binding, Resolution};
+use super::scanner::{Token, TokenType};
+use super::statement::{Function, Statement};
+
+// Same limit as the bytecode VM, deep recursion would otherwise overflow the
+// Rust stack
+const MAX_FRAMES: usize = 64;
+
+// Ways of leaving a statement early. `return` travels up to the call the same
+// way errors do.
+enum Unwind<'a> {
+  Return(Value<'a>),
+  Error(RuntimeError),
+}
+
+impl<'a> From<RuntimeError> for Unwind<'a> {
+  fn from(error: RuntimeError) -> Unwind<'a> {
+    Unwind::Error(error)
+  }
+}
+
+type ExecuteResult<'a> = Result<(), Unwind<'a>>;
+type EvaluateResult<'a> = Result<Value<'a>, RuntimeError>;
+
+struct CallFrame<'a> {
+  function: &'a str,
+  // Where the function was called from, in the caller
+  line: u32,
+  // The one holding the parameters, and the caller's at the time of the call
+  environment: EnvironmentRef<'a>,
+  caller: Option<EnvironmentRef<'a>>,
+}
+
+pub struct Interpreter<'r, 'a, W: Write> {
+  resolution: &'r Resolution,
+  globals: HashMap<&'a str, Value<'a>>,
+  // None while executing top level code
+  environment: Option<EnvironmentRef<'a>>,
+  frames: Vec<CallFrame<'a>>,
+  output: W,
+  debugger: Option<Debugger>,
+}
+
+impl<'r, 'a, W: Write> Interpreter<'r, 'a, W> {
+  pub fn new(resolution: &'r Resolution, output: W) -> Interpreter<'r, 'a, W> {
+    Interpreter::with_natives(resolution, output, &NativeRegistry::default())
+  }
+
+  pub fn with_natives(
+    resolution: &'r Resolution,
+    output: W,
+    natives: &NativeRegistry,
+  ) -> Interpreter<'r, 'a, W> {
+    let globals = natives
+      .iter()
+      .map(|native| (native.name, Value::Native(Rc::clone(native))))
+      .collect();
+
+    Interpreter {
+      resolution,
+      globals,
+      environment: None,
+      frames: Vec::new(),
+      output,
+      debugger: None,
+    }
+  }
+
+  pub fn output(&self) -> &W {
+    &self.output
+  }
+
+  pub fn into_output(self) -> W {
+    self.output
+  }
+
+  pub fn interpret(&mut self, statements: &[Statement<'a>]) -> Result<(), RuntimeError> {
+    for statement in statements {
+      match self.execute(statement) {
+        Ok(()) => (),
+        Err(Unwind::Error(error)) => return Err(self.with_trace(error)),
+        Err(Unwind::Return(_)) => unreachable!("[bug] return outside of function"),
+      }
+    }
+
+    Ok(())
+  }
+
+  fn execute(&mut self, statement: &Statement<'a>) -> ExecuteResult<'a> {
+    if self.debugger.is_some() {
+      if let Some(line) = statement.line() {
+        self.debug(line)?;
+      }
+    }
+
+    match statement {
+      Statement::Block { statements } => {
+        let environment = Environment::new(self.environment.clone());
+        self.execute_block(statements, environment)
+      }
+      Statement::Class {
+        id,
+        name,
+        superclass,
+        methods,
+      } => self.execute_class(*id, name, superclass.as_ref(), methods),
+      Statement::Expression { expression } => {
+        self.evaluate(expression)?;
+        Ok(())
+      }
+      Statement::Function { function } => {
+        let value = Value::Function(Rc::new(LoxFunction {
+          declaration: Rc::clone(function),
+          closure: self.environment.clone(),
+          is_initializer: false,
+        }));
+
+        self.define(function.id, function.name.text, value);
+        Ok(())
+      }
+      Statement::If {
+        condition,
+        then_branch,
+        else_branch,
+      } => {
+        if self.evaluate(condition)?.is_truthy() {
+          self.execute(then_branch)
+        } else if let Some(else_branch) = else_branch {
+          self.execute(else_branch)
+        } else {
+          Ok(())
+        }
+      }
+      Statement::Print { expression } => {
+        let value = self.evaluate(expression)?;
+
+        writeln!(self.output, "{}", value).map_err(|error| {
+          RuntimeError::new(
+            expression.line(),
+            format!("Couldn't write output: {}", error),
+          )
+        })?;
+        Ok(())
+      }
+      Statement::Return { value, .. } => {
+        let value = match value {
+          Some(value) => self.evaluate(value)?,
+          None => Value::Nil,
+        };
+
+        Err(Unwind::Return(value))
+      }
+      Statement::Var {
+        id,
+        name,
+        initializer,
+      } => {
+        let value = match initializer {
+          Some(initializer) => self.evaluate(initializer)?,
+          None => Value::Nil,
+        };
+
+        self.define(*id, name.text, value);
+        Ok(())
+      }
+      Statement::While { condition, body } => {
+        while self.evaluate(condition)?.is_truthy() {
+          self.execute(body)?;
+
+          // Back at the condition, the VM starts its line again here too
+          if self.debugger.is_some() {
+            self.debug(condition.line())?;
+          }
+        }
+
+        Ok(())
+      }
+    }
+  }
+
+  fn execute_block(
+    &mut self,
+    statements: &[Statement<'a>],
+    environment: EnvironmentRef<'a>,
+  ) -> ExecuteResult<'a> {
+    let saved = self.environment.replace(environment);
+    let result = statements
+      .iter()
+      .try_for_each(|statement| self.execute(statement));
+    self.environment = saved;
+
+    result
+  }
+
+  fn execute_class(
+    &mut self,
+    id: NodeId,
+    name: &Token<'a>,
+    superclass: Option<&Expression<'a>>,
+    methods: &[Rc<Function<'a>>],
+  ) -> ExecuteResult<'a> {
+    let superclass = match superclass {
+      Some(expression) => match self.evaluate(expression)? {
+        Value::Class(class) => Some(class),
+        _ => return Err(RuntimeError::new(expression.line(), "Superclass is not a class").into()),
+      },
+      None => None,
+    };
+
+    // Methods of a subclass close over an extra environment holding `super`
+    let closure = match &superclass {
+      Some(superclass) => {
+        let environment = Environment::new(self.environment.clone());
+        environment
+          .borrow_mut()
+          .define("super", Value::Class(Rc::clone(superclass)));
+
+        Some(environment)
+      }
+      None => self.environment.clone(),
+    };
+
+    let methods = methods
+      .iter()
+      .map(|method| {
+        let function = LoxFunction {
+          declaration: Rc::clone(method),
+          closure: closure.clone(),
+          is_initializer: method.name.text == "init",
+        };
+
+        (method.name.text, Rc::new(function))
+      })
+      .collect();
+
+    let class = Class {
+      name: name.text,
+      superclass,
+      methods,
+    };
+
+    self.define(id, name.text, Value::Class(Rc::new(class)));
+    Ok(())
+  }
+
+  fn evaluate(&mut self, expression: &Expression<'a>) -> EvaluateResult<'a> {
+    match expression {
+      Expression::Assign { id, name, value } => {
+        let value = self.evaluate(value)?;
+        self.assign(*id, name, value.clone())?;
+
+        Ok(value)
+      }
+      Expression::Binary {
+        left,
+        operator,
+        right,
+      } => {
+        let left = self.evaluate(left)?;
+        let right = self.evaluate(right)?;
+
+        binary(operator, left, right)
+      }
+      Expression::Call {
+        callee,
+        paren,
+        arguments,
+      } => {
+        let callee = self.evaluate(callee)?;
+        let arguments = arguments
+          .iter()
+          .map(|argument| self.evaluate(argument))
+          .collect::<Result<Vec<_>, _>>()?;
+
+        self.call(callee, arguments, paren)
+      }
+      Expression::Get { object, name } => match self.evaluate(object)? {
+        Value::Instance(instance) => get_property(instance, name),
+        _ => Err(RuntimeError::new(
+          name.line,
+          "Can't access property of non-object value",
+        )),
+      },
+      Expression::Grouping { expression } => self.evaluate(expression),
+      Expression::Literal { value, .. } => Ok(match value {
+        Literal::Number(number) => Value::Number(*number),
+        Literal::String(string) => Value::String(Rc::from(*string)),
+        Literal::Bool(boolean) => Value::Bool(*boolean),
+        Literal::Nil => Value::Nil,
+      }),
+      Expression::Logical {
+        left,
+        operator,
+        right,
+      } => {
+        let left = self.evaluate(left)?;
+
+        let short_circuits = match operator.token_type {
+          TokenType::Or => left.is_truthy(),
+          _ => !left.is_truthy(),
+        };
+
+        if short_circuits {
+          Ok(left)
+        } else {
+          self.evaluate(right)
+        }
+      }
+      Expression::Set {
+        object,
+        name,
+        value,
+      } => match self.evaluate(object)? {
+        Value::Instance(instance) => {
+          let value = self.evaluate(value)?;
+          instance
+            .fields
+            .borrow_mut()
+            .insert(name.text, value.clone());
+
+          Ok(value)
+        }
+        _ => Err(RuntimeError::new(
+          name.line,
+          "Can't access property of non-object value",
+        )),
+      },
+      Expression::Super {
+        id,
+        keyword,
+        method,
+      } => self.evaluate_super(*id, keyword, method),
+      Expression::This { id, keyword } => self.look_up(*id, keyword),
+      Expression::Unary { operator, right } => {
+        let right = self.evaluate(right)?;
+
+        match operator.token_type {
+          TokenType::Bang => Ok(Value::Bool(!right.is_truthy())),
+          _ => match right {
+            Value::Number(number) => Ok(Value::Number(-number)),
+            _ => Err(RuntimeError::new(operator.line, "Must be a number")),
+          },
+        }
+      }
+      Expression::Variable { id, name } => self.look_up(*id, name),
+    }
+  }
+
+  fn evaluate_super(&self, id: NodeId, keyword: &Token, method: &Token) -> EvaluateResult<'a> {
+    let depth = self.resolution.binding(id).depth().unwrap();
+    let environment = self
+      .environment
+      .as_ref()
+      .expect("[bug] super used outside of any environment");
+
+    let superclass = match ancestor(environment, depth).borrow().get(keyword.text) {
+      Some(Value::Class(class)) => class,
+      _ => unreachable!("[bug] super bound to something other than a class"),
+    };
+
+    // `this` is always bound in the environment right inside the one holding
+    // `super`
+    let instance = match ancestor(environment, depth - 1).borrow().get("this") {
+      Some(Value::Instance(instance)) => instance,
+      _ => unreachable!("[bug] super used without a bound instance"),
+    };
+
+    match superclass.find_method(method.text) {
+      Some(found) => Ok(Value::Function(Rc::new(found.bind(instance)))),
+      None => Err(RuntimeError::new(
+        method.line,
+        format!("Undefined property {}", method.text),
+      )),
+    }
+  }
+
+  fn call(
+    &mut self,
+    callee: Value<'a>,
+    arguments: Vec<Value<'a>>,
+    paren: &Token,
+  ) -> EvaluateResult<'a> {
+    let arity = match &callee {
+      Value::Function(function) => function.arity(),
+      Value::Native(native) => native.arity,
+      Value::Class(class) => class.arity(),
+      _ => {
+        return Err(RuntimeError::new(
+          paren.line,
+          "Expected function or class for callee",
+        ))
+      }
+    };
+
+    if arguments.len() != arity {
+      return Err(RuntimeError::new(
+        paren.line,
+        format!("Expected {} arguments but got {}", arity, arguments.len()),
+      ));
+    }
+
+    match callee {
+      Value::Function(function) => self.call_function(&function, arguments, paren.line),
+      Value::Native(native) => call_native(&native, &arguments, paren.line),
+      Value::Class(class) => {
+        let instance = Rc::new(Instance::new(Rc::clone(&class)));
+
+        if let Some(initializer) = class.find_method("init") {
+          let initializer = initializer.bind(Rc::clone(&instance));
+          self.call_function(&initializer, arguments, paren.line)?;
+        }
+
+        Ok(Value::Instance(instance))
+      }
+      _ => unreachable!(),
+    }
+  }
+
+  fn call_function(
+    &mut self,
+    function: &LoxFunction<'a>,
+    arguments: Vec<Value<'a>>,
+    line: u32,
+  ) -> EvaluateResult<'a> {
+    if self.frames.len() == MAX_FRAMES {
+      return Err(RuntimeError::new(line, "Stack overflow."));
+    }
+
+    let environment = Environment::new(function.closure.clone());
+
+    for (parameter, argument) in function.declaration.parameters.iter().zip(arguments) {
+      environment.borrow_mut().define(parameter.text, argument);
+    }
+
+    self.frames.push(CallFrame {
+      function: function.declaration.name.text,
+      line,
+      environment: Rc::clone(&environment),
+      caller: self.environment.clone(),
+    });
+
+    if let Some(debugger) = &mut self.debugger {
+      debugger.called();
+    }
+
+    let result = self.execute_block(&function.declaration.body, environment);
+    let returned = match result {
+      Ok(()) => Value::Nil,
+      Err(Unwind::Return(value)) => value,
+      Err(Unwind::Error(error)) => {
+        let error = self.with_trace(error);
+        self.frames.pop();
+        return Err(error);
+      }
+    };
+    self.frames.pop();
+
+    // Initializers always hand back the instance, even when called directly
+    if function.is_initializer {
+      let closure = function
+        .closure
+        .as_ref()
+        .expect("[bug] initializer without a bound instance");
+
+      return Ok(closure.borrow().get("this").unwrap_or(Value::Nil));
+    }
+
+    Ok(returned)
+  }
+
+  // Errors get their trace in the frame they are raised in, while the call
+  // stack still has every frame leading up to it
+  fn with_trace(&self, mut error: RuntimeError) -> RuntimeError {
+    if !error.trace.is_empty() {
+      return error;
+    }
+
+    let mut line = error.line;
+
+    for frame in self.frames.iter().rev() {
+      error.trace.push(StackFrame {
+        function: Some(frame.function.to_string()),
+        line,
+      });
+      line = frame.line;
+    }
+
+    error.trace.push(StackFrame {
+      function: None,
+      line,
+    });
+
+    error
+  }
+
+  fn define(&mut self, id: NodeId, name: &'a str, value: Value<'a>) {
+    match self.resolution.binding(id) {
+      Binding::Global => {
+        self.globals.insert(name, value);
+      }
+      _ => self
+        .environment
+        .as_ref()
+        .expect("[bug] local declared outside of any environment")
+        .borrow_mut()
+        .define(name, value),
+    }
+  }
+
+  fn look_up(&self, id: NodeId, name: &Token) -> EvaluateResult<'a> {
+    match self.resolution.binding(id) {
+      Binding::Global => self
+        .globals
+        .get(name.text)
+        .cloned()
+        .ok_or_else(|| RuntimeError::new(name.line, format!("Undefined name {}.", name.text))),
+      binding => Ok(
+        self
+          .ancestor(binding)
+          .borrow()
+          .get(name.text)
+          .expect("[bug] resolved local missing from its environment"),
+      ),
+    }
+  }
+
+  fn assign(&mut self, id: NodeId, name: &Token, value: Value<'a>) -> Result<(), RuntimeError> {
+    match self.resolution.binding(id) {
+      Binding::Global => match self.globals.get_mut(name.text) {
+        Some(existing) => {
+          *existing = value;
+          Ok(())
+        }
+        None => Err(RuntimeError::new(
+          name.line,
+          format!("Undefined variable {}", name.text),
+        )),
+      },
+      binding => {
+        let assigned = self.ancestor(binding).borrow_mut().assign(name.text, value);
+        assert!(
+          assigned,
+          "[bug] resolved local missing from its environment"
+        );
+
+        Ok(())
+      }
+    }
+  }
+
+  fn ancestor(&self, binding: Binding) -> EnvironmentRef<'a> {
+    let environment = self
+      .environment
+      .as_ref()
+      .expect("[bug] local used outside of any environment");
+
+    ancestor(environment, binding.depth().unwrap())
+  }
+}
+
+fn get_property<'a>(instance: Rc<Instance<'a>>, name: &Token) -> EvaluateResult<'a> {
+  if let Some(value) = instance.fields.borrow().get(name.text) {
+    return Ok(value.clone());
+  }
+
+  match instance.class.find_method(name.text) {
+    Some(method) => Ok(Value::Function(Rc::new(method.bind(Rc::clone(&instance))))),
+    None => Err(RuntimeError::new(
+      name.line,
+      format!(
+        "Missing property {} for {}",
+        name.text,
+        Value::Instance(Rc::clone(&instance))
+      ),
+    )),
+  }
+}
+
+// Shared with the debugger, which evaluates expressions on what it inspects
+pub fn binary<V: Operand>(operator: &Token, left: V, right: V) -> Result<V, RuntimeError> {
+  match operator.token_type {
+    TokenType::EqualEqual => return Ok(V::bool(left == right)),
+    TokenType::BangEqual => return Ok(V::bool(left != right)),
+    TokenType::Plus => {
+      if let (Some(left), Some(right)) = (left.as_number(), right.as_number()) {
+        return Ok(V::number(left + right));
+      }
+
+      return match (left.as_string(), right.as_string()) {
+        (Some(left), Some(right)) => Ok(V::string(Rc::from(format!("{}{}", left, right)))),
+        _ => Err(RuntimeError::new(
+          operator.line,
+          "Operands must be two numbers or two strings",
+        )),
+      };
+    }
+    _ => (),
+  }
+
+  let (left, right) = match (left.as_number(), right.as_number()) {
+    (Some(left), Some(right)) => (left, right),
+    _ => return Err(RuntimeError::new(operator.line, "Must be a number")),
+  };
+
+  Ok(match operator.token_type {
+    TokenType::Minus => V::number(left - right),
+    TokenType::Slash => V::number(left / right),
+    TokenType::Star => V::number(left * right),
+    TokenType::Greater => V::bool(left > right),
+    TokenType::GreaterEqual => V::bool(left >= right),
+    TokenType::Less => V::bool(left < right),
+    TokenType::LessEqual => V::bool(left <= right),
+    _ => unreachable!("[bug] unknown binary operator {}", operator.text),
+  })
+}
+
+fn call_native<'a>(native: &Native, arguments: &[Value<'a>], line: u32) -> EvaluateResult<'a> {
+  let arguments: Vec<_> = arguments.iter().map(Value::to_native).collect();
+  let returned =
+    (native.function)(&arguments).map_err(|message| RuntimeError::new(line, message))?;
+
+  Value::from_native(returned).ok_or_else(|| {
+    RuntimeError::new(
+      line,
+      format!("Native function {} returned an object", native.name),
+    )
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::native::NativeValue;
+  use crate::parser;
+  use crate::resolver;
+
+  fn run(source: &str) -> Result<String, RuntimeError> {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+    let mut interpreter = Interpreter::new(&resolution, Vec::new());
+
+    interpreter.interpret(&statements)?;
+
+    Ok(String::from_utf8(interpreter.into_output()).unwrap())
+  }
+
+  fn expectations(source: &str) -> String {
+    source
+      .match_indices("// => ")
+      .map(|(index, marker)| {
+        let rest = &source[index + marker.len()..];
+        format!("{}\n", rest.lines().next().unwrap())
+      })
+      .collect()
+  }
+
+  fn read(path: &str) -> String {
+    std::fs::read_to_string(format!("{}/../{}", env!("CARGO_MANIFEST_DIR"), path)).unwrap()
+  }
+
+  #[test]
+  fn test_arithmetic() {
+    assert_eq!(run("print 1 + 2 * 3 - 4 / 2;").unwrap(), "5.0\n");
+  }
+
+  #[test]
+  fn test_string_concatenation() {
+    assert_eq!(run("print \"a\" + \"b\";").unwrap(), "ab\n");
+  }
+
+  #[test]
+  fn test_truthiness() {
+    assert_eq!(
+      run("print !nil; print !0; print nil or \"x\"; print 1 and 2;").unwrap(),
+      "true\nfalse\nx\n2.0\n"
+    );
+  }
+
+  #[test]
+  fn test_scoping() {
+    assert_eq!(
+      run("var a = 1; { var a = 2; { print a; } } print a;").unwrap(),
+      "2.0\n1.0\n"
+    );
+  }
+
+  #[test]
+  fn test_closures() {
+    let source = "
+      fun counter() {
+        var count = 0;
+        fun increment() { count = count + 1; return count; }
+        return increment;
+      }
+      var c = counter();
+      c();
+      print c();
+    ";
+
+    assert_eq!(run(source).unwrap(), "2.0\n");
+  }
+
+  #[test]
+  fn test_printing_callables() {
+    assert_eq!(
+      run("fun f() {} class A {} print f; print clock; print A; print A();").unwrap(),
+      "<fn f>\n<native fn>\nA\nA instance\n"
+    );
+  }
+
+  #[test]
+  fn test_fields_and_methods() {
+    let source = "
+      class Point {
+        init(x) { this.x = x; }
+        double() { return this.x * 2; }
+      }
+      var p = Point(2);
+      p.y = 3;
+      print p.double() + p.y;
+    ";
+
+    assert_eq!(run(source).unwrap(), "7.0\n");
+  }
+
+  #[test]
+  fn test_inherited_methods() {
+    let source = "
+      class A { name() { return \"A\"; } greet() { return \"hi \" + this.name(); } }
+      class B < A { name() { return \"B\"; } }
+      print B().greet();
+    ";
+
+    assert_eq!(run(source).unwrap(), "hi B\n");
+  }
+
+  #[test]
+  fn test_super_binds_this() {
+    let source = "
+      class A { init(x) { this.x = x; } describe() { return this.x; } }
+      class B < A {
+        init(x) { super.init(x * 2); }
+        describe() {
+          fun later() { return super.describe() + 1; }
+          return later;
+        }
+      }
+      print B(1).describe()();
+    ";
+
+    assert_eq!(run(source).unwrap(), "3.0\n");
+  }
+
+  #[test]
+  fn test_initializer_returns_instance() {
+    let source = "
+      class A {
+        init() { this.calls = 0; return; }
+      }
+      var a = A();
+      print a.init() == a;
+      class B < A {}
+      print B().calls;
+    ";
+
+    assert_eq!(run(source).unwrap(), "true\n0.0\n");
+  }
+
+  fn fail(source: &str) -> String {
+    run(source).unwrap_err().to_string()
+  }
+
+  #[test]
+  fn test_inheritance_errors() {
+    assert_eq!(
+      fail("var A = 1;\nclass B < A {}"),
+      "runtime error. line: 2 - error: Superclass is not a class\n[line 2] in script"
+    );
+    assert_eq!(
+      fail("class A {}\nclass B < A { m() { return super.missing; } }\nB().m();"),
+      "runtime error. line: 2 - error: Undefined property missing\n[line 2] in m()\n[line 3] in script"
+    );
+  }
+
+  #[test]
+  fn test_stdlib() {
+    let source = "
+      class A {}
+      print len(\"abc\") + sqrt(16);
+      print type(A()) + \" \" + type(clock) + \" \" + type(nil);
+      print toString(A()) + \"!\";
+    ";
+
+    assert_eq!(
+      run(source).unwrap(),
+      "7.0\ninstance function nil\nA instance!\n"
+    );
+  }
+
+  #[test]
+  fn test_custom_natives() {
+    let mut natives = NativeRegistry::new();
+    natives.register("twice", 1, |arguments| match &arguments[0] {
+      NativeValue::String(string) => Ok(NativeValue::String(Rc::from(string.repeat(2)))),
+      _ => Err(String::from("Expected a string")),
+    });
+    natives.register("identity", 1, |arguments| Ok(arguments[0].clone()));
+
+    let run_with_natives = |source| {
+      let statements = parser::parse(source).unwrap();
+      let resolution = resolver::resolve(&statements).unwrap();
+      let mut interpreter = Interpreter::with_natives(&resolution, Vec::new(), &natives);
+
+      interpreter
+        .interpret(&statements)
+        .map(|()| String::from_utf8(interpreter.into_output()).unwrap())
+        .map_err(|error| error.to_string())
+    };
+
+    assert_eq!(
+      run_with_natives("print twice(\"ab\");"),
+      Ok(String::from("abab\n"))
+    );
+    assert_eq!(
+      run_with_natives("fun f() {\n  twice(1);\n}\nf();"),
+      Err(String::from(
+        "runtime error. line: 2 - error: Expected a string\n[line 2] in f()\n[line 4] in script"
+      ))
+    );
+    assert_eq!(
+      run_with_natives("print sqrt(4);"),
+      Err(String::from(
+        "runtime error. line: 1 - error: Undefined name sqrt.\n[line 1] in script"
+      ))
+    );
+    assert!(run_with_natives("identity(clock);").is_err());
+    assert_eq!(
+      run_with_natives("print identity(1);"),
+      Ok(String::from("1.0\n"))
+    );
+  }
+
+  #[test]
+  fn test_runtime_errors() {
+    assert_eq!(
+      fail("print 1 + \"a\";"),
+      "runtime error. line: 1 - error: Operands must be two numbers or two strings\n[line 1] in script"
+    );
+    assert_eq!(
+      fail("\nprint a;"),
+      "runtime error. line: 2 - error: Undefined name a.\n[line 2] in script"
+    );
+    assert_eq!(
+      fail("fun f(a) {} f();"),
+      "runtime error. line: 1 - error: Expected 1 arguments but got 0\n[line 1] in script"
+    );
+    assert_eq!(
+      fail("\"str\"();"),
+      "runtime error. line: 1 - error: Expected function or class for callee\n[line 1] in script"
+    );
+  }
+
+  #[test]
+  fn test_stack_trace() {
+    let source = "
+fun inner(x) {
+  return x + \"a\";
+}
+
+fun outer() {
+  return inner(1);
+}
+
+class A {
+  init() {
+    outer();
+  }
+}
+
+A();
+";
+
+    assert_eq!(
+      run(source).unwrap_err(),
+      RuntimeError {
+        line: 3,
+        message: String::from("Operands must be two numbers or two strings"),
+        trace: vec![
+          StackFrame {
+            function: Some(String::from("inner")),
+            line: 3
+          },
+          StackFrame {
+            function: Some(String::from("outer")),
+            line: 7
+          },
+          StackFrame {
+            function: Some(String::from("init")),
+            line: 12
+          },
+          StackFrame {
+            function: None,
+            line: 16
+          },
+        ],
+      }
+    );
+  }
+
+  #[test]
+  fn test_stack_overflow() {
+    let error = run("fun f(n) {\n  return f(n + 1);\n}\nf(0);").unwrap_err();
+
+    assert_eq!(error.message, "Stack overflow.");
+    assert_eq!(error.trace.len(), MAX_FRAMES + 1);
+    assert_eq!(
+      error.trace[0],
+      StackFrame {
+        function: Some(String::from("f")),
+        line: 2
+      }
+    );
+    assert_eq!(
+      error.trace[MAX_FRAMES],
+      StackFrame {
+        function: None,
+        line: 4
+      }
+    );
+  }
+
+  #[test]
+  fn test_frames_unwound_after_error() {
+    let statements = parser::parse("fun f() { return nil + 1; } f();").unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+    let mut interpreter = Interpreter::new(&resolution, Vec::new());
+
+    assert!(interpreter.interpret(&statements).is_err());
+    assert!(interpreter.frames.is_empty());
+  }
+
+  #[test]
+  fn test_output_before_error_is_kept() {
+    let statements = parser::parse("print 1; print -nil;").unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+    let mut interpreter = Interpreter::new(&resolution, Vec::new());
+
+    assert!(interpreter.interpret(&statements).is_err());
+    assert_eq!(interpreter.output(), b"1.0\n");
+  }
+
+  #[test]
+  fn test_test_suite() {
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../s1-web/test_suite");
+
+    for entry in std::fs::read_dir(directory).unwrap() {
+      let path = entry.unwrap().path();
+
+      if path.extension().is_some_and(|extension| extension == "lox") {
+        let source = std::fs::read_to_string(&path).unwrap();
+
+        assert_eq!(run(&source).unwrap(), expectations(&source), "{:?}", path);
+      }
+    }
+  }
+
+  #[test]
+  fn test_inheritance_chapter_sources() {
+    assert_eq!(
+      run(&read("13-inheritance/test_sources/blocks.lox")).unwrap(),
+      "inner a\nouter b\nglobal c\nouter a\nouter b\nglobal c\nglobal a\nglobal b\nglobal c\n"
+    );
+    assert_eq!(
+      run(&read("13-inheritance/test_sources/resolver.lox")).unwrap(),
+      "20.0\nnil\n"
+    );
+    assert_eq!(
+      run(&read("13-inheritance/test_sources/largish.lox")).unwrap(),
+      "true\ntrue\ntrue\ntrue\ntrue\ntrue\ntrue\ntrue\nfalse\ntrue\ntrue\nBark\nBARK\n\
+       Bark\nBARK\ntrue\ntrue\nHi, I'm cat and I have size big\ntrue\n"
+    );
+
+    assert_eq!(
+      run(&read("13-inheritance/test_sources/super.lox")).unwrap(),
+      "A method\nB method\n"
+    );
+
+    let fibonacci = run(&read("13-inheritance/test_sources/fibonacci.lox")).unwrap();
+    assert!(fibonacci.trim_end().parse::<f64>().is_ok());
+  }
+}
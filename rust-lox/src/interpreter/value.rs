@@ -0,0 +1,250 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use super::environment::{Environment, EnvironmentRef};
+use crate::native::{Native, NativeValue};
+use crate::statement::Function;
+
+#[derive(Clone)]
+pub enum Value<'a> {
+  Nil,
+  Bool(bool),
+  Number(f64),
+  String(Rc<str>),
+  Function(Rc<LoxFunction<'a>>),
+  Native(Rc<Native>),
+  Class(Rc<Class<'a>>),
+  Instance(Rc<Instance<'a>>),
+}
+
+impl<'a> Value<'a> {
+  pub fn is_truthy(&self) -> bool {
+    !matches!(self, Value::Nil | Value::Bool(false))
+  }
+
+  pub fn to_native(&self) -> NativeValue {
+    let kind = match self {
+      Value::Nil => return NativeValue::Nil,
+      Value::Bool(boolean) => return NativeValue::Bool(*boolean),
+      Value::Number(number) => return NativeValue::Number(*number),
+      Value::String(string) => return NativeValue::String(Rc::clone(string)),
+      Value::Function(_) | Value::Native(_) => "function",
+      Value::Class(_) => "class",
+      Value::Instance(_) => "instance",
+    };
+
+    NativeValue::Object {
+      kind,
+      description: Rc::from(self.to_string()),
+    }
+  }
+
+  // None for objects, natives only get to look at them
+  pub fn from_native(value: NativeValue) -> Option<Value<'a>> {
+    match value {
+      NativeValue::Nil => Some(Value::Nil),
+      NativeValue::Bool(boolean) => Some(Value::Bool(boolean)),
+      NativeValue::Number(number) => Some(Value::Number(number)),
+      NativeValue::String(string) => Some(Value::String(string)),
+      NativeValue::Object { .. } => None,
+    }
+  }
+}
+
+// What binary operators look at, so the debugger can evaluate them on the
+// values it inspects the same way the interpreter does
+pub trait Operand: PartialEq + Sized {
+  fn number(number: f64) -> Self;
+  fn bool(boolean: bool) -> Self;
+  fn string(string: Rc<str>) -> Self;
+  fn as_number(&self) -> Option<f64>;
+  fn as_string(&self) -> Option<&str>;
+}
+
+impl<'a> Operand for Value<'a> {
+  fn number(number: f64) -> Value<'a> {
+    Value::Number(number)
+  }
+
+  fn bool(boolean: bool) -> Value<'a> {
+    Value::Bool(boolean)
+  }
+
+  fn string(string: Rc<str>) -> Value<'a> {
+    Value::String(string)
+  }
+
+  fn as_number(&self) -> Option<f64> {
+    match self {
+      Value::Number(number) => Some(*number),
+      _ => None,
+    }
+  }
+
+  fn as_string(&self) -> Option<&str> {
+    match self {
+      Value::String(string) => Some(string),
+      _ => None,
+    }
+  }
+}
+
+// Objects are only equal to themselves
+impl<'a> PartialEq for Value<'a> {
+  fn eq(&self, other: &Value<'a>) -> bool {
+    match (self, other) {
+      (Value::Nil, Value::Nil) => true,
+      (Value::Bool(a), Value::Bool(b)) => a == b,
+      (Value::Number(a), Value::Number(b)) => a == b,
+      (Value::String(a), Value::String(b)) => a == b,
+      (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+      (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+      (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+      (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+      _ => false,
+    }
+  }
+}
+
+impl<'a> fmt::Display for Value<'a> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Value::Nil => write!(f, "nil"),
+      Value::Bool(boolean) => write!(f, "{}", boolean),
+      Value::Number(number) => write!(f, "{}", format_number(*number)),
+      Value::String(string) => write!(f, "{}", string),
+      Value::Function(function) => write!(f, "<fn {}>", function.declaration.name.text),
+      Value::Native(_) => write!(f, "<native fn>"),
+      Value::Class(class) => write!(f, "{}", class.name),
+      Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+    }
+  }
+}
+
+// Environments and closures point at each other, printing the whole structure
+// would never end
+impl<'a> fmt::Debug for Value<'a> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Value::String(string) => write!(f, "{:?}", string),
+      _ => write!(f, "{}", self),
+    }
+  }
+}
+
+// Same as Ruby's Float#to_s, which is what the test suites expect
+pub fn format_number(number: f64) -> String {
+  if number.is_nan() {
+    return String::from("NaN");
+  }
+
+  if number.is_infinite() {
+    return String::from(if number > 0.0 {
+      "Infinity"
+    } else {
+      "-Infinity"
+    });
+  }
+
+  if number == 0.0 || (1e-4..1e16).contains(&number.abs()) {
+    return format!("{:?}", number);
+  }
+
+  let scientific = format!("{:e}", number);
+  let (mantissa, exponent) = scientific.split_once('e').unwrap();
+  let (sign, digits) = match exponent.strip_prefix('-') {
+    Some(digits) => ('-', digits),
+    None => ('+', exponent),
+  };
+
+  if mantissa.contains('.') {
+    format!("{}e{}{:0>2}", mantissa, sign, digits)
+  } else {
+    format!("{}.0e{}{:0>2}", mantissa, sign, digits)
+  }
+}
+
+pub struct LoxFunction<'a> {
+  pub declaration: Rc<Function<'a>>,
+  // None for functions declared at the top level, they only see globals
+  pub closure: Option<EnvironmentRef<'a>>,
+  pub is_initializer: bool,
+}
+
+impl<'a> LoxFunction<'a> {
+  pub fn arity(&self) -> usize {
+    self.declaration.parameters.len()
+  }
+
+  pub fn bind(&self, instance: Rc<Instance<'a>>) -> LoxFunction<'a> {
+    let environment = Environment::new(self.closure.clone());
+    environment
+      .borrow_mut()
+      .define("this", Value::Instance(instance));
+
+    LoxFunction {
+      declaration: Rc::clone(&self.declaration),
+      closure: Some(environment),
+      is_initializer: self.is_initializer,
+    }
+  }
+}
+
+pub struct Class<'a> {
+  pub name: &'a str,
+  pub superclass: Option<Rc<Class<'a>>>,
+  pub methods: HashMap<&'a str, Rc<LoxFunction<'a>>>,
+}
+
+impl<'a> Class<'a> {
+  pub fn find_method(&self, name: &str) -> Option<Rc<LoxFunction<'a>>> {
+    match self.methods.get(name) {
+      Some(method) => Some(Rc::clone(method)),
+      None => self.superclass.as_ref()?.find_method(name),
+    }
+  }
+
+  pub fn arity(&self) -> usize {
+    self.find_method("init").map_or(0, |init| init.arity())
+  }
+}
+
+pub struct Instance<'a> {
+  pub class: Rc<Class<'a>>,
+  pub fields: RefCell<HashMap<&'a str, Value<'a>>>,
+}
+
+impl<'a> Instance<'a> {
+  pub fn new(class: Rc<Class<'a>>) -> Instance<'a> {
+    Instance {
+      class,
+      fields: RefCell::new(HashMap::new()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_format_number() {
+    assert_eq!(format_number(1.0), "1.0");
+    assert_eq!(format_number(-5.0), "-5.0");
+    assert_eq!(format_number(0.5), "0.5");
+    assert_eq!(format_number(0.0001), "0.0001");
+    assert_eq!(format_number(0.000001), "1.0e-06");
+    assert_eq!(format_number(1.5e20), "1.5e+20");
+    assert_eq!(format_number(1.0 / 0.0), "Infinity");
+  }
+
+  #[test]
+  fn test_equality() {
+    assert_eq!(Value::Number(1.0), Value::Number(1.0));
+    assert_eq!(Value::String("a".into()), Value::String("a".into()));
+    assert_ne!(Value::Nil, Value::Bool(false));
+    assert_ne!(Value::Number(0.0), Value::Bool(false));
+  }
+}
@@ -1,2 +1,21 @@
+pub mod alox;
+pub mod chunk;
+pub mod compiler;
+pub mod debug;
+pub mod debugger;
+pub mod error;
+pub mod expression;
+pub mod interner;
+pub mod interpreter;
+pub mod lint;
+pub mod loxc;
+pub mod native;
+pub mod optimizer;
+pub mod parser;
+pub mod resolver;
 pub mod scanner;
+pub mod statement;
+pub mod tree_printer;
 pub mod util;
+pub mod verifier;
+pub mod vm;
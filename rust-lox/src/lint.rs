@@ -0,0 +1,566 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::expression::Expression;
+use super::parser::{ParseError, Parser};
+use super::resolver::{Binding, Resolution, Resolver, VariableKind};
+use super::scanner::{Scan, TokenType};
+use super::statement::{Function, Statement};
+
+const ALLOW_DIRECTIVE: &str = "lox-allow:";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Level {
+  Allow,
+  Warn,
+  Deny,
+}
+
+impl fmt::Display for Level {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Level::Allow => write!(f, "allow"),
+      Level::Warn => write!(f, "warning"),
+      Level::Deny => write!(f, "error"),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Lint {
+  pub id: &'static str,
+  pub default_level: Level,
+  pub description: &'static str,
+}
+
+pub const UNUSED_VARIABLE: Lint = Lint {
+  id: "unused-variable",
+  default_level: Level::Warn,
+  description: "local variable, function or class that is never read",
+};
+
+pub const UNUSED_PARAMETER: Lint = Lint {
+  id: "unused-parameter",
+  default_level: Level::Warn,
+  description: "function parameter that is never read",
+};
+
+pub const SHADOWING: Lint = Lint {
+  id: "shadowing",
+  default_level: Level::Warn,
+  description: "local with the same name as a variable from an enclosing scope",
+};
+
+pub const UNREACHABLE_CODE: Lint = Lint {
+  id: "unreachable-code",
+  default_level: Level::Warn,
+  description: "statement after a return",
+};
+
+pub const UNDECLARED_ASSIGNMENT: Lint = Lint {
+  id: "undeclared-assignment",
+  default_level: Level::Warn,
+  description: "assignment to a global that is never declared",
+};
+
+pub const THIS_OUTSIDE_CLASS: Lint = Lint {
+  id: "this-outside-class",
+  default_level: Level::Deny,
+  description: "`this` used outside of a method",
+};
+
+// `while (true)` is the only way to write an infinite loop so it's exempt
+pub const CONSTANT_CONDITION: Lint = Lint {
+  id: "constant-condition",
+  default_level: Level::Warn,
+  description: "if or while condition that doesn't depend on anything",
+};
+
+pub const LINTS: [&Lint; 7] = [
+  &UNUSED_VARIABLE,
+  &UNUSED_PARAMETER,
+  &SHADOWING,
+  &UNREACHABLE_CODE,
+  &UNDECLARED_ASSIGNMENT,
+  &THIS_OUTSIDE_CLASS,
+  &CONSTANT_CONDITION,
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+  pub lint: &'static str,
+  pub level: Level,
+  pub line: u32,
+  pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{}[{}]: line {} - {}",
+      self.level, self.lint, self.line, self.message
+    )
+  }
+}
+
+pub struct Linter {
+  levels: HashMap<&'static str, Level>,
+}
+
+impl Linter {
+  pub fn new() -> Linter {
+    Linter {
+      levels: LINTS
+        .iter()
+        .map(|lint| (lint.id, lint.default_level))
+        .collect(),
+    }
+  }
+
+  pub fn with_level(mut self, lint: &Lint, level: Level) -> Linter {
+    self.levels.insert(lint.id, level);
+    self
+  }
+
+  pub fn check(&self, source: &str) -> Result<Vec<Diagnostic>, Vec<ParseError>> {
+    let statements = Parser::new(Scan::new(source)).parse()?;
+    // Some of the lints overlap with resolver errors, those are reported by
+    // whoever compiles the program
+    let (resolution, _) = Resolver::new().resolve_lenient(&statements);
+
+    let mut checker = Checker {
+      resolution: &resolution,
+      globals: global_declarations(&statements),
+      class_depth: 0,
+      findings: Vec::new(),
+    };
+
+    checker.check_variables();
+    checker.check_statements(&statements);
+
+    let allowed = allow_directives(source);
+
+    let mut diagnostics: Vec<Diagnostic> = checker
+      .findings
+      .into_iter()
+      .filter(|(lint, line, _)| {
+        !allowed.contains(&(*line, lint.id))
+          && (*line <= 1 || !allowed.contains(&(line - 1, lint.id)))
+      })
+      .filter_map(|(lint, line, message)| match self.levels[lint.id] {
+        Level::Allow => None,
+        level => Some(Diagnostic {
+          lint: lint.id,
+          level,
+          line,
+          message,
+        }),
+      })
+      .collect();
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+
+    Ok(diagnostics)
+  }
+}
+
+impl Default for Linter {
+  fn default() -> Self {
+    Linter::new()
+  }
+}
+
+// `// lox-allow: <id>, <id>` silences the lints on its own line and the next
+fn allow_directives(source: &str) -> HashSet<(u32, &str)> {
+  Scan::new(source)
+    .keeping_comments()
+    .filter(|token| token.token_type == TokenType::Comment)
+    .filter_map(|token| {
+      token.text[2..]
+        .trim()
+        .strip_prefix(ALLOW_DIRECTIVE)
+        .map(|ids| (token.line, ids))
+    })
+    .flat_map(|(line, ids)| ids.split(',').map(move |id| (line, id.trim())))
+    .collect()
+}
+
+fn global_declarations<'a>(statements: &[Statement<'a>]) -> HashSet<&'a str> {
+  statements
+    .iter()
+    .filter_map(|statement| match statement {
+      Statement::Var { name, .. } | Statement::Class { name, .. } => Some(name.text),
+      Statement::Function { function } => Some(function.name.text),
+      _ => None,
+    })
+    .collect()
+}
+
+struct Checker<'r, 'a> {
+  resolution: &'r Resolution,
+  globals: HashSet<&'a str>,
+  class_depth: usize,
+  findings: Vec<(&'static Lint, u32, String)>,
+}
+
+impl<'r, 'a> Checker<'r, 'a> {
+  fn report(&mut self, lint: &'static Lint, line: u32, message: String) {
+    self.findings.push((lint, line, message));
+  }
+
+  fn check_variables(&mut self) {
+    for variable in self.resolution.variables() {
+      let lint = match variable.kind {
+        VariableKind::This | VariableKind::Super => continue,
+        VariableKind::Parameter => &UNUSED_PARAMETER,
+        _ => &UNUSED_VARIABLE,
+      };
+
+      if variable.reads == 0 && !variable.name.starts_with('_') {
+        self.findings.push((
+          lint,
+          variable.line,
+          format!("`{}` is never used", variable.name),
+        ));
+      }
+
+      if variable.shadows || self.globals.contains(variable.name.as_str()) {
+        self.findings.push((
+          &SHADOWING,
+          variable.line,
+          format!(
+            "`{}` shadows a variable from an enclosing scope",
+            variable.name
+          ),
+        ));
+      }
+    }
+  }
+
+  fn check_statements(&mut self, statements: &[Statement]) {
+    let mut returned = false;
+
+    for statement in statements {
+      if returned {
+        self.report(
+          &UNREACHABLE_CODE,
+          first_line(statement),
+          String::from("unreachable code after return"),
+        );
+        break;
+      }
+
+      self.check_statement(statement);
+      returned = always_returns(statement);
+    }
+  }
+
+  fn check_statement(&mut self, statement: &Statement) {
+    match statement {
+      Statement::Block { statements } => self.check_statements(statements),
+      Statement::Class {
+        superclass,
+        methods,
+        ..
+      } => {
+        if let Some(superclass) = superclass {
+          self.check_expression(superclass);
+        }
+
+        self.class_depth += 1;
+        methods
+          .iter()
+          .for_each(|method| self.check_function(method));
+        self.class_depth -= 1;
+      }
+      Statement::Expression { expression } | Statement::Print { expression } => {
+        self.check_expression(expression)
+      }
+      Statement::Function { function } => self.check_function(function),
+      Statement::If {
+        condition,
+        then_branch,
+        else_branch,
+      } => {
+        self.check_condition(condition, false);
+        self.check_expression(condition);
+        self.check_statement(then_branch);
+
+        if let Some(else_branch) = else_branch {
+          self.check_statement(else_branch);
+        }
+      }
+      Statement::Return { value, .. } => {
+        if let Some(value) = value {
+          self.check_expression(value);
+        }
+      }
+      Statement::Var { initializer, .. } => {
+        if let Some(initializer) = initializer {
+          self.check_expression(initializer);
+        }
+      }
+      Statement::While { condition, body } => {
+        self.check_condition(condition, true);
+        self.check_expression(condition);
+        self.check_statement(body);
+      }
+    }
+  }
+
+  fn check_function(&mut self, function: &Function) {
+    self.check_statements(&function.body);
+  }
+
+  fn check_condition(&mut self, condition: &Expression, is_loop: bool) {
+    let infinite_loop = matches!(
+      condition,
+      Expression::Literal {
+        value: crate::expression::Literal::Bool(true),
+        ..
+      }
+    );
+
+    if is_constant(condition) && !(is_loop && infinite_loop) {
+      self.report(
+        &CONSTANT_CONDITION,
+        condition.line(),
+        String::from("condition is constant"),
+      );
+    }
+  }
+
+  fn check_expression(&mut self, expression: &Expression) {
+    match expression {
+      Expression::Assign { id, name, value } => {
+        if self.resolution.binding(*id) == Binding::Global && !self.globals.contains(name.text) {
+          self.report(
+            &UNDECLARED_ASSIGNMENT,
+            name.line,
+            format!("assignment to undeclared global `{}`", name.text),
+          );
+        }
+
+        self.check_expression(value);
+      }
+      Expression::Binary { left, right, .. } | Expression::Logical { left, right, .. } => {
+        self.check_expression(left);
+        self.check_expression(right);
+      }
+      Expression::Call {
+        callee, arguments, ..
+      } => {
+        self.check_expression(callee);
+        arguments
+          .iter()
+          .for_each(|argument| self.check_expression(argument));
+      }
+      Expression::Get { object, .. } => self.check_expression(object),
+      Expression::Grouping { expression } => self.check_expression(expression),
+      Expression::Set { object, value, .. } => {
+        self.check_expression(object);
+        self.check_expression(value);
+      }
+      Expression::This { keyword, .. } => {
+        if self.class_depth == 0 {
+          self.report(
+            &THIS_OUTSIDE_CLASS,
+            keyword.line,
+            String::from("`this` used outside of a class"),
+          );
+        }
+      }
+      Expression::Unary { right, .. } => self.check_expression(right),
+      Expression::Literal { .. } | Expression::Super { .. } | Expression::Variable { .. } => (),
+    }
+  }
+}
+
+fn always_returns(statement: &Statement) -> bool {
+  match statement {
+    Statement::Return { .. } => true,
+    Statement::Block { statements } => statements.iter().any(always_returns),
+    Statement::If {
+      then_branch,
+      else_branch: Some(else_branch),
+      ..
+    } => always_returns(then_branch) && always_returns(else_branch),
+    _ => false,
+  }
+}
+
+fn is_constant(expression: &Expression) -> bool {
+  match expression {
+    Expression::Literal { .. } => true,
+    Expression::Grouping { expression } => is_constant(expression),
+    Expression::Unary { right, .. } => is_constant(right),
+    Expression::Binary { left, right, .. } | Expression::Logical { left, right, .. } => {
+      is_constant(left) && is_constant(right)
+    }
+    _ => false,
+  }
+}
+
+fn first_line(statement: &Statement) -> u32 {
+  match statement {
+    Statement::Block { statements } => statements.first().map_or(0, first_line),
+    Statement::Class { name, .. } | Statement::Var { name, .. } => name.line,
+    Statement::Expression { expression } | Statement::Print { expression } => expression.line(),
+    Statement::Function { function } => function.name.line,
+    Statement::If { condition, .. } | Statement::While { condition, .. } => condition.line(),
+    Statement::Return { keyword, .. } => keyword.line,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn check(source: &str) -> Vec<String> {
+    Linter::new()
+      .check(source)
+      .unwrap()
+      .iter()
+      .map(|diagnostic| diagnostic.to_string())
+      .collect()
+  }
+
+  #[test]
+  fn test_unused_variable() {
+    assert_eq!(
+      check("fun f() {\n  var a = 1;\n  var b = 2;\n  return b;\n}\nf();"),
+      vec!["warning[unused-variable]: line 2 - `a` is never used"]
+    );
+  }
+
+  #[test]
+  fn test_underscore_is_never_unused() {
+    assert!(check("{ var _a = 1; }").is_empty());
+  }
+
+  #[test]
+  fn test_unused_parameter() {
+    assert_eq!(
+      check("fun f(a, b) { return a; }\nf(1, 2);"),
+      vec!["warning[unused-parameter]: line 1 - `b` is never used"]
+    );
+  }
+
+  #[test]
+  fn test_shadowing() {
+    assert_eq!(
+      check("fun f(a) {\n  {\n    var a = 2;\n    print a;\n  }\n}\nf(1);"),
+      vec![
+        "warning[unused-parameter]: line 1 - `a` is never used",
+        "warning[shadowing]: line 3 - `a` shadows a variable from an enclosing scope"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_shadowing_global() {
+    assert_eq!(
+      check("var a = 1;\n{\n  var a = 2;\n  print a;\n}"),
+      vec!["warning[shadowing]: line 3 - `a` shadows a variable from an enclosing scope"]
+    );
+  }
+
+  #[test]
+  fn test_unreachable_code() {
+    assert_eq!(
+      check("fun f() {\n  return 1;\n  print 2;\n}\nf();"),
+      vec!["warning[unreachable-code]: line 3 - unreachable code after return"]
+    );
+  }
+
+  #[test]
+  fn test_unreachable_after_returning_if() {
+    assert_eq!(
+      check("fun f(a) {\n  if (a) return 1; else return 2;\n  print a;\n}\nf(1);"),
+      vec!["warning[unreachable-code]: line 3 - unreachable code after return"]
+    );
+  }
+
+  #[test]
+  fn test_undeclared_assignment() {
+    assert_eq!(
+      check("var a;\na = 1;\nb = 2;"),
+      vec!["warning[undeclared-assignment]: line 3 - assignment to undeclared global `b`"]
+    );
+  }
+
+  #[test]
+  fn test_this_outside_class() {
+    assert_eq!(
+      check("fun f() {\n  return this;\n}\nf();"),
+      vec!["error[this-outside-class]: line 2 - `this` used outside of a class"]
+    );
+  }
+
+  #[test]
+  fn test_this_in_closure_inside_method() {
+    assert!(check("class A { m() { fun f() { return this; } return f; } }").is_empty());
+  }
+
+  #[test]
+  fn test_constant_condition() {
+    assert_eq!(
+      check("if (1 == 2) print 1;\nwhile (!false) print 2;\nwhile (true) print 3;"),
+      vec![
+        "warning[constant-condition]: line 1 - condition is constant",
+        "warning[constant-condition]: line 2 - condition is constant"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_allow_directive_same_line() {
+    assert!(check("fun f(a) { return 1; } // lox-allow: unused-parameter\nf(1);").is_empty());
+  }
+
+  #[test]
+  fn test_allow_directive_previous_line() {
+    let source = "fun f() {\n  // lox-allow: unused-variable, shadowing\n  var f = 1;\n}\nf();";
+
+    assert!(check(source).is_empty());
+  }
+
+  #[test]
+  fn test_allow_directive_other_lint() {
+    assert_eq!(check("{ var a; } // lox-allow: shadowing").len(), 1);
+  }
+
+  #[test]
+  fn test_levels() {
+    let diagnostics = Linter::new()
+      .with_level(&UNUSED_VARIABLE, Level::Deny)
+      .with_level(&SHADOWING, Level::Allow)
+      .check("var a; { var a; }")
+      .unwrap();
+
+    assert_eq!(
+      diagnostics,
+      vec![Diagnostic {
+        lint: "unused-variable",
+        level: Level::Deny,
+        line: 1,
+        message: String::from("`a` is never used"),
+      }]
+    );
+  }
+
+  #[test]
+  fn test_test_suite_is_clean() {
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../s1-web/test_suite");
+
+    assert!(check(
+      &std::fs::read_to_string(format!("{}/closure-assignment.lox", directory)).unwrap()
+    )
+    .is_empty());
+    assert_eq!(
+      check(&std::fs::read_to_string(format!("{}/closure-self-use.lox", directory)).unwrap()),
+      vec!["warning[unused-variable]: line 3 - `inner` is never used"]
+    );
+  }
+}
@@ -0,0 +1,551 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use super::chunk::{Chunk, CompiledFunction, Constant, LocalName, MAX_CONSTANTS};
+use super::verifier::{self, VerifyError};
+
+// Compiled functions in .loxc files, so a program can be compiled once and
+// shipped as bytecode. All numbers are big endian, like the operands in code.
+//
+// header:   "LOXC", u16 version, u32 checksum of the body, u32 body length
+// function: name (u8 0 for the script, or 1 and a string), u32 arity,
+//           u32 upvalue count, u32 constant count and the constants, u32 code
+//           length and the code, u32 line run count and a u32 offset and u32
+//           line for each run, u32 local count and the locals, u32 upvalue
+//           name count and the names
+// local:    name string, then u32 slot, start and end
+// constant: u8 tag, then the f64 bits of a number (0), a string (1) or a
+//           nested function (2)
+// string:   u32 length and UTF-8 bytes
+//
+// The upvalue descriptors stay in the operands of OP_CLOSURE, as in clox.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 2;
+
+// Functions nested deeper than this are rejected instead of overflowing the
+// loader's stack
+const MAX_DEPTH: usize = 256;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+#[derive(Debug)]
+pub enum LoadError {
+  Io(io::Error),
+  BadMagic,
+  UnsupportedVersion(u16),
+  ChecksumMismatch,
+  // The data ran out or doesn't make sense, at this offset of the body
+  Malformed(usize, String),
+  // Well formed, but the code would take the VM out of bounds
+  InvalidCode(VerifyError),
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LoadError::Io(error) => write!(f, "load error: {}", error),
+      LoadError::BadMagic => write!(f, "load error: not a .loxc file"),
+      LoadError::UnsupportedVersion(version) => write!(
+        f,
+        "load error: version {} isn't supported, expected {}",
+        version, VERSION
+      ),
+      LoadError::ChecksumMismatch => write!(f, "load error: checksum mismatch"),
+      LoadError::Malformed(offset, message) => {
+        write!(f, "load error: at byte {}: {}", offset, message)
+      }
+      LoadError::InvalidCode(error) => write!(f, "load error: {}", error),
+    }
+  }
+}
+
+impl From<io::Error> for LoadError {
+  fn from(error: io::Error) -> LoadError {
+    LoadError::Io(error)
+  }
+}
+
+impl CompiledFunction {
+  pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    let mut body = Vec::new();
+    write_function(&mut body, self);
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_be_bytes())?;
+    writer.write_all(&checksum(&body).to_be_bytes())?;
+    writer.write_all(&(body.len() as u32).to_be_bytes())?;
+    writer.write_all(&body)
+  }
+
+  // Everything is checked before the VM gets to see it, any bytecode that
+  // loads runs without the VM indexing out of bounds
+  pub fn read_from<R: Read>(reader: &mut R) -> Result<CompiledFunction, LoadError> {
+    let mut header = [0; 14];
+    reader.read_exact(&mut header)?;
+
+    if &header[0..4] != MAGIC {
+      return Err(LoadError::BadMagic);
+    }
+
+    let version = u16::from_be_bytes([header[4], header[5]]);
+
+    if version != VERSION {
+      return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let expected = u32::from_be_bytes([header[6], header[7], header[8], header[9]]);
+    let length = u32::from_be_bytes([header[10], header[11], header[12], header[13]]);
+
+    // Not trusting the length with an allocation up front
+    let mut body = Vec::new();
+    reader.by_ref().take(length as u64).read_to_end(&mut body)?;
+
+    if body.len() != length as usize {
+      return Err(LoadError::Malformed(
+        body.len(),
+        String::from("truncated body"),
+      ));
+    }
+
+    if checksum(&body) != expected {
+      return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut input = Input {
+      bytes: &body,
+      position: 0,
+    };
+    let function = input.function(0)?;
+
+    if input.position != body.len() {
+      return Err(input.malformed("trailing bytes after the script"));
+    }
+
+    if function.name.is_some() || function.arity != 0 || function.upvalue_count != 0 {
+      return Err(input.malformed("the outermost function isn't a script"));
+    }
+
+    verifier::verify(&function).map_err(LoadError::InvalidCode)?;
+
+    Ok(function)
+  }
+}
+
+// FNV-1a
+fn checksum(bytes: &[u8]) -> u32 {
+  bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+    (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+  })
+}
+
+fn write_u32(body: &mut Vec<u8>, value: usize) {
+  body.extend_from_slice(&(value as u32).to_be_bytes());
+}
+
+fn write_string(body: &mut Vec<u8>, string: &str) {
+  write_u32(body, string.len());
+  body.extend_from_slice(string.as_bytes());
+}
+
+fn write_function(body: &mut Vec<u8>, function: &CompiledFunction) {
+  match &function.name {
+    Some(name) => {
+      body.push(1);
+      write_string(body, name);
+    }
+    None => body.push(0),
+  }
+
+  write_u32(body, function.arity);
+  write_u32(body, function.upvalue_count);
+
+  let chunk = &function.chunk;
+  write_u32(body, chunk.constants.len());
+
+  for constant in &chunk.constants {
+    match constant {
+      Constant::Number(number) => {
+        body.push(TAG_NUMBER);
+        body.extend_from_slice(&number.to_bits().to_be_bytes());
+      }
+      Constant::String(string) => {
+        body.push(TAG_STRING);
+        write_string(body, string);
+      }
+      Constant::Function(inner) => {
+        body.push(TAG_FUNCTION);
+        write_function(body, inner);
+      }
+    }
+  }
+
+  write_u32(body, chunk.len());
+  body.extend_from_slice(&chunk.code);
+
+  write_u32(body, chunk.line_runs());
+
+  for (offset, line) in chunk.line_starts() {
+    write_u32(body, offset);
+    write_u32(body, line as usize);
+  }
+
+  write_u32(body, function.locals.len());
+
+  for local in &function.locals {
+    write_string(body, &local.name);
+    write_u32(body, local.slot);
+    write_u32(body, local.start);
+    write_u32(body, local.end);
+  }
+
+  write_u32(body, function.upvalue_names.len());
+
+  for name in &function.upvalue_names {
+    write_string(body, name);
+  }
+}
+
+struct Input<'b> {
+  bytes: &'b [u8],
+  position: usize,
+}
+
+impl<'b> Input<'b> {
+  fn malformed(&self, message: impl Into<String>) -> LoadError {
+    LoadError::Malformed(self.position, message.into())
+  }
+
+  fn bytes(&mut self, count: usize) -> Result<&'b [u8], LoadError> {
+    if self.bytes.len() - self.position < count {
+      return Err(self.malformed("unexpected end of data"));
+    }
+
+    let bytes = &self.bytes[self.position..self.position + count];
+    self.position += count;
+
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8, LoadError> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn u32(&mut self) -> Result<usize, LoadError> {
+    let bytes = self.bytes(4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+  }
+
+  fn string(&mut self) -> Result<Rc<str>, LoadError> {
+    let length = self.u32()?;
+    let bytes = self.bytes(length)?;
+
+    match std::str::from_utf8(bytes) {
+      Ok(string) => Ok(Rc::from(string)),
+      Err(_) => Err(self.malformed("string isn't valid UTF-8")),
+    }
+  }
+
+  fn function(&mut self, depth: usize) -> Result<CompiledFunction, LoadError> {
+    if depth > MAX_DEPTH {
+      return Err(self.malformed("functions nested too deep"));
+    }
+
+    let name = match self.u8()? {
+      0 => None,
+      1 => Some(self.string()?),
+      _ => return Err(self.malformed("bad function name tag")),
+    };
+
+    let arity = self.u32()?;
+    let upvalue_count = self.u32()?;
+
+    // The VM's frames only have 256 slots, and captures are one byte each
+    if arity > u8::MAX as usize || upvalue_count > u8::MAX as usize + 1 {
+      return Err(self.malformed("arity or upvalue count out of range"));
+    }
+
+    let constant_count = self.u32()?;
+
+    if constant_count > MAX_CONSTANTS {
+      return Err(self.malformed("too many constants"));
+    }
+
+    let mut constants = Vec::new();
+
+    for _ in 0..constant_count {
+      let constant = match self.u8()? {
+        TAG_NUMBER => {
+          let bytes = self.bytes(8)?;
+          let mut bits = [0; 8];
+          bits.copy_from_slice(bytes);
+          Constant::Number(f64::from_bits(u64::from_be_bytes(bits)))
+        }
+        TAG_STRING => Constant::String(self.string()?),
+        TAG_FUNCTION => Constant::Function(Rc::new(self.function(depth + 1)?)),
+        _ => return Err(self.malformed("bad constant tag")),
+      };
+
+      constants.push(constant);
+    }
+
+    let code_length = self.u32()?;
+    let code = self.bytes(code_length)?;
+    let run_count = self.u32()?;
+    let mut runs = Vec::new();
+
+    for _ in 0..run_count {
+      let offset = self.u32()?;
+      let line = self.u32()? as u32;
+
+      let ordered = match runs.last() {
+        Some((previous, _)) => offset > *previous,
+        None => offset == 0,
+      };
+
+      if !ordered || offset >= code.len() {
+        return Err(self.malformed("line runs out of order"));
+      }
+
+      runs.push((offset, line));
+    }
+
+    if runs.is_empty() != code.is_empty() {
+      return Err(self.malformed("code without lines"));
+    }
+
+    let mut chunk = Chunk::new();
+    chunk.constants = constants;
+
+    for (run, (start, line)) in runs.iter().enumerate() {
+      let end = runs.get(run + 1).map_or(code.len(), |(next, _)| *next);
+
+      for byte in &code[*start..end] {
+        chunk.write(*byte, *line);
+      }
+    }
+
+    let local_count = self.u32()?;
+    let mut locals = Vec::new();
+
+    for _ in 0..local_count {
+      let local = LocalName {
+        name: self.string()?,
+        slot: self.u32()?,
+        start: self.u32()?,
+        end: self.u32()?,
+      };
+
+      if local.slot > u8::MAX as usize || local.start > local.end || local.end > code.len() {
+        return Err(self.malformed("local out of range"));
+      }
+
+      locals.push(local);
+    }
+
+    let name_count = self.u32()?;
+
+    // Functions put together by hand can leave their upvalues unnamed
+    if name_count != 0 && name_count != upvalue_count {
+      return Err(self.malformed("upvalue names don't match the upvalues"));
+    }
+
+    let upvalue_names = (0..name_count)
+      .map(|_| self.string())
+      .collect::<Result<_, _>>()?;
+
+    Ok(CompiledFunction {
+      name,
+      arity,
+      upvalue_count,
+      chunk,
+      locals,
+      upvalue_names,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::OpCode;
+  use crate::compiler;
+  use crate::optimizer;
+  use crate::parser;
+  use crate::resolver;
+  use crate::vm::{Vm, VmError};
+
+  fn compile(source: &str) -> CompiledFunction {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    (*compiler::compile(&statements, &resolution).unwrap()).clone()
+  }
+
+  fn round_trip(function: &CompiledFunction) -> Result<CompiledFunction, LoadError> {
+    let mut bytes = Vec::new();
+    function.write_to(&mut bytes).unwrap();
+
+    CompiledFunction::read_from(&mut bytes.as_slice())
+  }
+
+  fn run(function: CompiledFunction) -> String {
+    let mut vm = Vm::new(Vec::new());
+    vm.interpret(Rc::new(function)).unwrap();
+
+    String::from_utf8(vm.into_output()).unwrap()
+  }
+
+  // A script with the given code, written out with a correct checksum
+  fn script(code: &[u8], constants: Vec<Constant>) -> CompiledFunction {
+    let mut function = CompiledFunction::default();
+    function.chunk.constants = constants;
+
+    for byte in code {
+      function.chunk.write(*byte, 1);
+    }
+
+    function
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../s1-web/test_suite");
+
+    for entry in std::fs::read_dir(directory).unwrap() {
+      let path = entry.unwrap().path();
+
+      if path.extension().is_some_and(|extension| extension == "lox") {
+        let function = compile(&std::fs::read_to_string(&path).unwrap());
+        let optimized = optimizer::optimize(&function);
+
+        assert_eq!(round_trip(&function).unwrap(), function, "{:?}", path);
+        assert_eq!(round_trip(&optimized).unwrap(), optimized, "{:?}", path);
+      }
+    }
+
+    let source = "fun counter() {\n  var count = 0;\n  fun increment() { count = count + 1; return count; }\n  return increment;\n}\nvar next = counter();\nnext();\nprint next() + 0.5;\nprint \"a\" + \"b\";";
+    let function = compile(source);
+
+    assert_eq!(run(round_trip(&function).unwrap()), run(function));
+  }
+
+  #[test]
+  fn test_rejects_bad_headers() {
+    let mut bytes = Vec::new();
+    compile("print 1;").write_to(&mut bytes).unwrap();
+
+    let mut wrong_magic = bytes.clone();
+    wrong_magic[0] = b'X';
+    assert!(matches!(
+      CompiledFunction::read_from(&mut wrong_magic.as_slice()),
+      Err(LoadError::BadMagic)
+    ));
+
+    let mut wrong_version = bytes.clone();
+    wrong_version[5] = 9;
+    assert!(matches!(
+      CompiledFunction::read_from(&mut wrong_version.as_slice()),
+      Err(LoadError::UnsupportedVersion(9))
+    ));
+
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert!(matches!(
+      CompiledFunction::read_from(&mut corrupted.as_slice()),
+      Err(LoadError::ChecksumMismatch)
+    ));
+
+    for length in 0..bytes.len() {
+      assert!(CompiledFunction::read_from(&mut &bytes[..length]).is_err());
+    }
+
+    let mut huge = bytes.clone();
+    huge[10..14].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(matches!(
+      CompiledFunction::read_from(&mut huge.as_slice()),
+      Err(LoadError::Malformed(_, _))
+    ));
+  }
+
+  #[test]
+  fn test_rejects_invalid_code() {
+    let nil = OpCode::Nil as u8;
+    let ret = OpCode::Return as u8;
+
+    // Past the end of the pool, and popping slot zero
+    for code in &[
+      vec![OpCode::Constant as u8, 1, ret],
+      vec![OpCode::Pop as u8, nil, ret],
+    ] {
+      assert!(matches!(
+        round_trip(&script(code, vec![Constant::Number(1.0)])),
+        Err(LoadError::InvalidCode(_))
+      ));
+    }
+
+    let mut inner = script(&[OpCode::GetUpvalue as u8, 1, ret], Vec::new());
+    inner.name = Some(Rc::from("inner"));
+    inner.upvalue_count = 1;
+    let outer = script(
+      &[OpCode::Closure as u8, 0, 1, 0, OpCode::Pop as u8, nil, ret],
+      vec![Constant::Function(Rc::new(inner))],
+    );
+
+    assert_eq!(
+      round_trip(&outer).unwrap_err().to_string(),
+      "load error: verify error: <fn inner> at offset 0: upvalue index out of bounds"
+    );
+  }
+
+  // Loads fine since the verifier doesn't know about types, but the VM fails
+  // on the first instruction given the wrong kind of value
+  fn run_invalid(code: &[u8]) -> String {
+    let function = script(code, vec![Constant::String(Rc::from("m"))]);
+    let mut vm = Vm::new(Vec::new());
+
+    match vm.interpret(Rc::new(round_trip(&function).unwrap())) {
+      Err(VmError::Runtime(error)) => error.message,
+      result => panic!("{:?}", result.map_err(|error| error.to_string())),
+    }
+  }
+
+  #[test]
+  fn test_method_on_wrong_types() {
+    let (nil, pop, ret) = (OpCode::Nil as u8, OpCode::Pop as u8, OpCode::Return as u8);
+
+    assert_eq!(
+      run_invalid(&[nil, nil, OpCode::Method as u8, 0, pop, nil, ret]),
+      "Method is not a function"
+    );
+  }
+
+  #[test]
+  fn test_inherit_into_wrong_type() {
+    let (nil, pop, ret) = (OpCode::Nil as u8, OpCode::Pop as u8, OpCode::Return as u8);
+    let code = [
+      OpCode::Class as u8,
+      0,
+      OpCode::Constant as u8,
+      0,
+      OpCode::Inherit as u8,
+      pop,
+      nil,
+      ret,
+    ];
+
+    assert_eq!(run_invalid(&code), "Subclass is not a class");
+  }
+
+  #[test]
+  fn test_super_on_wrong_types() {
+    let (nil, pop, ret) = (OpCode::Nil as u8, OpCode::Pop as u8, OpCode::Return as u8);
+
+    assert_eq!(
+      run_invalid(&[nil, nil, OpCode::GetSuper as u8, 0, pop, nil, ret]),
+      "Super bound to something other than a class"
+    );
+  }
+}
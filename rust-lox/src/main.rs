@@ -1,18 +1,190 @@
-mod expression;
-mod parser;
-mod scanner;
-mod util;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use std::rc::Rc;
+
+use rust_lox::chunk::CompiledFunction;
+use rust_lox::compiler;
+use rust_lox::debugger;
+use rust_lox::interpreter::Interpreter;
+use rust_lox::optimizer;
+use rust_lox::parser;
+use rust_lox::resolver;
+use rust_lox::vm::{Profiler, TraceFormat, Tracer, Vm, Weight};
+
+const USAGE: &str = "Usage: rust-lox [-O] [--trace | --trace-json] [--profile] \
+  [--folded output] [-c output.loxc] [source_file | bytecode.loxc]\n       \
+  rust-lox --dap";
+
+// How to run on the VM
+#[derive(Default)]
+struct Options {
+  trace: Option<TraceFormat>,
+  // Print a profile to stderr at the end
+  profile: bool,
+  // File to write folded stacks to, for flamegraphs
+  folded: Option<String>,
+}
+
+impl Options {
+  fn on_vm(&self) -> bool {
+    self.trace.is_some() || self.profile || self.folded.is_some()
+  }
+}
 
 fn main() {
-    let token: scanner::Token;
+  // A debug adapter for editors, the program comes with the launch request
+  if env::args().nth(1).as_deref() == Some("--dap") {
+    if env::args().len() > 2 {
+      usage();
+    }
+
+    if let Err(error) = debugger::serve(io::BufReader::new(io::stdin()), io::stdout()) {
+      eprintln!("{}", error);
+      process::exit(74);
+    }
+
+    return;
+  }
 
-    {
-        let source = String::from("      print 200;");
+  let mut arguments = env::args().skip(1);
+  let mut optimize = false;
+  let mut options = Options::default();
+  let mut output = None;
+  let mut path = None;
 
-        let scan = scanner::Scan::new(source.as_str());
+  // -O runs optimized bytecode on the VM instead of walking the tree, -c
+  // writes the bytecode to a .loxc file instead of running it. Tracing runs
+  // and profiling run on the VM too.
+  while let Some(argument) = arguments.next() {
+    match argument.as_str() {
+      "-O" => optimize = true,
+      "--trace" if options.trace.is_none() => options.trace = Some(TraceFormat::Text),
+      "--trace-json" if options.trace.is_none() => options.trace = Some(TraceFormat::JsonLines),
+      "--profile" => options.profile = true,
+      "--folded" if options.folded.is_none() => {
+        options.folded = Some(arguments.next().unwrap_or_else(|| usage()))
+      }
+      "-c" if output.is_none() => output = Some(arguments.next().unwrap_or_else(|| usage())),
+      _ if path.is_none() && !argument.starts_with('-') => path = Some(argument),
+      _ => usage(),
+    }
+  }
+
+  let path = path.unwrap_or_else(|| usage());
+
+  if path.ends_with(".loxc") {
+    if output.is_some() {
+      usage();
+    }
+
+    return run(load(&path), &options);
+  }
 
-        println!("{:?}", scan.collect::<Vec<scanner::Token>>());
+  let source = fs::read_to_string(&path).unwrap_or_else(|error| {
+    eprintln!("Couldn't read {}: {}", path, error);
+    process::exit(74);
+  });
+
+  let statements = parser::parse(&source).unwrap_or_else(|errors| {
+    errors.iter().for_each(|error| eprintln!("{}", error));
+    process::exit(65);
+  });
+
+  let resolution = resolver::resolve(&statements).unwrap_or_else(|errors| {
+    errors.iter().for_each(|error| eprintln!("{}", error));
+    process::exit(65);
+  });
+
+  if optimize || options.on_vm() || output.is_some() {
+    let mut script = compiler::compile(&statements, &resolution).unwrap_or_else(|errors| {
+      errors.iter().for_each(|error| eprintln!("{}", error));
+      process::exit(65);
+    });
+
+    if optimize {
+      script = Rc::new(optimizer::optimize(&script));
     }
 
-    // println!("{:?}", token);
+    return match output {
+      Some(output) => save(&script, &output),
+      None => run(script, &options),
+    };
+  }
+
+  let stdout = io::stdout();
+  let mut interpreter = Interpreter::new(&resolution, stdout.lock());
+
+  if let Err(error) = interpreter.interpret(&statements) {
+    eprintln!("{}", error);
+    process::exit(70);
+  }
+}
+
+fn usage() -> ! {
+  eprintln!("{}", USAGE);
+  process::exit(64);
+}
+
+fn run(script: Rc<CompiledFunction>, options: &Options) {
+  let stdout = io::stdout();
+  let mut vm = Vm::new(stdout.lock());
+  vm.set_tracer(
+    options
+      .trace
+      .map(|format| Tracer::new(io::stderr(), format)),
+  );
+
+  if options.profile || options.folded.is_some() {
+    vm.set_profiler(Some(Profiler::new()));
+  }
+
+  let result = vm.interpret(script);
+
+  // Also for programs that fail, that's when it's most interesting
+  if let Some(profiler) = vm.set_profiler(None) {
+    if options.profile {
+      eprint!("{}", profiler.summary());
+    }
+
+    if let Some(path) = &options.folded {
+      if let Err(error) = fs::write(path, profiler.folded_stacks(Weight::Microseconds)) {
+        eprintln!("Couldn't write {}: {}", path, error);
+        process::exit(74);
+      }
+    }
+  }
+
+  if let Err(error) = result {
+    eprintln!("{}", error);
+    process::exit(70);
+  }
+}
+
+fn load(path: &str) -> Rc<CompiledFunction> {
+  let file = fs::File::open(path).unwrap_or_else(|error| {
+    eprintln!("Couldn't read {}: {}", path, error);
+    process::exit(74);
+  });
+
+  let script = CompiledFunction::read_from(&mut io::BufReader::new(file));
+
+  Rc::new(script.unwrap_or_else(|error| {
+    eprintln!("{}", error);
+    process::exit(65);
+  }))
+}
+
+fn save(script: &CompiledFunction, path: &str) {
+  let written = fs::File::create(path).and_then(|file| {
+    let mut writer = io::BufWriter::new(file);
+    script.write_to(&mut writer)?;
+    writer.flush()
+  });
+
+  if let Err(error) = written {
+    eprintln!("Couldn't write {}: {}", path, error);
+    process::exit(74);
+  }
 }
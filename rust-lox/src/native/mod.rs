@@ -0,0 +1,162 @@
+mod stdlib;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::interpreter::format_number;
+
+// What natives see of Lox values. Backends convert to and from their own
+// representation so the same natives work everywhere. Objects can be passed in
+// but natives can't create them.
+#[derive(Debug, PartialEq, Clone)]
+pub enum NativeValue {
+  Nil,
+  Bool(bool),
+  Number(f64),
+  String(Rc<str>),
+  Object {
+    // What `type()` reports: "function", "class" or "instance"
+    kind: &'static str,
+    description: Rc<str>,
+  },
+}
+
+impl NativeValue {
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      NativeValue::Nil => "nil",
+      NativeValue::Bool(_) => "boolean",
+      NativeValue::Number(_) => "number",
+      NativeValue::String(_) => "string",
+      NativeValue::Object { kind, .. } => kind,
+    }
+  }
+}
+
+impl fmt::Display for NativeValue {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      NativeValue::Nil => write!(f, "nil"),
+      NativeValue::Bool(boolean) => write!(f, "{}", boolean),
+      NativeValue::Number(number) => write!(f, "{}", format_number(*number)),
+      NativeValue::String(string) => write!(f, "{}", string),
+      NativeValue::Object { description, .. } => write!(f, "{}", description),
+    }
+  }
+}
+
+// Errors are plain messages, the backend adds the line and stack trace
+pub type NativeResult = Result<NativeValue, String>;
+
+pub type NativeFn = dyn Fn(&[NativeValue]) -> NativeResult;
+
+pub struct Native {
+  pub name: &'static str,
+  pub arity: usize,
+  pub function: Box<NativeFn>,
+}
+
+impl fmt::Debug for Native {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "<native fn {}/{}>", self.name, self.arity)
+  }
+}
+
+// The functions a program starts with. `clock` is always there, everything
+// else has to be registered by whoever embeds Lox or comes from the stdlib.
+#[derive(Debug, Clone)]
+pub struct NativeRegistry {
+  natives: HashMap<&'static str, Rc<Native>>,
+}
+
+impl NativeRegistry {
+  pub fn new() -> NativeRegistry {
+    let mut registry = NativeRegistry {
+      natives: HashMap::new(),
+    };
+
+    registry.register("clock", 0, |_| {
+      let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_secs_f64());
+
+      Ok(NativeValue::Number(now))
+    });
+
+    registry
+  }
+
+  pub fn with_stdlib(mut self) -> NativeRegistry {
+    stdlib::register(&mut self);
+    self
+  }
+
+  // Replaces any native already registered under the same name
+  pub fn register<F>(&mut self, name: &'static str, arity: usize, function: F)
+  where
+    F: Fn(&[NativeValue]) -> NativeResult + 'static,
+  {
+    self.natives.insert(
+      name,
+      Rc::new(Native {
+        name,
+        arity,
+        function: Box::new(function),
+      }),
+    );
+  }
+
+  pub fn unregister(&mut self, name: &str) -> bool {
+    self.natives.remove(name).is_some()
+  }
+
+  pub fn get(&self, name: &str) -> Option<&Rc<Native>> {
+    self.natives.get(name)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Rc<Native>> {
+    self.natives.values()
+  }
+}
+
+// The stdlib is on unless asked otherwise
+impl Default for NativeRegistry {
+  fn default() -> Self {
+    NativeRegistry::new().with_stdlib()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_clock_is_always_registered() {
+    let registry = NativeRegistry::new();
+
+    assert_eq!(registry.iter().count(), 1);
+    assert_eq!(registry.get("clock").unwrap().arity, 0);
+    assert!(registry.get("sqrt").is_none());
+  }
+
+  #[test]
+  fn test_register() {
+    let mut registry = NativeRegistry::new();
+    registry.register("double", 1, |arguments| match arguments[0] {
+      NativeValue::Number(number) => Ok(NativeValue::Number(number * 2.0)),
+      _ => Err(String::from("Expected a number")),
+    });
+
+    let double = registry.get("double").unwrap();
+
+    assert_eq!(
+      (double.function)(&[NativeValue::Number(2.0)]),
+      Ok(NativeValue::Number(4.0))
+    );
+    assert!((double.function)(&[NativeValue::Nil]).is_err());
+    assert!(registry.unregister("double"));
+    assert!(registry.get("double").is_none());
+  }
+}
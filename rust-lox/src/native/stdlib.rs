@@ -0,0 +1,352 @@
+use std::cell::Cell;
+use std::io::{self, BufRead};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{NativeRegistry, NativeValue};
+
+// Past this formatNumber would only pad with zeros, and format! panics on
+// precisions that don't fit in a u16
+const MAX_DECIMALS: usize = 100;
+
+pub fn register(registry: &mut NativeRegistry) {
+  registry.register("len", 1, |arguments| {
+    let string = string(arguments, 0)?;
+
+    Ok(NativeValue::Number(string.chars().count() as f64))
+  });
+
+  // Indices count characters, `end` is exclusive
+  registry.register("substring", 3, |arguments| {
+    let string = string(arguments, 0)?;
+    let start = index(arguments, 1)?;
+    let end = index(arguments, 2)?;
+    let length = string.chars().count();
+
+    if start > end || end > length {
+      return Err(format!(
+        "Substring {}..{} out of range for length {}",
+        start, end, length
+      ));
+    }
+
+    let substring: String = string.chars().skip(start).take(end - start).collect();
+
+    Ok(NativeValue::String(Rc::from(substring)))
+  });
+
+  registry.register("indexOf", 2, |arguments| {
+    let haystack = string(arguments, 0)?;
+    let needle = string(arguments, 1)?;
+
+    Ok(NativeValue::Number(match haystack.find(needle) {
+      Some(byte_index) => haystack[..byte_index].chars().count() as f64,
+      None => -1.0,
+    }))
+  });
+
+  registry.register("toString", 1, |arguments| {
+    Ok(NativeValue::String(Rc::from(arguments[0].to_string())))
+  });
+
+  registry.register("formatNumber", 2, |arguments| {
+    let number = number(arguments, 0)?;
+    let decimals = index(arguments, 1)?;
+
+    if decimals > MAX_DECIMALS {
+      return Err(format!(
+        "Expected at most {} decimals but got {}",
+        MAX_DECIMALS, arguments[1]
+      ));
+    }
+
+    Ok(NativeValue::String(Rc::from(format!(
+      "{:.*}",
+      decimals, number
+    ))))
+  });
+
+  registry.register("parseNumber", 1, |arguments| {
+    Ok(match string(arguments, 0)?.trim().parse() {
+      Ok(number) => NativeValue::Number(number),
+      Err(_) => NativeValue::Nil,
+    })
+  });
+
+  registry.register("sqrt", 1, |arguments| {
+    Ok(NativeValue::Number(number(arguments, 0)?.sqrt()))
+  });
+
+  registry.register("floor", 1, |arguments| {
+    Ok(NativeValue::Number(number(arguments, 0)?.floor()))
+  });
+
+  registry.register("pow", 2, |arguments| {
+    Ok(NativeValue::Number(
+      number(arguments, 0)?.powf(number(arguments, 1)?),
+    ))
+  });
+
+  let state = Rc::new(Cell::new(seed_from_clock()));
+
+  let random_state = Rc::clone(&state);
+  registry.register("random", 0, move |_| {
+    Ok(NativeValue::Number(next_random(&random_state)))
+  });
+
+  registry.register("seedRandom", 1, move |arguments| {
+    state.set(scramble(number(arguments, 0)?.to_bits()));
+    Ok(NativeValue::Nil)
+  });
+
+  // nil once the input runs out
+  registry.register("readLine", 0, |_| {
+    let mut line = String::new();
+
+    match io::stdin().lock().read_line(&mut line) {
+      Ok(0) => Ok(NativeValue::Nil),
+      Ok(_) => Ok(NativeValue::String(Rc::from(
+        line.trim_end_matches(&['\r', '\n'][..]),
+      ))),
+      Err(error) => Err(format!("Couldn't read input: {}", error)),
+    }
+  });
+
+  registry.register("type", 1, |arguments| {
+    Ok(NativeValue::String(Rc::from(arguments[0].type_name())))
+  });
+}
+
+fn string(arguments: &[NativeValue], position: usize) -> Result<&str, String> {
+  match &arguments[position] {
+    NativeValue::String(string) => Ok(string),
+    other => Err(format!(
+      "Expected a string for argument {} but got {}",
+      position + 1,
+      other.type_name()
+    )),
+  }
+}
+
+fn number(arguments: &[NativeValue], position: usize) -> Result<f64, String> {
+  match arguments[position] {
+    NativeValue::Number(number) => Ok(number),
+    ref other => Err(format!(
+      "Expected a number for argument {} but got {}",
+      position + 1,
+      other.type_name()
+    )),
+  }
+}
+
+fn index(arguments: &[NativeValue], position: usize) -> Result<usize, String> {
+  let number = number(arguments, position)?;
+
+  if number < 0.0 || number.fract() != 0.0 {
+    return Err(format!(
+      "Expected a whole non-negative number for argument {} but got {}",
+      position + 1,
+      arguments[position]
+    ));
+  }
+
+  Ok(number as usize)
+}
+
+fn seed_from_clock() -> u64 {
+  let nanos = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |duration| duration.as_nanos() as u64);
+
+  scramble(nanos)
+}
+
+// xorshift gets stuck on zero so the seed goes through splitmix first
+fn scramble(seed: u64) -> u64 {
+  let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+  match z ^ (z >> 31) {
+    0 => 1,
+    scrambled => scrambled,
+  }
+}
+
+// xorshift64*, in [0, 1)
+fn next_random(state: &Cell<u64>) -> f64 {
+  let mut x = state.get();
+  x ^= x >> 12;
+  x ^= x << 25;
+  x ^= x >> 27;
+  state.set(x);
+
+  (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::native::NativeResult;
+
+  fn call(name: &str, arguments: &[NativeValue]) -> NativeResult {
+    let registry = NativeRegistry::default();
+    let native = registry.get(name).unwrap();
+
+    assert_eq!(native.arity, arguments.len());
+
+    (native.function)(arguments)
+  }
+
+  fn string(string: &str) -> NativeValue {
+    NativeValue::String(Rc::from(string))
+  }
+
+  #[test]
+  fn test_strings() {
+    assert_eq!(
+      call("len", &[string("héllo")]),
+      Ok(NativeValue::Number(5.0))
+    );
+    assert_eq!(
+      call(
+        "substring",
+        &[
+          string("héllo"),
+          NativeValue::Number(1.0),
+          NativeValue::Number(3.0)
+        ]
+      ),
+      Ok(string("él"))
+    );
+    assert_eq!(
+      call("indexOf", &[string("héllo"), string("llo")]),
+      Ok(NativeValue::Number(2.0))
+    );
+    assert_eq!(
+      call("indexOf", &[string("hello"), string("x")]),
+      Ok(NativeValue::Number(-1.0))
+    );
+    assert_eq!(
+      call(
+        "formatNumber",
+        &[NativeValue::Number(1.0), NativeValue::Number(65536.0)]
+      ),
+      Err(String::from(
+        "Expected at most 100 decimals but got 65536.0"
+      ))
+    );
+  }
+
+  #[test]
+  fn test_substring_out_of_range() {
+    assert_eq!(
+      call(
+        "substring",
+        &[
+          string("abc"),
+          NativeValue::Number(1.0),
+          NativeValue::Number(4.0)
+        ]
+      ),
+      Err(String::from("Substring 1..4 out of range for length 3"))
+    );
+  }
+
+  #[test]
+  fn test_argument_types() {
+    assert_eq!(
+      call("len", &[NativeValue::Number(1.0)]),
+      Err(String::from(
+        "Expected a string for argument 1 but got number"
+      ))
+    );
+    assert_eq!(
+      call("sqrt", &[NativeValue::Nil]),
+      Err(String::from("Expected a number for argument 1 but got nil"))
+    );
+  }
+
+  #[test]
+  fn test_numbers() {
+    assert_eq!(
+      call("toString", &[NativeValue::Number(2.0)]),
+      Ok(string("2.0"))
+    );
+    assert_eq!(
+      call(
+        "formatNumber",
+        &[NativeValue::Number(1.23456), NativeValue::Number(2.0)]
+      ),
+      Ok(string("1.23"))
+    );
+    assert_eq!(
+      call("parseNumber", &[string(" 12.5 ")]),
+      Ok(NativeValue::Number(12.5))
+    );
+    assert_eq!(
+      call("parseNumber", &[string("twelve")]),
+      Ok(NativeValue::Nil)
+    );
+  }
+
+  #[test]
+  fn test_math() {
+    assert_eq!(
+      call("sqrt", &[NativeValue::Number(9.0)]),
+      Ok(NativeValue::Number(3.0))
+    );
+    assert_eq!(
+      call("floor", &[NativeValue::Number(-1.5)]),
+      Ok(NativeValue::Number(-2.0))
+    );
+    assert_eq!(
+      call(
+        "pow",
+        &[NativeValue::Number(2.0), NativeValue::Number(10.0)]
+      ),
+      Ok(NativeValue::Number(1024.0))
+    );
+  }
+
+  #[test]
+  fn test_seeded_random() {
+    let registry = NativeRegistry::default();
+    let random = &registry.get("random").unwrap().function;
+    let seed = &registry.get("seedRandom").unwrap().function;
+
+    seed(&[NativeValue::Number(42.0)]).unwrap();
+    let first = (random(&[]).unwrap(), random(&[]).unwrap());
+    seed(&[NativeValue::Number(42.0)]).unwrap();
+    let second = (random(&[]).unwrap(), random(&[]).unwrap());
+
+    assert_eq!(first, second);
+    assert_ne!(first.0, first.1);
+
+    for _ in 0..100 {
+      match random(&[]).unwrap() {
+        NativeValue::Number(number) => assert!((0.0..1.0).contains(&number)),
+        other => panic!("{:?}", other),
+      }
+    }
+  }
+
+  #[test]
+  fn test_type() {
+    assert_eq!(call("type", &[NativeValue::Nil]), Ok(string("nil")));
+    assert_eq!(
+      call("type", &[NativeValue::Bool(true)]),
+      Ok(string("boolean"))
+    );
+    assert_eq!(
+      call(
+        "type",
+        &[NativeValue::Object {
+          kind: "instance",
+          description: Rc::from("A instance"),
+        }]
+      ),
+      Ok(string("instance"))
+    );
+  }
+}
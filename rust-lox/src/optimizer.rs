@@ -0,0 +1,724 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use super::chunk::{Chunk, CompiledFunction, Constant, LocalName, OpCode, MAX_CONSTANTS};
+use super::debug;
+
+// One instruction while the chunk is being rewritten. Jumps point at other
+// instructions by index so they survive instructions being removed.
+#[derive(Debug, Clone)]
+struct Op {
+  op: OpCode,
+  // Everything after the opcode, except for jumps
+  operands: Vec<u8>,
+  line: u32,
+  target: Option<usize>,
+  // Where it started in the original chunk. No rewrite makes the code
+  // bigger, so this bounds how far apart two instructions can end up.
+  offset: usize,
+}
+
+impl Op {
+  fn new(op: OpCode, operands: Vec<u8>, line: u32, offset: usize) -> Op {
+    Op {
+      op,
+      operands,
+      line,
+      target: None,
+      offset,
+    }
+  }
+
+  fn is_unconditional_jump(&self) -> bool {
+    matches!(self.op, OpCode::Jump | OpCode::Loop)
+  }
+
+  fn is_jump(&self) -> bool {
+    self.target.is_some()
+  }
+
+  fn size(&self) -> usize {
+    if self.is_jump() {
+      3
+    } else {
+      1 + self.operands.len()
+    }
+  }
+}
+
+// Peephole pass over the bytecode of a function and of every function nested
+// in it. Runs what the compiler emitted through a few rewrites until none of
+// them applies any more:
+// - arithmetic, comparisons and concatenation of constants are folded
+// - a pure expression that only gets popped is removed
+// - jumps landing on unconditional jumps go straight to the final target
+// and then fuses common sequences into superinstructions.
+pub fn optimize(function: &CompiledFunction) -> CompiledFunction {
+  let mut constants: Vec<Constant> = function
+    .chunk
+    .constants
+    .iter()
+    .map(|constant| match constant {
+      Constant::Function(inner) => Constant::Function(Rc::new(optimize(inner))),
+      constant => constant.clone(),
+    })
+    .collect();
+
+  let mut ops = decode(&function.chunk);
+
+  while fold(&mut ops, &mut constants) | remove_pops(&mut ops) | thread_jumps(&mut ops) {}
+
+  fuse(&mut ops);
+
+  // Constants are only ever added, so the original code can still use the
+  // optimized pool if the rewritten code can't be encoded
+  let (chunk, locals) = match encode(&ops, constants.clone()) {
+    Some(chunk) => (chunk, move_locals(&function.locals, &ops)),
+    None => {
+      let mut chunk = function.chunk.clone();
+      chunk.constants = constants;
+
+      (chunk, function.locals.clone())
+    }
+  };
+
+  CompiledFunction {
+    name: function.name.clone(),
+    arity: function.arity,
+    upvalue_count: function.upvalue_count,
+    chunk,
+    locals,
+    upvalue_names: function.upvalue_names.clone(),
+  }
+}
+
+fn decode(chunk: &Chunk) -> Vec<Op> {
+  let instructions = debug::disassemble(chunk);
+  let indices: HashMap<usize, usize> = instructions
+    .iter()
+    .enumerate()
+    .map(|(index, instruction)| (instruction.offset, index))
+    .chain(std::iter::once((chunk.len(), instructions.len())))
+    .collect();
+
+  instructions
+    .iter()
+    .map(|instruction| {
+      let op = OpCode::try_from(chunk.code[instruction.offset])
+        .unwrap_or_else(|byte| panic!("[bug] unknown opcode {}", byte));
+      let mut decoded = Op::new(op, Vec::new(), instruction.line, instruction.offset);
+
+      match instruction.target {
+        Some(target) => decoded.target = Some(indices[&target]),
+        None => decoded.operands = chunk.code[instruction.offset + 1..instruction.next].to_vec(),
+      }
+
+      decoded
+    })
+    .collect()
+}
+
+// Where each op ends up, and the length of the code as the last entry
+fn offsets(ops: &[Op]) -> Vec<usize> {
+  let mut offsets = Vec::with_capacity(ops.len() + 1);
+  let mut offset = 0;
+
+  for op in ops {
+    offsets.push(offset);
+    offset += op.size();
+  }
+
+  offsets.push(offset);
+  offsets
+}
+
+// None when a jump ends up too far for its two bytes
+fn encode(ops: &[Op], constants: Vec<Constant>) -> Option<Chunk> {
+  let offsets = offsets(ops);
+  let mut chunk = Chunk::new();
+  chunk.constants = constants;
+
+  for (index, op) in ops.iter().enumerate() {
+    match op.target {
+      Some(target) => {
+        let after = offsets[index] + 3;
+        let (code, jump) = match op.op {
+          OpCode::JumpIfFalse => (OpCode::JumpIfFalse, offsets[target] - after),
+          _ if offsets[target] >= after => (OpCode::Jump, offsets[target] - after),
+          _ => (OpCode::Loop, after - offsets[target]),
+        };
+
+        if jump > u16::MAX as usize {
+          return None;
+        }
+
+        chunk.write_op(code, op.line);
+        chunk.write((jump >> 8) as u8, op.line);
+        chunk.write(jump as u8, op.line);
+      }
+      None => {
+        chunk.write_op(op.op, op.line);
+
+        for byte in &op.operands {
+          chunk.write(*byte, op.line);
+        }
+      }
+    }
+  }
+
+  Some(chunk)
+}
+
+// Puts the code ranges of the locals on the ops they started and ended at, or
+// the ones right after when those were removed
+fn move_locals(locals: &[LocalName], ops: &[Op]) -> Vec<LocalName> {
+  let offsets = offsets(ops);
+  let moved = |original: usize| offsets[ops.partition_point(|op| op.offset < original)];
+
+  locals
+    .iter()
+    .map(|local| LocalName {
+      start: moved(local.start),
+      end: moved(local.end),
+      ..local.clone()
+    })
+    .collect()
+}
+
+fn jump_targets(ops: &[Op]) -> HashSet<usize> {
+  ops.iter().filter_map(|op| op.target).collect()
+}
+
+// Whether ops[start..start + count] are free to be merged into one, as nothing
+// jumps into the middle of them
+fn mergeable(ops: &[Op], targets: &HashSet<usize>, start: usize, count: usize) -> bool {
+  start + count <= ops.len() && (start + 1..start + count).all(|index| !targets.contains(&index))
+}
+
+// Drops the ops marked as removed. Jumps to a removed op land on the next one
+// that's kept.
+fn compact(ops: &mut Vec<Op>, removed: &[bool]) {
+  let mut kept = 0;
+  let indices: Vec<usize> = removed
+    .iter()
+    .chain(std::iter::once(&false))
+    .map(|removed| {
+      let index = kept;
+      kept += !removed as usize;
+      index
+    })
+    .collect();
+
+  let mut index = 0;
+  ops.retain(|_| {
+    index += 1;
+    !removed[index - 1]
+  });
+
+  for op in ops.iter_mut() {
+    if let Some(target) = op.target {
+      op.target = Some(indices[target]);
+    }
+  }
+}
+
+// Replaces ops[start..start + count] with the given op
+fn merge(ops: &mut Vec<Op>, start: usize, count: usize, mut op: Op) {
+  let mut removed = vec![false; ops.len()];
+  removed[start + 1..start + count]
+    .iter_mut()
+    .for_each(|removed| *removed = true);
+
+  op.line = ops[start + count - 1].line;
+  op.offset = ops[start].offset;
+  ops[start] = op;
+
+  compact(ops, &removed);
+}
+
+fn constant_of<'c>(op: &Op, constants: &'c [Constant]) -> Option<&'c Constant> {
+  match op.op {
+    OpCode::Constant => Some(&constants[op.operands[0] as usize]),
+    OpCode::ConstantLong => {
+      let index = (op.operands[0] as usize) << 16 | (op.operands[1] as usize) << 8;
+      Some(&constants[index | op.operands[2] as usize])
+    }
+    _ => None,
+  }
+}
+
+// Loads a constant, reusing an equal one that's already in the pool. None when
+// the pool is full.
+fn load_constant(constant: Constant, constants: &mut Vec<Constant>) -> Option<Op> {
+  let existing = constants.iter().position(|other| match (other, &constant) {
+    // Bits, so 0 and -0 stay apart
+    (Constant::Number(other), Constant::Number(number)) => other.to_bits() == number.to_bits(),
+    (Constant::String(other), Constant::String(string)) => other == string,
+    _ => false,
+  });
+
+  let index = match existing {
+    Some(index) => index,
+    None if constants.len() < MAX_CONSTANTS => {
+      constants.push(constant);
+      constants.len() - 1
+    }
+    None => return None,
+  };
+
+  Some(if index <= u8::MAX as usize {
+    Op::new(OpCode::Constant, vec![index as u8], 0, 0)
+  } else {
+    let bytes = vec![(index >> 16) as u8, (index >> 8) as u8, index as u8];
+    Op::new(OpCode::ConstantLong, bytes, 0, 0)
+  })
+}
+
+fn load_bool(boolean: bool) -> Op {
+  let op = if boolean { OpCode::True } else { OpCode::False };
+  Op::new(op, Vec::new(), 0, 0)
+}
+
+enum Folded {
+  Constant(Constant),
+  Bool(bool),
+}
+
+// What the VM would compute, None when it would be a runtime error
+fn fold_binary(op: OpCode, left: &Constant, right: &Constant) -> Option<Folded> {
+  use Constant::{Number, String};
+
+  let folded = match (op, left, right) {
+    (OpCode::Add, Number(left), Number(right)) => Folded::Constant(Number(left + right)),
+    (OpCode::Add, String(left), String(right)) => {
+      Folded::Constant(String(Rc::from(format!("{}{}", left, right))))
+    }
+    (OpCode::Subtract, Number(left), Number(right)) => Folded::Constant(Number(left - right)),
+    (OpCode::Multiply, Number(left), Number(right)) => Folded::Constant(Number(left * right)),
+    (OpCode::Divide, Number(left), Number(right)) => Folded::Constant(Number(left / right)),
+    (OpCode::Greater, Number(left), Number(right)) => Folded::Bool(left > right),
+    (OpCode::Less, Number(left), Number(right)) => Folded::Bool(left < right),
+    (OpCode::Equal, Number(left), Number(right)) => Folded::Bool(left == right),
+    // Strings are interned so the VM compares them by content too
+    (OpCode::Equal, String(left), String(right)) => Folded::Bool(left == right),
+    (OpCode::Equal, _, _) => Folded::Bool(false),
+    _ => return None,
+  };
+
+  Some(folded)
+}
+
+fn fold_unary(op: &Op, operand: &Op, constants: &[Constant]) -> Option<Folded> {
+  match (op.op, operand.op, constant_of(operand, constants)) {
+    (OpCode::Negate, _, Some(Constant::Number(number))) => {
+      Some(Folded::Constant(Constant::Number(-number)))
+    }
+    (OpCode::Not, OpCode::Nil, _) | (OpCode::Not, OpCode::False, _) => Some(Folded::Bool(true)),
+    // Numbers and strings are all truthy
+    (OpCode::Not, OpCode::True, _) | (OpCode::Not, _, Some(_)) => Some(Folded::Bool(false)),
+    _ => None,
+  }
+}
+
+fn fold(ops: &mut Vec<Op>, constants: &mut Vec<Constant>) -> bool {
+  let mut changed = false;
+  let mut index = 0;
+  // Only merging moves ops around, so the targets are looked for again after
+  // that and not at every op
+  let mut targets = jump_targets(ops);
+
+  while index < ops.len() {
+    let mut folded = None;
+
+    if mergeable(ops, &targets, index, 3) {
+      if let (Some(left), Some(right)) = (
+        constant_of(&ops[index], constants),
+        constant_of(&ops[index + 1], constants),
+      ) {
+        folded = fold_binary(ops[index + 2].op, left, right).map(|folded| (folded, 3));
+      }
+    }
+
+    if folded.is_none() && mergeable(ops, &targets, index, 2) {
+      folded = fold_unary(&ops[index + 1], &ops[index], constants).map(|folded| (folded, 2));
+    }
+
+    let pool = constants.len();
+    let replacement = folded.and_then(|(folded, count)| {
+      let op = match folded {
+        Folded::Constant(constant) => load_constant(constant, constants)?,
+        Folded::Bool(boolean) => load_bool(boolean),
+      };
+
+      // Negating the constant in a short OP_CONSTANT can need an
+      // OP_CONSTANT_LONG, and jumps over it could then stop fitting
+      let replaced: usize = ops[index..index + count].iter().map(Op::size).sum();
+
+      if op.size() > replaced {
+        constants.truncate(pool);
+        return None;
+      }
+
+      Some((op, count))
+    });
+
+    match replacement {
+      Some((op, count)) => {
+        merge(ops, index, count, op);
+        targets = jump_targets(ops);
+        changed = true;
+        // The result may fold with what comes before it
+        index = index.saturating_sub(2);
+      }
+      None => index += 1,
+    }
+  }
+
+  changed
+}
+
+fn is_pure(op: &Op) -> bool {
+  matches!(
+    op.op,
+    OpCode::Constant
+      | OpCode::ConstantLong
+      | OpCode::Nil
+      | OpCode::True
+      | OpCode::False
+      | OpCode::GetLocal
+      | OpCode::GetUpvalue
+  )
+}
+
+// Pushing something without side effects just to pop it is a no-op, so jumps
+// to the push can go to what follows the pop
+fn remove_pops(ops: &mut Vec<Op>) -> bool {
+  let targets = jump_targets(ops);
+  let mut removed = vec![false; ops.len()];
+  let mut index = 0;
+
+  while index + 1 < ops.len() {
+    if is_pure(&ops[index]) && ops[index + 1].op == OpCode::Pop && !targets.contains(&(index + 1)) {
+      removed[index] = true;
+      removed[index + 1] = true;
+      index += 2;
+    } else {
+      index += 1;
+    }
+  }
+
+  let changed = removed.contains(&true);
+  compact(ops, &removed);
+
+  changed
+}
+
+fn fits_jump(ops: &[Op], from: usize, to: usize) -> bool {
+  let after = ops[from].offset + 3;
+  let distance = ops.get(to).map_or(after, |op| op.offset).abs_diff(after);
+
+  distance <= u16::MAX as usize
+}
+
+fn thread_jumps(ops: &mut Vec<Op>) -> bool {
+  let mut changed = false;
+
+  for index in 0..ops.len() {
+    let target = match ops[index].target {
+      Some(target) if target < ops.len() => target,
+      _ => continue,
+    };
+
+    // OP_JUMP_IF_FALSE leaves the condition on the stack, so one landing on
+    // another takes that one's jump too
+    let threaded = match (&ops[index], &ops[target]) {
+      (_, next) if next.is_unconditional_jump() => next.target,
+      (jump, next) if jump.op == OpCode::JumpIfFalse && next.op == OpCode::JumpIfFalse => {
+        next.target
+      }
+      _ => None,
+    };
+
+    if let Some(threaded) = threaded {
+      // Conditional jumps only go forward
+      let forward = ops[index].op != OpCode::JumpIfFalse || threaded > index;
+
+      if threaded != target && forward && fits_jump(ops, index, threaded) {
+        ops[index].target = Some(threaded);
+        changed = true;
+      }
+    }
+  }
+
+  // A jump to right after itself does nothing
+  let removed: Vec<bool> = ops
+    .iter()
+    .enumerate()
+    .map(|(index, op)| op.is_unconditional_jump() && op.target == Some(index + 1))
+    .collect();
+
+  if removed.contains(&true) {
+    compact(ops, &removed);
+    changed = true;
+  }
+
+  changed
+}
+
+// Runs last, the other passes don't know the superinstructions
+fn fuse(ops: &mut Vec<Op>) {
+  let mut index = 0;
+  let mut targets = jump_targets(ops);
+
+  while index < ops.len() {
+    let window: Vec<OpCode> = ops[index..ops.len().min(index + 3)]
+      .iter()
+      .map(|op| op.op)
+      .collect();
+
+    match window.as_slice() {
+      [OpCode::GetLocal, OpCode::GetLocal, OpCode::Add] if mergeable(ops, &targets, index, 3) => {
+        let slots = vec![ops[index].operands[0], ops[index + 1].operands[0]];
+        merge(ops, index, 3, Op::new(OpCode::AddLocals, slots, 0, 0));
+        targets = jump_targets(ops);
+      }
+      [OpCode::Constant, OpCode::Add, ..] if mergeable(ops, &targets, index, 2) => {
+        let constant = ops[index].operands.clone();
+        merge(ops, index, 2, Op::new(OpCode::AddConstant, constant, 0, 0));
+        targets = jump_targets(ops);
+      }
+      _ => (),
+    }
+
+    index += 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compiler;
+  use crate::debug::disassemble_function;
+  use crate::parser;
+  use crate::resolver;
+  use crate::vm::{Vm, VmError};
+
+  fn compile(source: &str) -> Rc<CompiledFunction> {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    compiler::compile(&statements, &resolution).unwrap()
+  }
+
+  fn optimized(source: &str) -> String {
+    disassemble_function(&optimize(&compile(source)))
+  }
+
+  fn outcome(function: Rc<CompiledFunction>) -> (String, Option<VmError>) {
+    let mut vm = Vm::new(Vec::new());
+    let error = vm.interpret(function).err();
+
+    (String::from_utf8(vm.into_output()).unwrap(), error)
+  }
+
+  fn size(function: &CompiledFunction) -> usize {
+    let inner: usize = function
+      .chunk
+      .constants
+      .iter()
+      .map(|constant| match constant {
+        Constant::Function(inner) => size(inner),
+        _ => 0,
+      })
+      .sum();
+
+    function.chunk.len() + inner
+  }
+
+  #[test]
+  fn test_fold_constants() {
+    assert_eq!(
+      optimized("print -(1 + 2) * 4 < 3;\nprint \"a\" + \"b\" + \"c\" == \"abc\";\nprint !nil;"),
+      "== <script> ==\n\
+       0000    1 OP_TRUE\n\
+       0001    | OP_PRINT\n\
+       0002    2 OP_TRUE\n\
+       0003    | OP_PRINT\n\
+       0004    3 OP_TRUE\n\
+       0005    | OP_PRINT\n\
+       0006    | OP_NIL\n\
+       0007    | OP_RETURN\n"
+    );
+
+    assert_eq!(
+      optimized("print 1 / 2 + \"a\";"),
+      "== <script> ==\n\
+       0000    1 OP_CONSTANT         3 '0.5'\n\
+       0002    | OP_ADD_CONSTANT     2 'a'\n\
+       0004    | OP_PRINT\n\
+       0005    | OP_NIL\n\
+       0006    | OP_RETURN\n"
+    );
+  }
+
+  #[test]
+  fn test_remove_pops() {
+    assert_eq!(
+      optimized("1 + 2;\n\"a\";\n{ var a = 1; a; }"),
+      "== <script> ==\n\
+       0000    3 OP_NIL\n\
+       0001    | OP_RETURN\n"
+    );
+  }
+
+  // Whether some jump lands right on another jump
+  fn jumps_to_jump(function: &CompiledFunction) -> bool {
+    let instructions = debug::disassemble(&function.chunk);
+
+    instructions.iter().any(|instruction| {
+      instructions
+        .iter()
+        .any(|other| instruction.target == Some(other.offset) && other.target.is_some())
+    })
+  }
+
+  #[test]
+  fn test_thread_jumps() {
+    let source = "var a = true;\nvar b = false;\nif (a and b) print 1; else print 2;";
+
+    assert!(disassemble_function(&compile(source)).contains("OP_JUMP_IF_FALSE    8 -> 14"));
+    assert_eq!(
+      optimized(source),
+      "== <script> ==\n\
+       0000    1 OP_TRUE\n\
+       0001    | OP_DEFINE_GLOBAL    0 'a'\n\
+       0003    2 OP_FALSE\n\
+       0004    | OP_DEFINE_GLOBAL    1 'b'\n\
+       0006    3 OP_GET_GLOBAL       0 'a'\n\
+       0008    | OP_JUMP_IF_FALSE    8 -> 24\n\
+       0011    | OP_POP\n\
+       0012    | OP_GET_GLOBAL       1 'b'\n\
+       0014    | OP_JUMP_IF_FALSE   14 -> 24\n\
+       0017    | OP_POP\n\
+       0018    | OP_CONSTANT         2 '1.0'\n\
+       0020    | OP_PRINT\n\
+       0021    | OP_JUMP            21 -> 28\n\
+       0024    | OP_POP\n\
+       0025    | OP_CONSTANT         3 '2.0'\n\
+       0027    | OP_PRINT\n\
+       0028    | OP_NIL\n\
+       0029    | OP_RETURN\n"
+    );
+
+    let nested = compile(
+      "var a = true;\nvar b = false;\n\
+       while (a) { if (b) print 1; else { a = false; print 2; } }",
+    );
+
+    assert!(jumps_to_jump(&nested));
+    assert!(!jumps_to_jump(&optimize(&nested)));
+    assert_eq!(outcome(Rc::new(optimize(&nested))), outcome(nested));
+  }
+
+  #[test]
+  fn test_superinstructions() {
+    assert_eq!(
+      optimized("fun add(a, b) {\n  return a + b + 1;\n}"),
+      "== <script> ==\n\
+       0000    1 OP_CLOSURE          1 '<fn add>'\n\
+       0002    | OP_DEFINE_GLOBAL    0 'add'\n\
+       0004    | OP_NIL\n\
+       0005    | OP_RETURN\n\
+       == <fn add> ==\n\
+       0000    2 OP_ADD_LOCALS       1    2\n\
+       0003    | OP_ADD_CONSTANT     0 '1.0'\n\
+       0005    | OP_RETURN\n\
+       0006    | OP_NIL\n\
+       0007    | OP_RETURN\n"
+    );
+
+    assert_eq!(
+      outcome(Rc::new(optimize(&compile(
+        "fun add(a, b) { return a + b + 1; }\nprint add(1, 2);\nprint add(\"a\", \"b\");\nadd(1, nil);"
+      )))),
+      outcome(compile(
+        "fun add(a, b) { return a + b + 1; }\nprint add(1, 2);\nprint add(\"a\", \"b\");\nadd(1, nil);"
+      ))
+    );
+  }
+
+  #[test]
+  fn test_unchanged_behavior() {
+    let root = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+    let mut paths = Vec::new();
+
+    for directory in &[
+      "s1-web/test_suite",
+      "26-gc/test",
+      "13-inheritance/test_sources",
+    ] {
+      for entry in std::fs::read_dir(format!("{}/{}", root, directory)).unwrap() {
+        let path = entry.unwrap().path();
+
+        // Loops forever, and fibonacci prints how long it took
+        if path.extension().is_some_and(|extension| extension == "lox")
+          && !path.ends_with("leak.lox")
+          && !path.ends_with("fibonacci.lox")
+        {
+          paths.push(path);
+        }
+      }
+    }
+
+    for path in paths {
+      let source = std::fs::read_to_string(&path).unwrap();
+      let statements = match parser::parse(&source) {
+        Ok(statements) => statements,
+        Err(_) => continue,
+      };
+      let function = match resolver::resolve(&statements)
+        .ok()
+        .and_then(|resolution| compiler::compile(&statements, &resolution).ok())
+      {
+        Some(function) => function,
+        None => continue,
+      };
+      let optimized = optimize(&function);
+
+      assert!(size(&optimized) <= size(&function), "{:?}", path);
+      assert_eq!(outcome(Rc::new(optimized)), outcome(function), "{:?}", path);
+    }
+  }
+
+  #[test]
+  fn test_folds_never_grow_the_code() {
+    // Negating constants 0 to 255 needs 256 new ones, past what OP_CONSTANT
+    // can load, with a jump over all of it that only just fits
+    let negated: String = (0..256).map(|n| format!("print -{};", n)).collect();
+    let source = format!(
+      "fun f(c) {{ if (c) {{ {}{} }} print 1 + 1; }}\nf(false);",
+      negated,
+      "print c;".repeat(21440)
+    );
+    let function = compile(&source);
+    let optimized = optimize(&function);
+
+    // Still optimized, not left as it was for a jump that doesn't fit
+    assert!(size(&optimized) < size(&function));
+    assert!(crate::verifier::verify(&optimized).is_ok());
+    assert_eq!(outcome(Rc::new(optimized)), outcome(function));
+  }
+
+  #[test]
+  fn test_encode_jump_out_of_range() {
+    let mut jump = Op::new(OpCode::Jump, Vec::new(), 1, 0);
+    jump.target = Some(2);
+    let far = Op::new(OpCode::Nil, vec![0; u16::MAX as usize], 1, 3);
+
+    assert!(encode(&[jump.clone(), far.clone(), far], Vec::new()).is_none());
+
+    let near = Op::new(OpCode::Nil, vec![0; u16::MAX as usize - 1], 1, 3);
+    assert!(encode(&[jump, near.clone(), near.clone()], Vec::new()).is_some());
+  }
+}
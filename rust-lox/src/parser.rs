@@ -1 +1,908 @@
+use std::fmt;
+use std::rc::Rc;
 
+use super::expression::{Expression, Literal, NodeId};
+use super::scanner::{Scan, Token, TokenType};
+use super::statement::{Function, Statement};
+use super::util::DoublePeeker;
+
+const MAX_ARGUMENTS: usize = 255;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseError {
+  Scanner {
+    line: u32,
+    message: String,
+  },
+  Parser {
+    line: u32,
+    // None when the error is at the end of the source
+    lexeme: Option<String>,
+    message: String,
+  },
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ParseError::Scanner { line, message } => {
+        write!(f, "scanner error. line: {} - error: {}", line, message)
+      }
+      ParseError::Parser {
+        line,
+        lexeme: None,
+        message,
+      } => write!(
+        f,
+        "parser error. line: {} at end - error: {}",
+        line, message
+      ),
+      ParseError::Parser {
+        line,
+        lexeme: Some(lexeme),
+        message,
+      } => write!(
+        f,
+        "parser error. line: {}, token: {} - error: {}",
+        line, lexeme, message
+      ),
+    }
+  }
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+pub fn parse(source: &str) -> Result<Vec<Statement<'_>>, Vec<ParseError>> {
+  Parser::new(Scan::new(source)).parse()
+}
+
+pub struct Parser<'a> {
+  tokens: DoublePeeker<Scan<'a>>,
+  previous: Option<Token<'a>>,
+  errors: Vec<ParseError>,
+  next_id: NodeId,
+}
+
+impl<'a> Parser<'a> {
+  pub fn new(scan: Scan<'a>) -> Parser<'a> {
+    Parser {
+      tokens: DoublePeeker::new(scan),
+      previous: None,
+      errors: Vec::new(),
+      next_id: 0,
+    }
+  }
+
+  pub fn parse(mut self) -> Result<Vec<Statement<'a>>, Vec<ParseError>> {
+    let mut statements = Vec::new();
+
+    while self.has_more() {
+      if let Some(statement) = self.parse_declaration() {
+        statements.push(statement);
+      }
+    }
+
+    if self.errors.is_empty() {
+      Ok(statements)
+    } else {
+      Err(self.errors)
+    }
+  }
+
+  fn parse_declaration(&mut self) -> Option<Statement<'a>> {
+    let result = if self.match_any(&[TokenType::Var]) {
+      self.parse_variable_declaration()
+    } else if self.match_any(&[TokenType::Fun]) {
+      self
+        .parse_function("function")
+        .map(|function| Statement::Function { function })
+    } else if self.match_any(&[TokenType::Class]) {
+      self.parse_class_declaration()
+    } else {
+      self.parse_statement()
+    };
+
+    match result {
+      Ok(statement) => Some(statement),
+      Err(error) => {
+        self.errors.push(error);
+        self.synchronize();
+        None
+      }
+    }
+  }
+
+  fn parse_variable_declaration(&mut self) -> ParseResult<Statement<'a>> {
+    let name = self.consume(TokenType::Identifier, "Expected variable name")?;
+
+    let initializer = if self.match_any(&[TokenType::Equal]) {
+      Some(self.parse_expression()?)
+    } else {
+      None
+    };
+
+    self.consume(
+      TokenType::Semicolon,
+      "Expected ; after variable declaration",
+    )?;
+
+    Ok(Statement::Var {
+      id: self.generate_id(),
+      name,
+      initializer,
+    })
+  }
+
+  fn parse_class_declaration(&mut self) -> ParseResult<Statement<'a>> {
+    let name = self.consume(TokenType::Identifier, "Expected class name")?;
+
+    let superclass = if self.match_any(&[TokenType::Less]) {
+      let superclass_name = self.consume(TokenType::Identifier, "Expected superclass name")?;
+
+      Some(Expression::Variable {
+        id: self.generate_id(),
+        name: superclass_name,
+      })
+    } else {
+      None
+    };
+
+    self.consume(TokenType::LeftBrace, "Expected { before class body")?;
+
+    let mut methods = Vec::new();
+
+    while !self.check(TokenType::RightBrace) && self.has_more() {
+      methods.push(self.parse_function("method")?);
+    }
+
+    self.consume(TokenType::RightBrace, "Expected } after class body")?;
+
+    Ok(Statement::Class {
+      id: self.generate_id(),
+      name,
+      superclass,
+      methods,
+    })
+  }
+
+  fn parse_function(&mut self, kind: &str) -> ParseResult<Rc<Function<'a>>> {
+    let name = self.consume(TokenType::Identifier, &format!("Expected {} name", kind))?;
+    self.consume(
+      TokenType::LeftParen,
+      &format!("Expected ( after {} name", kind),
+    )?;
+
+    let mut parameters = Vec::new();
+
+    if !self.check(TokenType::RightParen) {
+      loop {
+        if parameters.len() >= MAX_ARGUMENTS {
+          let error = self.error_at_peek("Expected function to have fewer than 255 parameters");
+          self.errors.push(error);
+        }
+
+        parameters.push(self.consume(TokenType::Identifier, "Expected parameter name")?);
+
+        if !self.match_any(&[TokenType::Comma]) {
+          break;
+        }
+      }
+    }
+
+    self.consume(
+      TokenType::RightParen,
+      &format!("Expected ) after {} parameter list", kind),
+    )?;
+    self.consume(
+      TokenType::LeftBrace,
+      &format!("Expected {{ before {} body", kind),
+    )?;
+
+    let body = self.parse_block()?;
+
+    Ok(Rc::new(Function {
+      id: self.generate_id(),
+      name,
+      parameters,
+      body,
+    }))
+  }
+
+  fn parse_statement(&mut self) -> ParseResult<Statement<'a>> {
+    if self.match_any(&[TokenType::Print]) {
+      self.parse_print_statement()
+    } else if self.match_any(&[TokenType::LeftBrace]) {
+      Ok(Statement::Block {
+        statements: self.parse_block()?,
+      })
+    } else if self.match_any(&[TokenType::If]) {
+      self.parse_if()
+    } else if self.match_any(&[TokenType::While]) {
+      self.parse_while()
+    } else if self.match_any(&[TokenType::For]) {
+      self.parse_for()
+    } else if self.match_any(&[TokenType::Return]) {
+      self.parse_return()
+    } else {
+      self.parse_expression_statement()
+    }
+  }
+
+  fn parse_if(&mut self) -> ParseResult<Statement<'a>> {
+    self.consume(TokenType::LeftParen, "Expected ( before if condition")?;
+    let condition = self.parse_expression()?;
+    self.consume(TokenType::RightParen, "Expected ) after if condition")?;
+
+    let then_branch = Box::new(self.parse_statement()?);
+
+    let else_branch = if self.match_any(&[TokenType::Else]) {
+      Some(Box::new(self.parse_statement()?))
+    } else {
+      None
+    };
+
+    Ok(Statement::If {
+      condition,
+      then_branch,
+      else_branch,
+    })
+  }
+
+  fn parse_while(&mut self) -> ParseResult<Statement<'a>> {
+    self.consume(TokenType::LeftParen, "Expected ( before while condition")?;
+    let condition = self.parse_expression()?;
+    self.consume(TokenType::RightParen, "Expected ) after while condition")?;
+
+    let body = Box::new(self.parse_statement()?);
+
+    Ok(Statement::While { condition, body })
+  }
+
+  fn parse_for(&mut self) -> ParseResult<Statement<'a>> {
+    let line = self.previous().line;
+    self.consume(TokenType::LeftParen, "Expected ( after for")?;
+
+    let initializer = if self.match_any(&[TokenType::Semicolon]) {
+      None
+    } else if self.match_any(&[TokenType::Var]) {
+      Some(self.parse_variable_declaration()?)
+    } else {
+      Some(self.parse_expression_statement()?)
+    };
+
+    let condition = if !self.check(TokenType::Semicolon) {
+      Some(self.parse_expression()?)
+    } else {
+      None
+    };
+
+    self.consume(TokenType::Semicolon, "Expected ; after for condition")?;
+
+    let increment = if !self.check(TokenType::RightParen) {
+      Some(self.parse_expression()?)
+    } else {
+      None
+    };
+
+    self.consume(TokenType::RightParen, "Expected ) after for clauses")?;
+
+    let mut body = self.parse_statement()?;
+
+    if let Some(increment) = increment {
+      body = Statement::Block {
+        statements: vec![
+          body,
+          Statement::Expression {
+            expression: increment,
+          },
+        ],
+      };
+    }
+
+    body = Statement::While {
+      condition: condition.unwrap_or(Expression::Literal {
+        value: Literal::Bool(true),
+        line,
+      }),
+      body: Box::new(body),
+    };
+
+    if let Some(initializer) = initializer {
+      body = Statement::Block {
+        statements: vec![initializer, body],
+      };
+    }
+
+    Ok(body)
+  }
+
+  fn parse_return(&mut self) -> ParseResult<Statement<'a>> {
+    let keyword = self.previous();
+
+    let value = if !self.check(TokenType::Semicolon) {
+      Some(self.parse_expression()?)
+    } else {
+      None
+    };
+
+    self.consume(TokenType::Semicolon, "Expected semicolon after return")?;
+
+    Ok(Statement::Return { keyword, value })
+  }
+
+  fn parse_print_statement(&mut self) -> ParseResult<Statement<'a>> {
+    let expression = self.parse_expression()?;
+    self.consume(TokenType::Semicolon, "Expected ; after expression")?;
+
+    Ok(Statement::Print { expression })
+  }
+
+  fn parse_block(&mut self) -> ParseResult<Vec<Statement<'a>>> {
+    let mut statements = Vec::new();
+
+    while !self.check(TokenType::RightBrace) && self.has_more() {
+      if let Some(statement) = self.parse_declaration() {
+        statements.push(statement);
+      }
+    }
+
+    self.consume(TokenType::RightBrace, "Expected } at end of block")?;
+
+    Ok(statements)
+  }
+
+  fn parse_expression_statement(&mut self) -> ParseResult<Statement<'a>> {
+    let expression = self.parse_expression()?;
+    self.consume(TokenType::Semicolon, "Expected ; after expression")?;
+
+    Ok(Statement::Expression { expression })
+  }
+
+  fn parse_expression(&mut self) -> ParseResult<Expression<'a>> {
+    self.parse_assignment()
+  }
+
+  fn parse_assignment(&mut self) -> ParseResult<Expression<'a>> {
+    let expression = self.parse_or()?;
+
+    if self.match_any(&[TokenType::Equal]) {
+      let equal = self.previous();
+      let value = Box::new(self.parse_assignment()?);
+
+      return match expression {
+        Expression::Variable { name, .. } => Ok(Expression::Assign {
+          id: self.generate_id(),
+          name,
+          value,
+        }),
+        Expression::Get { object, name } => Ok(Expression::Set {
+          object,
+          name,
+          value,
+        }),
+        _ => {
+          // Reported but not thrown, the parser isn't confused
+          let error = self.error_at(equal, "Expected variable name on left side of assignment");
+          self.errors.push(error);
+
+          Ok(expression)
+        }
+      };
+    }
+
+    Ok(expression)
+  }
+
+  fn parse_or(&mut self) -> ParseResult<Expression<'a>> {
+    let mut expression = self.parse_and()?;
+
+    while self.match_any(&[TokenType::Or]) {
+      let operator = self.previous();
+      let right = self.parse_and()?;
+
+      expression = Expression::Logical {
+        left: Box::new(expression),
+        operator,
+        right: Box::new(right),
+      };
+    }
+
+    Ok(expression)
+  }
+
+  fn parse_and(&mut self) -> ParseResult<Expression<'a>> {
+    let mut expression = self.parse_equality()?;
+
+    while self.match_any(&[TokenType::And]) {
+      let operator = self.previous();
+      let right = self.parse_equality()?;
+
+      expression = Expression::Logical {
+        left: Box::new(expression),
+        operator,
+        right: Box::new(right),
+      };
+    }
+
+    Ok(expression)
+  }
+
+  fn parse_equality(&mut self) -> ParseResult<Expression<'a>> {
+    self.parse_binary(
+      &[TokenType::BangEqual, TokenType::EqualEqual],
+      Parser::parse_comparison,
+    )
+  }
+
+  fn parse_comparison(&mut self) -> ParseResult<Expression<'a>> {
+    self.parse_binary(
+      &[
+        TokenType::Greater,
+        TokenType::GreaterEqual,
+        TokenType::Less,
+        TokenType::LessEqual,
+      ],
+      Parser::parse_term,
+    )
+  }
+
+  fn parse_term(&mut self) -> ParseResult<Expression<'a>> {
+    self.parse_binary(&[TokenType::Plus, TokenType::Minus], Parser::parse_factor)
+  }
+
+  fn parse_factor(&mut self) -> ParseResult<Expression<'a>> {
+    self.parse_binary(&[TokenType::Star, TokenType::Slash], Parser::parse_unary)
+  }
+
+  fn parse_binary(
+    &mut self,
+    operators: &[TokenType],
+    parse_operand: fn(&mut Parser<'a>) -> ParseResult<Expression<'a>>,
+  ) -> ParseResult<Expression<'a>> {
+    let mut expression = parse_operand(self)?;
+
+    while self.match_any(operators) {
+      let operator = self.previous();
+      let right = parse_operand(self)?;
+
+      expression = Expression::Binary {
+        left: Box::new(expression),
+        operator,
+        right: Box::new(right),
+      };
+    }
+
+    Ok(expression)
+  }
+
+  fn parse_unary(&mut self) -> ParseResult<Expression<'a>> {
+    if self.match_any(&[TokenType::Bang, TokenType::Minus]) {
+      let operator = self.previous();
+      let right = self.parse_unary()?;
+
+      return Ok(Expression::Unary {
+        operator,
+        right: Box::new(right),
+      });
+    }
+
+    self.parse_call()
+  }
+
+  fn parse_call(&mut self) -> ParseResult<Expression<'a>> {
+    let mut expression = self.parse_primary()?;
+
+    loop {
+      if self.match_any(&[TokenType::LeftParen]) {
+        expression = self.parse_finish_call(expression)?;
+      } else if self.match_any(&[TokenType::Dot]) {
+        let name = self.consume(TokenType::Identifier, "Expected property name after .")?;
+
+        expression = Expression::Get {
+          object: Box::new(expression),
+          name,
+        };
+      } else {
+        break;
+      }
+    }
+
+    Ok(expression)
+  }
+
+  fn parse_finish_call(&mut self, callee: Expression<'a>) -> ParseResult<Expression<'a>> {
+    let mut arguments = Vec::new();
+
+    if !self.check(TokenType::RightParen) {
+      loop {
+        if arguments.len() >= MAX_ARGUMENTS {
+          let error = self.error_at_peek("Can't have more than 255 arguments");
+          self.errors.push(error);
+        }
+
+        arguments.push(self.parse_expression()?);
+
+        if !self.match_any(&[TokenType::Comma]) {
+          break;
+        }
+      }
+    }
+
+    let paren = self.consume(TokenType::RightParen, "Expected ) after argument list")?;
+
+    Ok(Expression::Call {
+      callee: Box::new(callee),
+      paren,
+      arguments,
+    })
+  }
+
+  fn parse_primary(&mut self) -> ParseResult<Expression<'a>> {
+    if self.match_any(&[TokenType::Number]) {
+      let text = self.previous().text;
+
+      return Ok(Expression::Literal {
+        value: Literal::Number(text.parse().unwrap()),
+        line: self.previous().line,
+      });
+    }
+
+    if self.match_any(&[TokenType::String]) {
+      let text = self.previous().text;
+
+      return Ok(Expression::Literal {
+        value: Literal::String(&text[1..text.len() - 1]),
+        line: self.previous().line,
+      });
+    }
+
+    if self.match_any(&[TokenType::False]) {
+      return Ok(Expression::Literal {
+        value: Literal::Bool(false),
+        line: self.previous().line,
+      });
+    }
+
+    if self.match_any(&[TokenType::True]) {
+      return Ok(Expression::Literal {
+        value: Literal::Bool(true),
+        line: self.previous().line,
+      });
+    }
+
+    if self.match_any(&[TokenType::Nil]) {
+      return Ok(Expression::Literal {
+        value: Literal::Nil,
+        line: self.previous().line,
+      });
+    }
+
+    if self.match_any(&[TokenType::This]) {
+      return Ok(Expression::This {
+        id: self.generate_id(),
+        keyword: self.previous(),
+      });
+    }
+
+    if self.match_any(&[TokenType::Super]) {
+      let keyword = self.previous();
+      self.consume(TokenType::Dot, "Expected . after super")?;
+      let method = self.consume(TokenType::Identifier, "Expected superclass method name")?;
+
+      return Ok(Expression::Super {
+        id: self.generate_id(),
+        keyword,
+        method,
+      });
+    }
+
+    if self.match_any(&[TokenType::Identifier]) {
+      return Ok(Expression::Variable {
+        id: self.generate_id(),
+        name: self.previous(),
+      });
+    }
+
+    if self.match_any(&[TokenType::LeftParen]) {
+      let expression = self.parse_expression()?;
+      self.consume(TokenType::RightParen, "Expected ')' after expression")?;
+
+      return Ok(Expression::Grouping {
+        expression: Box::new(expression),
+      });
+    }
+
+    Err(self.error_at_peek("Expected expression"))
+  }
+
+  fn generate_id(&mut self) -> NodeId {
+    self.next_id += 1;
+    self.next_id
+  }
+
+  fn match_any(&mut self, token_types: &[TokenType]) -> bool {
+    for token_type in token_types {
+      if self.check(*token_type) {
+        self.advance();
+        return true;
+      }
+    }
+
+    false
+  }
+
+  fn check(&mut self, token_type: TokenType) -> bool {
+    self.peek().map(|token| token.token_type) == Some(token_type)
+  }
+
+  // Scanner errors come through as tokens, they're reported and skipped here
+  // so the rest of the parser never sees them
+  fn peek(&mut self) -> Option<Token<'a>> {
+    while let Some(token) = self.tokens.peek() {
+      if token.token_type != TokenType::Error {
+        return Some(token);
+      }
+
+      self.errors.push(ParseError::Scanner {
+        line: token.line,
+        message: token.text.to_string(),
+      });
+      self.tokens.next();
+    }
+
+    None
+  }
+
+  fn previous(&self) -> Token<'a> {
+    self.previous.expect("[bug] previous used before advancing")
+  }
+
+  fn advance(&mut self) -> Option<Token<'a>> {
+    self.peek()?;
+    self.previous = self.tokens.next();
+    self.previous
+  }
+
+  fn consume(&mut self, token_type: TokenType, message: &str) -> ParseResult<Token<'a>> {
+    if self.check(token_type) {
+      Ok(self.advance().unwrap())
+    } else {
+      Err(self.error_at_peek(message))
+    }
+  }
+
+  fn error_at_peek(&mut self, message: &str) -> ParseError {
+    match self.peek() {
+      Some(token) => self.error_at(token, message),
+      None => ParseError::Parser {
+        line: self.previous.map_or(1, |token| token.line),
+        lexeme: None,
+        message: message.to_string(),
+      },
+    }
+  }
+
+  fn error_at(&self, token: Token<'a>, message: &str) -> ParseError {
+    ParseError::Parser {
+      line: token.line,
+      lexeme: Some(token.text.to_string()),
+      message: message.to_string(),
+    }
+  }
+
+  fn synchronize(&mut self) {
+    self.advance();
+
+    while let Some(token) = self.peek() {
+      if self.previous.map(|previous| previous.token_type) == Some(TokenType::Semicolon) {
+        return;
+      }
+
+      match token.token_type {
+        TokenType::Class
+        | TokenType::For
+        | TokenType::Fun
+        | TokenType::If
+        | TokenType::Print
+        | TokenType::Return
+        | TokenType::Var
+        | TokenType::While => return,
+        _ => {
+          self.advance();
+        }
+      }
+    }
+  }
+
+  fn has_more(&mut self) -> bool {
+    self.peek().is_some()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn token(token_type: TokenType, text: &str, line: u32) -> Token<'_> {
+    Token {
+      token_type,
+      text,
+      line,
+    }
+  }
+
+  #[test]
+  fn test_precedence() {
+    let statements = parse("print 1 + 2 * 3;").unwrap();
+
+    assert_eq!(
+      statements,
+      vec![Statement::Print {
+        expression: Expression::Binary {
+          left: Box::new(Expression::Literal {
+            value: Literal::Number(1.0),
+            line: 1,
+          }),
+          operator: token(TokenType::Plus, "+", 1),
+          right: Box::new(Expression::Binary {
+            left: Box::new(Expression::Literal {
+              value: Literal::Number(2.0),
+              line: 1,
+            }),
+            operator: token(TokenType::Star, "*", 1),
+            right: Box::new(Expression::Literal {
+              value: Literal::Number(3.0),
+              line: 1,
+            }),
+          }),
+        }
+      }]
+    );
+  }
+
+  #[test]
+  fn test_string_literal() {
+    let statements = parse("\"a string\";").unwrap();
+
+    assert_eq!(
+      statements,
+      vec![Statement::Expression {
+        expression: Expression::Literal {
+          value: Literal::String("a string"),
+          line: 1,
+        }
+      }]
+    );
+  }
+
+  #[test]
+  fn test_assignment() {
+    let statements = parse("a = b;").unwrap();
+
+    match &statements[..] {
+      [Statement::Expression {
+        expression: Expression::Assign { name, value, .. },
+      }] => {
+        assert_eq!(name.text, "a");
+        assert!(matches!(**value, Expression::Variable { name, .. } if name.text == "b"));
+      }
+      _ => panic!("unexpected tree {:?}", statements),
+    }
+  }
+
+  #[test]
+  fn test_property_assignment() {
+    let statements = parse("a.b.c = 1;").unwrap();
+
+    assert!(matches!(
+      &statements[..],
+      [Statement::Expression {
+        expression: Expression::Set { name, .. },
+      }] if name.text == "c"
+    ));
+  }
+
+  #[test]
+  fn test_for_desugaring() {
+    let statements = parse("for (var i = 0; i < 3; i = i + 1) print i;").unwrap();
+
+    match &statements[..] {
+      [Statement::Block { statements }] => {
+        assert!(matches!(statements[0], Statement::Var { .. }));
+        assert!(matches!(statements[1], Statement::While { .. }));
+      }
+      _ => panic!("unexpected tree {:?}", statements),
+    }
+  }
+
+  #[test]
+  fn test_function() {
+    let statements = parse("fun add(a, b) { return a + b; }").unwrap();
+
+    match &statements[..] {
+      [Statement::Function { function }] => {
+        assert_eq!(function.name.text, "add");
+        assert_eq!(
+          function
+            .parameters
+            .iter()
+            .map(|parameter| parameter.text)
+            .collect::<Vec<_>>(),
+          vec!["a", "b"]
+        );
+        assert!(matches!(function.body[..], [Statement::Return { .. }]));
+      }
+      _ => panic!("unexpected tree {:?}", statements),
+    }
+  }
+
+  #[test]
+  fn test_class() {
+    let statements = parse("class B < A { method() { super.method(); } }").unwrap();
+
+    match &statements[..] {
+      [Statement::Class {
+        name,
+        superclass: Some(Expression::Variable {
+          name: superclass, ..
+        }),
+        methods,
+        ..
+      }] => {
+        assert_eq!(name.text, "B");
+        assert_eq!(superclass.text, "A");
+        assert_eq!(methods.len(), 1);
+      }
+      _ => panic!("unexpected tree {:?}", statements),
+    }
+  }
+
+  #[test]
+  fn test_error() {
+    let errors = parse("print 1").unwrap_err();
+
+    assert_eq!(
+      errors
+        .iter()
+        .map(|error| error.to_string())
+        .collect::<Vec<_>>(),
+      vec!["parser error. line: 1 at end - error: Expected ; after expression"]
+    );
+  }
+
+  #[test]
+  fn test_error_recovery() {
+    let errors = parse("var = 1; print 2 +; print 3;").unwrap_err();
+
+    assert_eq!(
+      errors
+        .iter()
+        .map(|error| error.to_string())
+        .collect::<Vec<_>>(),
+      vec![
+        "parser error. line: 1, token: = - error: Expected variable name",
+        "parser error. line: 1, token: ; - error: Expected expression"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_invalid_assignment_target() {
+    let errors = parse("1 = 2;").unwrap_err();
+
+    assert_eq!(
+      errors[0].to_string(),
+      "parser error. line: 1, token: = - error: Expected variable name on left side of assignment"
+    );
+  }
+
+  #[test]
+  fn test_scanner_error() {
+    let errors = parse("print \"unterminated;").unwrap_err();
+
+    assert_eq!(
+      errors[0].to_string(),
+      "scanner error. line: 1 - error: Unterminated string."
+    );
+  }
+}
@@ -0,0 +1,1131 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::expression::{Expression, NodeId};
+use super::scanner::Token;
+use super::statement::{Function, Statement};
+
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+
+pub type VariableId = usize;
+
+// Where a variable lives. `depth` is how many environments up the tree-walker
+// has to go to find it, `slot` and `index` are what the bytecode backend uses.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Binding {
+  Global,
+  Local {
+    slot: usize,
+    depth: usize,
+    variable: VariableId,
+  },
+  // Captured from an enclosing function, `index` is into the closure's upvalues
+  Upvalue {
+    index: usize,
+    depth: usize,
+    variable: VariableId,
+  },
+}
+
+impl Binding {
+  pub fn depth(&self) -> Option<usize> {
+    match self {
+      Binding::Global => None,
+      Binding::Local { depth, .. } | Binding::Upvalue { depth, .. } => Some(*depth),
+    }
+  }
+
+  pub fn variable(&self) -> Option<VariableId> {
+    match self {
+      Binding::Global => None,
+      Binding::Local { variable, .. } | Binding::Upvalue { variable, .. } => Some(*variable),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VariableKind {
+  Variable,
+  Parameter,
+  Function,
+  Class,
+  This,
+  Super,
+}
+
+// Every local (non-global) variable declared in the program
+#[derive(Debug, PartialEq, Clone)]
+pub struct Variable {
+  pub name: String,
+  pub line: u32,
+  pub kind: VariableKind,
+  // Referenced by an inner function so it has to outlive its stack slot. The
+  // rest can stay on the stack and never get boxed.
+  pub captured: bool,
+  pub reads: usize,
+  // Has the same name as a local of an enclosing scope
+  pub shadows: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Upvalue {
+  // Whether it captures a local of the immediately enclosing function or one of
+  // its upvalues
+  pub is_local: bool,
+  pub index: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResolveError {
+  pub line: u32,
+  pub message: String,
+}
+
+impl fmt::Display for ResolveError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "static analysis error. line: {} - error: {}",
+      self.line, self.message
+    )
+  }
+}
+
+// The side table produced by the resolver, keyed by the ids the parser gives
+// variable references and declarations
+#[derive(Debug, Default)]
+pub struct Resolution {
+  bindings: HashMap<NodeId, Binding>,
+  supers: HashMap<NodeId, Binding>,
+  receivers: HashMap<NodeId, Binding>,
+  upvalues: HashMap<NodeId, Vec<Upvalue>>,
+  variables: Vec<Variable>,
+}
+
+impl Resolution {
+  pub fn binding(&self, id: NodeId) -> Binding {
+    *self
+      .bindings
+      .get(&id)
+      .expect("[bug] binding used before being resolved")
+  }
+
+  // The local holding the superclass for the methods of a subclass
+  pub fn super_binding(&self, class: NodeId) -> Option<Binding> {
+    self.supers.get(&class).copied()
+  }
+
+  // Where `this` is for a super expression, which needs both
+  pub fn receiver(&self, super_expression: NodeId) -> Binding {
+    *self
+      .receivers
+      .get(&super_expression)
+      .expect("[bug] receiver used before being resolved")
+  }
+
+  pub fn upvalues(&self, function: NodeId) -> &[Upvalue] {
+    self
+      .upvalues
+      .get(&function)
+      .map_or(&[], |upvalues| &upvalues[..])
+  }
+
+  pub fn variable(&self, id: VariableId) -> &Variable {
+    &self.variables[id]
+  }
+
+  pub fn variables(&self) -> &[Variable] {
+    &self.variables
+  }
+
+  pub fn is_captured(&self, binding: Binding) -> bool {
+    match binding {
+      Binding::Global => false,
+      Binding::Local { variable, .. } => self.variables[variable].captured,
+      Binding::Upvalue { .. } => true,
+    }
+  }
+}
+
+pub fn resolve(statements: &[Statement]) -> Result<Resolution, Vec<ResolveError>> {
+  Resolver::new().resolve(statements)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FunctionKind {
+  Script,
+  Function,
+  Method,
+  Initializer,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ClassKind {
+  None,
+  Class,
+  Subclass,
+}
+
+struct Local {
+  variable: VariableId,
+  initialized: bool,
+}
+
+struct FunctionState {
+  kind: FunctionKind,
+  // Indexed by stack slot
+  locals: Vec<Option<Local>>,
+  upvalues: Vec<Upvalue>,
+}
+
+// Mirrors one environment of the tree-walker
+struct Scope<'a> {
+  function: usize,
+  names: Vec<(&'a str, usize)>,
+}
+
+pub struct Resolver<'a> {
+  functions: Vec<FunctionState>,
+  scopes: Vec<Scope<'a>>,
+  class_kind: ClassKind,
+  resolution: Resolution,
+  errors: Vec<ResolveError>,
+}
+
+impl<'a> Resolver<'a> {
+  pub fn new() -> Resolver<'a> {
+    Resolver {
+      functions: vec![FunctionState {
+        kind: FunctionKind::Script,
+        // Slot zero holds the function being called
+        locals: vec![None],
+        upvalues: Vec::new(),
+      }],
+      scopes: Vec::new(),
+      class_kind: ClassKind::None,
+      resolution: Resolution::default(),
+      errors: Vec::new(),
+    }
+  }
+
+  pub fn resolve(self, statements: &[Statement<'a>]) -> Result<Resolution, Vec<ResolveError>> {
+    let (resolution, errors) = self.resolve_lenient(statements);
+
+    if errors.is_empty() {
+      Ok(resolution)
+    } else {
+      Err(errors)
+    }
+  }
+
+  // Returns whatever could be resolved along with the errors, for tools like
+  // the linter that still want to look at broken programs. Nodes with errors
+  // may have no binding.
+  pub fn resolve_lenient(
+    mut self,
+    statements: &[Statement<'a>],
+  ) -> (Resolution, Vec<ResolveError>) {
+    self.resolve_statements(statements);
+
+    (self.resolution, self.errors)
+  }
+
+  fn resolve_statements(&mut self, statements: &[Statement<'a>]) {
+    for statement in statements {
+      self.resolve_statement(statement);
+    }
+  }
+
+  fn resolve_statement(&mut self, statement: &Statement<'a>) {
+    match statement {
+      Statement::Block { statements } => {
+        self.begin_scope();
+        self.resolve_statements(statements);
+        self.end_scope();
+      }
+      Statement::Class {
+        id,
+        name,
+        superclass,
+        methods,
+      } => self.resolve_class(*id, name, superclass.as_ref(), methods),
+      Statement::Expression { expression } => self.resolve_expression(expression),
+      Statement::Function { function } => {
+        let binding = self.declare(function.name, VariableKind::Function);
+        self.define();
+        self.resolution.bindings.insert(function.id, binding);
+
+        self.resolve_function(function, FunctionKind::Function);
+      }
+      Statement::If {
+        condition,
+        then_branch,
+        else_branch,
+      } => {
+        self.resolve_expression(condition);
+        self.resolve_statement(then_branch);
+
+        if let Some(else_branch) = else_branch {
+          self.resolve_statement(else_branch);
+        }
+      }
+      Statement::Print { expression } => self.resolve_expression(expression),
+      Statement::Return { keyword, value } => {
+        match self.current_function().kind {
+          FunctionKind::Script => self.error(keyword, "Can't return outside of function"),
+          FunctionKind::Initializer if value.is_some() => {
+            self.error(keyword, "Can't return a value from an initializer")
+          }
+          _ => (),
+        }
+
+        if let Some(value) = value {
+          self.resolve_expression(value);
+        }
+      }
+      Statement::Var {
+        id,
+        name,
+        initializer,
+      } => {
+        let binding = self.declare(*name, VariableKind::Variable);
+
+        if let Some(initializer) = initializer {
+          self.resolve_expression(initializer);
+        }
+
+        self.define();
+        self.resolution.bindings.insert(*id, binding);
+      }
+      Statement::While { condition, body } => {
+        self.resolve_expression(condition);
+        self.resolve_statement(body);
+      }
+    }
+  }
+
+  fn resolve_class(
+    &mut self,
+    id: NodeId,
+    name: &Token<'a>,
+    superclass: Option<&Expression<'a>>,
+    methods: &[std::rc::Rc<Function<'a>>],
+  ) {
+    let enclosing_class_kind = self.class_kind;
+    self.class_kind = ClassKind::Class;
+
+    let binding = self.declare(*name, VariableKind::Class);
+    self.define();
+    self.resolution.bindings.insert(id, binding);
+
+    if let Some(superclass) = superclass {
+      if let Expression::Variable {
+        name: superclass_name,
+        ..
+      } = superclass
+      {
+        if superclass_name.text == name.text {
+          self.error(superclass_name, "A class can't inherit from itself");
+        }
+      }
+
+      self.class_kind = ClassKind::Subclass;
+      self.resolve_expression(superclass);
+
+      self.begin_scope();
+      let super_token = Token {
+        text: "super",
+        ..*name
+      };
+      let binding = self.declare(super_token, VariableKind::Super);
+      self.define();
+      self.resolution.supers.insert(id, binding);
+    }
+
+    for method in methods {
+      let kind = if method.name.text == "init" {
+        FunctionKind::Initializer
+      } else {
+        FunctionKind::Method
+      };
+
+      self.resolve_function(method, kind);
+    }
+
+    if superclass.is_some() {
+      self.end_scope();
+    }
+
+    self.class_kind = enclosing_class_kind;
+  }
+
+  fn resolve_function(&mut self, function: &Function<'a>, kind: FunctionKind) {
+    self.functions.push(FunctionState {
+      kind,
+      locals: Vec::new(),
+      upvalues: Vec::new(),
+    });
+
+    if kind == FunctionKind::Function {
+      self.functions.last_mut().unwrap().locals.push(None);
+    } else {
+      // Methods keep the receiver in slot zero. It gets its own scope because
+      // the tree-walker binds `this` in an environment of its own.
+      self.begin_scope();
+      let this_token = Token {
+        text: "this",
+        ..function.name
+      };
+      self.declare(this_token, VariableKind::This);
+      self.define();
+    }
+
+    self.begin_scope();
+
+    for parameter in &function.parameters {
+      self.declare(*parameter, VariableKind::Parameter);
+      self.define();
+    }
+
+    self.resolve_statements(&function.body);
+    self.end_scope();
+
+    if kind != FunctionKind::Function {
+      self.end_scope();
+    }
+
+    let state = self.functions.pop().unwrap();
+    self.resolution.upvalues.insert(function.id, state.upvalues);
+  }
+
+  fn resolve_expression(&mut self, expression: &Expression<'a>) {
+    match expression {
+      Expression::Assign { id, name, value } => {
+        self.resolve_expression(value);
+        self.resolve_reference(*id, name, false);
+      }
+      Expression::Binary { left, right, .. } | Expression::Logical { left, right, .. } => {
+        self.resolve_expression(left);
+        self.resolve_expression(right);
+      }
+      Expression::Call {
+        callee, arguments, ..
+      } => {
+        self.resolve_expression(callee);
+
+        for argument in arguments {
+          self.resolve_expression(argument);
+        }
+      }
+      Expression::Get { object, .. } => self.resolve_expression(object),
+      Expression::Grouping { expression } => self.resolve_expression(expression),
+      Expression::Literal { .. } => (),
+      Expression::Set { object, value, .. } => {
+        self.resolve_expression(value);
+        self.resolve_expression(object);
+      }
+      Expression::Super { id, keyword, .. } => match self.class_kind {
+        ClassKind::None => self.error(keyword, "Can't use 'super' outside of a class"),
+        ClassKind::Class => self.error(keyword, "Can't use 'super' in a class with no superclass"),
+        ClassKind::Subclass => {
+          self.resolve_reference(*id, keyword, true);
+
+          let this_token = Token {
+            text: "this",
+            ..*keyword
+          };
+          let receiver = self.look_up(&this_token, true);
+          self.resolution.receivers.insert(*id, receiver);
+        }
+      },
+      Expression::This { id, keyword } => {
+        if self.class_kind == ClassKind::None {
+          self.error(keyword, "Can't use 'this' outside of a class");
+        } else {
+          self.resolve_reference(*id, keyword, true);
+        }
+      }
+      Expression::Unary { right, .. } => self.resolve_expression(right),
+      Expression::Variable { id, name } => self.resolve_reference(*id, name, true),
+    }
+  }
+
+  fn resolve_reference(&mut self, id: NodeId, name: &Token<'a>, is_read: bool) {
+    let binding = self.look_up(name, is_read);
+    self.resolution.bindings.insert(id, binding);
+  }
+
+  fn look_up(&mut self, name: &Token<'a>, is_read: bool) -> Binding {
+    let current = self.functions.len() - 1;
+    let mut found = None;
+
+    for (depth, scope) in self.scopes.iter().rev().enumerate() {
+      if let Some((_, slot)) = scope.names.iter().rev().find(|(n, _)| *n == name.text) {
+        found = Some((depth, scope.function, *slot));
+        break;
+      }
+    }
+
+    match found {
+      None => Binding::Global,
+      Some((depth, function, slot)) => {
+        let local = self.functions[function].locals[slot]
+          .as_ref()
+          .expect("[bug] named local in reserved slot");
+        let variable = local.variable;
+
+        if is_read {
+          self.resolution.variables[variable].reads += 1;
+        }
+
+        if !local.initialized {
+          self.error(name, "Can't read local variable in its own initializer");
+        }
+
+        if function == current {
+          Binding::Local {
+            slot,
+            depth,
+            variable,
+          }
+        } else {
+          Binding::Upvalue {
+            index: self.capture(function, slot, current, name),
+            depth,
+            variable,
+          }
+        }
+      }
+    }
+  }
+
+  // Threads the local through the upvalues of every function between the one
+  // declaring it and the one using it, returns the index in the last one
+  fn capture(&mut self, function: usize, slot: usize, target: usize, name: &Token) -> usize {
+    if let Some(local) = &self.functions[function].locals[slot] {
+      self.resolution.variables[local.variable].captured = true;
+    }
+
+    let mut index = self.add_upvalue(
+      function + 1,
+      Upvalue {
+        is_local: true,
+        index: slot,
+      },
+      name,
+    );
+
+    for intermediate in function + 2..=target {
+      index = self.add_upvalue(
+        intermediate,
+        Upvalue {
+          is_local: false,
+          index,
+        },
+        name,
+      );
+    }
+
+    index
+  }
+
+  fn add_upvalue(&mut self, function: usize, upvalue: Upvalue, name: &Token) -> usize {
+    let upvalues = &mut self.functions[function].upvalues;
+
+    if let Some(index) = upvalues.iter().position(|existing| *existing == upvalue) {
+      return index;
+    }
+
+    upvalues.push(upvalue);
+
+    if upvalues.len() > MAX_UPVALUES {
+      self.error(name, "Too many closure variables in function");
+    }
+
+    self.functions[function].upvalues.len() - 1
+  }
+
+  fn declare(&mut self, name: Token<'a>, kind: VariableKind) -> Binding {
+    if self.scopes.is_empty() {
+      return Binding::Global;
+    }
+
+    if self
+      .scopes
+      .last()
+      .unwrap()
+      .names
+      .iter()
+      .any(|(existing, _)| *existing == name.text)
+    {
+      self.error(&name, "Already a variable with this name in this scope");
+    }
+
+    let shadows = self.scopes[..self.scopes.len() - 1].iter().any(|scope| {
+      scope
+        .names
+        .iter()
+        .any(|(existing, _)| *existing == name.text)
+    });
+
+    let variable = self.resolution.variables.len();
+    self.resolution.variables.push(Variable {
+      name: name.text.to_string(),
+      line: name.line,
+      kind,
+      captured: false,
+      reads: 0,
+      shadows,
+    });
+
+    let locals = &mut self.functions.last_mut().unwrap().locals;
+    let slot = locals.len();
+    locals.push(Some(Local {
+      variable,
+      initialized: false,
+    }));
+
+    if slot >= MAX_LOCALS {
+      self.error(&name, "Too many local variables in function");
+    }
+
+    self
+      .scopes
+      .last_mut()
+      .unwrap()
+      .names
+      .push((name.text, slot));
+
+    Binding::Local {
+      slot,
+      depth: 0,
+      variable,
+    }
+  }
+
+  fn define(&mut self) {
+    if self.scopes.is_empty() {
+      return;
+    }
+
+    if let Some(Some(local)) = self.functions.last_mut().unwrap().locals.last_mut() {
+      local.initialized = true;
+    }
+  }
+
+  fn begin_scope(&mut self) {
+    self.scopes.push(Scope {
+      function: self.functions.len() - 1,
+      names: Vec::new(),
+    });
+  }
+
+  fn end_scope(&mut self) {
+    let scope = self.scopes.pop().unwrap();
+    let locals = &mut self.functions[scope.function].locals;
+    let remaining = locals.len() - scope.names.len();
+
+    locals.truncate(remaining);
+  }
+
+  fn current_function(&self) -> &FunctionState {
+    self.functions.last().unwrap()
+  }
+
+  fn error(&mut self, token: &Token, message: &str) {
+    self.errors.push(ResolveError {
+      line: token.line,
+      message: message.to_string(),
+    });
+  }
+}
+
+impl<'a> Default for Resolver<'a> {
+  fn default() -> Self {
+    Resolver::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser;
+
+  fn expression_ids<'s>(statements: &'s [Statement<'s>], name: &str) -> Vec<NodeId> {
+    let mut ids = Vec::new();
+
+    fn walk_expression(expression: &Expression, name: &str, ids: &mut Vec<NodeId>) {
+      match expression {
+        Expression::Variable { id, name: token }
+        | Expression::Assign {
+          id, name: token, ..
+        } if token.text == name => {
+          ids.push(*id);
+
+          if let Expression::Assign { value, .. } = expression {
+            walk_expression(value, name, ids);
+          }
+        }
+        Expression::This { id, .. } if name == "this" => ids.push(*id),
+        Expression::Super { id, .. } if name == "super" => ids.push(*id),
+        Expression::Assign { value, .. } => walk_expression(value, name, ids),
+        Expression::Binary { left, right, .. } | Expression::Logical { left, right, .. } => {
+          walk_expression(left, name, ids);
+          walk_expression(right, name, ids);
+        }
+        Expression::Call {
+          callee, arguments, ..
+        } => {
+          walk_expression(callee, name, ids);
+          arguments
+            .iter()
+            .for_each(|argument| walk_expression(argument, name, ids));
+        }
+        Expression::Get { object, .. } => walk_expression(object, name, ids),
+        Expression::Set { object, value, .. } => {
+          walk_expression(object, name, ids);
+          walk_expression(value, name, ids);
+        }
+        Expression::Grouping { expression } => walk_expression(expression, name, ids),
+        Expression::Unary { right, .. } => walk_expression(right, name, ids),
+        _ => (),
+      }
+    }
+
+    fn walk_statement(statement: &Statement, name: &str, ids: &mut Vec<NodeId>) {
+      match statement {
+        Statement::Block { statements } => statements
+          .iter()
+          .for_each(|statement| walk_statement(statement, name, ids)),
+        Statement::Class { methods, .. } => methods.iter().for_each(|method| {
+          method
+            .body
+            .iter()
+            .for_each(|statement| walk_statement(statement, name, ids))
+        }),
+        Statement::Expression { expression } | Statement::Print { expression } => {
+          walk_expression(expression, name, ids)
+        }
+        Statement::Function { function } => function
+          .body
+          .iter()
+          .for_each(|statement| walk_statement(statement, name, ids)),
+        Statement::If {
+          condition,
+          then_branch,
+          else_branch,
+        } => {
+          walk_expression(condition, name, ids);
+          walk_statement(then_branch, name, ids);
+          else_branch
+            .iter()
+            .for_each(|statement| walk_statement(statement, name, ids));
+        }
+        Statement::Return { value, .. } => value
+          .iter()
+          .for_each(|value| walk_expression(value, name, ids)),
+        Statement::Var { initializer, .. } => initializer
+          .iter()
+          .for_each(|initializer| walk_expression(initializer, name, ids)),
+        Statement::While { condition, body } => {
+          walk_expression(condition, name, ids);
+          walk_statement(body, name, ids);
+        }
+      }
+    }
+
+    statements
+      .iter()
+      .for_each(|statement| walk_statement(statement, name, &mut ids));
+
+    ids
+  }
+
+  fn bindings(source: &str, name: &str) -> Vec<Binding> {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolve(&statements).unwrap();
+
+    expression_ids(&statements, name)
+      .into_iter()
+      .map(|id| resolution.binding(id))
+      .collect()
+  }
+
+  fn errors(source: &str) -> Vec<String> {
+    let statements = parser::parse(source).unwrap();
+
+    resolve(&statements)
+      .unwrap_err()
+      .iter()
+      .map(|error| error.to_string())
+      .collect()
+  }
+
+  #[test]
+  fn test_global() {
+    assert_eq!(bindings("var a = 1; print a;", "a"), vec![Binding::Global]);
+  }
+
+  #[test]
+  fn test_undeclared_is_global() {
+    assert_eq!(bindings("{ print a; }", "a"), vec![Binding::Global]);
+  }
+
+  #[test]
+  fn test_local_slots() {
+    let source = "{ var a = 1; var b = 2; print a + b; }";
+
+    assert_eq!(
+      bindings(source, "a"),
+      vec![Binding::Local {
+        slot: 1,
+        depth: 0,
+        variable: 0
+      }]
+    );
+    assert_eq!(
+      bindings(source, "b"),
+      vec![Binding::Local {
+        slot: 2,
+        depth: 0,
+        variable: 1
+      }]
+    );
+  }
+
+  #[test]
+  fn test_slots_are_reused_after_block() {
+    let source = "{ { var a = 1; } var b = 2; print b; }";
+
+    assert!(matches!(
+      bindings(source, "b")[..],
+      [Binding::Local { slot: 1, .. }]
+    ));
+  }
+
+  #[test]
+  fn test_parameters() {
+    let source = "fun f(a, b) { return b; }";
+
+    assert_eq!(
+      bindings(source, "b"),
+      vec![Binding::Local {
+        slot: 2,
+        depth: 0,
+        variable: 1
+      }]
+    );
+  }
+
+  #[test]
+  fn test_depth() {
+    let source = "fun f(a) { { { print a; } } }";
+
+    assert!(matches!(
+      bindings(source, "a")[..],
+      [Binding::Local {
+        slot: 1,
+        depth: 2,
+        ..
+      }]
+    ));
+  }
+
+  #[test]
+  fn test_shadowing_in_initializer_scope() {
+    let source = "var a = 1; { var b = a; var a = 2; print a; }";
+
+    assert!(matches!(
+      bindings(source, "a")[..],
+      [Binding::Global, Binding::Local { slot: 2, .. }]
+    ));
+  }
+
+  #[test]
+  fn test_captured() {
+    let source = "fun outer() { var x = 1; fun inner() { return x; } return inner; }";
+
+    assert!(matches!(
+      bindings(source, "x")[..],
+      [Binding::Upvalue {
+        index: 0,
+        depth: 1,
+        ..
+      }]
+    ));
+  }
+
+  #[test]
+  fn test_deep_capture_upvalues() {
+    let source = "
+      fun outer() {
+        var p = 2;
+        fun middle() {
+          var q = 3;
+          fun inner() { return p * q; }
+          return inner;
+        }
+        return middle;
+      }
+    ";
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolve(&statements).unwrap();
+
+    let outer = match &statements[0] {
+      Statement::Function { function } => function,
+      _ => unreachable!(),
+    };
+    let middle = match &outer.body[1] {
+      Statement::Function { function } => function,
+      _ => unreachable!(),
+    };
+    let inner = match &middle.body[1] {
+      Statement::Function { function } => function,
+      _ => unreachable!(),
+    };
+
+    assert_eq!(resolution.upvalues(outer.id), &[]);
+    assert_eq!(
+      resolution.upvalues(middle.id),
+      &[Upvalue {
+        is_local: true,
+        index: 1
+      }]
+    );
+    assert_eq!(
+      resolution.upvalues(inner.id),
+      &[
+        Upvalue {
+          is_local: false,
+          index: 0
+        },
+        Upvalue {
+          is_local: true,
+          index: 1
+        }
+      ]
+    );
+  }
+
+  #[test]
+  fn test_this() {
+    let source = "class A { method() { return this; } }";
+
+    assert!(matches!(
+      bindings(source, "this")[..],
+      [Binding::Local {
+        slot: 0,
+        depth: 1,
+        ..
+      }]
+    ));
+  }
+
+  #[test]
+  fn test_super() {
+    let source = "class A {} class B < A { method() { super.method(); } }";
+
+    assert!(matches!(
+      bindings(source, "super")[..],
+      [Binding::Upvalue {
+        index: 0,
+        depth: 2,
+        ..
+      }]
+    ));
+  }
+
+  #[test]
+  fn test_super_receiver() {
+    let source = "class A {} class B < A { method() { fun f() { return super.method; } } }";
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolve(&statements).unwrap();
+    let super_expression = expression_ids(&statements, "super")[0];
+
+    assert!(matches!(
+      resolution.receiver(super_expression),
+      Binding::Upvalue {
+        index: 1,
+        depth: 2,
+        ..
+      }
+    ));
+  }
+
+  #[test]
+  fn test_own_initializer_error() {
+    assert_eq!(
+      errors("{ var a = a; }"),
+      vec![
+        "static analysis error. line: 1 - error: Can't read local variable in its own initializer"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_duplicate_declaration_error() {
+    assert_eq!(
+      errors("fun f() {\n var a = 1;\n var a = 2;\n}"),
+      vec![
+        "static analysis error. line: 3 - error: Already a variable with this name in this scope"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_global_redeclaration() {
+    let statements = parser::parse("var a = 1; var a = 2;").unwrap();
+
+    assert!(resolve(&statements).is_ok());
+  }
+
+  #[test]
+  fn test_top_level_return_error() {
+    assert_eq!(
+      errors("return 1;"),
+      vec!["static analysis error. line: 1 - error: Can't return outside of function"]
+    );
+  }
+
+  #[test]
+  fn test_initializer_return_error() {
+    assert_eq!(
+      errors("class A { init() { return 1; } }"),
+      vec!["static analysis error. line: 1 - error: Can't return a value from an initializer"]
+    );
+  }
+
+  #[test]
+  fn test_this_outside_class_error() {
+    assert_eq!(
+      errors("print this;"),
+      vec!["static analysis error. line: 1 - error: Can't use 'this' outside of a class"]
+    );
+  }
+
+  #[test]
+  fn test_super_errors() {
+    assert_eq!(
+      errors("class A { m() { super.m(); } } class B < B {}"),
+      vec![
+        "static analysis error. line: 1 - error: Can't use 'super' in a class with no superclass",
+        "static analysis error. line: 1 - error: A class can't inherit from itself"
+      ]
+    );
+  }
+
+  fn captured_variables(path: &str) -> Vec<String> {
+    let path = format!(
+      "{}/../s1-web/test_suite/{}",
+      env!("CARGO_MANIFEST_DIR"),
+      path
+    );
+    let source = std::fs::read_to_string(path).unwrap();
+    let statements = parser::parse(&source).unwrap();
+    let resolution = resolve(&statements).unwrap();
+
+    resolution
+      .variables()
+      .iter()
+      .filter(|variable| variable.captured)
+      .map(|variable| variable.name.clone())
+      .collect()
+  }
+
+  #[test]
+  fn test_closure_assignment_captures() {
+    assert_eq!(captured_variables("closure-assignment.lox"), vec!["p"]);
+  }
+
+  #[test]
+  fn test_deep_closure_captures() {
+    assert_eq!(captured_variables("deep-closure.lox"), vec!["p", "q"]);
+  }
+
+  #[test]
+  fn test_closure_self_use_captures() {
+    assert_eq!(captured_variables("closure-self-use.lox"), vec!["c"]);
+  }
+
+  #[test]
+  fn test_uncaptured_stay_on_stack() {
+    let source = "fun f(a) { var b = a; { var c = b; fun g() { return c; } } return b; }";
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolve(&statements).unwrap();
+
+    assert_eq!(
+      resolution
+        .variables()
+        .iter()
+        .map(|variable| (variable.name.as_str(), variable.captured))
+        .collect::<Vec<_>>(),
+      vec![("a", false), ("b", false), ("c", true), ("g", false)]
+    );
+  }
+
+  #[test]
+  fn test_captured_reference_in_declaring_function() {
+    let source = "fun f() { var c = 1; fun inner() { return c; } return c; }";
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolve(&statements).unwrap();
+    let bindings = expression_ids(&statements, "c")
+      .into_iter()
+      .map(|id| resolution.binding(id))
+      .collect::<Vec<_>>();
+
+    assert!(matches!(
+      bindings[..],
+      [Binding::Upvalue { .. }, Binding::Local { .. }]
+    ));
+    assert!(bindings
+      .iter()
+      .all(|binding| resolution.is_captured(*binding)));
+  }
+
+  #[test]
+  fn test_reads_and_shadowing() {
+    let source = "fun f(a, b) { var c; c = a; { var a = c; } }";
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolve(&statements).unwrap();
+
+    assert_eq!(
+      resolution
+        .variables()
+        .iter()
+        .map(|variable| (variable.name.as_str(), variable.reads, variable.shadows))
+        .collect::<Vec<_>>(),
+      vec![
+        ("a", 1, false),
+        ("b", 0, false),
+        ("c", 1, false),
+        ("a", 0, true)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_resolve_lenient() {
+    let statements = parser::parse("print this; { var a = 1; print a; }").unwrap();
+    let (resolution, errors) = Resolver::new().resolve_lenient(&statements);
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(resolution.variables()[0].reads, 1);
+  }
+
+  #[test]
+  fn test_test_suite_resolves() {
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../s1-web/test_suite");
+
+    for entry in std::fs::read_dir(directory).unwrap() {
+      let path = entry.unwrap().path();
+
+      if path.extension().is_some_and(|extension| extension == "lox") {
+        let source = std::fs::read_to_string(&path).unwrap();
+        let statements = parser::parse(&source).unwrap();
+
+        assert!(resolve(&statements).is_ok(), "{:?}", path);
+      }
+    }
+  }
+}
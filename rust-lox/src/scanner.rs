@@ -9,6 +9,7 @@ pub struct Scan<'a> {
   peek_next: Option<char>,
   chars: std::str::Chars<'a>,
   line: u32,
+  keep_comments: bool,
 }
 
 #[derive(Debug, PartialEq, Serialize, Clone, Copy)]
@@ -62,6 +63,8 @@ pub enum TokenType {
   True,
   Var,
   While,
+  // Only produced when comments are kept, see Scan::keeping_comments
+  Comment,
   Error,
   Eof,
 }
@@ -78,20 +81,28 @@ impl<'a> Iterator for Scan<'a> {
 }
 
 impl<'a> Scan<'a> {
-  pub fn new(source: &str) -> Scan {
+  pub fn new(source: &'a str) -> Scan<'a> {
     let mut chars = source.chars();
 
     Scan {
       start: 0,
       current: 0,
-      source: source,
+      source,
       peek: chars.next(),
       peek_next: chars.next(),
-      chars: chars,
+      chars,
       line: 1,
+      keep_comments: false,
     }
   }
 
+  // Comments come through as tokens instead of being skipped. Used by tools
+  // like the linter that read directives from them.
+  pub fn keeping_comments(mut self) -> Scan<'a> {
+    self.keep_comments = true;
+    self
+  }
+
   pub fn advance(&mut self) -> Option<char> {
     if self.at_end() {
       panic!("Can't advance beyond end");
@@ -101,7 +112,7 @@ impl<'a> Scan<'a> {
     self.peek = self.peek_next;
     self.peek_next = self.chars.next();
 
-    self.current += 1;
+    self.current += current_char.map_or(0, char::len_utf8);
 
     current_char
   }
@@ -144,6 +155,7 @@ impl<'a> Scan<'a> {
       ';' => self.make_token(TokenType::Semicolon),
       '-' => self.make_token(TokenType::Minus),
       '*' => self.make_token(TokenType::Star),
+      '/' if self.peek == Some('/') => self.scan_comment(),
       '/' => self.make_token(TokenType::Slash),
       ',' => self.make_token(TokenType::Comma),
       '.' => self.make_token(TokenType::Dot),
@@ -176,7 +188,7 @@ impl<'a> Scan<'a> {
         }
       }
       '"' => self.scan_string(),
-      _ => self.make_token(TokenType::Error),
+      _ => self.error_token("Unexpected character."),
     }
   }
 
@@ -211,7 +223,7 @@ impl<'a> Scan<'a> {
       "super" => TokenType::Super,
       "var" => TokenType::Var,
       "while" => TokenType::While,
-      "fn" => TokenType::Fun,
+      "fun" => TokenType::Fun,
       "false" => TokenType::False,
       "for" => TokenType::For,
       "this" => TokenType::This,
@@ -227,14 +239,14 @@ impl<'a> Scan<'a> {
       match self.peek {
         Some(c) if c.is_ascii_digit() => {
           self.advance();
-          ()
         }
-        Some(c) if c == '.' && !saw_decimal_point => {
+        Some('.')
+          if !saw_decimal_point && self.peek_next.map(|c| c.is_ascii_digit()) == Some(true) =>
+        {
           saw_decimal_point = true;
           self.advance();
-          ()
         }
-        _ => break (),
+        _ => break,
       }
     }
 
@@ -242,27 +254,47 @@ impl<'a> Scan<'a> {
   }
 
   fn scan_string(&mut self) -> Token<'a> {
-    while self.peek != Some('"') {
+    let line = self.line;
+
+    while !self.at_end() && self.peek != Some('"') {
+      if self.peek == Some('\n') {
+        self.line += 1;
+      }
+
       self.advance();
     }
 
+    if self.at_end() {
+      return self.error_token("Unterminated string.");
+    }
+
     self.advance();
 
-    self.make_token(TokenType::String)
+    Token {
+      token_type: TokenType::String,
+      text: self.current_token(),
+      line,
+    }
+  }
+
+  fn scan_comment(&mut self) -> Token<'a> {
+    while !self.at_end() && self.peek != Some('\n') {
+      self.advance();
+    }
+
+    self.make_token(TokenType::Comment)
   }
 
   fn eat_whitespace(&mut self) {
     loop {
       match self.peek {
-        Some(c) if c == '\n' => {
+        Some('\n') => {
           self.line += 1;
           self.advance();
         }
-        Some(c) if c == '/' => {
-          if self.peek_next == Some('/') {
-            while !self.at_end() && self.peek != Some('\n') {
-              self.advance();
-            }
+        Some('/') if self.peek_next == Some('/') && !self.keep_comments => {
+          while !self.at_end() && self.peek != Some('\n') {
+            self.advance();
           }
         }
         Some(c) if c.is_whitespace() => {
@@ -281,11 +313,19 @@ impl<'a> Scan<'a> {
 
   fn make_token(&self, token_type: TokenType) -> Token<'a> {
     Token {
-      token_type: token_type,
+      token_type,
       text: self.current_token(),
       line: self.line,
     }
   }
+
+  fn error_token(&self, message: &'static str) -> Token<'a> {
+    Token {
+      token_type: TokenType::Error,
+      text: message,
+      line: self.line,
+    }
+  }
 }
 
 #[cfg(test)]
@@ -421,12 +461,27 @@ mod tests {
       scan.scan_token(),
       Token {
         token_type: TokenType::String,
-        text: "🤪",
+        text: "\"🤪\"",
         line: 1,
       }
     );
   }
 
+  #[test]
+  fn test_after_non_ascii() {
+    let scan = Scan::new("\"🤪\"+ünï");
+    let tokens: Vec<_> = scan.map(|token| (token.token_type, token.text)).collect();
+
+    assert_eq!(
+      tokens,
+      Vec::from([
+        (TokenType::String, "\"🤪\""),
+        (TokenType::Plus, "+"),
+        (TokenType::Identifier, "ünï"),
+      ])
+    );
+  }
+
   #[test]
   fn test_keywords() {
     let scan = Scan::new("print 1");
@@ -479,7 +534,7 @@ mod tests {
   #[test]
   fn test_comments() {
     let source = r#"
-      fn a_fun(p) {
+      fun a_fun(p) {
         // just some stuff
         var l = p + 1; // some other stuff
         return v + 1;
@@ -494,7 +549,7 @@ mod tests {
       Vec::from([
         Token {
           token_type: TokenType::Fun,
-          text: "fn",
+          text: "fun",
           line: 2
         },
         Token {
@@ -591,10 +646,37 @@ mod tests {
     )
   }
 
+  #[test]
+  fn test_keeping_comments() {
+    let scan = Scan::new("var a; // lox-allow: unused-variable\n/ 2").keeping_comments();
+    let tokens: Vec<Token> = scan.collect();
+
+    assert_eq!(
+      tokens[3],
+      Token {
+        token_type: TokenType::Comment,
+        text: "// lox-allow: unused-variable",
+        line: 1
+      }
+    );
+    assert_eq!(tokens[4].token_type, TokenType::Slash);
+  }
+
+  #[test]
+  fn test_comments_skipped_by_default() {
+    let scan = Scan::new("1 // two\n/ 3");
+    let tokens: Vec<TokenType> = scan.map(|token| token.token_type).collect();
+
+    assert_eq!(
+      tokens,
+      vec![TokenType::Number, TokenType::Slash, TokenType::Number]
+    );
+  }
+
   #[test]
   fn test_newlines() {
     let source = r#"
-      fn a_fun(p) {
+      fun a_fun(p) {
         var l = p + 1;
         return v + 1;
       }
@@ -608,7 +690,7 @@ mod tests {
       Vec::from([
         Token {
           token_type: TokenType::Fun,
-          text: "fn",
+          text: "fun",
           line: 2
         },
         Token {
@@ -0,0 +1,72 @@
+use std::rc::Rc;
+
+use super::expression::{Expression, NodeId};
+use super::scanner;
+
+#[derive(Debug, PartialEq)]
+pub struct Function<'a> {
+  pub id: NodeId,
+  pub name: scanner::Token<'a>,
+  pub parameters: Vec<scanner::Token<'a>>,
+  pub body: Vec<Statement<'a>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Statement<'a> {
+  Block {
+    statements: Vec<Statement<'a>>,
+  },
+  Class {
+    id: NodeId,
+    name: scanner::Token<'a>,
+    superclass: Option<Expression<'a>>,
+    methods: Vec<Rc<Function<'a>>>,
+  },
+  Expression {
+    expression: Expression<'a>,
+  },
+  // Functions are shared with the runtime closures created from them
+  Function {
+    function: Rc<Function<'a>>,
+  },
+  If {
+    condition: Expression<'a>,
+    then_branch: Box<Statement<'a>>,
+    else_branch: Option<Box<Statement<'a>>>,
+  },
+  Print {
+    expression: Expression<'a>,
+  },
+  Return {
+    keyword: scanner::Token<'a>,
+    value: Option<Expression<'a>>,
+  },
+  Var {
+    id: NodeId,
+    name: scanner::Token<'a>,
+    initializer: Option<Expression<'a>>,
+  },
+  While {
+    condition: Expression<'a>,
+    body: Box<Statement<'a>>,
+  },
+}
+
+impl<'a> Statement<'a> {
+  // The line where the statement starts. None for blocks, which start with
+  // their first statement.
+  pub fn line(&self) -> Option<u32> {
+    match self {
+      Statement::Block { .. } => None,
+      Statement::Class { name, .. } | Statement::Var { name, .. } => Some(name.line),
+      Statement::Expression { expression } | Statement::Print { expression } => {
+        Some(expression.line())
+      }
+      Statement::Function { function } => Some(function.name.line),
+      Statement::If { condition, .. } | Statement::While { condition, .. } => {
+        Some(condition.line())
+      }
+      Statement::Return { keyword, .. } => Some(keyword.line),
+    }
+  }
+}
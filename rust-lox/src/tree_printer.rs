@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Map, Value};
+
+use super::expression::{Expression, Literal, NodeId};
+use super::resolver::{Binding, Resolution, VariableId, VariableKind};
+use super::statement::{Function, Statement};
+
+// Produces the {name, attributes, children} trees the web client renders, same
+// as s1-web/web/tree_printer.rb
+pub struct TreePrinter<'r> {
+  resolution: &'r Resolution,
+  // Numbered the way the client does, which isn't how the resolver does
+  stack_slots: HashMap<VariableId, usize>,
+}
+
+impl<'r> TreePrinter<'r> {
+  pub fn new(resolution: &'r Resolution) -> TreePrinter<'r> {
+    TreePrinter {
+      resolution,
+      stack_slots: HashMap::new(),
+    }
+  }
+
+  pub fn print(&mut self, statements: &[Statement]) -> Value {
+    self.stack_slots.clear();
+    self.number_stack_slots(statements, 0);
+
+    json!({
+      "name": "PROGRAM",
+      "children": self.print_statements(statements),
+    })
+  }
+
+  // Same as Phase2 of the client: only locals that stay on the stack get a
+  // slot, numbering starts over in every function and a block's slots are
+  // reused once it ends. The resolver also counts captured locals and the
+  // function in slot zero.
+  fn number_stack_slots(&mut self, statements: &[Statement], mut next: usize) {
+    for statement in statements {
+      match statement {
+        Statement::Var { id, .. } => self.number_stack_slot(*id, &mut next),
+        Statement::Function { function } => {
+          self.number_stack_slot(function.id, &mut next);
+          self.number_function_stack_slots(function);
+        }
+        Statement::Class { id, methods, .. } => {
+          self.number_stack_slot(*id, &mut next);
+
+          for method in methods {
+            self.number_function_stack_slots(method);
+          }
+        }
+        Statement::Block { statements } => self.number_stack_slots(statements, next),
+        Statement::If {
+          then_branch,
+          else_branch,
+          ..
+        } => {
+          self.number_stack_slots(std::slice::from_ref(then_branch), next);
+
+          if let Some(else_branch) = else_branch {
+            self.number_stack_slots(std::slice::from_ref(else_branch), next);
+          }
+        }
+        Statement::While { body, .. } => self.number_stack_slots(std::slice::from_ref(body), next),
+        Statement::Expression { .. } | Statement::Print { .. } | Statement::Return { .. } => (),
+      }
+    }
+  }
+
+  // The client gives every parameter a slot, captured or not. Parameters have
+  // no ids to number, see stack_slot.
+  fn number_function_stack_slots(&mut self, function: &Function) {
+    self.number_stack_slots(&function.body, function.parameters.len());
+  }
+
+  fn number_stack_slot(&mut self, id: NodeId, next: &mut usize) {
+    let binding = self.resolution.binding(id);
+
+    if let Binding::Local { variable, .. } = binding {
+      if !self.resolution.is_captured(binding) {
+        self.stack_slots.insert(variable, *next);
+        *next += 1;
+      }
+    }
+  }
+
+  fn stack_slot(&self, slot: usize, variable: VariableId) -> usize {
+    match self.resolution.variable(variable).kind {
+      // Right after the function or receiver in slot zero, in both numberings
+      VariableKind::Parameter => slot - 1,
+      _ => self.stack_slots[&variable],
+    }
+  }
+
+  fn print_statements(&self, statements: &[Statement]) -> Vec<Value> {
+    statements
+      .iter()
+      .map(|statement| self.print_statement(statement))
+      .collect()
+  }
+
+  fn print_statement(&self, statement: &Statement) -> Value {
+    match statement {
+      Statement::Expression { expression } => json!({
+        "name": "EXP-STMT",
+        "attributes": {},
+        "children": [self.print_expression(expression)],
+      }),
+      Statement::Function { function } => self.print_function(function),
+      Statement::Class {
+        name,
+        superclass,
+        methods,
+        ..
+      } => {
+        let mut attributes = Map::new();
+        attributes.insert("name".into(), json!(name.text));
+
+        if let Some(Expression::Variable { name, .. }) = superclass {
+          attributes.insert("superclass".into(), json!(name.text));
+        }
+
+        json!({
+          "name": "CLASS-DEF",
+          "attributes": attributes,
+          "children": methods
+            .iter()
+            .map(|method| self.print_function(method))
+            .collect::<Vec<_>>(),
+        })
+      }
+      Statement::Return { value, .. } => json!({
+        "name": "RETURN",
+        "attributes": {},
+        "children": value
+          .iter()
+          .map(|value| self.print_expression(value))
+          .collect::<Vec<_>>(),
+      }),
+      Statement::Print { expression } => json!({
+        "name": "PRINT",
+        "attributes": {},
+        "children": [self.print_expression(expression)],
+      }),
+      Statement::Var {
+        id,
+        name,
+        initializer,
+      } => json!({
+        "name": "VAR-DEF",
+        "attributes": self.scope_attributes(*id, name.text),
+        "children": initializer
+          .iter()
+          .map(|initializer| adorn(self.print_expression(initializer), "INITIALIZER"))
+          .collect::<Vec<_>>(),
+      }),
+      Statement::Block { statements } => json!({
+        "name": "BLOCK",
+        "attributes": {},
+        "children": self.print_statements(statements),
+      }),
+      Statement::If {
+        condition,
+        then_branch,
+        else_branch,
+      } => {
+        let mut children = vec![
+          adorn(self.print_expression(condition), "CONDITION"),
+          adorn(self.print_statement(then_branch), "THEN"),
+        ];
+
+        if let Some(else_branch) = else_branch {
+          children.push(adorn(self.print_statement(else_branch), "ELSE"));
+        }
+
+        json!({
+          "name": "IF",
+          "attributes": {},
+          "children": children,
+        })
+      }
+      Statement::While { condition, body } => json!({
+        "name": "WHILE",
+        "attributes": {},
+        "children": [
+          adorn(self.print_expression(condition), "CONDITION"),
+          adorn(self.print_statement(body), "BODY"),
+        ],
+      }),
+    }
+  }
+
+  fn print_function(&self, function: &Function) -> Value {
+    let parameters = function
+      .parameters
+      .iter()
+      .map(|parameter| parameter.text)
+      .collect::<Vec<_>>();
+
+    json!({
+      "name": "FUN-DEF",
+      "attributes": {
+        "name": format!("{}({})", function.name.text, parameters.join(", ")),
+      },
+      "children": self.print_statements(&function.body),
+    })
+  }
+
+  fn print_expression(&self, expression: &Expression) -> Value {
+    match expression {
+      Expression::Assign { id, name, value } => json!({
+        "name": "ASSIGN",
+        "attributes": self.scope_attributes(*id, name.text),
+        "children": [self.print_expression(value)],
+      }),
+      Expression::Binary {
+        left,
+        operator,
+        right,
+      } => json!({
+        "name": "BINARY_EXP",
+        "attributes": {
+          "operator": operator.text,
+        },
+        "children": [self.print_expression(left), self.print_expression(right)],
+      }),
+      Expression::Grouping { expression } => json!({
+        "name": "GROUP",
+        "children": [self.print_expression(expression)],
+      }),
+      Expression::Literal { value, .. } => json!({
+        "name": "LITERAL",
+        "attributes": {
+          "value": inspect(value),
+        },
+      }),
+      Expression::Logical {
+        left,
+        operator,
+        right,
+      } => json!({
+        "name": "LOGICAL",
+        "attributes": {
+          "operator": operator.text,
+        },
+        "children": [self.print_expression(left), self.print_expression(right)],
+      }),
+      Expression::Unary { operator, right } => json!({
+        "name": "UNARY",
+        "attributes": {
+          "operator": operator.text,
+        },
+        "children": [self.print_expression(right)],
+      }),
+      Expression::Variable { id, name } => json!({
+        "name": "VAR-LOOKUP",
+        "attributes": self.scope_attributes(*id, name.text),
+      }),
+      Expression::Call {
+        callee, arguments, ..
+      } => {
+        let mut children = vec![adorn(self.print_expression(callee), "CALLEE")];
+
+        children.extend(
+          arguments
+            .iter()
+            .map(|argument| adorn(self.print_expression(argument), "ARG")),
+        );
+
+        json!({
+          "name": "CALL",
+          "attributes": {},
+          "children": children,
+        })
+      }
+      Expression::Get { object, name } => json!({
+        "name": "GET",
+        "attributes": {
+          "name": name.text,
+        },
+        "children": [self.print_expression(object)],
+      }),
+      Expression::Set {
+        object,
+        name,
+        value,
+      } => json!({
+        "name": "SET",
+        "attributes": {
+          "name": name.text,
+        },
+        "children": [
+          adorn(self.print_expression(object), "OBJECT"),
+          adorn(self.print_expression(value), "VALUE"),
+        ],
+      }),
+      Expression::This { .. } => json!({
+        "name": "THIS",
+        "attributes": {},
+      }),
+      Expression::Super { method, .. } => json!({
+        "name": "SUPER",
+        "attributes": {
+          "method": method.text,
+        },
+      }),
+    }
+  }
+
+  fn scope_attributes(&self, id: NodeId, name: &str) -> Value {
+    let binding = self.resolution.binding(id);
+
+    match binding {
+      Binding::Global => json!({
+        "name": name,
+        "allocation": "GLOBAL",
+      }),
+      Binding::Local { slot, variable, .. } if !self.resolution.is_captured(binding) => json!({
+        "name": name,
+        "allocation": "STACK",
+        "stack slot": self.stack_slot(slot, variable),
+      }),
+      Binding::Local { variable, .. } | Binding::Upvalue { variable, .. } => json!({
+        "name": name,
+        "allocation": "HEAP",
+        "heap slot": variable,
+      }),
+    }
+  }
+}
+
+fn adorn(mut node: Value, role: &str) -> Value {
+  if let Some(node) = node.as_object_mut() {
+    node
+      .entry("attributes")
+      .or_insert_with(|| json!({}))
+      .as_object_mut()
+      .unwrap()
+      .insert("role".into(), json!(role));
+  }
+
+  node
+}
+
+// Mirrors Ruby's #inspect which is what the client is used to seeing
+fn inspect(literal: &Literal) -> String {
+  match literal {
+    Literal::Number(number) => format!("{:?}", number),
+    Literal::String(string) => format!("{:?}", string),
+    Literal::Bool(boolean) => boolean.to_string(),
+    Literal::Nil => String::from("nil"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser;
+  use crate::resolver;
+
+  fn print(source: &str) -> Value {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    TreePrinter::new(&resolution).print(&statements)
+  }
+
+  #[test]
+  fn test_expression_statement() {
+    assert_eq!(
+      print("1 + 2;"),
+      json!({
+        "name": "PROGRAM",
+        "children": [{
+          "name": "EXP-STMT",
+          "attributes": {},
+          "children": [{
+            "name": "BINARY_EXP",
+            "attributes": { "operator": "+" },
+            "children": [
+              { "name": "LITERAL", "attributes": { "value": "1.0" } },
+              { "name": "LITERAL", "attributes": { "value": "2.0" } },
+            ],
+          }],
+        }],
+      })
+    );
+  }
+
+  #[test]
+  fn test_var_definition() {
+    assert_eq!(
+      print("var a = \"str\";")["children"][0],
+      json!({
+        "name": "VAR-DEF",
+        "attributes": { "name": "a", "allocation": "GLOBAL" },
+        "children": [{
+          "name": "LITERAL",
+          "attributes": { "value": "\"str\"", "role": "INITIALIZER" },
+        }],
+      })
+    );
+  }
+
+  #[test]
+  fn test_function_definition() {
+    assert_eq!(
+      print("fun add(a, b) { return a + b; }")["children"][0]["attributes"],
+      json!({ "name": "add(a, b)" })
+    );
+  }
+
+  #[test]
+  fn test_call_roles() {
+    let call = &print("f(1, nil);")["children"][0]["children"][0];
+
+    assert_eq!(call["name"], "CALL");
+    assert_eq!(call["children"][0]["attributes"]["role"], "CALLEE");
+    assert_eq!(call["children"][1]["attributes"]["role"], "ARG");
+    assert_eq!(call["children"][2]["attributes"]["value"], "nil");
+  }
+
+  #[test]
+  fn test_stack_allocation() {
+    let block = &print("{ var a = 1; print a; }")["children"][0];
+
+    assert_eq!(
+      block["children"][1]["children"][0]["attributes"],
+      json!({ "name": "a", "allocation": "STACK", "stack slot": 0 })
+    );
+  }
+
+  #[test]
+  fn test_stack_slots_match_the_client() {
+    let block = &print("{ var x; { var y; } var z; }")["children"][0];
+    let slot = |node: &Value| node["attributes"]["stack slot"].clone();
+
+    assert_eq!(slot(&block["children"][0]), json!(0));
+    assert_eq!(slot(&block["children"][1]["children"][0]), json!(1));
+    assert_eq!(slot(&block["children"][2]), json!(1));
+
+    // Captured locals aren't on the stack, so they don't take a slot
+    let f = &print("fun f() { var x = 1; fun g() { return x; } var z = 2; }")["children"][0];
+    assert_eq!(slot(&f["children"][2]), json!(1));
+
+    // Parameters come first
+    let f = &print("fun f(a, b) { { var x; } var y; }")["children"][0];
+    assert_eq!(slot(&f["children"][0]["children"][0]), json!(2));
+    assert_eq!(slot(&f["children"][1]), json!(2));
+
+    let f = &print("fun f(a, b) { print b; }")["children"][0];
+    assert_eq!(slot(&f["children"][0]["children"][0]), json!(1));
+  }
+
+  #[test]
+  fn test_heap_allocation() {
+    let outer = &print("fun outer() { var a = 1; fun inner() { a = 2; } }")["children"][0];
+    let assign = &outer["children"][1]["children"][0]["children"][0];
+
+    assert_eq!(
+      assign["attributes"],
+      json!({ "name": "a", "allocation": "HEAP", "heap slot": 0 })
+    );
+  }
+
+  #[test]
+  fn test_captured_declaration_on_heap() {
+    let outer = &print("fun outer() { var a = 1; fun inner() { return a; } }")["children"][0];
+
+    assert_eq!(
+      outer["children"][0]["attributes"],
+      json!({ "name": "a", "allocation": "HEAP", "heap slot": 0 })
+    );
+  }
+
+  #[test]
+  fn test_adorned_group() {
+    let condition = &print("if ((true)) print 1;")["children"][0]["children"][0];
+
+    assert_eq!(condition["name"], "GROUP");
+    assert_eq!(condition["attributes"], json!({ "role": "CONDITION" }));
+  }
+}
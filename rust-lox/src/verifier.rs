@@ -0,0 +1,520 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::chunk::{CompiledFunction, Constant, OpCode};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VerifyError {
+  // As the disassembler names it
+  pub function: String,
+  pub offset: usize,
+  pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "verify error: {} at offset {}: {}",
+      self.function, self.offset, self.message
+    )
+  }
+}
+
+// What an instruction does to the stack and where execution goes after it
+struct Decoded {
+  op: OpCode,
+  next: usize,
+  // Values it takes off the stack and puts back
+  pops: usize,
+  pushes: usize,
+  // Local slots it reads, which have to be below the depth before it runs
+  slots: Vec<usize>,
+  // Local slots a closure captures. The VM captures before it pushes the
+  // closure, so a local function can capture the slot it's about to go in.
+  captures: Vec<usize>,
+  target: Option<usize>,
+}
+
+// Checks a function and every function in its constants before the VM runs
+// them. Code that passes can't make the VM index out of bounds: operands and
+// constants are there and of the right kind, jumps land on instructions,
+// locals are below the top of the stack and every path reaching an
+// instruction agrees on how deep the stack is there. The types of the values
+// on the stack aren't known here, the VM checks those as it runs and reports
+// wrong ones as runtime errors.
+pub fn verify(function: &CompiledFunction) -> Result<(), VerifyError> {
+  let instructions = decode(function)?;
+  let error = |offset: usize, message: String| VerifyError {
+    function: function.to_string(),
+    offset,
+    message,
+  };
+
+  // Stack depth before each instruction, counting slot zero and the
+  // arguments, filled in as paths reach it
+  let mut depths: Vec<Option<usize>> = vec![None; function.chunk.len()];
+  let mut pending = vec![(0, function.arity + 1)];
+
+  while let Some((offset, depth)) = pending.pop() {
+    match depths[offset] {
+      Some(existing) if existing == depth => continue,
+      Some(existing) => {
+        return Err(error(
+          offset,
+          format!("stack depth {} here, {} on another path", depth, existing),
+        ))
+      }
+      None => depths[offset] = Some(depth),
+    }
+
+    let instruction = &instructions[&offset];
+
+    for slot in &instruction.slots {
+      if *slot >= depth {
+        return Err(error(
+          offset,
+          format!("local slot {} with a stack depth of {}", slot, depth),
+        ));
+      }
+    }
+
+    for slot in &instruction.captures {
+      if *slot > depth {
+        return Err(error(
+          offset,
+          format!("capture of local slot {} with a stack depth of {}", slot, depth),
+        ));
+      }
+    }
+
+    // Slot zero belongs to the frame until it returns
+    if depth < instruction.pops + 1 {
+      return Err(error(offset, String::from("stack underflow")));
+    }
+
+    let after = depth - instruction.pops + instruction.pushes;
+
+    if instruction.op == OpCode::Return {
+      continue;
+    }
+
+    if instruction.op != OpCode::Jump && instruction.op != OpCode::Loop {
+      if instruction.next == function.chunk.len() {
+        return Err(error(
+          offset,
+          String::from("execution runs past the end of the code"),
+        ));
+      }
+
+      pending.push((instruction.next, after));
+    }
+
+    if let Some(target) = instruction.target {
+      pending.push((target, after));
+    }
+  }
+
+  for constant in &function.chunk.constants {
+    if let Constant::Function(inner) = constant {
+      verify(inner)?;
+    }
+  }
+
+  Ok(())
+}
+
+// Every instruction by offset, checking its operands on the way
+fn decode(function: &CompiledFunction) -> Result<BTreeMap<usize, Decoded>, VerifyError> {
+  let chunk = &function.chunk;
+  let code = &chunk.code;
+  let invalid = |offset: usize, message: &str| VerifyError {
+    function: function.to_string(),
+    offset,
+    message: String::from(message),
+  };
+  let operand = |offset: usize, index: usize| {
+    code
+      .get(offset + index)
+      .map(|byte| *byte as usize)
+      .ok_or_else(|| invalid(offset, "operand past the end of the code"))
+  };
+  let constant = |offset: usize, index: usize| {
+    chunk
+      .constants
+      .get(index)
+      .ok_or_else(|| invalid(offset, "constant index out of bounds"))
+  };
+
+  let mut instructions = BTreeMap::new();
+  let mut offset = 0;
+
+  while offset < code.len() {
+    let op = OpCode::try_from(code[offset]).map_err(|_| invalid(offset, "unknown opcode"))?;
+    let mut decoded = Decoded {
+      op,
+      next: offset + 1,
+      pops: 0,
+      pushes: 0,
+      slots: Vec::new(),
+      captures: Vec::new(),
+      target: None,
+    };
+
+    match op {
+      OpCode::Constant | OpCode::AddConstant => {
+        if let Constant::Function(_) = constant(offset, operand(offset, 1)?)? {
+          return Err(invalid(offset, "function loaded without a closure"));
+        }
+
+        decoded.next = offset + 2;
+      }
+      OpCode::ConstantLong => {
+        let index = operand(offset, 1)? << 16 | operand(offset, 2)? << 8 | operand(offset, 3)?;
+
+        if let Constant::Function(_) = constant(offset, index)? {
+          return Err(invalid(offset, "function loaded without a closure"));
+        }
+
+        decoded.next = offset + 4;
+      }
+      OpCode::DefineGlobal
+      | OpCode::SetGlobal
+      | OpCode::GetGlobal
+      | OpCode::Class
+      | OpCode::GetProperty
+      | OpCode::SetProperty
+      | OpCode::Method
+      | OpCode::GetSuper => {
+        match constant(offset, operand(offset, 1)?)? {
+          Constant::String(_) => (),
+          _ => return Err(invalid(offset, "name constant that isn't a string")),
+        }
+
+        decoded.next = offset + 2;
+      }
+      OpCode::Closure => {
+        let inner = match constant(offset, operand(offset, 1)?)? {
+          Constant::Function(inner) => inner,
+          _ => {
+            return Err(invalid(
+              offset,
+              "closure over a constant that isn't a function",
+            ))
+          }
+        };
+
+        if inner.name.is_none() {
+          return Err(invalid(offset, "closure over a script"));
+        }
+
+        for capture in 0..inner.upvalue_count {
+          let local = operand(offset, 2 + capture * 2)?;
+          let index = operand(offset, 3 + capture * 2)?;
+
+          match local {
+            1 => decoded.captures.push(index),
+            0 if index < function.upvalue_count => (),
+            _ => return Err(invalid(offset, "bad upvalue capture")),
+          }
+        }
+
+        decoded.next = offset + 2 + inner.upvalue_count * 2;
+      }
+      OpCode::SetUpvalue | OpCode::GetUpvalue => {
+        if operand(offset, 1)? >= function.upvalue_count {
+          return Err(invalid(offset, "upvalue index out of bounds"));
+        }
+
+        decoded.next = offset + 2;
+      }
+      OpCode::SetLocal | OpCode::GetLocal => {
+        decoded.slots.push(operand(offset, 1)?);
+        decoded.next = offset + 2;
+      }
+      OpCode::AddLocals => {
+        decoded.slots.push(operand(offset, 1)?);
+        decoded.slots.push(operand(offset, 2)?);
+        decoded.next = offset + 3;
+      }
+      OpCode::Call => {
+        decoded.pops = operand(offset, 1)? + 1;
+        decoded.next = offset + 2;
+      }
+      OpCode::JumpIfFalse | OpCode::Jump | OpCode::Loop => {
+        let jump = operand(offset, 1)? << 8 | operand(offset, 2)?;
+        let target = if op == OpCode::Loop {
+          (offset + 3)
+            .checked_sub(jump)
+            .ok_or_else(|| invalid(offset, "loop before the start of the code"))?
+        } else {
+          offset + 3 + jump
+        };
+
+        decoded.target = Some(target);
+        decoded.next = offset + 3;
+      }
+      _ => (),
+    }
+
+    let (pops, pushes) = match op {
+      OpCode::Constant
+      | OpCode::ConstantLong
+      | OpCode::Closure
+      | OpCode::GetGlobal
+      | OpCode::GetUpvalue
+      | OpCode::GetLocal
+      | OpCode::Nil
+      | OpCode::True
+      | OpCode::False
+      | OpCode::Class
+      | OpCode::AddLocals => (0, 1),
+      OpCode::DefineGlobal
+      | OpCode::Pop
+      | OpCode::Print
+      | OpCode::CloseUpvalue
+      | OpCode::Return => (1, 0),
+      OpCode::SetGlobal
+      | OpCode::SetUpvalue
+      | OpCode::SetLocal
+      | OpCode::Negate
+      | OpCode::Not
+      | OpCode::GetProperty
+      | OpCode::AddConstant
+      | OpCode::JumpIfFalse => (1, 1),
+      OpCode::Add
+      | OpCode::Subtract
+      | OpCode::Multiply
+      | OpCode::Divide
+      | OpCode::Equal
+      | OpCode::Greater
+      | OpCode::Less
+      | OpCode::SetProperty
+      | OpCode::Method
+      | OpCode::Inherit
+      | OpCode::GetSuper => (2, 1),
+      OpCode::Call => (decoded.pops, 1),
+      OpCode::Jump | OpCode::Loop => (0, 0),
+    };
+
+    decoded.pops = pops;
+    decoded.pushes = pushes;
+    instructions.insert(offset, decoded);
+    offset = instructions[&offset].next;
+  }
+
+  if offset > code.len() {
+    return Err(invalid(offset, "operand past the end of the code"));
+  }
+
+  if code.is_empty() {
+    return Err(invalid(0, "no code"));
+  }
+
+  for (offset, instruction) in &instructions {
+    if let Some(target) = instruction.target {
+      if !instructions.contains_key(&target) {
+        return Err(invalid(*offset, "jump that doesn't land on an instruction"));
+      }
+    }
+  }
+
+  Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::rc::Rc;
+
+  use super::*;
+  use crate::chunk;
+  use crate::compiler;
+  use crate::optimizer;
+  use crate::parser;
+  use crate::resolver;
+
+  fn compile(source: &str) -> Rc<CompiledFunction> {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    compiler::compile(&statements, &resolution).unwrap()
+  }
+
+  fn function(code: &[u8], constants: Vec<Constant>) -> CompiledFunction {
+    let mut function = CompiledFunction::default();
+    function.chunk.constants = constants;
+
+    for byte in code {
+      function.chunk.write(*byte, 1);
+    }
+
+    function
+  }
+
+  fn message(code: &[u8], constants: Vec<Constant>) -> String {
+    verify(&function(code, constants)).unwrap_err().message
+  }
+
+  #[test]
+  fn test_accepts_compiled_code() {
+    let root = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+
+    for directory in &["s1-web/test_suite", "26-gc/test", "13-inheritance/test_sources"] {
+      for entry in std::fs::read_dir(format!("{}/{}", root, directory)).unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.extension().is_some_and(|extension| extension == "lox") {
+          let script = compile(&std::fs::read_to_string(&path).unwrap());
+
+          assert_eq!(verify(&script), Ok(()), "{:?}", path);
+          assert_eq!(verify(&optimizer::optimize(&script)), Ok(()), "{:?}", path);
+        }
+      }
+    }
+
+    // A local function that refers to itself captures its own slot
+    assert_eq!(verify(&compile("{ fun f() { return f; } }")), Ok(()));
+  }
+
+  #[test]
+  fn test_rejects_bad_operands() {
+    use OpCode::*;
+
+    let number = || vec![chunk::Constant::Number(1.0)];
+    let string = || vec![chunk::Constant::String(Rc::from("name"))];
+
+    assert_eq!(
+      message(&[0xff, Nil as u8, Return as u8], number()),
+      "unknown opcode"
+    );
+    assert_eq!(
+      message(&[Nil as u8, Constant as u8], number()),
+      "operand past the end of the code"
+    );
+    assert_eq!(
+      message(&[Constant as u8, 1, Return as u8], number()),
+      "constant index out of bounds"
+    );
+    assert_eq!(
+      message(&[ConstantLong as u8, 0, 1, 0, Return as u8], number()),
+      "constant index out of bounds"
+    );
+    assert_eq!(
+      message(&[GetGlobal as u8, 0, Return as u8], number()),
+      "name constant that isn't a string"
+    );
+    assert_eq!(
+      message(&[Closure as u8, 0, Return as u8], string()),
+      "closure over a constant that isn't a function"
+    );
+    assert_eq!(
+      message(&[GetUpvalue as u8, 0, Return as u8], number()),
+      "upvalue index out of bounds"
+    );
+    assert_eq!(message(&[], number()), "no code");
+  }
+
+  #[test]
+  fn test_rejects_bad_jumps() {
+    use OpCode::*;
+
+    assert_eq!(
+      message(
+        &[Jump as u8, 0, 1, Constant as u8, 0, Return as u8],
+        vec![chunk::Constant::Number(1.0)]
+      ),
+      "jump that doesn't land on an instruction"
+    );
+    assert_eq!(
+      message(&[Jump as u8, 0, 9, Nil as u8, Return as u8], Vec::new()),
+      "jump that doesn't land on an instruction"
+    );
+    assert_eq!(
+      message(&[Loop as u8, 0, 9, Return as u8], Vec::new()),
+      "loop before the start of the code"
+    );
+  }
+
+  #[test]
+  fn test_rejects_bad_stack_use() {
+    use OpCode::*;
+
+    assert_eq!(
+      message(&[Pop as u8, Nil as u8, Return as u8], Vec::new()),
+      "stack underflow"
+    );
+    assert_eq!(
+      message(&[Nil as u8, Add as u8, Return as u8], Vec::new()),
+      "stack underflow"
+    );
+    assert_eq!(
+      message(&[Nil as u8, Call as u8, 1, Return as u8], Vec::new()),
+      "stack underflow"
+    );
+    assert_eq!(
+      message(&[GetLocal as u8, 1, Return as u8], Vec::new()),
+      "local slot 1 with a stack depth of 1"
+    );
+    assert_eq!(
+      message(
+        &[Nil as u8, AddLocals as u8, 0, 2, Return as u8],
+        Vec::new()
+      ),
+      "local slot 2 with a stack depth of 2"
+    );
+    assert_eq!(
+      message(&[Nil as u8, Print as u8], Vec::new()),
+      "execution runs past the end of the code"
+    );
+
+    // The false branch skips pushing nil, so the two paths meet at the return
+    // with different depths
+    assert_eq!(
+      message(
+        &[True as u8, JumpIfFalse as u8, 0, 1, Nil as u8, Return as u8],
+        Vec::new()
+      ),
+      "stack depth 3 here, 2 on another path"
+    );
+
+    // A loop that pushes on every iteration
+    assert_eq!(
+      message(&[Nil as u8, Loop as u8, 0, 4, Return as u8], Vec::new()),
+      "stack depth 2 here, 1 on another path"
+    );
+  }
+
+  #[test]
+  fn test_checks_nested_functions() {
+    use OpCode::*;
+
+    let mut inner = function(&[GetLocal as u8, 3, Return as u8], Vec::new());
+    inner.name = Some(Rc::from("inner"));
+    inner.arity = 2;
+
+    let script = function(
+      &[Closure as u8, 0, Pop as u8, Nil as u8, Return as u8],
+      vec![chunk::Constant::Function(Rc::new(inner))],
+    );
+
+    assert_eq!(
+      verify(&script).unwrap_err().to_string(),
+      "verify error: <fn inner> at offset 0: local slot 3 with a stack depth of 3"
+    );
+
+    let mut capturing = function(&[Nil as u8, Return as u8], Vec::new());
+    capturing.name = Some(Rc::from("capturing"));
+    capturing.upvalue_count = 1;
+
+    let script = function(
+      &[Closure as u8, 0, 1, 4, Pop as u8, Nil as u8, Return as u8],
+      vec![chunk::Constant::Function(Rc::new(capturing))],
+    );
+
+    assert_eq!(
+      verify(&script).unwrap_err().message,
+      "capture of local slot 4 with a stack depth of 1"
+    );
+  }
+}
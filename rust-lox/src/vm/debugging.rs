@@ -0,0 +1,247 @@
+use std::io::Write;
+
+use super::{CallFrame, Limit, ObjRef, Object, Unpacked, Upvalue, Value, Vm, VmError};
+use crate::chunk::LocalName;
+use crate::debugger::{Debugger, Inspected, Paused, Scope, ScopeKind, Variable};
+use crate::error::StackFrame;
+
+impl<W: Write> Vm<W> {
+  // Starts debugging, or stops it with None. Gives back the debugger there was.
+  pub fn set_debugger(&mut self, debugger: Option<Debugger>) -> Option<Debugger> {
+    std::mem::replace(&mut self.debugger, debugger)
+  }
+
+  // Before every instruction while debugging, tells the debugger about the
+  // ones that start a line. Ending the program from the debugger cancels it.
+  pub(super) fn debug(&mut self) -> Result<(), VmError> {
+    let (line, starts_line) = match self.frames.last() {
+      Some(frame) => {
+        let chunk = &frame.function.chunk;
+        let line = chunk.line_at(frame.ip);
+
+        (line, frame.ip == 0 || chunk.line_at(frame.ip - 1) != line)
+      }
+      None => return Ok(()),
+    };
+
+    if !starts_line {
+      return Ok(());
+    }
+
+    let mut debugger = match self.debugger.take() {
+      Some(debugger) => debugger,
+      None => return Ok(()),
+    };
+
+    let depth = self.frames.len();
+    let result = debugger.reached(
+      line,
+      depth,
+      &mut Inspector {
+        vm: self,
+        handles: Vec::new(),
+      },
+    );
+    self.debugger = Some(debugger);
+
+    result.map_err(|_| self.exceeded(Limit::Cancelled))
+  }
+}
+
+// The VM as the debugger sees it while it's stopped
+struct Inspector<'v, W: Write> {
+  vm: &'v Vm<W>,
+  // Objects handed out so far, by handle
+  handles: Vec<ObjRef>,
+}
+
+impl<'v, W: Write> Inspector<'v, W> {
+  fn frame(&self, frame: usize) -> &'v CallFrame {
+    &self.vm.frames[self.vm.frames.len() - 1 - frame]
+  }
+
+  // In scope where the frame is, innermost last. Shadowed ones are left out.
+  fn live_locals(&self, frame: usize) -> Vec<&'v LocalName> {
+    let frame = self.frame(frame);
+    let mut live: Vec<&LocalName> = Vec::new();
+
+    for local in frame.function.locals.iter().rev() {
+      if local.start <= frame.ip
+        && frame.ip < local.end
+        && !live.iter().any(|other| other.name == local.name)
+      {
+        live.push(local);
+      }
+    }
+
+    live.reverse();
+    live
+  }
+
+  fn local(&self, frame: usize, local: &LocalName) -> Option<Value> {
+    self
+      .vm
+      .stack
+      .get(self.frame(frame).base + local.slot)
+      .copied()
+  }
+
+  // Named as the compiler named them, by index for code without names
+  fn upvalues(&self, frame: usize) -> Vec<(String, Value)> {
+    let frame = self.frame(frame);
+    let heap = &self.vm.heap;
+
+    heap
+      .closure(frame.closure)
+      .upvalues
+      .iter()
+      .enumerate()
+      .map(|(index, upvalue)| {
+        let name = match frame.function.upvalue_names.get(index) {
+          Some(name) => name.to_string(),
+          None => format!("upvalue {}", index),
+        };
+
+        let value = match heap.upvalue(*upvalue) {
+          Upvalue::Open(slot) => self.vm.stack[*slot],
+          Upvalue::Closed(closed) => *closed,
+        };
+
+        (name, value)
+      })
+      .collect()
+  }
+
+  fn inspect(&mut self, value: Value) -> Inspected {
+    let heap = &self.vm.heap;
+
+    match value.unpack() {
+      Unpacked::Nil => Inspected::Nil,
+      Unpacked::Bool(boolean) => Inspected::Bool(boolean),
+      Unpacked::Number(number) => Inspected::Number(number),
+      Unpacked::Object(reference) => match heap.get(reference) {
+        Object::String(string) => Inspected::String(string.clone()),
+        object => {
+          let has_fields =
+            matches!(object, Object::Instance(instance) if !instance.fields.is_empty());
+
+          let handle = match self.handles.iter().position(|handle| *handle == reference) {
+            Some(handle) => handle,
+            None => {
+              self.handles.push(reference);
+              self.handles.len() - 1
+            }
+          };
+
+          Inspected::Object {
+            text: heap.display(value).to_string(),
+            handle,
+            has_fields,
+          }
+        }
+      },
+    }
+  }
+
+  fn variables(&mut self, variables: Vec<(String, Value)>) -> Vec<Variable> {
+    variables
+      .into_iter()
+      .map(|(name, value)| Variable {
+        name,
+        value: self.inspect(value),
+      })
+      .collect()
+  }
+}
+
+impl<'v, W: Write> Paused for Inspector<'v, W> {
+  fn frames(&self) -> Vec<StackFrame> {
+    self
+      .vm
+      .frames
+      .iter()
+      .rev()
+      .enumerate()
+      .map(|(index, frame)| {
+        // Frames that made a call are past the instruction that did it
+        let offset = if index == 0 { frame.ip } else { frame.ip - 1 };
+
+        StackFrame {
+          function: frame.function.name.as_ref().map(|name| name.to_string()),
+          line: frame.function.chunk.line_at(offset),
+        }
+      })
+      .collect()
+  }
+
+  fn scopes(&mut self, frame: usize) -> Vec<Scope> {
+    let locals = self
+      .live_locals(frame)
+      .into_iter()
+      .filter_map(|local| Some((local.name.to_string(), self.local(frame, local)?)))
+      .collect();
+
+    let upvalues = self.upvalues(frame);
+
+    let mut globals: Vec<_> = self
+      .vm
+      .globals
+      .iter()
+      .map(|(name, value)| (name.to_string(), *value))
+      .collect();
+    globals.sort_by(|left, right| left.0.cmp(&right.0));
+
+    vec![
+      Scope {
+        kind: ScopeKind::Locals,
+        variables: self.variables(locals),
+      },
+      Scope {
+        kind: ScopeKind::Upvalues,
+        variables: self.variables(upvalues),
+      },
+      Scope {
+        kind: ScopeKind::Globals,
+        variables: self.variables(globals),
+      },
+    ]
+  }
+
+  fn look_up(&mut self, frame: usize, name: &str) -> Option<Inspected> {
+    let local = self
+      .live_locals(frame)
+      .into_iter()
+      .find(|local| &*local.name == name)
+      .and_then(|local| self.local(frame, local));
+
+    let value = local
+      .or_else(|| {
+        self
+          .upvalues(frame)
+          .into_iter()
+          .find(|(upvalue, _)| upvalue == name)
+          .map(|(_, value)| value)
+      })
+      .or_else(|| self.vm.globals.get(name).copied())?;
+
+    Some(self.inspect(value))
+  }
+
+  fn fields(&mut self, handle: usize) -> Vec<Variable> {
+    let mut fields: Vec<_> = match self
+      .handles
+      .get(handle)
+      .map(|handle| self.vm.heap.get(*handle))
+    {
+      Some(Object::Instance(instance)) => instance
+        .fields
+        .iter()
+        .map(|(name, value)| (name.to_string(), *value))
+        .collect(),
+      _ => Vec::new(),
+    };
+
+    fields.sort_by(|left, right| left.0.cmp(&right.0));
+    self.variables(fields)
+  }
+}
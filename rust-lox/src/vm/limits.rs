@@ -0,0 +1,403 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::Vm;
+use crate::error::RuntimeError;
+use crate::verifier::VerifyError;
+
+// How often the clock gets looked at, reading it every instruction costs more
+// than the instruction
+const CLOCK_INTERVAL: u64 = 1024;
+
+// What a run is allowed to use, None for no limit. Every run started with
+// start or interpret gets the full allowance again.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Limits {
+  pub instructions: Option<u64>,
+  // Wall clock, counted from start so time between steps counts too
+  pub time: Option<Duration>,
+  // Frames below the script's. Past MAX_FRAMES it's a stack overflow either way.
+  pub call_depth: Option<usize>,
+  // Checked between instructions, after a collection to make sure it's not
+  // garbage that's over
+  pub heap_bytes: Option<usize>,
+  // What print writes, newlines included
+  pub output_bytes: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Limit {
+  Instructions(u64),
+  Time(Duration),
+  CallDepth(usize),
+  HeapBytes(usize),
+  OutputBytes(usize),
+  Cancelled,
+}
+
+impl fmt::Display for Limit {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Limit::Instructions(count) => write!(f, "Ran more than {} instructions", count),
+      Limit::Time(time) => write!(f, "Ran longer than {:?}", time),
+      Limit::CallDepth(depth) => write!(f, "Called more than {} functions deep", depth),
+      Limit::HeapBytes(bytes) => write!(f, "Allocated more than {} bytes", bytes),
+      Limit::OutputBytes(bytes) => write!(f, "Printed more than {} bytes", bytes),
+      Limit::Cancelled => write!(f, "Cancelled"),
+    }
+  }
+}
+
+// The run was stopped from outside rather than failing on its own
+#[derive(Debug, PartialEq, Clone)]
+pub struct LimitExceeded {
+  pub limit: Limit,
+  // Of the instruction the run stopped at
+  pub line: u32,
+}
+
+impl fmt::Display for LimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "limit exceeded. line: {} - error: {}",
+      self.line, self.limit
+    )
+  }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+  Runtime(RuntimeError),
+  LimitExceeded(LimitExceeded),
+  // The script was turned down by the verifier before it started
+  InvalidCode(VerifyError),
+}
+
+impl From<RuntimeError> for VmError {
+  fn from(error: RuntimeError) -> VmError {
+    VmError::Runtime(error)
+  }
+}
+
+impl fmt::Display for VmError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      VmError::Runtime(error) => error.fmt(f),
+      VmError::LimitExceeded(exceeded) => exceeded.fmt(f),
+      VmError::InvalidCode(error) => error.fmt(f),
+    }
+  }
+}
+
+// Stops a VM running on another thread. The VM notices before its next
+// instruction and fails the run with Limit::Cancelled.
+#[derive(Debug, Default, Clone)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+  pub fn cancel(&self) {
+    self.0.store(true, Ordering::Relaxed);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+// What the current run has used so far
+#[derive(Debug)]
+pub(super) struct Usage {
+  instructions: u64,
+  started: Instant,
+  output_bytes: usize,
+}
+
+impl Default for Usage {
+  fn default() -> Usage {
+    Usage {
+      instructions: 0,
+      started: Instant::now(),
+      output_bytes: 0,
+    }
+  }
+}
+
+impl<W: Write> Vm<W> {
+  pub fn set_limits(&mut self, limits: Limits) {
+    self.limits = limits;
+    self.update_limited();
+  }
+
+  pub fn limits(&self) -> &Limits {
+    &self.limits
+  }
+
+  // All handles cancel the same run. Cancelling between runs stops the next
+  // one before its first instruction.
+  pub fn cancel_handle(&mut self) -> CancelHandle {
+    let handle = self
+      .cancel
+      .get_or_insert_with(CancelHandle::default)
+      .clone();
+    self.update_limited();
+    handle
+  }
+
+  fn update_limited(&mut self) {
+    self.limited = self.limits != Limits::default() || self.cancel.is_some();
+  }
+
+  pub(super) fn reset_usage(&mut self) {
+    self.usage = Usage::default();
+  }
+
+  // Before every instruction when there are limits
+  pub(super) fn check_limits(&mut self) -> Result<(), VmError> {
+    self.usage.instructions += 1;
+
+    if let Some(cancel) = &self.cancel {
+      if cancel.0.swap(false, Ordering::Relaxed) {
+        return Err(self.exceeded(Limit::Cancelled));
+      }
+    }
+
+    if let Some(instructions) = self.limits.instructions {
+      if self.usage.instructions > instructions {
+        return Err(self.exceeded(Limit::Instructions(instructions)));
+      }
+    }
+
+    if let Some(time) = self.limits.time {
+      if self.usage.instructions % CLOCK_INTERVAL == 1 && self.usage.started.elapsed() > time {
+        return Err(self.exceeded(Limit::Time(time)));
+      }
+    }
+
+    if let Some(bytes) = self.limits.heap_bytes {
+      if self.heap.bytes_allocated() > bytes {
+        self.collect_garbage();
+
+        if self.heap.bytes_allocated() > bytes {
+          return Err(self.exceeded(Limit::HeapBytes(bytes)));
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  // Before a call pushes its frame
+  pub(super) fn check_call_depth(&mut self) -> Result<(), VmError> {
+    match self.limits.call_depth {
+      Some(depth) if self.frames.len() > depth => Err(self.exceeded(Limit::CallDepth(depth))),
+      _ => Ok(()),
+    }
+  }
+
+  // Before print writes anything
+  pub(super) fn count_output(&mut self, bytes: usize) -> Result<(), VmError> {
+    self.usage.output_bytes += bytes;
+
+    match self.limits.output_bytes {
+      Some(limit) if self.usage.output_bytes > limit => {
+        Err(self.exceeded(Limit::OutputBytes(limit)))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  // Unwinds everything like a runtime error does, so the VM is ready for the
+  // next script
+  pub(super) fn exceeded(&mut self, limit: Limit) -> VmError {
+    let line = self.frames.last().map_or(0, |frame| {
+      frame.function.chunk.line_at(frame.ip.saturating_sub(1))
+    });
+
+    self.unwind();
+
+    VmError::LimitExceeded(LimitExceeded { limit, line })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use super::*;
+  use crate::compiler;
+  use crate::parser;
+  use crate::resolver;
+
+  fn run(vm: &mut Vm<Vec<u8>>, source: &str) -> Result<(), VmError> {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    vm.interpret(compiler::compile(&statements, &resolution).unwrap())
+  }
+
+  fn limited(limits: Limits, source: &str) -> (String, Option<Limit>) {
+    let mut vm = Vm::new(Vec::new());
+    vm.set_limits(limits);
+
+    let limit = match run(&mut vm, source) {
+      Ok(()) => None,
+      Err(VmError::LimitExceeded(exceeded)) => Some(exceeded.limit),
+      Err(error) => panic!("unexpected {}", error),
+    };
+
+    (String::from_utf8(vm.into_output()).unwrap(), limit)
+  }
+
+  #[test]
+  fn test_instructions() {
+    let limits = Limits {
+      instructions: Some(1000),
+      ..Limits::default()
+    };
+
+    assert_eq!(
+      limited(limits.clone(), "while (true) {}"),
+      (String::new(), Some(Limit::Instructions(1000)))
+    );
+    assert_eq!(limited(limits, "print 1;").1, None);
+  }
+
+  #[test]
+  fn test_time() {
+    let limits = Limits {
+      time: Some(Duration::from_millis(50)),
+      ..Limits::default()
+    };
+
+    assert_eq!(
+      limited(limits, "while (true) {}").1,
+      Some(Limit::Time(Duration::from_millis(50)))
+    );
+  }
+
+  #[test]
+  fn test_call_depth() {
+    let limits = Limits {
+      call_depth: Some(10),
+      ..Limits::default()
+    };
+
+    // f(9) is ten calls deep
+    let source = "fun f(n) {\n  if (n > 0) f(n - 1);\n}\nf(9);\nprint 1;\nf(10);\nprint 2;";
+
+    assert_eq!(
+      limited(limits, source),
+      (String::from("1.0\n"), Some(Limit::CallDepth(10)))
+    );
+  }
+
+  #[test]
+  fn test_heap_bytes() {
+    let limits = Limits {
+      heap_bytes: Some(100_000),
+      ..Limits::default()
+    };
+
+    // Garbage doesn't count
+    let garbage = "class A {}\nfor (var i = 0; i < 10000; i = i + 1) {\n  var a = A();\n}";
+    assert_eq!(limited(limits.clone(), garbage).1, None);
+
+    let growing = "var s = \"a\";\nwhile (true) s = s + s;";
+    assert_eq!(limited(limits, growing).1, Some(Limit::HeapBytes(100_000)));
+  }
+
+  #[test]
+  fn test_output_bytes() {
+    let limits = Limits {
+      output_bytes: Some(10),
+      ..Limits::default()
+    };
+
+    // Nothing of the print that goes over gets written
+    assert_eq!(
+      limited(limits, "print 1;\nprint 2;\nprint 3;"),
+      (String::from("1.0\n2.0\n"), Some(Limit::OutputBytes(10)))
+    );
+  }
+
+  #[test]
+  fn test_line() {
+    let mut vm = Vm::new(Vec::new());
+    vm.set_limits(Limits {
+      instructions: Some(5),
+      ..Limits::default()
+    });
+
+    let error = run(&mut vm, "var a = 1;\nvar b = 2;\nvar c = 3;").unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "limit exceeded. line: 3 - error: Ran more than 5 instructions"
+    );
+  }
+
+  #[test]
+  fn test_cancel() {
+    let mut vm = Vm::new(Vec::new());
+    let handle = vm.cancel_handle();
+
+    let canceller = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      handle.cancel();
+    });
+
+    let error = run(&mut vm, "while (true) {}").unwrap_err();
+    canceller.join().unwrap();
+
+    assert!(matches!(
+      error,
+      VmError::LimitExceeded(LimitExceeded {
+        limit: Limit::Cancelled,
+        ..
+      })
+    ));
+
+    // Only that run is cancelled
+    run(&mut vm, "print 1;").unwrap();
+    assert_eq!(vm.output(), b"1.0\n");
+  }
+
+  #[test]
+  fn test_reset_between_runs() {
+    let mut vm = Vm::new(Vec::new());
+    vm.set_limits(Limits {
+      instructions: Some(100),
+      output_bytes: Some(4),
+      ..Limits::default()
+    });
+
+    for _ in 0..3 {
+      run(&mut vm, "for (var i = 0; i < 5; i = i + 1) {}\nprint 1;").unwrap();
+    }
+
+    assert_eq!(vm.output(), b"1.0\n1.0\n1.0\n");
+  }
+
+  #[test]
+  fn test_escaped_closure_after_limit() {
+    let mut vm = Vm::new(Vec::new());
+    vm.set_limits(Limits {
+      instructions: Some(1000),
+      ..Limits::default()
+    });
+
+    let error = run(
+      &mut vm,
+      "var g; fun f() { var a = 1; fun h() { return a; } g = h; while (true) {} } f();",
+    )
+    .unwrap_err();
+    assert!(matches!(error, VmError::LimitExceeded(_)));
+
+    run(&mut vm, "print g();").unwrap();
+    assert_eq!(vm.output(), b"1.0\n");
+  }
+}
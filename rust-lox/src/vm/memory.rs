@@ -0,0 +1,549 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::value::{Class, Closure, Instance, Object, Unpacked, Upvalue, Value};
+use crate::interpreter::format_number;
+use crate::native::NativeValue;
+
+// GC_HEAP_GROW_FACTOR in the book
+const GROW_FACTOR: usize = 2;
+// Where clox starts, and the least the threshold drops to after a collection
+const DEFAULT_THRESHOLD: usize = 1024 * 1024;
+// Objects traced or slots swept by each step of an incremental collection
+const STEP_SIZE: usize = 256;
+
+// Handle to an object in the heap. Only valid while the object is reachable,
+// the slot gets reused once it's swept.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ObjRef(pub(super) u32);
+
+struct Entry {
+  object: Object,
+  marked: bool,
+  // What the entry counted for in bytes_allocated
+  size: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum GcMode {
+  // Mark and sweep the whole heap in one go, like clox
+  StopTheWorld,
+  // Spread each collection over many allocations. Stores into objects that
+  // are already marked go through a write barrier, and the roots get marked
+  // again at the end of marking since the stack changes without one.
+  Incremental,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Phase {
+  Idle,
+  Marking,
+  // Slots from the cursor on haven't been swept yet
+  Sweeping(usize),
+}
+
+// Each pause is the time the program was stopped for one collection or one
+// step of one
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct GcStats {
+  pub collections: usize,
+  pub pauses: usize,
+  pub total_pause: Duration,
+  pub max_pause: Duration,
+  // Most bytes allocated at any one time
+  pub peak_bytes: usize,
+}
+
+// The VM's managed heap. Roots are marked by the VM, the heap traces from
+// them and sweeps whatever stayed white.
+pub struct Heap {
+  entries: Vec<Option<Entry>>,
+  free: Vec<u32>,
+  gray: Vec<ObjRef>,
+  // Every string object, vm.strings in clox. Doesn't keep them alive.
+  strings: HashMap<Rc<str>, ObjRef>,
+  bytes_allocated: usize,
+  next_gc: usize,
+  threshold: usize,
+  // Collect on every allocation, DEBUG_STRESS_GC in clox
+  stress: bool,
+  mode: GcMode,
+  phase: Phase,
+  stats: GcStats,
+}
+
+impl Heap {
+  pub fn new() -> Heap {
+    Heap {
+      entries: Vec::new(),
+      free: Vec::new(),
+      gray: Vec::new(),
+      strings: HashMap::new(),
+      bytes_allocated: 0,
+      next_gc: DEFAULT_THRESHOLD,
+      threshold: DEFAULT_THRESHOLD,
+      stress: false,
+      mode: GcMode::StopTheWorld,
+      phase: Phase::Idle,
+      stats: GcStats::default(),
+    }
+  }
+
+  pub fn bytes_allocated(&self) -> usize {
+    self.bytes_allocated
+  }
+
+  pub fn next_gc(&self) -> usize {
+    self.next_gc
+  }
+
+  pub fn stats(&self) -> &GcStats {
+    &self.stats
+  }
+
+  pub fn mode(&self) -> GcMode {
+    self.mode
+  }
+
+  // Switching in the middle of a collection is fine, the new mode picks it up
+  pub fn set_mode(&mut self, mode: GcMode) {
+    self.mode = mode;
+  }
+
+  // Live objects, reachable or not yet swept
+  pub fn len(&self) -> usize {
+    self.entries.len() - self.free.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn set_stress(&mut self, stress: bool) {
+    self.stress = stress;
+  }
+
+  // Lowest the next collection can be scheduled at
+  pub fn set_threshold(&mut self, bytes: usize) {
+    self.threshold = bytes;
+    self.next_gc = cmp::max(self.bytes_allocated * GROW_FACTOR, bytes);
+  }
+
+  pub fn should_collect(&self) -> bool {
+    self.stress || self.phase != Phase::Idle || self.bytes_allocated > self.next_gc
+  }
+
+  // The string object with these contents, if there's one
+  pub fn find_string(&mut self, string: &str) -> Option<ObjRef> {
+    let reference = *self.strings.get(string)?;
+    // It may have been unreachable until now and still unmarked
+    self.write_barrier(Value::object(reference));
+
+    Some(reference)
+  }
+
+  pub fn interned_strings(&self) -> usize {
+    self.strings.len()
+  }
+
+  // Strings have to be looked up with `find_string` first, there can only be
+  // one object for each
+  pub fn insert(&mut self, object: Object) -> ObjRef {
+    let index = match self.free.pop() {
+      Some(index) => index as usize,
+      None => {
+        self.entries.push(None);
+        self.entries.len() - 1
+      }
+    };
+
+    // Objects made during a collection survive it
+    let marked = match self.phase {
+      Phase::Idle => false,
+      Phase::Marking => {
+        self.mark_references(&object);
+        true
+      }
+      // Sweeping clears the mark of the ones it has yet to get to
+      Phase::Sweeping(cursor) => index >= cursor,
+    };
+    let size = size_of_object(&object);
+    let reference = ObjRef(index as u32);
+
+    if let Object::String(string) = &object {
+      self.strings.insert(Rc::clone(string), reference);
+    }
+
+    self.bytes_allocated += size;
+    self.stats.peak_bytes = cmp::max(self.stats.peak_bytes, self.bytes_allocated);
+    self.entries[index] = Some(Entry {
+      object,
+      marked,
+      size,
+    });
+
+    reference
+  }
+
+  pub fn get(&self, reference: ObjRef) -> &Object {
+    &self.entry(reference).object
+  }
+
+  pub fn get_mut(&mut self, reference: ObjRef) -> &mut Object {
+    &mut self.entry_mut(reference).object
+  }
+
+  // Objects growing in place, like instances getting new fields, have to
+  // report it for the heuristics to see
+  pub fn resize(&mut self, reference: ObjRef) {
+    let entry = self.entry_mut(reference);
+    let size = size_of_object(&entry.object);
+
+    let previous = mem::replace(&mut entry.size, size);
+
+    self.bytes_allocated = self.bytes_allocated - previous + size;
+    self.stats.peak_bytes = cmp::max(self.stats.peak_bytes, self.bytes_allocated);
+  }
+
+  pub fn mark_value(&mut self, value: Value) {
+    if let Some(reference) = value.as_object() {
+      self.mark_object(reference);
+    }
+  }
+
+  // Objects are marked when they come off the gray stack. Ones marked already
+  // are left out, or pushing them over and over would keep marking going.
+  pub fn mark_object(&mut self, reference: ObjRef) {
+    if !self.entry(reference).marked {
+      self.gray.push(reference);
+    }
+  }
+
+  // For an object that's about to be allocated, its references aren't
+  // reachable from any root yet
+  pub fn mark_references(&mut self, object: &Object) {
+    references(object, |reference| self.mark_object(reference));
+  }
+
+  // Has to be called when a value is stored into an object, so objects
+  // already marked in this collection don't hide it from the tracing
+  pub fn write_barrier(&mut self, value: Value) {
+    if self.phase == Phase::Marking {
+      self.mark_value(value);
+    }
+  }
+
+  // Marks everything reachable from the roots, then frees the rest, no matter
+  // the mode. `mark_roots` gets called once the heap is ready for them.
+  pub fn collect(&mut self, mark_roots: impl FnOnce(&mut Heap)) {
+    let start = Instant::now();
+
+    match self.phase {
+      Phase::Idle => {}
+      // Whatever got marked may have become garbage since, start over
+      Phase::Marking => {
+        self.gray.clear();
+        self
+          .entries
+          .iter_mut()
+          .flatten()
+          .for_each(|entry| entry.marked = false);
+      }
+      Phase::Sweeping(cursor) => {
+        self.sweep(cursor, usize::MAX);
+        self.finish_collection();
+      }
+    }
+
+    mark_roots(self);
+    self.trace_references(usize::MAX);
+    self.remove_white_strings();
+    self.sweep(0, usize::MAX);
+    self.finish_collection();
+
+    self.record_pause(start.elapsed());
+  }
+
+  // One slice of collecting in incremental mode, a whole collection otherwise
+  pub fn step(&mut self, mark_roots: impl FnOnce(&mut Heap)) {
+    if self.mode == GcMode::StopTheWorld {
+      return self.collect(mark_roots);
+    }
+
+    let start = Instant::now();
+    let mut mark_roots = Some(mark_roots);
+
+    match self.phase {
+      Phase::Idle => {
+        self.phase = Phase::Marking;
+        (mark_roots.take().unwrap())(self);
+        self.trace_references(STEP_SIZE);
+      }
+      Phase::Marking => self.trace_references(STEP_SIZE),
+      Phase::Sweeping(cursor) => {
+        let cursor = self.sweep(cursor, STEP_SIZE);
+
+        if cursor == self.entries.len() {
+          self.finish_collection();
+        } else {
+          self.phase = Phase::Sweeping(cursor);
+        }
+      }
+    }
+
+    // Out of gray objects, whatever the roots got since they were marked is
+    // traced in this same step so marking can't go on forever
+    if self.phase == Phase::Marking && self.gray.is_empty() {
+      if let Some(mark_roots) = mark_roots {
+        mark_roots(self);
+        self.trace_references(usize::MAX);
+      }
+
+      self.remove_white_strings();
+      self.phase = Phase::Sweeping(0);
+    }
+
+    self.record_pause(start.elapsed());
+  }
+
+  // tableRemoveWhite, the table can't be left pointing at swept strings
+  fn remove_white_strings(&mut self) {
+    let entries = &self.entries;
+
+    self
+      .strings
+      .retain(|_, reference| matches!(&entries[reference.0 as usize], Some(entry) if entry.marked));
+  }
+
+  fn finish_collection(&mut self) {
+    self.phase = Phase::Idle;
+    self.next_gc = cmp::max(self.bytes_allocated * GROW_FACTOR, self.threshold);
+    self.stats.collections += 1;
+  }
+
+  fn record_pause(&mut self, pause: Duration) {
+    self.stats.pauses += 1;
+    self.stats.total_pause += pause;
+    self.stats.max_pause = cmp::max(self.stats.max_pause, pause);
+  }
+
+  // Marks gray objects until the stack is empty or `budget` of them are done
+  fn trace_references(&mut self, budget: usize) {
+    let Heap { entries, gray, .. } = self;
+    let mut traced = 0;
+
+    while traced < budget {
+      let reference = match gray.pop() {
+        Some(reference) => reference,
+        None => break,
+      };
+      let entry = match &mut entries[reference.0 as usize] {
+        Some(entry) if !entry.marked => entry,
+        Some(_) => continue,
+        None => panic!("[bug] {:?} reachable after it was swept", reference),
+      };
+
+      entry.marked = true;
+      references(&entry.object, |reference| gray.push(reference));
+      traced += 1;
+    }
+  }
+
+  // Sweeps up to `budget` slots from `from` on, returns where it stopped
+  fn sweep(&mut self, from: usize, budget: usize) -> usize {
+    let to = cmp::min(self.entries.len(), from.saturating_add(budget));
+
+    for index in from..to {
+      match &mut self.entries[index] {
+        Some(entry) if entry.marked => entry.marked = false,
+        Some(entry) => {
+          self.bytes_allocated -= entry.size;
+          self.free.push(index as u32);
+          self.entries[index] = None;
+        }
+        None => {}
+      }
+    }
+
+    to
+  }
+
+  fn entry(&self, reference: ObjRef) -> &Entry {
+    match &self.entries[reference.0 as usize] {
+      Some(entry) => entry,
+      None => panic!("[bug] {:?} used after it was swept", reference),
+    }
+  }
+
+  fn entry_mut(&mut self, reference: ObjRef) -> &mut Entry {
+    match &mut self.entries[reference.0 as usize] {
+      Some(entry) => entry,
+      None => panic!("[bug] {:?} used after it was swept", reference),
+    }
+  }
+
+  pub fn string(&self, value: Value) -> Option<&Rc<str>> {
+    match value.as_object() {
+      Some(reference) => match self.get(reference) {
+        Object::String(string) => Some(string),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+
+  pub fn closure(&self, reference: ObjRef) -> &Closure {
+    match self.get(reference) {
+      Object::Closure(closure) => closure,
+      object => panic!("[bug] expected a closure, got {:?}", object),
+    }
+  }
+
+  pub fn class(&self, reference: ObjRef) -> &Class {
+    match self.get(reference) {
+      Object::Class(class) => class,
+      object => panic!("[bug] expected a class, got {:?}", object),
+    }
+  }
+
+  pub fn class_mut(&mut self, reference: ObjRef) -> &mut Class {
+    match self.get_mut(reference) {
+      Object::Class(class) => class,
+      object => panic!("[bug] expected a class, got {:?}", object),
+    }
+  }
+
+  pub fn instance(&self, reference: ObjRef) -> &Instance {
+    match self.get(reference) {
+      Object::Instance(instance) => instance,
+      object => panic!("[bug] expected an instance, got {:?}", object),
+    }
+  }
+
+  pub fn instance_mut(&mut self, reference: ObjRef) -> &mut Instance {
+    match self.get_mut(reference) {
+      Object::Instance(instance) => instance,
+      object => panic!("[bug] expected an instance, got {:?}", object),
+    }
+  }
+
+  pub fn upvalue(&self, reference: ObjRef) -> &Upvalue {
+    match self.get(reference) {
+      Object::Upvalue(upvalue) => upvalue,
+      object => panic!("[bug] expected an upvalue, got {:?}", object),
+    }
+  }
+
+  pub fn upvalue_mut(&mut self, reference: ObjRef) -> &mut Upvalue {
+    match self.get_mut(reference) {
+      Object::Upvalue(upvalue) => upvalue,
+      object => panic!("[bug] expected an upvalue, got {:?}", object),
+    }
+  }
+
+  pub fn to_native(&self, value: Value) -> NativeValue {
+    let reference = match value.unpack() {
+      Unpacked::Nil => return NativeValue::Nil,
+      Unpacked::Bool(boolean) => return NativeValue::Bool(boolean),
+      Unpacked::Number(number) => return NativeValue::Number(number),
+      Unpacked::Object(reference) => reference,
+    };
+
+    let kind = match self.get(reference) {
+      Object::String(string) => return NativeValue::String(Rc::clone(string)),
+      Object::Closure(_) | Object::Native(_) | Object::BoundMethod(_) => "function",
+      Object::Class(_) => "class",
+      Object::Instance(_) => "instance",
+      Object::Upvalue(_) => unreachable!("[bug] upvalue used as a value"),
+    };
+
+    NativeValue::Object {
+      kind,
+      description: Rc::from(self.display(value).to_string()),
+    }
+  }
+
+  pub fn display(&self, value: Value) -> Display<'_> {
+    Display { heap: self, value }
+  }
+}
+
+impl Default for Heap {
+  fn default() -> Heap {
+    Heap::new()
+  }
+}
+
+// Prints values the same as the tree-walker does
+pub struct Display<'h> {
+  heap: &'h Heap,
+  value: Value,
+}
+
+impl<'h> fmt::Display for Display<'h> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let reference = match self.value.unpack() {
+      Unpacked::Nil => return write!(f, "nil"),
+      Unpacked::Bool(boolean) => return write!(f, "{}", boolean),
+      Unpacked::Number(number) => return write!(f, "{}", format_number(number)),
+      Unpacked::Object(reference) => reference,
+    };
+
+    match self.heap.get(reference) {
+      Object::String(string) => write!(f, "{}", string),
+      Object::Closure(closure) => write!(f, "{}", closure.function),
+      Object::Upvalue(_) => write!(f, "upvalue"),
+      Object::Native(_) => write!(f, "<native fn>"),
+      Object::Class(class) => write!(f, "{}", class.name),
+      Object::Instance(instance) => {
+        let class = Value::object(instance.class);
+        write!(f, "{} instance", self.heap.display(class))
+      }
+      Object::BoundMethod(bound) => write!(f, "{}", self.heap.display(Value::object(bound.method))),
+    }
+  }
+}
+
+// Everything an object keeps alive
+fn references(object: &Object, mut visit: impl FnMut(ObjRef)) {
+  match object {
+    Object::Upvalue(Upvalue::Closed(value)) => {
+      if let Some(reference) = value.as_object() {
+        visit(reference);
+      }
+    }
+    Object::Closure(closure) => closure.upvalues.iter().for_each(|upvalue| visit(*upvalue)),
+    Object::Class(class) => class.methods.values().for_each(|method| visit(*method)),
+    Object::Instance(instance) => {
+      visit(instance.class);
+
+      for value in instance.fields.values() {
+        if let Some(reference) = value.as_object() {
+          visit(reference);
+        }
+      }
+    }
+    Object::BoundMethod(bound) => {
+      visit(bound.receiver);
+      visit(bound.method);
+    }
+    Object::String(_) | Object::Upvalue(_) | Object::Native(_) => {}
+  }
+}
+
+// An estimate, close enough for deciding when to collect
+fn size_of_object(object: &Object) -> usize {
+  let payload = match object {
+    Object::String(string) => string.len(),
+    Object::Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjRef>(),
+    Object::Class(class) => class.methods.len() * mem::size_of::<(Rc<str>, ObjRef)>(),
+    Object::Instance(instance) => instance.fields.len() * mem::size_of::<(Rc<str>, Value)>(),
+    Object::Upvalue(_) | Object::Native(_) | Object::BoundMethod(_) => 0,
+  };
+
+  mem::size_of::<Entry>() + payload
+}
@@ -0,0 +1,1361 @@
+mod debugging;
+mod limits;
+mod memory;
+mod profiler;
+mod step;
+mod tracer;
+mod value;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::rc::Rc;
+
+pub use self::limits::{CancelHandle, Limit, LimitExceeded, Limits, VmError};
+pub use self::memory::{GcMode, GcStats, Heap, ObjRef};
+pub use self::profiler::{FunctionProfile, Profiler, Weight};
+pub use self::step::{ExecutionState, FrameState};
+pub use self::tracer::{TraceFormat, Tracer};
+pub use self::value::{BoundMethod, Class, Closure, Instance, Object, Unpacked, Upvalue, Value};
+use super::chunk::{CompiledFunction, Constant, OpCode};
+use super::debugger::Debugger;
+use super::error::{RuntimeError, StackFrame};
+use super::native::{Native, NativeRegistry, NativeValue};
+use super::verifier;
+
+// FRAMES_MAX in 26-gc/vm.h. The script's frame doesn't count, same as in the
+// tree-walker.
+const MAX_FRAMES: usize = 64;
+
+struct CallFrame {
+  closure: ObjRef,
+  // The closure's function, kept here to read code without a heap lookup
+  function: Rc<CompiledFunction>,
+  // Offset of the next byte to read
+  ip: usize,
+  // Stack slot of the callee, the frame's slot zero
+  base: usize,
+}
+
+pub struct Vm<W: Write> {
+  stack: Vec<Value>,
+  frames: Vec<CallFrame>,
+  globals: HashMap<Rc<str>, Value>,
+  // Sorted by stack slot
+  open_upvalues: Vec<ObjRef>,
+  heap: Heap,
+  output: W,
+  tracer: Option<Tracer>,
+  profiler: Option<Profiler>,
+  limits: Limits,
+  usage: limits::Usage,
+  cancel: Option<CancelHandle>,
+  // Whether there's anything to check before each instruction
+  limited: bool,
+  debugger: Option<Debugger>,
+}
+
+impl<W: Write> Vm<W> {
+  pub fn new(output: W) -> Vm<W> {
+    Vm::with_natives(output, &NativeRegistry::default())
+  }
+
+  pub fn with_natives(output: W, natives: &NativeRegistry) -> Vm<W> {
+    let mut heap = Heap::new();
+    let globals = natives
+      .iter()
+      .map(|native| {
+        let object = heap.insert(Object::Native(Rc::clone(native)));
+        (Rc::from(native.name), Value::object(object))
+      })
+      .collect();
+
+    Vm {
+      stack: Vec::new(),
+      frames: Vec::new(),
+      globals,
+      open_upvalues: Vec::new(),
+      heap,
+      output,
+      tracer: None,
+      profiler: None,
+      limits: Limits::default(),
+      usage: limits::Usage::default(),
+      cancel: None,
+      limited: false,
+      debugger: None,
+    }
+  }
+
+  pub fn output(&self) -> &W {
+    &self.output
+  }
+
+  pub fn into_output(self) -> W {
+    self.output
+  }
+
+  pub fn heap(&self) -> &Heap {
+    &self.heap
+  }
+
+  // For turning on stress mode or moving the threshold
+  pub fn heap_mut(&mut self) -> &mut Heap {
+    &mut self.heap
+  }
+
+  // Globals survive between calls so a REPL can feed scripts one by one
+  pub fn interpret(&mut self, script: Rc<CompiledFunction>) -> Result<(), VmError> {
+    self.start(script)?;
+    self.run()
+  }
+
+  // Sets the script up without running any of it, for stepping through
+  pub fn start(&mut self, script: Rc<CompiledFunction>) -> Result<(), VmError> {
+    // Whether it was compiled, optimized or loaded, code that would take the
+    // VM out of bounds is an error and not a panic
+    verifier::verify(&script).map_err(VmError::InvalidCode)?;
+    self.reset_usage();
+
+    let closure = self.allocate(Object::Closure(Closure {
+      function: script,
+      upvalues: Vec::new(),
+    }));
+
+    self.stack.push(Value::object(closure));
+    self.call(closure, 0)
+  }
+
+  pub fn collect_garbage(&mut self) {
+    let Vm {
+      stack,
+      frames,
+      globals,
+      open_upvalues,
+      heap,
+      ..
+    } = self;
+
+    heap.collect(|heap| mark_roots(heap, stack, frames, open_upvalues, globals));
+  }
+
+  fn allocate(&mut self, object: Object) -> ObjRef {
+    if self.heap.should_collect() {
+      let Vm {
+        stack,
+        frames,
+        globals,
+        open_upvalues,
+        heap,
+        ..
+      } = self;
+
+      heap.step(|heap| {
+        mark_roots(heap, stack, frames, open_upvalues, globals);
+        // What the new object points at isn't reachable from any root yet
+        heap.mark_references(&object);
+      });
+    }
+
+    self.heap.insert(object)
+  }
+
+  // Equal strings are the same object, so comparing them is comparing
+  // handles
+  fn intern(&mut self, string: Rc<str>) -> Value {
+    let reference = match self.heap.find_string(&string) {
+      Some(reference) => reference,
+      None => self.allocate(Object::String(string)),
+    };
+
+    Value::object(reference)
+  }
+
+  fn run(&mut self) -> Result<(), VmError> {
+    let result = loop {
+      match self.execute() {
+        Ok(true) => break Ok(()),
+        Ok(false) => (),
+        Err(error) => break Err(error),
+      }
+    };
+
+    self.pause_profiler();
+
+    result
+  }
+
+  // Runs the next instruction, true once the script has returned
+  fn execute(&mut self) -> Result<bool, VmError> {
+    if self.limited {
+      self.check_limits()?;
+    }
+
+    if self.debugger.is_some() {
+      self.debug()?;
+    }
+
+    if self.tracer.is_some() {
+      self.trace();
+    }
+
+    let byte = self.read_byte();
+    let op = match OpCode::try_from(byte) {
+      Ok(op) => op,
+      Err(byte) => return Err(self.error(format!("Unknown opcode {}", byte))),
+    };
+
+    if self.profiler.is_some() {
+      self.profile(op);
+    }
+
+    match op {
+      OpCode::Constant => {
+        let index = self.read_byte() as usize;
+        let value = self.constant(index);
+        self.stack.push(value);
+      }
+      OpCode::ConstantLong => {
+        let index = self.read_u24();
+        let value = self.constant(index);
+        self.stack.push(value);
+      }
+      OpCode::Closure => self.make_closure(),
+      OpCode::DefineGlobal => {
+        let name = self.read_string();
+        let value = self.stack.pop().unwrap();
+        self.globals.insert(name, value);
+      }
+      OpCode::SetGlobal => {
+        let name = self.read_string();
+        let value = self.peek(0);
+
+        match self.globals.get_mut(&name) {
+          Some(existing) => *existing = value,
+          None => return Err(self.error(format!("Undefined variable {}", name))),
+        }
+      }
+      OpCode::GetGlobal => {
+        let name = self.read_string();
+
+        match self.globals.get(&name) {
+          Some(value) => self.stack.push(*value),
+          None => return Err(self.error(format!("Undefined name {}.", name))),
+        }
+      }
+      OpCode::SetUpvalue => {
+        let index = self.read_byte() as usize;
+        let upvalue = self.upvalue(index);
+        let value = self.peek(0);
+
+        match self.heap.upvalue_mut(upvalue) {
+          Upvalue::Open(slot) => self.stack[*slot] = value,
+          Upvalue::Closed(closed) => {
+            *closed = value;
+            self.heap.write_barrier(value);
+          }
+        }
+      }
+      OpCode::GetUpvalue => {
+        let index = self.read_byte() as usize;
+        let value = match self.heap.upvalue(self.upvalue(index)) {
+          Upvalue::Open(slot) => self.stack[*slot],
+          Upvalue::Closed(closed) => *closed,
+        };
+
+        self.stack.push(value);
+      }
+      OpCode::SetLocal => {
+        let slot = self.frame().base + self.read_byte() as usize;
+        self.stack[slot] = self.peek(0);
+      }
+      OpCode::GetLocal => {
+        let slot = self.frame().base + self.read_byte() as usize;
+        self.stack.push(self.stack[slot]);
+      }
+      OpCode::Pop => {
+        self.stack.pop();
+      }
+      OpCode::Nil => self.stack.push(Value::NIL),
+      OpCode::True => self.stack.push(Value::bool(true)),
+      OpCode::False => self.stack.push(Value::bool(false)),
+      OpCode::Negate => match self.peek(0).unpack() {
+        Unpacked::Number(number) => *self.stack.last_mut().unwrap() = Value::number(-number),
+        _ => return Err(self.error("Must be a number")),
+      },
+      OpCode::Add => {
+        let sum = self.add(self.peek(1), self.peek(0))?;
+        self.replace_operands(sum);
+      }
+      OpCode::AddLocals => {
+        let left = self.frame().base + self.read_byte() as usize;
+        let right = self.frame().base + self.read_byte() as usize;
+        let sum = self.add(self.stack[left], self.stack[right])?;
+
+        self.stack.push(sum);
+      }
+      OpCode::AddConstant => {
+        let index = self.read_byte() as usize;
+        // Pushed so a string constant stays rooted while the sum is allocated
+        let right = self.constant(index);
+        self.stack.push(right);
+
+        let sum = self.add(self.peek(1), right)?;
+        self.replace_operands(sum);
+      }
+      OpCode::Subtract => self.binary_number(|left, right| Value::number(left - right))?,
+      OpCode::Multiply => self.binary_number(|left, right| Value::number(left * right))?,
+      OpCode::Divide => self.binary_number(|left, right| Value::number(left / right))?,
+      OpCode::Greater => self.binary_number(|left, right| Value::bool(left > right))?,
+      OpCode::Less => self.binary_number(|left, right| Value::bool(left < right))?,
+      OpCode::Not => {
+        let value = self.stack.pop().unwrap();
+        self.stack.push(Value::bool(!value.is_truthy()));
+      }
+      OpCode::Equal => {
+        let equal = Value::bool(self.peek(1) == self.peek(0));
+        self.replace_operands(equal);
+      }
+      OpCode::Print => {
+        let value = self.stack.pop().unwrap();
+        let text = format!("{}\n", self.heap.display(value));
+
+        if self.limited {
+          self.count_output(text.len())?;
+        }
+
+        if let Err(error) = self.output.write_all(text.as_bytes()) {
+          return Err(self.error(format!("Couldn't write output: {}", error)));
+        }
+      }
+      OpCode::JumpIfFalse => {
+        let offset = self.read_u16();
+
+        if !self.peek(0).is_truthy() {
+          self.frame_mut().ip += offset;
+        }
+      }
+      OpCode::Jump => {
+        let offset = self.read_u16();
+        self.frame_mut().ip += offset;
+      }
+      OpCode::Loop => {
+        let offset = self.read_u16();
+        self.frame_mut().ip -= offset;
+      }
+      OpCode::Return => {
+        let result = self.stack.pop().unwrap();
+        let frame = self.frames.pop().unwrap();
+
+        self.close_upvalues(frame.base);
+        self.stack.truncate(frame.base);
+
+        if self.frames.is_empty() {
+          return Ok(true);
+        }
+
+        self.stack.push(result);
+      }
+      OpCode::Call => {
+        let argument_count = self.read_byte() as usize;
+        self.call_value(argument_count)?;
+      }
+      OpCode::CloseUpvalue => {
+        self.close_upvalues(self.stack.len() - 1);
+        self.stack.pop();
+      }
+      OpCode::Class => {
+        let name = self.read_string();
+        let class = self.allocate(Object::Class(Class::new(name)));
+        self.stack.push(Value::object(class));
+      }
+      OpCode::GetProperty => {
+        let name = self.read_string();
+        let instance = match self.instance(self.peek(0)) {
+          Some(instance) => instance,
+          None => return Err(self.error("Can't access property of non-object value")),
+        };
+
+        let value = match self.heap.instance(instance).fields.get(&name) {
+          Some(value) => *value,
+          None => match self.bind_method(instance, &name) {
+            Some(bound) => bound,
+            None => {
+              let class = Value::object(self.heap.instance(instance).class);
+              let message = format!(
+                "Missing property {} for {} instance",
+                name,
+                self.heap.display(class)
+              );
+
+              return Err(self.error(message));
+            }
+          },
+        };
+
+        *self.stack.last_mut().unwrap() = value;
+      }
+      OpCode::SetProperty => {
+        let name = self.read_string();
+        let instance = match self.instance(self.peek(1)) {
+          Some(instance) => instance,
+          None => return Err(self.error("Can't access property of non-object value")),
+        };
+
+        let value = self.peek(0);
+        self.heap.instance_mut(instance).fields.insert(name, value);
+        self.heap.resize(instance);
+        self.heap.write_barrier(value);
+        self.replace_operands(value);
+      }
+      // The compiler only emits these with the right operands, but the
+      // verifier doesn't know about types so .loxc files can get them wrong
+      OpCode::Method => {
+        let name = self.read_string();
+        let method = match self.stack.pop().and_then(Value::as_object) {
+          Some(method) if matches!(self.heap.get(method), Object::Closure(_)) => method,
+          _ => return Err(self.error("Method is not a function")),
+        };
+        let class = match self.class(self.peek(0)) {
+          Some(class) => class,
+          None => return Err(self.error("Method defined outside of a class")),
+        };
+
+        self.heap.class_mut(class).methods.insert(name, method);
+        self.heap.resize(class);
+        self.heap.write_barrier(Value::object(method));
+      }
+      OpCode::Inherit => {
+        let superclass = match self.class(self.peek(1)) {
+          Some(superclass) => superclass,
+          None => return Err(self.error("Superclass is not a class")),
+        };
+        let subclass = match self.class(self.peek(0)) {
+          Some(subclass) => subclass,
+          None => return Err(self.error("Subclass is not a class")),
+        };
+        self.stack.pop();
+
+        let inherited = self.heap.class(superclass).methods.clone();
+
+        for method in inherited.values() {
+          self.heap.write_barrier(Value::object(*method));
+        }
+
+        self.heap.class_mut(subclass).methods.extend(inherited);
+        self.heap.resize(subclass);
+      }
+      OpCode::GetSuper => {
+        let name = self.read_string();
+        let superclass = match self.class(self.peek(0)) {
+          Some(superclass) => superclass,
+          None => return Err(self.error("Super bound to something other than a class")),
+        };
+        self.stack.pop();
+        let receiver = match self.instance(self.peek(0)) {
+          Some(receiver) => receiver,
+          None => return Err(self.error("Super used without an instance")),
+        };
+
+        match self.heap.class(superclass).methods.get(&name).copied() {
+          Some(method) => {
+            let bound = self.allocate(Object::BoundMethod(BoundMethod { receiver, method }));
+            *self.stack.last_mut().unwrap() = Value::object(bound);
+          }
+          None => return Err(self.error(format!("Undefined property {}", name))),
+        }
+      }
+    }
+
+    Ok(false)
+  }
+
+  fn call_value(&mut self, argument_count: usize) -> Result<(), VmError> {
+    let callee_slot = self.stack.len() - argument_count - 1;
+    let callee = match self.stack[callee_slot].as_object() {
+      Some(callee) => callee,
+      _ => return Err(self.error("Expected function or class for callee")),
+    };
+
+    match self.heap.get(callee) {
+      Object::Closure(_) => self.call(callee, argument_count),
+      Object::Native(native) => {
+        let native = Rc::clone(native);
+        self.call_native(&native, argument_count)
+      }
+      Object::Class(_) => {
+        // The class stays in the callee slot until the instance replaces it
+        let instance = self.allocate(Object::Instance(Instance::new(callee)));
+        self.stack[callee_slot] = Value::object(instance);
+
+        match self.heap.class(callee).methods.get("init").copied() {
+          Some(initializer) => self.call(initializer, argument_count),
+          None if argument_count != 0 => {
+            Err(self.error(format!("Expected 0 arguments but got {}", argument_count)))
+          }
+          None => Ok(()),
+        }
+      }
+      Object::BoundMethod(bound) => {
+        let method = bound.method;
+        self.stack[callee_slot] = Value::object(bound.receiver);
+        self.call(method, argument_count)
+      }
+      _ => Err(self.error("Expected function or class for callee")),
+    }
+  }
+
+  fn call(&mut self, closure: ObjRef, argument_count: usize) -> Result<(), VmError> {
+    let function = Rc::clone(&self.heap.closure(closure).function);
+
+    if argument_count != function.arity {
+      return Err(self.error(format!(
+        "Expected {} arguments but got {}",
+        function.arity, argument_count
+      )));
+    }
+
+    if self.frames.len() > MAX_FRAMES {
+      return Err(self.error("Stack overflow."));
+    }
+
+    self.check_call_depth()?;
+
+    self.frames.push(CallFrame {
+      closure,
+      function,
+      ip: 0,
+      base: self.stack.len() - argument_count - 1,
+    });
+
+    if let Some(debugger) = &mut self.debugger {
+      debugger.called();
+    }
+
+    Ok(())
+  }
+
+  fn call_native(&mut self, native: &Native, argument_count: usize) -> Result<(), VmError> {
+    if argument_count != native.arity {
+      return Err(self.error(format!(
+        "Expected {} arguments but got {}",
+        native.arity, argument_count
+      )));
+    }
+
+    let first = self.stack.len() - argument_count;
+    let arguments: Vec<_> = self.stack[first..]
+      .iter()
+      .map(|argument| self.heap.to_native(*argument))
+      .collect();
+
+    let value = match (native.function)(&arguments) {
+      Ok(NativeValue::Nil) => Value::NIL,
+      Ok(NativeValue::Bool(boolean)) => Value::bool(boolean),
+      Ok(NativeValue::Number(number)) => Value::number(number),
+      Ok(NativeValue::String(string)) => self.intern(string),
+      Ok(NativeValue::Object { .. }) => {
+        return Err(self.error(format!(
+          "Native function {} returned an object",
+          native.name
+        )))
+      }
+      Err(message) => return Err(self.error(message)),
+    };
+
+    self.stack.truncate(first - 1);
+    self.stack.push(value);
+
+    Ok(())
+  }
+
+  fn make_closure(&mut self) {
+    let index = self.read_byte() as usize;
+    let function = match self.constant_at(index) {
+      Constant::Function(function) => function,
+      _ => unreachable!("[bug] closure over a constant that isn't a function"),
+    };
+
+    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+
+    for _ in 0..function.upvalue_count {
+      let is_local = self.read_byte() == 1;
+      let index = self.read_byte() as usize;
+
+      upvalues.push(if is_local {
+        self.capture_upvalue(self.frame().base + index)
+      } else {
+        self.upvalue(index)
+      });
+    }
+
+    let closure = self.allocate(Object::Closure(Closure { function, upvalues }));
+    self.stack.push(Value::object(closure));
+  }
+
+  // Closures capturing the same variable share its upvalue
+  fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+    let heap = &self.heap;
+    let position = self
+      .open_upvalues
+      .partition_point(|upvalue| open_slot(heap, *upvalue) < slot);
+
+    if let Some(existing) = self.open_upvalues.get(position) {
+      if open_slot(&self.heap, *existing) == slot {
+        return *existing;
+      }
+    }
+
+    let upvalue = self.allocate(Object::Upvalue(Upvalue::Open(slot)));
+    self.open_upvalues.insert(position, upvalue);
+
+    upvalue
+  }
+
+  // Moves every variable at or above `from` off the stack into its upvalue
+  fn close_upvalues(&mut self, from: usize) {
+    let heap = &self.heap;
+    let first = self
+      .open_upvalues
+      .partition_point(|upvalue| open_slot(heap, *upvalue) < from);
+
+    for upvalue in self.open_upvalues.drain(first..) {
+      let upvalue = self.heap.upvalue_mut(upvalue);
+
+      if let Upvalue::Open(slot) = *upvalue {
+        let value = self.stack[slot];
+        *upvalue = Upvalue::Closed(value);
+        self.heap.write_barrier(value);
+      }
+    }
+  }
+
+  // Wraps the receiver's method, if its class has one by that name
+  fn bind_method(&mut self, receiver: ObjRef, name: &str) -> Option<Value> {
+    let class = self.heap.instance(receiver).class;
+    let method = self.heap.class(class).methods.get(name).copied()?;
+    let bound = self.allocate(Object::BoundMethod(BoundMethod { receiver, method }));
+
+    Some(Value::object(bound))
+  }
+
+  fn instance(&self, value: Value) -> Option<ObjRef> {
+    match value.as_object() {
+      Some(reference) if matches!(self.heap.get(reference), Object::Instance(_)) => Some(reference),
+      _ => None,
+    }
+  }
+
+  fn class(&self, value: Value) -> Option<ObjRef> {
+    match value.as_object() {
+      Some(reference) if matches!(self.heap.get(reference), Object::Class(_)) => Some(reference),
+      _ => None,
+    }
+  }
+
+  fn upvalue(&self, index: usize) -> ObjRef {
+    self.heap.closure(self.frame().closure).upvalues[index]
+  }
+
+  // The operands have to be rooted, concatenating strings allocates
+  fn add(&mut self, left: Value, right: Value) -> Result<Value, VmError> {
+    if let (Unpacked::Number(left), Unpacked::Number(right)) = (left.unpack(), right.unpack()) {
+      return Ok(Value::number(left + right));
+    }
+
+    match (self.heap.string(left), self.heap.string(right)) {
+      (Some(left), Some(right)) => {
+        let concatenated = Rc::from(format!("{}{}", left, right));
+        Ok(self.intern(concatenated))
+      }
+      _ => Err(self.error("Operands must be two numbers or two strings")),
+    }
+  }
+
+  fn binary_number(&mut self, operation: fn(f64, f64) -> Value) -> Result<(), VmError> {
+    match (self.peek(1).unpack(), self.peek(0).unpack()) {
+      (Unpacked::Number(left), Unpacked::Number(right)) => {
+        self.replace_operands(operation(left, right));
+        Ok(())
+      }
+      _ => Err(self.error("Must be a number")),
+    }
+  }
+
+  // Pops the two operands of a binary instruction and pushes its result
+  fn replace_operands(&mut self, result: Value) {
+    self.stack.pop();
+    *self.stack.last_mut().unwrap() = result;
+  }
+
+  fn peek(&self, distance: usize) -> Value {
+    self.stack[self.stack.len() - 1 - distance]
+  }
+
+  fn frame(&self) -> &CallFrame {
+    self.frames.last().unwrap()
+  }
+
+  fn frame_mut(&mut self) -> &mut CallFrame {
+    self.frames.last_mut().unwrap()
+  }
+
+  fn read_byte(&mut self) -> u8 {
+    let frame = self.frame_mut();
+    let byte = frame.function.chunk.code[frame.ip];
+    frame.ip += 1;
+
+    byte
+  }
+
+  fn read_u16(&mut self) -> usize {
+    let frame = self.frame_mut();
+    let value = frame.function.chunk.read_u16(frame.ip);
+    frame.ip += 2;
+
+    value as usize
+  }
+
+  fn read_u24(&mut self) -> usize {
+    let frame = self.frame_mut();
+    let value = frame.function.chunk.read_u24(frame.ip);
+    frame.ip += 3;
+
+    value
+  }
+
+  fn constant_at(&self, index: usize) -> Constant {
+    self.frame().function.chunk.constants[index].clone()
+  }
+
+  fn constant(&mut self, index: usize) -> Value {
+    match self.constant_at(index) {
+      Constant::Number(number) => Value::number(number),
+      Constant::String(string) => self.intern(string),
+      Constant::Function(_) => unreachable!("[bug] function constant loaded without a closure"),
+    }
+  }
+
+  fn read_string(&mut self) -> Rc<str> {
+    let index = self.read_byte() as usize;
+
+    match self.constant_at(index) {
+      Constant::String(string) => string,
+      _ => unreachable!("[bug] name constant that isn't a string"),
+    }
+  }
+
+  // Builds the error with the trace of the current call stack and unwinds it
+  // all, as clox's runtimeError does
+  fn error(&mut self, message: impl Into<String>) -> VmError {
+    let trace: Vec<_> = self
+      .frames
+      .iter()
+      .rev()
+      .map(|frame| StackFrame {
+        function: frame.function.name.as_ref().map(|name| name.to_string()),
+        line: frame.function.chunk.line_at(frame.ip.saturating_sub(1)),
+      })
+      .collect();
+
+    self.unwind();
+
+    VmError::Runtime(RuntimeError {
+      line: trace.first().map_or(0, |frame| frame.line),
+      message: message.into(),
+      trace,
+    })
+  }
+
+  // Closures that outlive the run still need the values they captured, so
+  // their upvalues are closed before the stack goes
+  fn unwind(&mut self) {
+    self.close_upvalues(0);
+    self.stack.clear();
+    self.frames.clear();
+  }
+}
+
+// The compiler is done before the VM starts and keeps nothing on its heap, so
+// unlike clox there are no compiler roots
+fn mark_roots(
+  heap: &mut Heap,
+  stack: &[Value],
+  frames: &[CallFrame],
+  open_upvalues: &[ObjRef],
+  globals: &HashMap<Rc<str>, Value>,
+) {
+  for value in stack {
+    heap.mark_value(*value);
+  }
+
+  for frame in frames {
+    heap.mark_object(frame.closure);
+  }
+
+  for upvalue in open_upvalues {
+    heap.mark_object(*upvalue);
+  }
+
+  for value in globals.values() {
+    heap.mark_value(*value);
+  }
+}
+
+fn open_slot(heap: &Heap, upvalue: ObjRef) -> usize {
+  match heap.upvalue(upvalue) {
+    Upvalue::Open(slot) => *slot,
+    Upvalue::Closed(_) => unreachable!("[bug] closed upvalue in the open list"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compiler;
+  use crate::interpreter::Interpreter;
+  use crate::native::NativeValue;
+  use crate::parser;
+  use crate::resolver;
+
+  fn run(source: &str) -> Result<String, RuntimeError> {
+    let mut vm = Vm::new(Vec::new());
+    let result = execute(&mut vm, source);
+
+    result.map(|()| String::from_utf8(vm.into_output()).unwrap())
+  }
+
+  // No limits are set here, so every error is one the tree-walker can have
+  fn execute(vm: &mut Vm<Vec<u8>>, source: &str) -> Result<(), RuntimeError> {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    match vm.interpret(compiler::compile(&statements, &resolution).unwrap()) {
+      Err(VmError::Runtime(error)) => Err(error),
+      Err(error) => panic!("unexpected {}", error),
+      Ok(()) => Ok(()),
+    }
+  }
+
+  fn outcome(mut vm: Vm<Vec<u8>>, source: &str) -> (String, Option<RuntimeError>) {
+    let error = execute(&mut vm, source).err();
+
+    (String::from_utf8(vm.into_output()).unwrap(), error)
+  }
+
+  // Output and error of the tree-walker, which the VM has to agree with
+  fn interpret(source: &str) -> (String, Option<RuntimeError>) {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+    let mut interpreter = Interpreter::new(&resolution, Vec::new());
+    let error = interpreter.interpret(&statements).err();
+
+    (String::from_utf8(interpreter.into_output()).unwrap(), error)
+  }
+
+  fn expectations(source: &str) -> String {
+    source
+      .match_indices("// => ")
+      .map(|(index, marker)| {
+        let rest = &source[index + marker.len()..];
+        format!("{}\n", rest.lines().next().unwrap())
+      })
+      .collect()
+  }
+
+  fn read(path: &str) -> String {
+    std::fs::read_to_string(format!("{}/../{}", env!("CARGO_MANIFEST_DIR"), path)).unwrap()
+  }
+
+  #[test]
+  fn test_arithmetic() {
+    assert_eq!(
+      run("print 1 + 2 * 3 - 4 / 2; print -(1 - 3) >= 2;").unwrap(),
+      "5.0\ntrue\n"
+    );
+  }
+
+  #[test]
+  fn test_strings() {
+    assert_eq!(
+      run("var a = \"a\"; print a + \"b\" == \"ab\";").unwrap(),
+      "true\n"
+    );
+  }
+
+  #[test]
+  fn test_closures() {
+    assert_eq!(
+      run(
+        "var closures;
+         {
+           var i = 1;
+           fun first() { return i; }
+           i = 2;
+           fun second() { i = i + 1; return i; }
+           closures = first;
+           print second();
+         }
+         print closures();"
+      )
+      .unwrap(),
+      "3.0\n3.0\n"
+    );
+  }
+
+  #[test]
+  fn test_closures_in_loop() {
+    assert_eq!(
+      run(
+        "var a; var b;
+         for (var i = 0; i < 2; i = i + 1) {
+           var j = i;
+           fun f() { return j; }
+           if (i == 0) a = f; else b = f;
+         }
+         print a(); print b();"
+      )
+      .unwrap(),
+      "0.0\n1.0\n"
+    );
+  }
+
+  #[test]
+  fn test_classes() {
+    assert_eq!(
+      run(
+        "class A {
+           init(name) { this.name = name; }
+           greet() { return \"I'm \" + this.name; }
+         }
+         class B < A {
+           greet() { return super.greet() + \" from B\"; }
+         }
+         var b = B(\"b\");
+         var greet = b.greet;
+         print greet();
+         print b.init(\"c\") == b;
+         print b.name;
+         print B;
+         print b;
+         print greet;"
+      )
+      .unwrap(),
+      "I'm b from B\ntrue\nc\nB\nB instance\n<fn greet>\n"
+    );
+  }
+
+  #[test]
+  fn test_natives() {
+    assert_eq!(
+      run("print len(\"abc\") + sqrt(4); print clock() > 0;").unwrap(),
+      "5.0\ntrue\n"
+    );
+
+    let mut registry = NativeRegistry::new();
+    registry.register("answer", 0, |_| Ok(NativeValue::Number(42.0)));
+    let mut vm = Vm::with_natives(Vec::new(), &registry);
+
+    execute(&mut vm, "print answer();").unwrap();
+    assert!(execute(&mut vm, "len(\"a\");").is_err());
+    assert_eq!(vm.output(), b"42.0\n");
+  }
+
+  #[test]
+  fn test_globals_persist_between_scripts() {
+    let mut vm = Vm::new(Vec::new());
+
+    execute(&mut vm, "var a = 1;").unwrap();
+    execute(&mut vm, "print a + 1;").unwrap();
+
+    assert_eq!(vm.output(), b"2.0\n");
+  }
+
+  #[test]
+  fn test_runtime_errors_match_interpreter() {
+    let sources = [
+      "print 1 + nil;",
+      "print -\"a\";",
+      "var a = 1;\na();",
+      "fun f(a) {}\nf();",
+      "class A {}\nA(1);",
+      "print nope;",
+      "nope = 1;",
+      "print 1.x;",
+      "class A {}\nprint A().x;",
+      "var A = 1;\nclass B < A {}",
+      "class A {}\nclass B < A { m() { return super.m; } }\nB().m();",
+      "print len(1);",
+      "fun inner() {\n  return 1 < \"a\";\n}\nfun outer() {\n  inner();\n}\nouter();",
+      "fun f() { f(); }\nf();",
+    ];
+
+    for source in &sources {
+      assert_eq!(run(source).err(), interpret(source).1, "{}", source);
+    }
+  }
+
+  #[test]
+  fn test_stack_trace() {
+    let error =
+      run("fun inner() {\n  return -nil;\n}\nclass A {\n  m() {\n    inner();\n  }\n}\nA().m();")
+        .unwrap_err();
+
+    assert_eq!(
+      error.to_string(),
+      "runtime error. line: 2 - error: Must be a number\n\
+       [line 2] in inner()\n\
+       [line 6] in m()\n\
+       [line 9] in script"
+    );
+  }
+
+  #[test]
+  fn test_reusable_after_error() {
+    let mut vm = Vm::new(Vec::new());
+
+    assert!(execute(
+      &mut vm,
+      "fun f() { { var a = 1; fun g() { a; } nil(); } }\nf();"
+    )
+    .is_err());
+    execute(&mut vm, "print 1;").unwrap();
+
+    assert_eq!(vm.output(), b"1.0\n");
+  }
+
+  #[test]
+  fn test_escaped_closure_after_error() {
+    let mut vm = Vm::new(Vec::new());
+
+    assert!(execute(
+      &mut vm,
+      "var g; fun f() { var a = 1; fun h() { return a; } g = h; nil(); } f();"
+    )
+    .is_err());
+    execute(&mut vm, "print g();").unwrap();
+
+    assert_eq!(vm.output(), b"1.0\n");
+  }
+
+  #[test]
+  fn test_error_before_first_frame() {
+    // Valid code, but start calls it without the argument it wants
+    let mut script = CompiledFunction {
+      arity: 1,
+      ..CompiledFunction::default()
+    };
+    script.chunk.write_op(OpCode::Nil, 1);
+    script.chunk.write_op(OpCode::Return, 1);
+
+    let mut vm = Vm::new(Vec::new());
+
+    match vm.interpret(Rc::new(script)) {
+      Err(VmError::Runtime(error)) => {
+        assert!(error.trace.is_empty());
+        assert_eq!(error.line, 0);
+      }
+      result => panic!("expected a runtime error, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn test_invalid_code() {
+    let mut vm = Vm::new(Vec::new());
+
+    for code in [
+      vec![255],
+      vec![OpCode::GetLocal as u8, 7, OpCode::Return as u8],
+      vec![OpCode::Constant as u8, 0, OpCode::Return as u8],
+    ] {
+      let mut script = CompiledFunction::default();
+
+      for byte in code {
+        script.chunk.write(byte, 3);
+      }
+
+      assert!(matches!(
+        vm.interpret(Rc::new(script)),
+        Err(VmError::InvalidCode(_))
+      ));
+    }
+
+    execute(&mut vm, "print 1;").unwrap();
+    assert_eq!(vm.output(), b"1.0\n");
+  }
+
+  #[test]
+  fn test_interned_strings() {
+    let mut vm = Vm::new(Vec::new());
+
+    execute(
+      &mut vm,
+      "var a = \"ab\";
+       var b = \"a\" + \"b\";
+       print a == b;
+       print a + b == \"abab\";
+       print len(a) == len(b);",
+    )
+    .unwrap();
+    assert_eq!(vm.output(), b"true\ntrue\ntrue\n");
+
+    let objects = vm.heap().len();
+    execute(
+      &mut vm,
+      "for (var i = 0; i < 10; i = i + 1) { var c = \"a\" + \"b\"; }",
+    )
+    .unwrap();
+    // Only the script's closure is new
+    assert_eq!(vm.heap().len(), objects + 1);
+
+    // Strings only the table knows about get collected
+    let interned = vm.heap().interned_strings();
+    execute(&mut vm, "{ var t = \"tmp\" + \"orary\"; }").unwrap();
+    assert_eq!(vm.heap().interned_strings(), interned + 3);
+
+    vm.collect_garbage();
+    // "ab" is still in the globals
+    assert_eq!(vm.heap().interned_strings(), 1);
+  }
+
+  #[test]
+  fn test_collects_cycles() {
+    let mut vm = Vm::new(Vec::new());
+
+    execute(&mut vm, "class Node { m() {} }").unwrap();
+    vm.collect_garbage();
+    let live = vm.heap().len();
+
+    execute(
+      &mut vm,
+      "for (var i = 0; i < 100; i = i + 1) {
+         var a = Node();
+         var b = Node();
+         a.next = b;
+         b.next = a;
+         a.m = a.m;
+         fun f() { return f; }
+       }",
+    )
+    .unwrap();
+    assert!(vm.heap().len() > live);
+
+    vm.collect_garbage();
+    assert_eq!(vm.heap().len(), live);
+  }
+
+  #[test]
+  fn test_collection_threshold() {
+    let mut vm = Vm::new(Vec::new());
+    vm.heap_mut().set_threshold(4096);
+
+    execute(
+      &mut vm,
+      "var kept = \"\";
+       for (var i = 0; i < 1000; i = i + 1) kept = kept + \"x\";",
+    )
+    .unwrap();
+
+    assert!(vm.heap().stats().collections > 0);
+    assert!(vm.heap().bytes_allocated() < 64 * 1024);
+
+    vm.collect_garbage();
+    let bytes_allocated = vm.heap().bytes_allocated();
+
+    assert!(bytes_allocated > 1000);
+    assert_eq!(vm.heap().next_gc(), (bytes_allocated * 2).max(4096));
+  }
+
+  #[test]
+  fn test_incremental_gc() {
+    let mut vm = Vm::new(Vec::new());
+    vm.heap_mut().set_mode(GcMode::Incremental);
+    vm.heap_mut().set_threshold(16 * 1024);
+
+    execute(&mut vm, "class Node { init(next) { this.next = next; } }").unwrap();
+    vm.collect_garbage();
+    let live = vm.heap().len();
+
+    execute(
+      &mut vm,
+      "var list = nil;
+       for (var i = 0; i < 2000; i = i + 1) {
+         list = Node(list);
+         var garbage = Node(nil);
+         garbage.next = garbage;
+       }
+       var length = 0;
+       while (list != nil) {
+         length = length + 1;
+         list = list.next;
+       }
+       print length;",
+    )
+    .unwrap();
+
+    let stats = vm.heap().stats().clone();
+    assert!(stats.collections > 0);
+    assert!(stats.pauses > stats.collections);
+    assert!(stats.max_pause <= stats.total_pause);
+
+    vm.collect_garbage();
+    // Only the globals the script added are left
+    assert_eq!(vm.heap().len(), live);
+    assert_eq!(vm.output(), b"2000.0\n");
+  }
+
+  #[test]
+  fn test_incremental_gc_finishes() {
+    // New instances point at their class, which is marked early on. Marking
+    // has to end anyway for the garbage to go.
+    let source = "class T {}
+       for (var i = 0; i < 100000; i = i + 1) {
+         var garbage = T();
+       }";
+
+    for mode in &[GcMode::StopTheWorld, GcMode::Incremental] {
+      let mut vm = Vm::new(Vec::new());
+      vm.heap_mut().set_mode(*mode);
+      vm.heap_mut().set_threshold(16 * 1024);
+      execute(&mut vm, source).unwrap();
+
+      let stats = vm.heap().stats();
+      assert!(stats.collections > 10, "{:?} {:?}", mode, stats);
+      assert!(stats.peak_bytes < 256 * 1024, "{:?} {:?}", mode, stats);
+      assert!(vm.heap().len() < 5000, "{:?} {}", mode, vm.heap().len());
+    }
+  }
+
+  #[test]
+  fn test_write_barrier() {
+    let mut vm = Vm::new(Vec::new());
+    vm.heap_mut().set_mode(GcMode::Incremental);
+    vm.heap_mut().set_stress(true);
+
+    // Globals are traced before the stack, so the box gets marked right away
+    // and the end of the list only many steps later. Moving the string from
+    // there into the box while the list is being traced leaves it reachable
+    // from marked objects only.
+    execute(
+      &mut vm,
+      "class Node { init(next) { this.next = next; } }
+       var box = Node(nil);
+       {
+         var list = nil;
+         for (var i = 0; i < 3000; i = i + 1) list = Node(list);
+         fun tail() {
+           var node = list;
+           while (node.next != nil) node = node.next;
+           return node;
+         }
+         tail().value = \"a\" + \"b\";
+         for (var i = 0; i < 50; i = i + 1) {
+           box.value = tail().value;
+           tail().value = nil;
+           var garbage = \"x\" + \"y\";
+           tail().value = box.value;
+           box.value = nil;
+           garbage = \"x\" + \"y\";
+         }
+         print tail().value;
+       }",
+    )
+    .unwrap();
+
+    assert_eq!(vm.output(), b"ab\n");
+  }
+
+  #[test]
+  fn test_stress_gc() {
+    let mut sources = vec![
+      read("26-gc/test/closure.lox"),
+      read("26-gc/test/open_closure.lox"),
+      read("26-gc/test/poor_object.lox"),
+      read("13-inheritance/test_sources/super.lox"),
+      read("13-inheritance/test_sources/largish.lox"),
+    ];
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../s1-web/test_suite");
+
+    for entry in std::fs::read_dir(directory).unwrap() {
+      let path = entry.unwrap().path();
+
+      if path.extension().is_some_and(|extension| extension == "lox") {
+        sources.push(std::fs::read_to_string(&path).unwrap());
+      }
+    }
+
+    for source in &sources {
+      for mode in &[GcMode::StopTheWorld, GcMode::Incremental] {
+        let mut stressed = Vm::new(Vec::new());
+        stressed.heap_mut().set_stress(true);
+        stressed.heap_mut().set_mode(*mode);
+
+        assert_eq!(
+          outcome(stressed, source),
+          outcome(Vm::new(Vec::new()), source),
+          "{:?} {}",
+          mode,
+          source
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn test_test_suite() {
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../s1-web/test_suite");
+
+    for entry in std::fs::read_dir(directory).unwrap() {
+      let path = entry.unwrap().path();
+
+      if path.extension().is_some_and(|extension| extension == "lox") {
+        let source = std::fs::read_to_string(&path).unwrap();
+
+        assert_eq!(run(&source).unwrap(), expectations(&source), "{:?}", path);
+      }
+    }
+  }
+
+  #[test]
+  fn test_gc_chapter_sources() {
+    let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../26-gc/test");
+
+    for entry in std::fs::read_dir(directory).unwrap() {
+      let path = entry.unwrap().path();
+
+      // Loops forever to give the collector something to do
+      if path.ends_with("leak.lox") {
+        continue;
+      }
+
+      let source = std::fs::read_to_string(&path).unwrap();
+      assert_eq!(
+        outcome(Vm::new(Vec::new()), &source),
+        interpret(&source),
+        "{:?}",
+        path
+      );
+    }
+
+    assert_eq!(
+      run(&read("26-gc/test/closed_closure.lox"))
+        .unwrap_err()
+        .to_string(),
+      "runtime error. line: 18 - error: Expected function or class for callee\n[line 18] in script"
+    );
+  }
+
+  #[test]
+  fn test_inheritance_chapter_sources() {
+    for name in &["blocks", "resolver", "largish", "super", "fibonacci"] {
+      let source = read(&format!("13-inheritance/test_sources/{}.lox", name));
+      let output = run(&source).unwrap();
+
+      if *name == "fibonacci" {
+        assert!(output.trim_end().parse::<f64>().is_ok());
+      } else {
+        assert_eq!(output, interpret(&source).0, "{}", name);
+      }
+    }
+  }
+}
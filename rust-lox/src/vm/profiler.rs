@@ -0,0 +1,376 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::{CallFrame, Vm};
+use crate::chunk::{CompiledFunction, OpCode};
+
+// Instructions and time spent under one call stack. Every instruction is
+// counted, nothing is sampled.
+#[derive(Debug, Default, Clone, Copy)]
+struct StackCounts {
+  instructions: u64,
+  time: Duration,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionProfile {
+  // "script" for top level code
+  pub name: String,
+  pub calls: u64,
+  // Inclusive counts take in the functions it called, exclusive ones only
+  // its own instructions. Recursive calls are only counted once.
+  pub inclusive_instructions: u64,
+  pub exclusive_instructions: u64,
+  pub inclusive_time: Duration,
+  pub exclusive_time: Duration,
+}
+
+// What to weigh folded stacks by
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Weight {
+  Instructions,
+  Microseconds,
+}
+
+// Counts what the VM runs, by opcode and by call stack. Everything per
+// function is worked out from the stacks once the run is over, so all that
+// happens per instruction is bumping a couple of counters.
+#[derive(Debug, Default)]
+pub struct Profiler {
+  opcodes: HashMap<OpCode, u64>,
+  // Functions by address, since different functions can share a name
+  ids: HashMap<*const CompiledFunction, usize>,
+  names: Vec<String>,
+  calls: Vec<u64>,
+  stacks: Vec<(Vec<usize>, StackCounts)>,
+  stack_ids: HashMap<Vec<usize>, usize>,
+  // The call stack as function ids and the index of its counts in stacks
+  stack: Vec<usize>,
+  top: Option<*const CompiledFunction>,
+  current: usize,
+  // When the instruction before this one started, None while paused
+  last: Option<(usize, Instant)>,
+  // Keeps the functions alive so their addresses can't be reused
+  functions: Vec<Rc<CompiledFunction>>,
+}
+
+impl Profiler {
+  pub fn new() -> Profiler {
+    Profiler::default()
+  }
+
+  fn record(&mut self, frames: &[CallFrame], op: OpCode) {
+    let now = Instant::now();
+
+    if let Some((index, start)) = self.last {
+      self.stacks[index].1.time += now - start;
+    }
+
+    let top = frames.last().map(|frame| Rc::as_ptr(&frame.function));
+
+    // Calls and returns move the stack by one frame, so it only has to be
+    // looked at again when its height or its top changes
+    if frames.len() != self.stack.len() || top != self.top {
+      if frames.len() > self.stack.len() {
+        let id = self.id(&frames[frames.len() - 1].function);
+        self.calls[id] += 1;
+      }
+
+      let stack: Vec<usize> = frames
+        .iter()
+        .map(|frame| self.id(&frame.function))
+        .collect();
+      self.current = self.stack_index(stack.clone());
+      self.stack = stack;
+      self.top = top;
+    }
+
+    self.stacks[self.current].1.instructions += 1;
+    *self.opcodes.entry(op).or_insert(0) += 1;
+    self.last = Some((self.current, now));
+  }
+
+  // Stops the clock between runs
+  fn pause(&mut self) {
+    if let Some((index, start)) = self.last.take() {
+      self.stacks[index].1.time += start.elapsed();
+    }
+
+    self.stack.clear();
+    self.top = None;
+  }
+
+  fn id(&mut self, function: &Rc<CompiledFunction>) -> usize {
+    if let Some(id) = self.ids.get(&Rc::as_ptr(function)) {
+      return *id;
+    }
+
+    let id = self.names.len();
+    self.ids.insert(Rc::as_ptr(function), id);
+    self.names.push(match &function.name {
+      Some(name) => name.to_string(),
+      None => String::from("script"),
+    });
+    self.calls.push(0);
+    self.functions.push(Rc::clone(function));
+
+    id
+  }
+
+  fn stack_index(&mut self, stack: Vec<usize>) -> usize {
+    if let Some(index) = self.stack_ids.get(&stack) {
+      return *index;
+    }
+
+    self.stacks.push((stack.clone(), StackCounts::default()));
+    self.stack_ids.insert(stack, self.stacks.len() - 1);
+
+    self.stacks.len() - 1
+  }
+
+  // Instructions run of each opcode, most run first
+  pub fn opcodes(&self) -> Vec<(&'static str, u64)> {
+    let mut opcodes: Vec<_> = self
+      .opcodes
+      .iter()
+      .map(|(op, count)| (op.name(), *count))
+      .collect();
+
+    opcodes.sort_by(|left, right| right.1.cmp(&left.1).then(left.0.cmp(right.0)));
+    opcodes
+  }
+
+  // Functions that ran, the most time inclusive first. Functions with the same
+  // name are reported together.
+  pub fn functions(&self) -> Vec<FunctionProfile> {
+    let mut profiles: Vec<FunctionProfile> = Vec::new();
+    let mut indices: HashMap<&str, usize> = HashMap::new();
+
+    for (id, name) in self.names.iter().enumerate() {
+      let index = *indices.entry(name).or_insert_with(|| {
+        profiles.push(FunctionProfile {
+          name: name.clone(),
+          calls: 0,
+          inclusive_instructions: 0,
+          exclusive_instructions: 0,
+          inclusive_time: Duration::default(),
+          exclusive_time: Duration::default(),
+        });
+
+        profiles.len() - 1
+      });
+
+      profiles[index].calls += self.calls[id];
+    }
+
+    for (stack, counts) in &self.stacks {
+      let mut seen = HashSet::new();
+
+      for id in stack {
+        let profile = &mut profiles[indices[self.names[*id].as_str()]];
+
+        if seen.insert(profile.name.clone()) {
+          profile.inclusive_instructions += counts.instructions;
+          profile.inclusive_time += counts.time;
+        }
+      }
+
+      if let Some(leaf) = stack.last() {
+        let profile = &mut profiles[indices[self.names[*leaf].as_str()]];
+        profile.exclusive_instructions += counts.instructions;
+        profile.exclusive_time += counts.time;
+      }
+    }
+
+    profiles.sort_by(|left, right| {
+      (right.inclusive_time, right.inclusive_instructions)
+        .cmp(&(left.inclusive_time, left.inclusive_instructions))
+        .then(left.name.cmp(&right.name))
+    });
+    profiles
+  }
+
+  // A line per call stack, outermost function first, in the format
+  // flamegraph.pl and inferno take
+  pub fn folded_stacks(&self, weight: Weight) -> String {
+    let mut lines: Vec<String> = self
+      .stacks
+      .iter()
+      .filter_map(|(stack, counts)| {
+        let value = match weight {
+          Weight::Instructions => counts.instructions,
+          Weight::Microseconds => counts.time.as_micros() as u64,
+        };
+
+        if value == 0 || stack.is_empty() {
+          return None;
+        }
+
+        let names: Vec<&str> = stack.iter().map(|id| self.names[*id].as_str()).collect();
+        Some(format!("{} {}", names.join(";"), value))
+      })
+      .collect();
+
+    lines.sort();
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+  }
+
+  pub fn summary(&self) -> String {
+    let mut text = format!(
+      "{:<20} {:>8} {:>14} {:>14} {:>14} {:>14}\n",
+      "function", "calls", "excl. instrs", "incl. instrs", "incl. time", "excl. time"
+    );
+
+    for profile in self.functions() {
+      writeln!(
+        text,
+        "{:<20} {:>8} {:>14} {:>14} {:>14?} {:>14?}",
+        profile.name,
+        profile.calls,
+        profile.exclusive_instructions,
+        profile.inclusive_instructions,
+        profile.inclusive_time,
+        profile.exclusive_time
+      )
+      .unwrap();
+    }
+
+    writeln!(text, "\n{:<20} {:>14}", "opcode", "executed").unwrap();
+
+    for (name, count) in self.opcodes() {
+      writeln!(text, "{:<20} {:>14}", name, count).unwrap();
+    }
+
+    text
+  }
+}
+
+impl<W: Write> Vm<W> {
+  // Starts profiling, or stops it with None. Gives back the profiler there
+  // was, with what it collected.
+  pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+    let mut previous = std::mem::replace(&mut self.profiler, profiler);
+
+    if let Some(previous) = &mut previous {
+      previous.pause();
+    }
+
+    previous
+  }
+
+  pub fn profiler(&self) -> Option<&Profiler> {
+    self.profiler.as_ref()
+  }
+
+  pub(super) fn profile(&mut self, op: OpCode) {
+    if let Some(profiler) = &mut self.profiler {
+      profiler.record(&self.frames, op);
+    }
+  }
+
+  pub(super) fn pause_profiler(&mut self) {
+    if let Some(profiler) = &mut self.profiler {
+      profiler.pause();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compiler;
+  use crate::parser;
+  use crate::resolver;
+
+  fn profile(source: &str) -> Profiler {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+    let mut vm = Vm::new(Vec::new());
+    vm.set_profiler(Some(Profiler::new()));
+
+    vm.interpret(compiler::compile(&statements, &resolution).unwrap())
+      .unwrap();
+
+    vm.set_profiler(None).unwrap()
+  }
+
+  const FIB: &str =
+    "fun fib(n) {\n  if (n < 2) return n;\n  return fib(n - 1) + fib(n - 2);\n}\nprint fib(5);";
+
+  #[test]
+  fn test_opcodes() {
+    let profiler = profile("var a = 1;\nprint a + a;");
+
+    assert_eq!(
+      profiler.opcodes(),
+      vec![
+        ("OP_GET_GLOBAL", 2),
+        ("OP_ADD", 1),
+        ("OP_CONSTANT", 1),
+        ("OP_DEFINE_GLOBAL", 1),
+        ("OP_NIL", 1),
+        ("OP_PRINT", 1),
+        ("OP_RETURN", 1),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_functions() {
+    let functions = profile(FIB).functions();
+    let names: Vec<_> = functions
+      .iter()
+      .map(|profile| profile.name.as_str())
+      .collect();
+
+    assert_eq!(names, vec!["script", "fib"]);
+
+    let (script, fib) = (&functions[0], &functions[1]);
+
+    // fib(5) makes 15 calls, 8 of them hit the base case
+    assert_eq!(script.calls, 1);
+    assert_eq!(fib.calls, 15);
+    assert_eq!(script.exclusive_instructions, 8);
+    assert_eq!(fib.exclusive_instructions, 8 * 7 + 7 * 17);
+    assert_eq!(fib.inclusive_instructions, fib.exclusive_instructions);
+    assert_eq!(
+      script.inclusive_instructions,
+      script.exclusive_instructions + fib.inclusive_instructions
+    );
+    assert!(script.inclusive_time >= fib.inclusive_time);
+    assert!(fib.inclusive_time >= fib.exclusive_time);
+  }
+
+  #[test]
+  fn test_folded_stacks() {
+    let profiler = profile(FIB);
+    let folded = profiler.folded_stacks(Weight::Instructions);
+    let lines: Vec<_> = folded.lines().collect();
+
+    assert_eq!(lines.len(), 6);
+    assert_eq!(lines[0], "script 8");
+    assert_eq!(lines[1], "script;fib 17");
+    assert_eq!(lines[5], "script;fib;fib;fib;fib;fib 14");
+
+    let total: u64 = lines
+      .iter()
+      .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+      .sum();
+    assert_eq!(total, profiler.functions()[0].inclusive_instructions);
+  }
+
+  #[test]
+  fn test_summary() {
+    let summary = profile(FIB).summary();
+    let lines: Vec<_> = summary.lines().collect();
+
+    assert!(lines[0].starts_with("function"));
+    assert!(lines[1].starts_with("script"));
+    assert!(lines[2].starts_with("fib                        15"));
+    assert!(summary.contains("\nopcode"));
+    assert!(summary.contains("OP_CALL"));
+  }
+}
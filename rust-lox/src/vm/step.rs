@@ -0,0 +1,227 @@
+use std::io::Write;
+
+use serde::Serialize;
+
+use super::{Vm, VmError};
+use crate::debug::{self, Instruction};
+
+// Characters each stack value is cut down to, so a long string or a big
+// instance doesn't end up in every step
+const MAX_DISPLAY_CHARS: usize = 256;
+
+// Where the VM is after an instruction, for the web client to show one step
+// at a time
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub struct ExecutionState {
+  // The instruction that just ran
+  pub instruction: Instruction,
+  // Bottom first, printed the way print would but cut short after
+  // MAX_DISPLAY_CHARS with an ellipsis
+  pub stack: Vec<String>,
+  // None once the script has returned
+  pub frame: Option<FrameState>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub struct FrameState {
+  // As the disassembler names it
+  pub function: String,
+  // Offset of the next instruction to run
+  pub ip: usize,
+  // Stack slot of the frame's slot zero
+  pub base: usize,
+  // How many frames there are, the script's included
+  pub depth: usize,
+}
+
+impl<W: Write> Vm<W> {
+  // Runs a single instruction of the script set up with start. None when
+  // there's nothing left to run.
+  pub fn step(&mut self) -> Result<Option<ExecutionState>, VmError> {
+    let instruction = match self.frames.last() {
+      Some(frame) => debug::disassemble_instruction(&frame.function.chunk, frame.ip),
+      None => return Ok(None),
+    };
+
+    self.execute()?;
+
+    Ok(Some(self.state(instruction)))
+  }
+
+  fn state(&self, instruction: Instruction) -> ExecutionState {
+    let stack = self
+      .stack
+      .iter()
+      .map(|value| truncate(self.heap.display(*value).to_string()))
+      .collect();
+
+    let frame = self.frames.last().map(|frame| FrameState {
+      function: frame.function.to_string(),
+      ip: frame.ip,
+      base: frame.base,
+      depth: self.frames.len(),
+    });
+
+    ExecutionState {
+      instruction,
+      stack,
+      frame,
+    }
+  }
+}
+
+fn truncate(mut displayed: String) -> String {
+  if let Some((index, _)) = displayed.char_indices().nth(MAX_DISPLAY_CHARS) {
+    displayed.truncate(index);
+    displayed.push('…');
+  }
+
+  displayed
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+  use crate::compiler;
+  use crate::parser;
+  use crate::resolver;
+
+  fn start(vm: &mut Vm<Vec<u8>>, source: &str) {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    vm.start(compiler::compile(&statements, &resolution).unwrap())
+      .unwrap();
+  }
+
+  fn steps(source: &str) -> Vec<ExecutionState> {
+    let mut vm = Vm::new(Vec::new());
+    start(&mut vm, source);
+
+    std::iter::from_fn(|| vm.step().unwrap()).collect()
+  }
+
+  #[test]
+  fn test_step() {
+    let steps = steps("var a = 1;\nprint a + 2;");
+    let trace: Vec<_> = steps
+      .iter()
+      .map(|step| (step.instruction.name, step.stack.join(" ")))
+      .collect();
+
+    assert_eq!(
+      trace,
+      vec![
+        ("OP_CONSTANT", String::from("<script> 1.0")),
+        ("OP_DEFINE_GLOBAL", String::from("<script>")),
+        ("OP_GET_GLOBAL", String::from("<script> 1.0")),
+        ("OP_CONSTANT", String::from("<script> 1.0 2.0")),
+        ("OP_ADD", String::from("<script> 3.0")),
+        ("OP_PRINT", String::from("<script>")),
+        ("OP_NIL", String::from("<script> nil")),
+        ("OP_RETURN", String::new()),
+      ]
+    );
+
+    assert_eq!(steps[4].instruction.offset, 8);
+    assert_eq!(
+      steps[4].frame,
+      Some(FrameState {
+        function: String::from("<script>"),
+        ip: 9,
+        base: 0,
+        depth: 1,
+      })
+    );
+    assert_eq!(steps.last().unwrap().frame, None);
+  }
+
+  #[test]
+  fn test_step_into_calls() {
+    let steps = steps("fun f(n) {\n  return n;\n}\nprint f(4);");
+    let depths: Vec<_> = steps
+      .iter()
+      .map(|step| step.frame.as_ref().map_or(0, |frame| frame.depth))
+      .collect();
+
+    assert_eq!(depths, vec![1, 1, 1, 1, 2, 2, 1, 1, 1, 0]);
+
+    let call = &steps[4];
+    assert_eq!(call.instruction.name, "OP_CALL");
+    assert_eq!(call.stack, vec!["<script>", "<fn f>", "4.0"]);
+    assert_eq!(call.frame.as_ref().unwrap().function, "<fn f>");
+    assert_eq!(call.frame.as_ref().unwrap().base, 1);
+  }
+
+  #[test]
+  fn test_step_matches_run() {
+    let source =
+      "fun fib(n) {\n  if (n < 2) return n;\n  return fib(n - 1) + fib(n - 2);\n}\nprint fib(10);";
+    let mut stepped = Vm::new(Vec::new());
+    start(&mut stepped, source);
+    while stepped.step().unwrap().is_some() {}
+
+    let mut run = Vm::new(Vec::new());
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+    run
+      .interpret(compiler::compile(&statements, &resolution).unwrap())
+      .unwrap();
+
+    assert_eq!(stepped.into_output(), run.into_output());
+  }
+
+  #[test]
+  fn test_step_error() {
+    let mut vm = Vm::new(Vec::new());
+    start(&mut vm, "print 1;\nprint -nil;");
+
+    let error = std::iter::repeat_with(|| vm.step())
+      .find_map(Result::err)
+      .unwrap();
+
+    assert!(matches!(error, VmError::Runtime(error) if error.line == 2));
+    assert_eq!(vm.step(), Ok(None));
+
+    // Ready for the next script
+    start(&mut vm, "print 2;");
+    while vm.step().unwrap().is_some() {}
+    assert_eq!(String::from_utf8(vm.into_output()).unwrap(), "1.0\n2.0\n");
+  }
+
+  #[test]
+  fn test_serialize() {
+    let mut vm = Vm::new(Vec::new());
+    start(&mut vm, "print 1;");
+
+    assert_eq!(
+      serde_json::to_value(vm.step().unwrap().unwrap()).unwrap(),
+      json!({
+        "instruction": {
+          "offset": 0,
+          "line": 1,
+          "name": "OP_CONSTANT",
+          "operand": 0,
+          "constant": "1.0",
+        },
+        "stack": ["<script>", "1.0"],
+        "frame": { "function": "<script>", "ip": 2, "base": 0, "depth": 1 },
+      })
+    );
+  }
+
+  #[test]
+  fn test_long_values_are_truncated() {
+    let steps = steps("var s = \"ü\";\nfor (var i = 0; i < 9; i = i + 1) s = s + s;\nprint s;");
+    let longest = steps
+      .iter()
+      .flat_map(|step| &step.stack)
+      .max_by_key(|value| value.chars().count())
+      .unwrap();
+
+    assert_eq!(longest.chars().count(), MAX_DISPLAY_CHARS + 1);
+    assert!(longest.ends_with("üü…"));
+  }
+}
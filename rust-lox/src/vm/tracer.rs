@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::io::Write;
+
+use serde_json::json;
+
+use super::Vm;
+use crate::debug;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceFormat {
+  // The stack and the instruction, as clox's DEBUG_TRACE_EXECUTION prints them
+  Text,
+  // A JSON object per instruction, for scripts to go through
+  JsonLines,
+}
+
+// Writes out every instruction the VM is about to run along with the stack.
+// The clox flag is compile time, this one gets handed to a VM when needed.
+pub struct Tracer {
+  sink: Box<dyn Write>,
+  format: TraceFormat,
+  // Names of the functions to trace, "script" for top level code. Everything
+  // when empty.
+  functions: HashSet<String>,
+}
+
+impl Tracer {
+  pub fn new(sink: impl Write + 'static, format: TraceFormat) -> Tracer {
+    Tracer {
+      sink: Box::new(sink),
+      format,
+      functions: HashSet::new(),
+    }
+  }
+
+  // Narrows the trace down to the given function, can be called for several
+  pub fn only(mut self, function: &str) -> Tracer {
+    self.functions.insert(String::from(function));
+    self
+  }
+
+  fn traces(&self, function: &str) -> bool {
+    self.functions.is_empty() || self.functions.contains(function)
+  }
+}
+
+impl<W: Write> Vm<W> {
+  // Starts tracing, or stops it with None. Gives back the tracer there was.
+  pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+    std::mem::replace(&mut self.tracer, tracer)
+  }
+
+  pub(super) fn trace(&mut self) {
+    let Vm {
+      tracer,
+      stack,
+      frames,
+      heap,
+      ..
+    } = self;
+
+    let (tracer, frame) = match (tracer, frames.last()) {
+      (Some(tracer), Some(frame)) => (tracer, frame),
+      _ => return,
+    };
+
+    let function = frame.function.name.as_deref().unwrap_or("script");
+
+    if !tracer.traces(function) {
+      return;
+    }
+
+    let chunk = &frame.function.chunk;
+
+    let line = match tracer.format {
+      TraceFormat::Text => {
+        let mut line = String::from("          ");
+
+        for value in stack.iter() {
+          write!(line, "[ {} ]", heap.display(*value)).unwrap();
+        }
+
+        line.push('\n');
+        line.push_str(&debug::instruction_text(chunk, frame.ip));
+        line
+      }
+      TraceFormat::JsonLines => {
+        let stack: Vec<_> = stack
+          .iter()
+          .map(|value| heap.display(*value).to_string())
+          .collect();
+
+        let event = json!({
+          "function": function,
+          "depth": frames.len(),
+          "instruction": debug::disassemble_instruction(chunk, frame.ip),
+          "stack": stack,
+        });
+
+        format!("{}\n", event)
+      }
+    };
+
+    // Best effort, a sink that fails doesn't stop the program
+    let _ = tracer.sink.write_all(line.as_bytes());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::RefCell;
+  use std::io;
+  use std::rc::Rc;
+
+  use serde_json::Value;
+
+  use super::*;
+  use crate::compiler;
+  use crate::parser;
+  use crate::resolver;
+
+  // Lets the test read what the tracer wrote while the VM still owns it
+  #[derive(Clone, Default)]
+  struct Shared(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for Shared {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Shared {
+    fn text(&self) -> String {
+      String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+  }
+
+  fn run(vm: &mut Vm<Vec<u8>>, source: &str) {
+    let statements = parser::parse(source).unwrap();
+    let resolution = resolver::resolve(&statements).unwrap();
+
+    vm.interpret(compiler::compile(&statements, &resolution).unwrap())
+      .unwrap();
+  }
+
+  #[test]
+  fn test_text() {
+    let sink = Shared::default();
+    let mut vm = Vm::new(Vec::new());
+    vm.set_tracer(Some(Tracer::new(sink.clone(), TraceFormat::Text)));
+
+    run(&mut vm, "print 1 + 2;");
+
+    assert_eq!(
+      sink.text(),
+      "          [ <script> ]\n\
+       0000    1 OP_CONSTANT         0 '1.0'\n          \
+       [ <script> ][ 1.0 ]\n\
+       0002    | OP_CONSTANT         1 '2.0'\n          \
+       [ <script> ][ 1.0 ][ 2.0 ]\n\
+       0004    | OP_ADD\n          \
+       [ <script> ][ 3.0 ]\n\
+       0005    | OP_PRINT\n          \
+       [ <script> ]\n\
+       0006    | OP_NIL\n          \
+       [ <script> ][ nil ]\n\
+       0007    | OP_RETURN\n"
+    );
+    assert_eq!(vm.output(), b"3.0\n");
+  }
+
+  #[test]
+  fn test_toggle() {
+    let sink = Shared::default();
+    let mut vm = Vm::new(Vec::new());
+
+    run(&mut vm, "print 1;");
+    vm.set_tracer(Some(Tracer::new(sink.clone(), TraceFormat::Text)));
+    run(&mut vm, "print 2;");
+    let tracer = vm.set_tracer(None);
+    run(&mut vm, "print 3;");
+
+    assert!(tracer.is_some());
+    assert_eq!(sink.text().matches("OP_PRINT").count(), 1);
+    assert!(sink.text().contains("'2.0'"));
+  }
+
+  #[test]
+  fn test_json_lines() {
+    let sink = Shared::default();
+    let mut vm = Vm::new(Vec::new());
+    let tracer = Tracer::new(sink.clone(), TraceFormat::JsonLines).only("add");
+    vm.set_tracer(Some(tracer));
+
+    run(
+      &mut vm,
+      "fun add(a, b) {\n  return a + b;\n}\nfun other() {\n  return 0;\n}\nprint add(1, other());",
+    );
+
+    let events: Vec<Value> = sink
+      .text()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+
+    assert_eq!(events.len(), 4);
+    assert!(events.iter().all(|event| event["function"] == "add"));
+    assert_eq!(
+      events[2],
+      json!({
+        "function": "add",
+        "depth": 2,
+        "instruction": { "offset": 4, "line": 2, "name": "OP_ADD" },
+        "stack": ["<script>", "<fn add>", "1.0", "0.0", "1.0", "0.0"],
+      })
+    );
+  }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+#[cfg(feature = "nan-boxing")]
+use std::fmt;
+use std::rc::Rc;
+
+use super::memory::ObjRef;
+use crate::chunk::CompiledFunction;
+use crate::native::Native;
+
+// What a value holds. Values are kept packed and get unpacked to look at.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Unpacked {
+  Nil,
+  Bool(bool),
+  Number(f64),
+  // Objects live in the VM's heap, values only hold handles to them
+  Object(ObjRef),
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Value(Unpacked);
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+  pub const NIL: Value = Value(Unpacked::Nil);
+
+  pub fn pack(unpacked: Unpacked) -> Value {
+    Value(unpacked)
+  }
+
+  pub fn unpack(self) -> Unpacked {
+    self.0
+  }
+}
+
+// Every value in a single u64, as in the optimization chapter of the book.
+// Numbers are stored as they are, everything else hides in the payload of a
+// quiet NaN: nil and booleans as small tags, objects with the sign bit set and
+// their handle in the low bits.
+#[cfg(feature = "nan-boxing")]
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const TAG_NIL: u64 = 1;
+#[cfg(feature = "nan-boxing")]
+const TAG_FALSE: u64 = 2;
+#[cfg(feature = "nan-boxing")]
+const TAG_TRUE: u64 = 3;
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+  pub const NIL: Value = Value(QNAN | TAG_NIL);
+
+  pub fn pack(unpacked: Unpacked) -> Value {
+    match unpacked {
+      Unpacked::Nil => Value::NIL,
+      Unpacked::Bool(false) => Value(QNAN | TAG_FALSE),
+      Unpacked::Bool(true) => Value(QNAN | TAG_TRUE),
+      // Other NaNs can have the bits of a tagged value, like ones read from
+      // bytecode files or made by arithmetic
+      Unpacked::Number(number) if number.is_nan() => Value(f64::NAN.to_bits()),
+      Unpacked::Number(number) => Value(number.to_bits()),
+      Unpacked::Object(reference) => Value(SIGN_BIT | QNAN | reference.0 as u64),
+    }
+  }
+
+  pub fn unpack(self) -> Unpacked {
+    if self.0 & QNAN != QNAN {
+      Unpacked::Number(f64::from_bits(self.0))
+    } else if self.0 & SIGN_BIT != 0 {
+      Unpacked::Object(ObjRef((self.0 & !(SIGN_BIT | QNAN)) as u32))
+    } else {
+      match self.0 & !QNAN {
+        TAG_NIL => Unpacked::Nil,
+        TAG_FALSE => Unpacked::Bool(false),
+        TAG_TRUE => Unpacked::Bool(true),
+        _ => unreachable!("[bug] malformed value {:#x}", self.0),
+      }
+    }
+  }
+}
+
+// Comparing bits would make NaN equal to itself
+#[cfg(feature = "nan-boxing")]
+impl PartialEq for Value {
+  fn eq(&self, other: &Value) -> bool {
+    self.unpack() == other.unpack()
+  }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl fmt::Debug for Value {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:?}", self.unpack())
+  }
+}
+
+impl Value {
+  pub fn bool(boolean: bool) -> Value {
+    Value::pack(Unpacked::Bool(boolean))
+  }
+
+  pub fn number(number: f64) -> Value {
+    Value::pack(Unpacked::Number(number))
+  }
+
+  pub fn object(reference: ObjRef) -> Value {
+    Value::pack(Unpacked::Object(reference))
+  }
+
+  pub fn as_object(self) -> Option<ObjRef> {
+    match self.unpack() {
+      Unpacked::Object(reference) => Some(reference),
+      _ => None,
+    }
+  }
+
+  pub fn is_truthy(self) -> bool {
+    !matches!(self.unpack(), Unpacked::Nil | Unpacked::Bool(false))
+  }
+}
+
+#[derive(Debug)]
+pub enum Object {
+  String(Rc<str>),
+  Closure(Closure),
+  Upvalue(Upvalue),
+  Native(Rc<Native>),
+  Class(Class),
+  Instance(Instance),
+  BoundMethod(BoundMethod),
+}
+
+// Open while the variable is still on the stack, at the given slot
+#[derive(Debug)]
+pub enum Upvalue {
+  Open(usize),
+  Closed(Value),
+}
+
+// Compiled functions are immutable and can't point back at objects, so they
+// stay outside of the heap
+#[derive(Debug)]
+pub struct Closure {
+  pub function: Rc<CompiledFunction>,
+  pub upvalues: Vec<ObjRef>,
+}
+
+#[derive(Debug)]
+pub struct Class {
+  pub name: Rc<str>,
+  // Inherited methods are copied in, like clox does
+  pub methods: HashMap<Rc<str>, ObjRef>,
+}
+
+impl Class {
+  pub fn new(name: Rc<str>) -> Class {
+    Class {
+      name,
+      methods: HashMap::new(),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+  pub class: ObjRef,
+  pub fields: HashMap<Rc<str>, Value>,
+}
+
+impl Instance {
+  pub fn new(class: ObjRef) -> Instance {
+    Instance {
+      class,
+      fields: HashMap::new(),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+  pub receiver: ObjRef,
+  pub method: ObjRef,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pack() {
+    let values = [
+      Unpacked::Nil,
+      Unpacked::Bool(false),
+      Unpacked::Bool(true),
+      Unpacked::Number(0.0),
+      Unpacked::Number(-1.5),
+      Unpacked::Number(f64::INFINITY),
+      Unpacked::Object(ObjRef(0)),
+      Unpacked::Object(ObjRef(u32::MAX)),
+    ];
+
+    for value in &values {
+      assert_eq!(Value::pack(*value).unpack(), *value);
+    }
+
+    for nan in &[Value::number(f64::NAN), Value::number(-f64::NAN)] {
+      assert!(matches!(nan.unpack(), Unpacked::Number(number) if number.is_nan()));
+      assert_ne!(nan, nan);
+    }
+
+    assert_ne!(Value::NIL, Value::bool(false));
+  }
+
+  #[test]
+  fn test_pack_nan_bits() {
+    // Quiet NaNs with the bits of an object handle and of nil
+    for bits in &[
+      0xfffc_0000_0000_3039,
+      0xfffc_0000_0000_0000,
+      0x7ffc_0000_0000_0001,
+    ] {
+      let value = Value::number(f64::from_bits(*bits));
+      assert!(matches!(value.unpack(), Unpacked::Number(number) if number.is_nan()));
+    }
+  }
+
+  #[cfg(feature = "nan-boxing")]
+  #[test]
+  fn test_size() {
+    assert_eq!(std::mem::size_of::<Value>(), 8);
+  }
+}